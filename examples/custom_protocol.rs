@@ -0,0 +1,101 @@
+//! Reusing the stream and codec machinery with an application-defined message
+//! type instead of the echo protocol's `Message`.
+
+use iroh::{
+    Endpoint,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler, Router},
+};
+use n0_error::{Result, StdResultExt};
+use serde::{Deserialize, Serialize};
+use wstest::{
+    Bincode, Compression, FramedConnection, MessageEnvelope, PROTOCOL_VERSION, SendQueue,
+    encode_message, recv_message,
+};
+
+const GAME_ALPN: &[u8] = b"example/game/0";
+
+/// Inputs the client lets wait for a stream before `send` waits too
+const INPUT_QUEUE: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum GameMsg {
+    Join { name: String },
+    Move { x: i32, y: i32 },
+    Snapshot { players: Vec<(String, i32, i32)> },
+}
+
+#[derive(Debug, Clone)]
+struct Game;
+
+impl ProtocolHandler for Game {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        // Inputs arrive one per stream, snapshots go out on a framed stream
+        let mut snapshots = FramedConnection::accept(&connection, Bincode)
+            .await
+            .map_err(AcceptError::from_err)?;
+        let mut name = String::new();
+        while let Ok(recv) = connection.accept_uni().await {
+            let input: MessageEnvelope<GameMsg> = recv_message(&Bincode, recv)
+                .await
+                .map_err(AcceptError::from_err)?;
+            match input.body {
+                GameMsg::Join { name: joined } => name = joined,
+                GameMsg::Move { x, y } => {
+                    let snapshot = MessageEnvelope::new(
+                        input.id,
+                        GameMsg::Snapshot {
+                            players: vec![(name.clone(), x, y)],
+                        },
+                    );
+                    snapshots
+                        .send(&snapshot)
+                        .await
+                        .map_err(AcceptError::from_err)?;
+                }
+                GameMsg::Snapshot { .. } => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let router = Router::builder(Endpoint::bind().await?)
+        .accept(GAME_ALPN, Game)
+        .spawn();
+
+    let conn = Endpoint::bind()
+        .await?
+        .connect(router.endpoint().addr(), GAME_ALPN)
+        .await?;
+    let mut snapshots = FramedConnection::open(&conn, Bincode).await?;
+    // The server only sees the framed stream once something is written on it
+    snapshots.send(&()).await?;
+
+    // `recv_message` reads the current version's layout, which does not
+    // depend on the ALPN the connection speaks
+    let inputs = SendQueue::new(conn.clone(), INPUT_QUEUE);
+    let encode = |input: &MessageEnvelope<GameMsg>| {
+        encode_message(&Bincode, Compression::None, PROTOCOL_VERSION, input)
+    };
+
+    let join = MessageEnvelope::new(
+        0,
+        GameMsg::Join {
+            name: "alice".to_string(),
+        },
+    );
+    inputs.send(encode(&join)?).await?;
+    for (id, (x, y)) in [(1, 2), (3, 5), (8, 13)].into_iter().enumerate() {
+        let input = MessageEnvelope::new(id as u64 + 1, GameMsg::Move { x, y });
+        inputs.send(encode(&input)?).await?;
+        let snapshot: Option<MessageEnvelope<GameMsg>> = snapshots.recv().await?;
+        println!("{:?}", snapshot);
+    }
+
+    conn.close(0u32.into(), b"done");
+    router.shutdown().await.anyerr()?;
+    Ok(())
+}
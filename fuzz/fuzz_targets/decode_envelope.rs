@@ -0,0 +1,23 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wstest::{
+    Bincode, Cbor, Json, PROTOCOL_VERSION, Postcard, ProtocolConfig, protocol::MIN_PROTOCOL_VERSION,
+};
+
+// The first byte picks the codec and protocol version, the rest is what a
+// peer sent, compression header included
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, bytes)) = data.split_first() else {
+        return;
+    };
+    let config = ProtocolConfig::default();
+    let versions = PROTOCOL_VERSION - MIN_PROTOCOL_VERSION + 1;
+    let version = MIN_PROTOCOL_VERSION + (selector >> 2) as u32 % versions;
+    let _ = match selector & 3 {
+        0 => config.decode(&Bincode, version, bytes).map(drop),
+        1 => config.decode(&Json, version, bytes).map(drop),
+        2 => config.decode(&Postcard, version, bytes).map(drop),
+        _ => config.decode(&Cbor, version, bytes).map(drop),
+    };
+});
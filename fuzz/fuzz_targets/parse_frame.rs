@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wstest::{MAX_MESSAGE_SIZE, parse_frame};
+
+// Split a buffer into frames the way a reader would, decoding each payload
+fuzz_target!(|data: &[u8]| {
+    let mut buf = data;
+    while let Ok(Some((payload, used))) = parse_frame(buf, MAX_MESSAGE_SIZE) {
+        assert!(used <= buf.len());
+        let _ = wstest::decode_message(payload);
+        buf = &buf[used..];
+    }
+});
@@ -0,0 +1,41 @@
+use std::collections::HashSet;
+
+use iroh::{EndpointId, endpoint::VarInt};
+
+use crate::close::CloseReason;
+
+/// Application close code for connections from peers the policy rejects
+pub const ACCESS_DENIED: VarInt = CloseReason::AccessDenied.code();
+
+/// Which peers a server is willing to talk to, decided by their EndpointId
+///
+/// EndpointIds are authenticated by the QUIC handshake, so a peer cannot claim
+/// an id it does not hold the key for.
+#[derive(Debug, Clone, Default)]
+pub enum AccessPolicy {
+    /// Serve everyone
+    #[default]
+    AllowAll,
+    /// Serve only these peers
+    AllowList(HashSet<EndpointId>),
+    /// Serve everyone except these peers
+    DenyList(HashSet<EndpointId>),
+}
+
+impl AccessPolicy {
+    pub fn allow_list(ids: impl IntoIterator<Item = EndpointId>) -> Self {
+        AccessPolicy::AllowList(ids.into_iter().collect())
+    }
+
+    pub fn deny_list(ids: impl IntoIterator<Item = EndpointId>) -> Self {
+        AccessPolicy::DenyList(ids.into_iter().collect())
+    }
+
+    pub fn is_allowed(&self, id: &EndpointId) -> bool {
+        match self {
+            AccessPolicy::AllowAll => true,
+            AccessPolicy::AllowList(ids) => ids.contains(id),
+            AccessPolicy::DenyList(ids) => !ids.contains(id),
+        }
+    }
+}
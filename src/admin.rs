@@ -0,0 +1,216 @@
+use std::{
+    collections::HashMap,
+    future::ready,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use iroh::{Endpoint, EndpointAddr, EndpointId, endpoint::VarInt};
+use n0_error::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access::AccessPolicy,
+    close::CloseReason,
+    codec::Codec,
+    rpc::{Rpc, RpcClient, RpcServer},
+    server::Echo,
+};
+
+/// ALPN of the operator control channel, served next to the echo protocol
+pub const ADMIN_ALPN: &[u8] = b"iroh-example/admin/0";
+
+/// Application close code for connections an operator kicked or banned
+pub const KICKED: VarInt = CloseReason::Kicked.code();
+
+// ====================
+// Bans
+// ====================
+
+/// A banned peer, and until when if the ban expires
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub peer: EndpointId,
+    pub until: Option<SystemTime>,
+}
+
+/// Peers refused by a server on top of its [`AccessPolicy`], changeable at
+/// runtime
+///
+/// Clones share the same list.
+#[derive(Debug, Clone, Default)]
+pub struct Bans(Arc<Mutex<HashMap<EndpointId, Option<SystemTime>>>>);
+
+impl Bans {
+    /// Ban `peer` for `duration`, or for good if `None`
+    pub fn ban(&self, peer: EndpointId, duration: Option<Duration>) {
+        let until = duration.map(|duration| SystemTime::now() + duration);
+        self.0.lock().expect("poisoned").insert(peer, until);
+    }
+
+    /// Lift the ban on `peer`, returning false if it was not banned
+    pub fn unban(&self, peer: &EndpointId) -> bool {
+        self.0.lock().expect("poisoned").remove(peer).is_some()
+    }
+
+    pub fn is_banned(&self, peer: &EndpointId) -> bool {
+        let mut bans = self.0.lock().expect("poisoned");
+        prune(&mut bans);
+        bans.contains_key(peer)
+    }
+
+    /// Every ban still in force
+    pub fn list(&self) -> Vec<Ban> {
+        let mut bans = self.0.lock().expect("poisoned");
+        prune(&mut bans);
+        let bans = bans.iter().map(|(peer, until)| Ban {
+            peer: *peer,
+            until: *until,
+        });
+        bans.collect()
+    }
+}
+
+fn prune(bans: &mut HashMap<EndpointId, Option<SystemTime>>) {
+    let now = SystemTime::now();
+    bans.retain(|_, until| until.is_none_or(|until| until > now));
+}
+
+// ====================
+// Commands
+// ====================
+
+/// A connected peer, as listed for an operator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub peer: EndpointId,
+    pub rtt: Duration,
+    /// Time since the peer was last heard from
+    pub idle: Duration,
+    /// The name the peer logged in with, if it has a session
+    pub name: Option<String>,
+}
+
+/// What a server is doing, as reported to an operator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub connections: usize,
+    /// Stream handlers running now
+    pub in_flight: usize,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub rooms: usize,
+    pub players: usize,
+    /// Clients waiting for a match
+    pub queued: usize,
+    pub bans: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanRequest {
+    pub peer: EndpointId,
+    /// How long the ban lasts, for good if `None`
+    pub duration: Option<Duration>,
+}
+
+/// Every connected peer
+#[derive(Debug)]
+pub struct ListConnections;
+
+impl Rpc for ListConnections {
+    const NAME: &'static str = "admin/connections";
+    type Req = ();
+    type Resp = Vec<ConnectionInfo>;
+}
+
+/// Close the connection of a peer, answering whether it was connected
+#[derive(Debug)]
+pub struct Kick;
+
+impl Rpc for Kick {
+    const NAME: &'static str = "admin/kick";
+    type Req = EndpointId;
+    type Resp = bool;
+}
+
+/// Ban a peer and kick it if connected
+#[derive(Debug)]
+pub struct BanPeer;
+
+impl Rpc for BanPeer {
+    const NAME: &'static str = "admin/ban";
+    type Req = BanRequest;
+    type Resp = ();
+}
+
+/// Lift a ban, answering whether the peer was banned
+#[derive(Debug)]
+pub struct Unban;
+
+impl Rpc for Unban {
+    const NAME: &'static str = "admin/unban";
+    type Req = EndpointId;
+    type Resp = bool;
+}
+
+#[derive(Debug)]
+pub struct ListBans;
+
+impl Rpc for ListBans {
+    const NAME: &'static str = "admin/bans";
+    type Req = ();
+    type Resp = Vec<Ban>;
+}
+
+#[derive(Debug)]
+pub struct Stats;
+
+impl Rpc for Stats {
+    const NAME: &'static str = "admin/stats";
+    type Req = ();
+    type Resp = ServerStats;
+}
+
+/// The control channel of `echo`, served only to `operators`
+pub fn server<C: Codec>(echo: Echo<C>, operators: AccessPolicy) -> RpcServer<C> {
+    RpcServer::new(echo.codec().clone())
+        .with_access(operators)
+        .register::<ListConnections, _, _>({
+            let echo = echo.clone();
+            move |()| ready(Ok(echo.connections()))
+        })
+        .register::<Kick, _, _>({
+            let echo = echo.clone();
+            move |peer| ready(Ok(echo.kick(&peer)))
+        })
+        .register::<BanPeer, _, _>({
+            let echo = echo.clone();
+            move |ban: BanRequest| {
+                echo.ban(ban.peer, ban.duration);
+                ready(Ok(()))
+            }
+        })
+        .register::<Unban, _, _>({
+            let echo = echo.clone();
+            move |peer| ready(Ok(echo.bans().unban(&peer)))
+        })
+        .register::<ListBans, _, _>({
+            let echo = echo.clone();
+            move |()| ready(Ok(echo.bans().list()))
+        })
+        .register::<Stats, _, _>({
+            let echo = echo.clone();
+            move |()| ready(Ok(echo.stats()))
+        })
+}
+
+/// Connect to the control channel of the server at `addr` from `endpoint`,
+/// whose id must be one of the server's operators
+pub async fn connect<C: Codec>(
+    endpoint: &Endpoint,
+    addr: EndpointAddr,
+    codec: C,
+) -> Result<RpcClient<C>> {
+    let conn = endpoint.connect(addr, ADMIN_ALPN).await?;
+    Ok(RpcClient::new(conn, codec))
+}
@@ -0,0 +1,218 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use iroh::EndpointId;
+use n0_error::{Result, StdResultExt};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{capture::Direction, protocol::MessageKind};
+
+/// Size at which [`AuditLog`] starts a new file by default
+pub const AUDIT_ROTATE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How many rotated files [`AuditLog`] keeps by default
+pub const AUDIT_KEEP: usize = 4;
+
+// ====================
+// Audit Records
+// ====================
+
+/// What became of an audited message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Received and answered, or consumed without needing an answer
+    Handled,
+    /// Received, then dropped unanswered by middleware or the app, or for
+    /// arriving past its deadline
+    Dropped,
+    /// Received, but failed to decode
+    Malformed,
+    /// Handed to the transport
+    Sent,
+    /// Could not be sent
+    Failed,
+}
+
+impl Outcome {
+    /// The outcome of sending, [`Outcome::Sent`] or [`Outcome::Failed`]
+    pub(crate) fn of_send<T>(sent: &Result<T>) -> Self {
+        match sent {
+            Ok(_) => Outcome::Sent,
+            Err(_) => Outcome::Failed,
+        }
+    }
+
+    /// The outcome of handling, [`Outcome::Handled`] unless there is no
+    /// answer where one was expected
+    pub(crate) fn of_answer<T>(answer: &Option<T>) -> Self {
+        match answer {
+            Some(_) => Outcome::Handled,
+            None => Outcome::Dropped,
+        }
+    }
+}
+
+/// One line of an audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the Unix epoch
+    pub at_ms: u64,
+    pub peer: EndpointId,
+    pub direction: Direction,
+    /// Envelope id, absent for datagrams and for what failed to decode that
+    /// far
+    pub id: Option<u64>,
+    /// Absent for what failed to decode
+    pub kind: Option<MessageKind>,
+    /// Encoded size in bytes
+    pub size: usize,
+    pub outcome: Outcome,
+}
+
+// ====================
+// Audit Log
+// ====================
+
+/// Appends an [`AuditRecord`] for every message an
+/// [`Echo`](crate::server::Echo) handler receives or sends to a file, as JSON
+/// lines
+///
+/// Once the file reaches its size limit it is renamed to `<path>.1`, older
+/// files moving up by one and the oldest being deleted, and a fresh file is
+/// started. Clones write to the same file, and each line is flushed as it is
+/// written.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    file: Arc<Mutex<Rotating>>,
+}
+
+#[derive(Debug)]
+struct Rotating {
+    path: PathBuf,
+    file: BufWriter<File>,
+    len: u64,
+    max_len: u64,
+    keep: usize,
+}
+
+impl AuditLog {
+    /// Append to the log at `path`, creating it if needed, rotating at
+    /// [`AUDIT_ROTATE_SIZE`] and keeping [`AUDIT_KEEP`] old files
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let (file, len) = append(&path)?;
+        let file = Rotating {
+            path,
+            file,
+            len,
+            max_len: AUDIT_ROTATE_SIZE,
+            keep: AUDIT_KEEP,
+        };
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Start a new file once this one holds `max_len` bytes, keeping `keep`
+    /// old files; `keep` 0 deletes the full file instead
+    pub fn with_rotation(self, max_len: u64, keep: usize) -> Self {
+        {
+            let mut file = self.file.lock().expect("poisoned");
+            file.max_len = max_len.max(1);
+            file.keep = keep;
+        }
+        self
+    }
+
+    /// Append `record`; a failed write is logged rather than failing the
+    /// traffic it records
+    pub fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("error encoding audit record: {:#}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut file = self.file.lock().expect("poisoned");
+        if let Err(e) = file.write(&line) {
+            warn!("error writing audit log: {:#}", e);
+        }
+    }
+}
+
+impl Rotating {
+    fn write(&mut self, line: &[u8]) -> Result<()> {
+        if self.len > 0 && self.len + line.len() as u64 > self.max_len {
+            self.rotate()?;
+        }
+        self.file.write_all(line).anyerr()?;
+        self.file.flush().anyerr()?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush().anyerr()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path).std_context("deleting full audit log")?;
+        } else {
+            rename_if_exists(&rotated(&self.path, self.keep), None)?;
+            for n in (1..self.keep).rev() {
+                rename_if_exists(&rotated(&self.path, n), Some(&rotated(&self.path, n + 1)))?;
+            }
+            fs::rename(&self.path, rotated(&self.path, 1)).std_context("rotating audit log")?;
+        }
+        (self.file, self.len) = append(&self.path)?;
+        Ok(())
+    }
+}
+
+/// `path` opened for appending, along with its current length
+fn append(path: &Path) -> Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_std_context(|_| format!("opening audit log {}", path.display()))?;
+    let len = file.metadata().anyerr()?.len();
+    Ok((BufWriter::new(file), len))
+}
+
+/// The `n`th rotated file of the log at `path`
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    name.into()
+}
+
+/// Move `from` to `to`, or delete it without `to`, if it exists
+fn rename_if_exists(from: &Path, to: Option<&Path>) -> Result<()> {
+    let result = match to {
+        Some(to) => fs::rename(from, to),
+        None => fs::remove_file(from),
+    };
+    match result {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_std_context(|_| format!("rotating {}", from.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Milliseconds since the Unix epoch
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| {
+            elapsed.as_millis().try_into().unwrap_or(u64::MAX)
+        })
+}
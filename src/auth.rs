@@ -0,0 +1,370 @@
+use std::{collections::HashMap, fmt, time::Duration};
+
+use iroh::{
+    EndpointId, PublicKey, SecretKey, Signature,
+    endpoint::{Connection, VarInt},
+};
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    close::CloseReason,
+    codec::Codec,
+    framed::FramedConnection,
+    permissions::Capabilities,
+    protocol::{ErrorCode, RemoteError},
+    sealing::{Sealer, SessionKey},
+};
+
+/// Application close code for connections that failed authentication
+pub const AUTH_FAILED: VarInt = CloseReason::AuthFailed.code();
+
+/// How long either side waits for the other's half of the handshake
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest credential a server is willing to read
+const MAX_CREDENTIAL_SIZE: usize = 4096;
+
+/// Label for deriving the challenge from the connection's TLS secrets
+const CHALLENGE_LABEL: &[u8] = b"iroh-example/auth/0";
+
+/// Label for deriving what a session key offered in the handshake is bound to
+const SESSION_KEY_LABEL: &[u8] = b"iroh-example/auth/session-key/0";
+
+/// Produces the credential a client answers a server's challenge with
+///
+/// The challenge is derived from the server's nonce and the secrets of the
+/// connection it was sent on, so an answer cannot be replayed on, or relayed
+/// to, any other connection.
+pub trait AuthProvider: fmt::Debug + Send + Sync + 'static {
+    fn respond(&self, challenge: &[u8; 32]) -> Result<Vec<u8>>;
+}
+
+/// Decides whether a client's credential answers the challenge it was sent,
+/// and what the client may do once it does
+pub trait AuthVerifier: fmt::Debug + Send + Sync + 'static {
+    fn verify(&self, peer: EndpointId, challenge: &[u8; 32], credential: &[u8]) -> Result<()>;
+
+    /// What a client whose `credential` passed [`verify`](Self::verify) may
+    /// do on its connection, everything unless overridden
+    fn capabilities(&self, peer: EndpointId, credential: &[u8]) -> Capabilities {
+        let _ = (peer, credential);
+        Capabilities::all()
+    }
+}
+
+/// What the server sends first on the auth stream
+#[derive(Debug, Serialize, Deserialize)]
+struct Challenge {
+    nonce: [u8; 32],
+}
+
+/// The server's verdict on the credential, the error description on rejection
+type AuthResponse = std::result::Result<(), String>;
+
+/// What a client established in the auth handshake
+#[derive(Debug, Clone)]
+pub struct Authenticated {
+    /// What the client may do on its connection
+    pub capabilities: Capabilities,
+    /// The session key the client offered for sealing payloads to, signed by
+    /// its owner for this connection
+    pub session_key: Option<SessionKey>,
+}
+
+// ====================
+// Handshake
+// ====================
+
+/// Challenge the client on `conn` and wait for `verifier` to accept its
+/// answer, returning the capabilities it grants the client
+///
+/// The server opens a bidirectional stream carrying a random nonce, the client
+/// answers with its credential and the server replies with the outcome. A
+/// client that answers wrongly, or not within [`AUTH_TIMEOUT`], has its
+/// connection closed with [`AUTH_FAILED`].
+///
+/// A client may follow its credential with a session key, see
+/// [`authenticate_sealing`]. One not signed by its owner for this connection
+/// is ignored.
+pub async fn challenge<C: Codec>(
+    conn: &Connection,
+    codec: &C,
+    verifier: &dyn AuthVerifier,
+) -> Result<Authenticated> {
+    let result = tokio::time::timeout(AUTH_TIMEOUT, run_challenge(conn, codec, verifier))
+        .await
+        .unwrap_or_else(|_| Err(anyerr!("no answer to the auth challenge in time")));
+    if result.is_err() {
+        CloseReason::AuthFailed.close(conn, "authentication failed");
+    }
+    result
+}
+
+async fn run_challenge<C: Codec>(
+    conn: &Connection,
+    codec: &C,
+    verifier: &dyn AuthVerifier,
+) -> Result<Authenticated> {
+    let mut framed = FramedConnection::open(conn, codec.clone())
+        .await?
+        .with_max_frame_size(MAX_CREDENTIAL_SIZE);
+    let nonce: [u8; 32] = rand::random();
+    framed.send(&Challenge { nonce }).await?;
+    let credential: Vec<u8> = framed
+        .recv()
+        .await?
+        .std_context("stream finished before the credential")?;
+
+    let result = derive_challenge(conn, &nonce)
+        .and_then(|challenge| verifier.verify(conn.remote_id(), &challenge, &credential));
+    let response: AuthResponse = match &result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("{e:#}")),
+    };
+    framed.send(&response).await?;
+    framed.finish()?;
+    let session_key = match result {
+        Ok(()) => framed.recv::<SessionKey>().await?,
+        Err(_) => None,
+    };
+    let session_key = session_key.filter(|key| match check_session_key(conn, key) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("ignoring session key: {:#}", e);
+            false
+        }
+    });
+    // Let the verdict reach the client before a rejection closes the connection
+    let (mut send, _) = framed.into_streams();
+    send.stopped().await.ok();
+    result.map(|()| Authenticated {
+        capabilities: verifier.capabilities(conn.remote_id(), &credential),
+        session_key,
+    })
+}
+
+/// Answer the server's auth challenge on `conn` with `provider`'s credential
+///
+/// Must run before any other stream is used, see [`challenge`]. A rejected
+/// credential fails with [`ErrorCode::Unauthorized`].
+pub async fn authenticate<C: Codec>(
+    conn: &Connection,
+    codec: &C,
+    provider: &dyn AuthProvider,
+) -> Result<()> {
+    tokio::time::timeout(AUTH_TIMEOUT, run_authenticate(conn, codec, provider, None))
+        .await
+        .std_context("server sent no auth challenge in time")?
+}
+
+/// Like [`authenticate`], also offering the server a fresh session key of
+/// `sealer`, which peers seal payloads to while `conn` lasts
+pub async fn authenticate_sealing<C: Codec>(
+    conn: &Connection,
+    codec: &C,
+    provider: &dyn AuthProvider,
+    sealer: &Sealer,
+) -> Result<()> {
+    let handshake = run_authenticate(conn, codec, provider, Some(sealer));
+    tokio::time::timeout(AUTH_TIMEOUT, handshake)
+        .await
+        .std_context("server sent no auth challenge in time")?
+}
+
+async fn run_authenticate<C: Codec>(
+    conn: &Connection,
+    codec: &C,
+    provider: &dyn AuthProvider,
+    sealer: Option<&Sealer>,
+) -> Result<()> {
+    let mut framed = FramedConnection::accept(conn, codec.clone()).await?;
+    let Challenge { nonce } = framed
+        .recv()
+        .await?
+        .std_context("stream finished before the auth challenge")?;
+    let credential = provider.respond(&derive_challenge(conn, &nonce)?)?;
+    framed.send(&credential).await?;
+    if let Some(sealer) = sealer {
+        framed.send(&sealer.offer(session_binding(conn)?)).await?;
+    }
+    framed.finish()?;
+    let response: AuthResponse = framed
+        .recv()
+        .await?
+        .std_context("stream finished before the auth verdict")?;
+    response.map_err(|e| RemoteError::new(ErrorCode::Unauthorized, e).into())
+}
+
+/// Bind `nonce` to the TLS session of `conn`, which both ends share
+fn derive_challenge(conn: &Connection, nonce: &[u8; 32]) -> Result<[u8; 32]> {
+    let mut challenge = [0u8; 32];
+    conn.export_keying_material(&mut challenge, CHALLENGE_LABEL, nonce)
+        .map_err(|_| anyerr!("failed to derive the auth challenge"))?;
+    Ok(challenge)
+}
+
+/// What a session key offered on `conn` is bound to, which both ends share
+fn session_binding(conn: &Connection) -> Result<[u8; 32]> {
+    let mut binding = [0u8; 32];
+    conn.export_keying_material(&mut binding, SESSION_KEY_LABEL, &[])
+        .map_err(|_| anyerr!("failed to derive the session key binding"))?;
+    Ok(binding)
+}
+
+/// Check `key` was signed by its owner for `conn`
+fn check_session_key(conn: &Connection, key: &SessionKey) -> Result<()> {
+    if key.binding != session_binding(conn)? {
+        return Err(anyerr!("session key was offered for another connection"));
+    }
+    key.verify().map(|_| ())
+}
+
+// ====================
+// Credentials
+// ====================
+
+/// A secret shared by server and clients out of band
+///
+/// The answer is a keyed hash of the challenge, so the token itself never
+/// travels over the connection. Every client knowing the token gets the same
+/// capabilities.
+#[derive(Clone)]
+pub struct SharedToken {
+    key: blake3::Hash,
+    capabilities: Capabilities,
+}
+
+impl fmt::Debug for SharedToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedToken").finish_non_exhaustive()
+    }
+}
+
+impl SharedToken {
+    pub fn new(token: impl AsRef<[u8]>) -> Self {
+        Self {
+            key: blake3::hash(token.as_ref()),
+            capabilities: Capabilities::all(),
+        }
+    }
+
+    /// Grant clients knowing the token `capabilities` instead of everything
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    fn answer(&self, challenge: &[u8; 32]) -> blake3::Hash {
+        blake3::keyed_hash(self.key.as_bytes(), challenge)
+    }
+}
+
+impl AuthProvider for SharedToken {
+    fn respond(&self, challenge: &[u8; 32]) -> Result<Vec<u8>> {
+        Ok(self.answer(challenge).as_bytes().to_vec())
+    }
+}
+
+impl AuthVerifier for SharedToken {
+    fn verify(&self, _peer: EndpointId, challenge: &[u8; 32], credential: &[u8]) -> Result<()> {
+        let credential: [u8; 32] = credential
+            .try_into()
+            .std_context("malformed token credential")?;
+        // Hash comparison is constant time
+        if self.answer(challenge) == blake3::Hash::from_bytes(credential) {
+            Ok(())
+        } else {
+            Err(anyerr!("wrong token"))
+        }
+    }
+
+    fn capabilities(&self, _peer: EndpointId, _credential: &[u8]) -> Capabilities {
+        self.capabilities
+    }
+}
+
+/// An application-level keypair, independent of the endpoint's identity
+///
+/// The credential is the public key followed by its signature over the
+/// challenge; servers accept it through [`TrustedKeys`].
+#[derive(Clone)]
+pub struct KeyCredential {
+    secret: SecretKey,
+}
+
+impl fmt::Debug for KeyCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyCredential")
+            .field("public", &self.secret.public())
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyCredential {
+    pub fn new(secret: SecretKey) -> Self {
+        Self { secret }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        self.secret.public()
+    }
+}
+
+impl AuthProvider for KeyCredential {
+    fn respond(&self, challenge: &[u8; 32]) -> Result<Vec<u8>> {
+        let signature = self.secret.sign(challenge);
+        Ok([self.public().as_bytes().as_slice(), &signature.to_bytes()].concat())
+    }
+}
+
+/// Accepts [`KeyCredential`]s signed by any of a set of public keys, each
+/// with capabilities of its own
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys {
+    keys: HashMap<PublicKey, Capabilities>,
+}
+
+impl TrustedKeys {
+    /// Trust `keys`, granting each everything
+    pub fn new(keys: impl IntoIterator<Item = PublicKey>) -> Self {
+        Self {
+            keys: keys
+                .into_iter()
+                .map(|key| (key, Capabilities::all()))
+                .collect(),
+        }
+    }
+
+    /// Also trust `key`, granting it `capabilities`
+    pub fn with_key(mut self, key: PublicKey, capabilities: Capabilities) -> Self {
+        self.keys.insert(key, capabilities);
+        self
+    }
+}
+
+impl AuthVerifier for TrustedKeys {
+    fn verify(&self, _peer: EndpointId, challenge: &[u8; 32], credential: &[u8]) -> Result<()> {
+        let (key, signature) = credential
+            .split_first_chunk::<32>()
+            .std_context("malformed key credential")?;
+        let signature: &[u8; Signature::LENGTH] = signature
+            .try_into()
+            .std_context("malformed key credential")?;
+        let key = PublicKey::from_bytes(key).anyerr()?;
+        if !self.keys.contains_key(&key) {
+            return Err(anyerr!("untrusted key {}", key.fmt_short()));
+        }
+        key.verify(challenge, &Signature::from_bytes(signature))
+            .std_context("invalid signature")
+    }
+
+    fn capabilities(&self, _peer: EndpointId, credential: &[u8]) -> Capabilities {
+        credential
+            .first_chunk::<32>()
+            .and_then(|key| PublicKey::from_bytes(key).ok())
+            .and_then(|key| self.keys.get(&key).copied())
+            .unwrap_or(Capabilities::none())
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use futures::future::{try_join, try_join_all};
+use n0_error::{Result, anyerr};
+use tokio::sync::mpsc;
+
+use crate::{
+    client::Client,
+    codec::Codec,
+    framed::FramedConnection,
+    protocol::{Message, MessageEnvelope, decode_frame},
+    rtt::percentile,
+};
+
+/// What a benchmark run sends
+#[derive(Debug, Clone, Copy)]
+pub struct BenchConfig {
+    /// Payload bytes per message
+    pub size: usize,
+    /// Messages to send in total
+    pub count: u64,
+    /// Requests kept in flight at once, each on its own stream unless
+    /// `framed`
+    pub streams: usize,
+    /// Pipeline every request over one framed stream instead, answered in
+    /// order
+    pub framed: bool,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            size: 1024,
+            count: 10_000,
+            streams: 8,
+            framed: false,
+        }
+    }
+}
+
+/// Throughput and latency of a benchmark run
+#[derive(Debug, Clone, Copy)]
+pub struct BenchReport {
+    /// Messages echoed back
+    pub messages: u64,
+    /// Payload bytes echoed back
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl BenchReport {
+    pub fn messages_per_sec(&self) -> f64 {
+        self.messages as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Payload throughput in megabytes (10^6 bytes) per second
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / 1e6 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Flood the server behind `client` with [`Message::Data`] requests and time
+/// how fast they are echoed
+///
+/// Fails on the first request that errors or comes back altered.
+pub async fn run_bench<C: Codec>(client: &Client<C>, config: BenchConfig) -> Result<BenchReport> {
+    let started = Instant::now();
+    let mut latencies = match config.framed {
+        true => bench_framed(client, config).await?,
+        false => bench_streams(client, config).await?,
+    };
+    let elapsed = started.elapsed();
+
+    if latencies.is_empty() {
+        return Err(anyerr!("no messages sent"));
+    }
+    latencies.sort_unstable();
+    let messages = latencies.len() as u64;
+    Ok(BenchReport {
+        messages,
+        bytes: messages * config.size as u64,
+        elapsed,
+        p50: percentile(&latencies, 50),
+        p90: percentile(&latencies, 90),
+        p99: percentile(&latencies, 99),
+        max: latencies[latencies.len() - 1],
+    })
+}
+
+/// Latencies of requests sent by `streams` workers, each waiting for its
+/// answer before sending the next
+async fn bench_streams<C: Codec>(client: &Client<C>, config: BenchConfig) -> Result<Vec<Duration>> {
+    let payload = vec![0u8; config.size];
+    let next = AtomicU64::new(0);
+    let worker = || async {
+        let mut latencies = Vec::new();
+        while next.fetch_add(1, Ordering::Relaxed) < config.count {
+            let started = Instant::now();
+            match client.request(Message::Data(payload.clone())).await? {
+                Message::Data(data) if data.len() == config.size => {
+                    latencies.push(started.elapsed())
+                }
+                other => return Err(anyerr!("unexpected response {:?}", other.kind())),
+            }
+        }
+        Ok(latencies)
+    };
+    let workers = try_join_all((0..config.streams.max(1)).map(|_| worker())).await?;
+    Ok(workers.into_iter().flatten().collect())
+}
+
+/// Latencies of requests pipelined over one framed stream, up to `streams`
+/// of them unanswered at a time
+async fn bench_framed<C: Codec>(client: &Client<C>, config: BenchConfig) -> Result<Vec<Duration>> {
+    let payload = vec![0u8; config.size];
+    let framed = FramedConnection::open(client.connection(), client.codec().clone())
+        .await?
+        .with_max_frame_size(client.config().max_message_size);
+    let (sender, mut receiver) = framed.split();
+    let depth = config.streams.max(1);
+    let pipeline = sender.pipelined(depth);
+    // When each unanswered request was sent, bounding how many are in flight
+    let (sent_tx, mut sent_rx) = mpsc::channel(depth);
+
+    let send = async {
+        for id in 0..config.count {
+            sent_tx.send(Instant::now()).await.ok();
+            let mut msg = MessageEnvelope::new(id, Message::Data(payload.clone()));
+            client.intercept(&mut msg);
+            pipeline
+                .send_raw(msg.frame(client.codec(), client.protocol_version())?)
+                .await?;
+        }
+        Ok(())
+    };
+    let receive = async {
+        let mut latencies = Vec::with_capacity(config.count as usize);
+        for id in 0..config.count {
+            let bytes = receiver
+                .recv_bytes()
+                .await?
+                .ok_or_else(|| anyerr!("server finished the framed stream"))?;
+            let started = sent_rx.recv().await.expect("sent before answered");
+            let reply = decode_frame(client.codec(), client.protocol_version(), &bytes)?;
+            match reply.body {
+                Message::Data(data) if reply.id == id && data.len() == config.size => {
+                    latencies.push(started.elapsed())
+                }
+                other => return Err(anyerr!("unexpected response {:?}", other.kind())),
+            }
+        }
+        Ok(latencies)
+    };
+    let ((), latencies) = try_join(send, receive).await?;
+    pipeline.finish().await?.finish()?;
+    Ok(latencies)
+}
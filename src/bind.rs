@@ -0,0 +1,96 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use iroh::{Endpoint, endpoint};
+use n0_error::{Result, anyerr};
+
+// ====================
+// Bind Addresses
+// ====================
+
+/// Where an endpoint binds its UDP sockets
+///
+/// iroh always opens one IPv4 socket and, if it can, one IPv6 socket. A port
+/// that is taken makes iroh quietly pick another one, which [`BindAddr::bind`]
+/// turns into an error, since a port given on purpose is usually one a
+/// firewall lets through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindAddr {
+    /// Interface for the IPv4 socket, all of them by default
+    pub ipv4: Ipv4Addr,
+    /// Interface for the IPv6 socket, all of them by default; `None` keeps
+    /// it on loopback so no peer is reached over IPv6
+    pub ipv6: Option<Ipv6Addr>,
+    /// Port for both sockets, with 0 picking any
+    pub port: u16,
+}
+
+impl Default for BindAddr {
+    fn default() -> Self {
+        Self {
+            ipv4: Ipv4Addr::UNSPECIFIED,
+            ipv6: Some(Ipv6Addr::UNSPECIFIED),
+            port: 0,
+        }
+    }
+}
+
+impl BindAddr {
+    /// All interfaces on `port`
+    pub fn port(port: u16) -> Self {
+        Self {
+            port,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Bind IPv4 to the interface with address `ip` only
+    pub fn with_ipv4(mut self, ip: Ipv4Addr) -> Self {
+        self.ipv4 = ip;
+        self
+    }
+
+    /// Bind IPv6 to the interface with address `ip` only
+    pub fn with_ipv6(mut self, ip: Ipv6Addr) -> Self {
+        self.ipv6 = Some(ip);
+        self
+    }
+
+    /// Reach peers over IPv4 only
+    pub fn without_ipv6(mut self) -> Self {
+        self.ipv6 = None;
+        self
+    }
+
+    /// Configure `builder` to bind here
+    pub fn apply(&self, builder: endpoint::Builder) -> endpoint::Builder {
+        let ipv6 = self.ipv6.unwrap_or(Ipv6Addr::LOCALHOST);
+        builder
+            .bind_addr_v4(SocketAddrV4::new(self.ipv4, self.port))
+            .bind_addr_v6(SocketAddrV6::new(ipv6, self.port, 0, 0))
+    }
+
+    /// Bind `builder` here, failing rather than falling back to another port
+    /// if the one asked for is taken
+    pub async fn bind(&self, builder: endpoint::Builder) -> Result<Endpoint> {
+        let endpoint = self.apply(builder).bind().await?;
+        if self.port == 0 {
+            return Ok(endpoint);
+        }
+        let moved = endpoint
+            .bound_sockets()
+            .into_iter()
+            .find(|addr| addr.port() != self.port && (addr.is_ipv4() || self.ipv6.is_some()));
+        match moved {
+            None => Ok(endpoint),
+            Some(addr) => {
+                endpoint.close().await;
+                Err(anyerr!("port {} is taken, got {} instead", self.port, addr))
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::path::Path;
+
+use iroh::{Endpoint, protocol::RouterBuilder};
+pub use iroh_blobs::ticket::BlobTicket;
+use iroh_blobs::{BlobsProtocol, store::mem::MemStore};
+use n0_error::{Result, StdResultExt};
+
+/// Content-addressed file sharing next to the echo protocol
+///
+/// Files are imported into an in-memory store and served under
+/// [`iroh_blobs::ALPN`]; a [`BlobTicket`] names both the content hash and the
+/// endpoint to fetch it from. Fetched content is verified against its hash
+/// while streaming.
+#[derive(Debug, Clone)]
+pub struct BlobShare {
+    endpoint: Endpoint,
+    store: MemStore,
+}
+
+impl BlobShare {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            store: MemStore::new(),
+        }
+    }
+
+    /// Serve this share's blobs on the router being built
+    pub fn register(&self, builder: RouterBuilder) -> RouterBuilder {
+        builder.accept(iroh_blobs::ALPN, BlobsProtocol::new(&self.store, None))
+    }
+
+    /// Import the file at `path` and return a ticket other peers can fetch it with
+    pub async fn share_file(&self, path: impl AsRef<Path>) -> Result<BlobTicket> {
+        let path = std::path::absolute(path).anyerr()?;
+        let tag = self.store.blobs().add_path(path).await.anyerr()?;
+        Ok(BlobTicket::new(self.endpoint.addr(), tag.hash, tag.format))
+    }
+
+    /// Download the blob named by `ticket` and write it to `dest`, returning its size
+    pub async fn fetch(&self, ticket: &BlobTicket, dest: impl AsRef<Path>) -> Result<u64> {
+        let conn = self
+            .endpoint
+            .connect(ticket.addr().clone(), iroh_blobs::ALPN)
+            .await?;
+        self.store
+            .remote()
+            .fetch(conn, ticket.hash_and_format())
+            .await
+            .anyerr()?;
+        let dest = std::path::absolute(dest).anyerr()?;
+        self.store
+            .blobs()
+            .export(ticket.hash(), dest)
+            .await
+            .anyerr()
+    }
+}
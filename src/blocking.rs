@@ -0,0 +1,120 @@
+use std::{sync::Arc, time::Duration};
+
+use iroh::EndpointAddr;
+use n0_error::{Result, StdResultExt, anyerr};
+use tokio::{runtime::Runtime, sync::mpsc, task::JoinHandle};
+
+use crate::{
+    client::Client,
+    codec::{Bincode, Codec},
+    protocol::Message,
+};
+
+// ====================
+// Blocking Client
+// ====================
+
+/// A [`Client`] for code that does not run in an async runtime
+///
+/// It owns a small runtime with one worker thread, which keeps receiving
+/// while the caller does other things. Responses to [`send`](Self::send) and
+/// server pushes queue up in arrival order until taken with
+/// [`recv_timeout`](Self::recv_timeout).
+///
+/// Must not be used from within an async runtime, whose threads may not
+/// block.
+#[derive(Debug)]
+pub struct BlockingClient<C = Bincode> {
+    client: Arc<Client<C>>,
+    received: mpsc::UnboundedReceiver<Result<Message>>,
+    sender: mpsc::UnboundedSender<Result<Message>>,
+    pushes: JoinHandle<()>,
+    // Dropped last, so the tasks above can be aborted while it runs
+    runtime: Runtime,
+}
+
+impl BlockingClient {
+    /// Connect to the echo server at `addr` using the default codec
+    pub fn connect(addr: EndpointAddr) -> Result<Self> {
+        Self::connect_with_codec(addr, Bincode)
+    }
+}
+
+impl<C: Codec> BlockingClient<C> {
+    /// Connect to the echo server at `addr`, speaking `codec`
+    pub fn connect_with_codec(addr: EndpointAddr, codec: C) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .std_context("failed to start runtime")?;
+        let client = runtime.block_on(Client::connect_with_codec(addr, codec))?;
+        Ok(Self::new(runtime, client))
+    }
+
+    fn new(runtime: Runtime, client: Client<C>) -> Self {
+        let (sender, received) = mpsc::unbounded_channel();
+        let mut pushes = client.subscribe_pushes();
+        let push_sender = sender.clone();
+        let pushes = runtime.spawn(async move {
+            while let Ok(push) = pushes.recv().await {
+                if push_sender.send(Ok(push)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            client: Arc::new(client),
+            received,
+            sender,
+            pushes,
+            runtime,
+        }
+    }
+
+    /// The async client underneath, for everything without a blocking
+    /// counterpart
+    pub fn client(&self) -> &Client<C> {
+        &self.client
+    }
+
+    /// Send `msg` and return at once; the response arrives through
+    /// [`recv_timeout`](Self::recv_timeout)
+    ///
+    /// A request that fails arrives there as an error instead.
+    pub fn send(&self, msg: Message) {
+        let client = self.client.clone();
+        let sender = self.sender.clone();
+        self.runtime.spawn(async move {
+            sender.send(client.request(msg).await).ok();
+        });
+    }
+
+    /// Send `msg` and wait for its response, bypassing
+    /// [`recv_timeout`](Self::recv_timeout)
+    pub fn request(&self, msg: Message) -> Result<Message> {
+        self.runtime.block_on(self.client.request(msg))
+    }
+
+    /// Wait up to `timeout` for the next response or push
+    ///
+    /// Returns `None` if nothing arrived in time.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        let received = &mut self.received;
+        match self
+            .runtime
+            .block_on(async { tokio::time::timeout(timeout, received.recv()).await })
+        {
+            Ok(Some(msg)) => msg.map(Some),
+            // Cannot happen while `self` holds a sender
+            Ok(None) => Err(anyerr!("client is gone")),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl<C> Drop for BlockingClient<C> {
+    fn drop(&mut self) {
+        self.pushes.abort();
+    }
+}
@@ -0,0 +1,154 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use iroh::{Endpoint, EndpointAddr};
+use n0_error::{Result, StdResultExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::{Instrument, info, info_span, warn};
+
+use crate::{
+    client::{Client, dial},
+    close::CloseReason,
+    codec::{Codec, Json},
+    protocol::{Message, MessageEnvelope, PUSH_ID, RemoteError},
+};
+
+// ====================
+// WebSocket Bridge
+// ====================
+
+/// Gateway translating browser WebSocket traffic into the echo protocol
+///
+/// Every WebSocket connection gets its own iroh connection to the server. Text
+/// frames carry a JSON [`MessageEnvelope`]; the response comes back as a text
+/// frame with the same id, and server pushes arrive with id [`PUSH_ID`]. A
+/// request with a deadline is given up on once it passes, with no response
+/// frame. The bridge speaks `codec` towards the iroh server, so the server
+/// need not use JSON itself.
+#[derive(Debug, Clone)]
+pub struct WsBridge<C> {
+    endpoint: Endpoint,
+    server: EndpointAddr,
+    codec: C,
+}
+
+impl<C: Codec> WsBridge<C> {
+    pub fn new(endpoint: Endpoint, server: EndpointAddr, codec: C) -> Self {
+        Self {
+            endpoint,
+            server,
+            codec,
+        }
+    }
+
+    /// Accept WebSocket connections on `listen` until an error occurs
+    pub async fn serve(self, listen: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(listen).await.anyerr()?;
+        info!(
+            "WebSocket bridge listening on ws://{}",
+            listener.local_addr().anyerr()?
+        );
+
+        let bridge = Arc::new(self);
+        loop {
+            let (stream, peer) = listener.accept().await.anyerr()?;
+            let bridge = bridge.clone();
+            let span = info_span!("ws", %peer);
+            tokio::spawn(
+                async move {
+                    if let Err(e) = bridge.serve_socket(stream).await {
+                        warn!("WebSocket client failed: {:#}", e);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    async fn serve_socket(&self, stream: TcpStream) -> Result<()> {
+        let ws = tokio_tungstenite::accept_async(stream).await.anyerr()?;
+        let (mut ws_send, mut ws_recv) = ws.split();
+
+        let conn = dial(&self.endpoint, self.server.clone()).await?;
+        let client = Arc::new(Client::new(conn, self.codec.clone()));
+
+        // Responses and pushes are produced by many tasks but written by one
+        let (frames, mut outgoing) = mpsc::channel::<MessageEnvelope>(64);
+        let writer = tokio::spawn(async move {
+            while let Some(envelope) = outgoing.recv().await {
+                let Ok(text) = Json.encode(&envelope) else {
+                    continue;
+                };
+                let text = String::from_utf8(text).expect("json is utf-8");
+                if ws_send.send(WsMessage::text(text)).await.is_err() {
+                    break;
+                }
+            }
+            ws_send.close().await.ok();
+        });
+
+        let mut pushes = client.subscribe_pushes();
+        let push_frames = frames.clone();
+        let forward_pushes = tokio::spawn(async move {
+            while let Ok(body) = pushes.recv().await {
+                let push = MessageEnvelope::new(PUSH_ID, body);
+                if push_frames.send(push).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Some(frame) = ws_recv.next().await {
+            let payload = match frame.anyerr()? {
+                WsMessage::Text(text) => text.as_bytes().to_vec(),
+                WsMessage::Binary(bytes) => bytes.to_vec(),
+                WsMessage::Close(_) => break,
+                _ => continue,
+            };
+            let request: MessageEnvelope = match Json.decode(&payload) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("ignoring malformed WebSocket frame: {:#}", e);
+                    continue;
+                }
+            };
+
+            let client = client.clone();
+            let frames = frames.clone();
+            tokio::spawn(async move {
+                let response = match request.deadline {
+                    Some(deadline) => {
+                        let timeout = Duration::from_millis(deadline);
+                        client.call_with_timeout(request.body, timeout).await
+                    }
+                    None => client.request(request.body).await,
+                };
+                // Errors from the server are passed on as such
+                let response = response.or_else(|e| match e.downcast_ref::<RemoteError>() {
+                    Some(e) => Ok(Message::Error {
+                        code: e.code,
+                        detail: e.detail.clone(),
+                    }),
+                    None => Err(e),
+                });
+                match response {
+                    Ok(body) => {
+                        let response = MessageEnvelope::new(request.id, body);
+                        frames.send(response).await.ok();
+                    }
+                    Err(e) => warn!(id = request.id, "bridged request failed: {:#}", e),
+                }
+            });
+        }
+
+        forward_pushes.abort();
+        drop(frames);
+        writer.await.ok();
+        CloseReason::Normal.close(client.connection(), "websocket closed");
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::{Arc, LazyLock, Mutex};
+
+use bytes::{Bytes, BytesMut};
+use n0_error::Result;
+
+/// Capacity a [`BufferPool`] gives its buffers by default
+pub const BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// How many idle buffers a [`BufferPool`] keeps by default
+pub const POOL_SIZE: usize = 32;
+
+/// Room a pooled buffer needs left to be handed out as is, rather than
+/// reclaiming or reallocating its space first
+const MIN_ROOM: usize = 4 * 1024;
+
+/// The pool every message encoded by this crate is written into
+static SHARED: LazyLock<BufferPool> = LazyLock::new(BufferPool::default);
+
+// ====================
+// Buffer Pool
+// ====================
+
+/// Reusable buffers to encode messages into, so sending one does not
+/// allocate
+///
+/// Each message is split off the buffer it was written into as [`Bytes`],
+/// and the rest of the buffer goes back to the pool. Once every message
+/// split off a buffer has been dropped, the buffer reclaims their space
+/// rather than allocating anew. Clones share their buffers.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<BytesMut>>>,
+    capacity: usize,
+    max_free: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(BUFFER_CAPACITY, POOL_SIZE)
+    }
+}
+
+impl BufferPool {
+    /// A pool of buffers of `capacity` bytes, keeping up to `max_free` of
+    /// them while they are not in use
+    pub fn new(capacity: usize, max_free: usize) -> Self {
+        Self {
+            free: Arc::new(Mutex::new(Vec::with_capacity(max_free))),
+            capacity: capacity.max(1),
+            max_free,
+        }
+    }
+
+    /// The pool this crate encodes messages into
+    pub fn shared() -> &'static BufferPool {
+        &SHARED
+    }
+
+    /// An empty buffer, pooled if one is free
+    ///
+    /// A pooled buffer low on room reclaims the space of messages split off
+    /// it that are gone, and otherwise moves to a fresh allocation of the
+    /// pool's capacity.
+    pub fn get(&self) -> BytesMut {
+        let free = self.free.lock().expect("poisoned").pop();
+        match free {
+            Some(mut buf) => {
+                buf.clear();
+                buf.reserve(MIN_ROOM);
+                buf
+            }
+            None => BytesMut::with_capacity(self.capacity),
+        }
+    }
+
+    /// Hand `buf` back for reuse, dropping it if the pool is full
+    pub fn put(&self, buf: BytesMut) {
+        let mut free = self.free.lock().expect("poisoned");
+        if free.len() < self.max_free {
+            free.push(buf);
+        }
+    }
+
+    /// The bytes `write` puts into a pooled buffer
+    pub fn fill(&self, write: impl FnOnce(&mut BytesMut) -> Result<()>) -> Result<Bytes> {
+        let mut buf = self.get();
+        let written = write(&mut buf);
+        let bytes = buf.split().freeze();
+        self.put(buf);
+        written.map(|()| bytes)
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{StreamExt, stream::FuturesUnordered};
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    client::Client,
+    codec::Codec,
+    protocol::{Message, MessageEnvelope},
+};
+
+// ====================
+// Traffic Capture
+// ====================
+
+/// Which way a captured envelope went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One line of a capture file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Captured {
+    /// Milliseconds since the capture started
+    pub at_ms: u64,
+    pub direction: Direction,
+    pub envelope: MessageEnvelope,
+}
+
+/// Writes every envelope a [`Client`] sends or receives to a file, as JSON
+/// lines
+///
+/// Clones write to the same file. Each line is flushed as it is written, so
+/// a capture survives the process being killed.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    started: Instant,
+    file: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl Capture {
+    /// Start capturing to `path`, replacing whatever was there
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_std_context(|_| format!("creating capture file {}", path.display()))?;
+        Ok(Self {
+            started: Instant::now(),
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+        })
+    }
+
+    /// Append `envelope`; a failed write is logged rather than failing the
+    /// traffic it records
+    pub fn record(&self, direction: Direction, envelope: &MessageEnvelope) {
+        let line = Captured {
+            at_ms: self
+                .started
+                .elapsed()
+                .as_millis()
+                .try_into()
+                .unwrap_or(u64::MAX),
+            direction,
+            envelope: envelope.clone(),
+        };
+        let mut file = self.file.lock().expect("poisoned");
+        let written = serde_json::to_writer(&mut *file, &line)
+            .anyerr()
+            .and_then(|()| writeln!(file).anyerr())
+            .and_then(|()| file.flush().anyerr());
+        if let Err(e) = written {
+            warn!("error writing capture: {:#}", e);
+        }
+    }
+}
+
+/// Read back every line of a capture file
+pub fn read_capture(path: impl AsRef<Path>) -> Result<Vec<Captured>> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .with_std_context(|_| format!("opening capture file {}", path.display()))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(i, line)| {
+            let line = line.anyerr()?;
+            serde_json::from_str(&line)
+                .with_std_context(|_| format!("invalid capture line {}", i + 1))
+        })
+        .collect()
+}
+
+// ====================
+// Traffic Replay
+// ====================
+
+/// How a replayed trace went
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplayReport {
+    /// Requests sent
+    pub sent: u64,
+    /// Requests the server answered, with anything
+    pub answered: u64,
+    /// Requests that failed, including remote errors
+    pub failed: u64,
+    pub elapsed: Duration,
+}
+
+/// Re-send every message `trace` records as sent, to the server behind
+/// `client`
+///
+/// Messages go out at their captured offsets divided by `speed`, so `2.0`
+/// replays twice as fast and `f64::INFINITY` as fast as possible. They are
+/// sent as requests without waiting for earlier answers, as the original
+/// client may have done; the answers are only counted, since a server need
+/// not answer the same twice.
+pub async fn replay<C: Codec>(
+    client: &Client<C>,
+    trace: &[Captured],
+    speed: f64,
+) -> Result<ReplayReport> {
+    if speed.is_nan() || speed <= 0.0 {
+        return Err(anyerr!("replay speed must be positive"));
+    }
+    let started = Instant::now();
+    let mut report = ReplayReport::default();
+    let mut in_flight = FuturesUnordered::new();
+    let sent = trace
+        .iter()
+        .filter(|captured| captured.direction == Direction::Sent);
+    for captured in sent {
+        let due = started + Duration::from_millis(captured.at_ms).div_f64(speed);
+        // Keep collecting answers while waiting for the next send
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(due.into()) => break,
+                Some(answer) = in_flight.next() => report.count(answer),
+            }
+        }
+        in_flight.push(client.request(captured.envelope.body.clone()));
+        report.sent += 1;
+    }
+    while let Some(answer) = in_flight.next().await {
+        report.count(answer);
+    }
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+impl ReplayReport {
+    fn count(&mut self, answer: Result<Message>) {
+        match answer {
+            Ok(_) => self.answered += 1,
+            Err(e) => {
+                warn!("replayed request failed: {:#}", e);
+                self.failed += 1;
+            }
+        }
+    }
+}
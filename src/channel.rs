@@ -0,0 +1,300 @@
+use std::{collections::HashMap, sync::Arc};
+
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::{
+    sync::{Mutex, mpsc},
+    task::JoinHandle,
+};
+use tracing::{Instrument, debug, info_span, warn};
+
+use crate::{
+    codec::{Bincode, Codec},
+    datagram::{decode_datagram, send_datagram},
+    framed::FramedConnection,
+    protocol::{MAX_MESSAGE_SIZE, PROTOCOL_VERSION, recv_message, send_message},
+};
+
+/// How many received but unread messages a channel buffers
+const INBOX_CAPACITY: usize = 256;
+
+/// How the messages of a [`Channel`] travel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reliability {
+    /// One persistent stream: reliable and in order, but a lost packet
+    /// delays every message behind it
+    Ordered,
+    /// A fresh stream per message: reliable, in no particular order
+    Unordered,
+    /// Datagrams: may be lost or reordered, never retransmitted
+    Unreliable,
+}
+
+/// The channels both ends of a connection agree on, by name
+#[derive(Debug, Clone, Default)]
+pub struct ChannelConfig {
+    channels: HashMap<String, Reliability>,
+    max_message_size: Option<usize>,
+}
+
+impl ChannelConfig {
+    pub fn with_channel(mut self, name: impl Into<String>, reliability: Reliability) -> Self {
+        self.channels.insert(name.into(), reliability);
+        self
+    }
+
+    /// Refuse messages larger than `max_message_size` bytes encoded,
+    /// [`MAX_MESSAGE_SIZE`] by default
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+}
+
+/// A message on a per-message stream or datagram, tagged with its channel
+#[derive(Debug, Serialize, Deserialize)]
+struct ChannelFrame {
+    channel: String,
+    payload: Vec<u8>,
+}
+
+// ====================
+// Channels
+// ====================
+
+/// Named logical channels multiplexed over one connection
+///
+/// Takes over the connection: every incoming stream and datagram is read as
+/// channel traffic, so it is meant for connections on an application ALPN
+/// rather than the echo protocol. Traffic for a channel the local config does
+/// not name is dropped.
+#[derive(Debug)]
+pub struct Channels<C = Bincode> {
+    channels: HashMap<String, Channel<C>>,
+    incoming: JoinHandle<()>,
+}
+
+impl<C: Codec> Channels<C> {
+    /// Start routing the traffic of `conn` to the channels of `config`
+    pub fn new(conn: Connection, codec: C, config: ChannelConfig) -> Self {
+        let max_message_size = config.max_message_size.unwrap_or(MAX_MESSAGE_SIZE);
+        let mut inboxes = HashMap::new();
+        let channels = config
+            .channels
+            .into_iter()
+            .map(|(name, reliability)| {
+                let (tx, rx) = mpsc::channel(INBOX_CAPACITY);
+                inboxes.insert(name.clone(), tx);
+                let channel = Channel {
+                    name: name.clone().into(),
+                    reliability,
+                    conn: conn.clone(),
+                    codec: codec.clone(),
+                    max_message_size,
+                    stream: Arc::default(),
+                    inbox: Arc::new(Mutex::new(rx)),
+                };
+                (name, channel)
+            })
+            .collect();
+        let router = Router {
+            conn: conn.clone(),
+            codec,
+            max_message_size,
+            inboxes: Arc::new(inboxes),
+        };
+        let span = info_span!("channels", remote = %conn.remote_id().fmt_short());
+        let incoming = tokio::spawn(router.run().instrument(span));
+        Self { channels, incoming }
+    }
+
+    /// The channel called `name`, which must be part of the config
+    ///
+    /// Every handle on the same channel shares its stream and its inbox.
+    pub fn channel(&self, name: &str) -> Result<Channel<C>> {
+        self.channels
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyerr!("unknown channel {}", name))
+    }
+}
+
+impl<C> Drop for Channels<C> {
+    fn drop(&mut self) {
+        self.incoming.abort();
+    }
+}
+
+/// One named channel of a [`Channels`] connection
+#[derive(Debug, Clone)]
+pub struct Channel<C = Bincode> {
+    name: Arc<str>,
+    reliability: Reliability,
+    conn: Connection,
+    codec: C,
+    max_message_size: usize,
+    /// The outgoing stream of an ordered channel, opened by the first send
+    stream: Arc<Mutex<Option<FramedConnection<C>>>>,
+    inbox: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>,
+}
+
+impl<C: Codec> Channel<C> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn reliability(&self) -> Reliability {
+        self.reliability
+    }
+
+    /// Send `msg` on this channel
+    ///
+    /// On an ordered channel this waits for earlier sends to be written;
+    /// an unreliable send is done once the datagram is queued.
+    pub async fn send<T: Serialize>(&self, msg: &T) -> Result<()> {
+        let payload = self.codec.encode(msg)?;
+        if payload.len() > self.max_message_size {
+            return Err(anyerr!(
+                "message of {} bytes exceeds the limit of {} bytes",
+                payload.len(),
+                self.max_message_size
+            ));
+        }
+        match self.reliability {
+            Reliability::Ordered => {
+                let mut stream = self.stream.lock().await;
+                let framed = match stream.as_mut() {
+                    Some(framed) => framed,
+                    None => {
+                        let mut framed = FramedConnection::open(&self.conn, self.codec.clone())
+                            .await?
+                            .with_max_frame_size(self.max_message_size);
+                        framed.send(&self.name()).await?;
+                        stream.insert(framed)
+                    }
+                };
+                framed.send_bytes(&payload).await
+            }
+            Reliability::Unordered => {
+                send_message(&self.conn, &self.codec, &self.frame(payload)).await
+            }
+            // Like `send_message`, in the layout of the current version
+            Reliability::Unreliable => send_datagram(
+                &self.conn,
+                &self.codec,
+                PROTOCOL_VERSION,
+                &self.frame(payload),
+            ),
+        }
+    }
+
+    /// The next message received on this channel, or `None` once the
+    /// connection is gone
+    pub async fn recv<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        match self.inbox.lock().await.recv().await {
+            Some(payload) => self.codec.decode(&payload).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn frame(&self, payload: Vec<u8>) -> ChannelFrame {
+        ChannelFrame {
+            channel: self.name.to_string(),
+            payload,
+        }
+    }
+}
+
+// ====================
+// Incoming Traffic
+// ====================
+
+/// Hands every incoming stream and datagram to the inbox of its channel
+#[derive(Debug, Clone)]
+struct Router<C> {
+    conn: Connection,
+    codec: C,
+    max_message_size: usize,
+    inboxes: Arc<HashMap<String, mpsc::Sender<Vec<u8>>>>,
+}
+
+impl<C: Codec> Router<C> {
+    async fn run(self) {
+        loop {
+            tokio::select! {
+                uni = self.conn.accept_uni() => match uni {
+                    Ok(recv) => {
+                        tokio::spawn(self.clone().message(recv).in_current_span());
+                    }
+                    Err(_) => break,
+                },
+                bi = self.conn.accept_bi() => match bi {
+                    Ok((send, recv)) => {
+                        tokio::spawn(self.clone().ordered(send, recv).in_current_span());
+                    }
+                    Err(_) => break,
+                },
+                datagram = self.conn.read_datagram() => match datagram {
+                    Ok(datagram) => match decode_datagram::<_, ChannelFrame>(&self.codec, PROTOCOL_VERSION, &datagram) {
+                        // A full inbox drops the datagram, as the network could have
+                        Ok(frame) => {
+                            if let Some(inbox) = self.inbox(&frame.channel) {
+                                inbox.try_send(frame.payload).ok();
+                            }
+                        }
+                        Err(e) => debug!("error decoding datagram: {:#}", e),
+                    },
+                    Err(_) => break,
+                },
+            }
+        }
+    }
+
+    fn inbox(&self, channel: &str) -> Option<&mpsc::Sender<Vec<u8>>> {
+        let inbox = self.inboxes.get(channel);
+        if inbox.is_none() {
+            warn!(channel, "dropping traffic for unknown channel");
+        }
+        inbox
+    }
+
+    /// Read the one message of a per-message stream
+    async fn message(self, recv: RecvStream) {
+        match recv_message::<_, ChannelFrame>(&self.codec, recv).await {
+            Ok(frame) => {
+                if let Some(inbox) = self.inbox(&frame.channel) {
+                    inbox.send(frame.payload).await.ok();
+                }
+            }
+            Err(e) => warn!("error receiving channel message: {:#}", e),
+        }
+    }
+
+    /// Read an ordered stream: the channel name, then its messages
+    async fn ordered(self, send: SendStream, recv: RecvStream) {
+        let mut framed = FramedConnection::from_streams(send, recv, self.codec.clone())
+            .with_max_frame_size(self.max_message_size);
+        // Only the opener's direction carries messages
+        framed.finish().ok();
+        let result = async {
+            let name: String = framed
+                .recv()
+                .await?
+                .std_context("stream finished before the channel name")?;
+            let Some(inbox) = self.inbox(&name) else {
+                return Ok(());
+            };
+            while let Some(payload) = framed.recv_bytes().await? {
+                // A slow reader holds the stream back through flow control
+                if inbox.send(payload).await.is_err() {
+                    break;
+                }
+            }
+            Ok::<_, n0_error::AnyError>(())
+        };
+        if let Err(e) = result.await {
+            warn!("error receiving on ordered channel: {:#}", e);
+        }
+    }
+}
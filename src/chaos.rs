@@ -0,0 +1,98 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use iroh::endpoint::{Connection, VarInt};
+use rand::Rng;
+use tracing::debug;
+
+use crate::close::CloseReason;
+
+/// Application code for streams reset on purpose by a [`ChaosConfig`]
+pub const CHAOS_RESET: VarInt = VarInt::from_u32(3);
+
+/// Application close code for connections closed on purpose by a
+/// [`ChaosConfig`]
+pub const CHAOS_CLOSE: VarInt = CloseReason::Chaos.code();
+
+// ====================
+// Fault Injection
+// ====================
+
+/// Faults to inject into the messages an endpoint receives, for testing how
+/// applications cope with a bad network
+///
+/// Each received message independently closes the whole connection with
+/// probability `close`, has its stream reset with probability `reset`, or is
+/// silently dropped with probability `drop`. Whatever survives is delayed by
+/// `latency` plus up to `jitter`, uniformly distributed. Datagrams cannot be
+/// reset and are dropped instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChaosConfig {
+    pub drop: f64,
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub reset: f64,
+    pub close: f64,
+}
+
+/// What a [`ChaosConfig`] decided for one message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strike {
+    /// Hand the message on, after any latency
+    Deliver,
+    /// Act as if the message never arrived
+    Drop,
+    /// Reset the stream it arrived on with [`CHAOS_RESET`]
+    Reset,
+}
+
+impl ChaosConfig {
+    /// Whether any fault is configured at all
+    pub fn is_active(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Decide the fate of one message received on `conn`
+    ///
+    /// Closing the connection and waiting out the latency happen here; a
+    /// closed connection delivers nothing.
+    pub async fn strike(&self, conn: &Connection) -> Strike {
+        let (roll, delay) = {
+            let mut rng = rand::rng();
+            let roll: f64 = rng.random();
+            let jitter = self.jitter.mul_f64(rng.random());
+            (roll, self.latency + jitter)
+        };
+        if roll < self.close {
+            debug!("chaos: closing connection");
+            CloseReason::Chaos.close(conn, "chaos");
+            return Strike::Drop;
+        }
+        if roll < self.close + self.reset {
+            debug!("chaos: resetting stream");
+            return Strike::Reset;
+        }
+        if roll < self.close + self.reset + self.drop {
+            debug!("chaos: dropping message");
+            return Strike::Drop;
+        }
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        Strike::Deliver
+    }
+}
+
+/// A [`ChaosConfig`] that can be set after a background task took a copy
+pub(crate) type ChaosSlot = Arc<Mutex<Option<ChaosConfig>>>;
+
+/// Strike with the config in `slot`, delivering everything if there is none
+pub(crate) async fn strike(slot: &ChaosSlot, conn: &Connection) -> Strike {
+    let chaos = *slot.lock().expect("poisoned");
+    match chaos {
+        Some(chaos) => chaos.strike(conn).await,
+        None => Strike::Deliver,
+    }
+}
@@ -0,0 +1,81 @@
+use std::io;
+
+use bytes::Bytes;
+use iroh::endpoint::{Connection, ReadExactError, RecvStream, SendStream};
+use n0_error::{Result, StdResultExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+// ====================
+// Chunked Stream Solution
+// ====================
+
+/// Largest chunk written by [`write_chunks`] and accepted by [`recv_stream`]
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Pipe everything `reader` produces to the peer on a new unidirectional stream
+///
+/// Unlike [`send_message`](crate::send_message) there is no size ceiling and
+/// only one chunk is held in memory at a time. Returns the number of payload
+/// bytes sent.
+pub async fn send_stream(conn: &Connection, reader: impl AsyncRead + Unpin) -> Result<u64> {
+    let mut send = conn.open_uni().await.anyerr()?;
+    let total = write_chunks(&mut send, reader).await?;
+    send.finish().anyerr()?;
+    Ok(total)
+}
+
+/// Write `reader` to `send` as big-endian `u32` length-prefixed chunks,
+/// terminated by an empty chunk
+///
+/// The terminator lets the receiver tell a complete transfer from a stream
+/// that was cut short. `send` is left open for the caller to finish or reuse.
+pub async fn write_chunks(
+    send: &mut SendStream,
+    mut reader: impl AsyncRead + Unpin,
+) -> Result<u64> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(&mut buf).await.anyerr()?;
+        send.write_all(&(n as u32).to_be_bytes()).await.anyerr()?;
+        if n == 0 {
+            return Ok(total);
+        }
+        send.write_all(&buf[..n]).await.anyerr()?;
+        total += n as u64;
+    }
+}
+
+/// Read a chunked transfer written by [`write_chunks`] as a byte stream
+///
+/// The reader reports `UnexpectedEof` if the stream ends before the
+/// terminating chunk.
+pub fn recv_stream(recv: RecvStream) -> impl AsyncRead + Send + Unpin {
+    let chunks = futures::stream::try_unfold(recv, |mut recv| async move {
+        let mut len = [0u8; 4];
+        recv.read_exact(&mut len).await.map_err(read_error)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        if len > CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk of {} bytes exceeds limit", len),
+            ));
+        }
+
+        let mut chunk = vec![0u8; len];
+        recv.read_exact(&mut chunk).await.map_err(read_error)?;
+        Ok(Some((Bytes::from(chunk), recv)))
+    });
+    StreamReader::new(Box::pin(chunks))
+}
+
+fn read_error(e: ReadExactError) -> io::Error {
+    match e {
+        ReadExactError::FinishedEarly(_) => io::ErrorKind::UnexpectedEof.into(),
+        ReadExactError::ReadError(e) => io::Error::other(e),
+    }
+}
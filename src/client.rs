@@ -0,0 +1,1221 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{Stream, stream};
+use iroh::{
+    Endpoint, EndpointAddr, EndpointId, PublicKey,
+    endpoint::{
+        ConnectOptions, ConnectingError, Connection, ConnectionError, SendStream,
+        TransportErrorCode, ZeroRttStatus,
+    },
+};
+use iroh_quinn_proto::Side;
+use n0_error::{AnyError, Result, StackResultExt, StdResultExt, anyerr, stack_error};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use tracing::{Instrument, debug, debug_span, info_span, warn};
+
+use crate::{
+    capture::{Capture, Direction},
+    chaos::{self, CHAOS_RESET, ChaosConfig, ChaosSlot, Strike},
+    close::RemoteClose,
+    coalesce::{Coalescer, MAX_BATCH},
+    codec::{Bincode, Codec, CodecKind},
+    datagram,
+    events::{ConnEvent, Events},
+    framed::FramedConnection,
+    heartbeat::{HeartbeatConfig, Liveness, is_heartbeat, run_heartbeat, run_idle_timeout},
+    lobby::{LobbyRequest, RoomEvent, RoomRefused},
+    middleware::{Interceptor, intercept},
+    protocol::{
+        ErrorCode, MIN_PROTOCOL_VERSION, Message, MessageEnvelope, PROTOCOL_VERSION, PUSH_ID,
+        ProtocolConfig, RemoteError, alpn_for_version, connection_version, decode_frame,
+        send_message, supported_alpns,
+    },
+    qlog::Qlog,
+    queue::{SEND_QUEUE_CAPACITY, SendQueue},
+    replay::{self, MessageCounter, ReplayWindow},
+    sealing::{Sealer, SessionKey},
+    session::{Presence, Status},
+    signing::{self, KeyProvider},
+    stats::ConnectionStats,
+    streams::StreamPool,
+    telemetry::TraceContext,
+    ticket::EchoTicket,
+};
+
+/// Bind a fresh endpoint and connect to the echo server at `addr`
+pub async fn connect(addr: EndpointAddr) -> Result<Connection> {
+    let endpoint = Endpoint::bind().await?;
+    dial(&endpoint, addr).await
+}
+
+/// Connect to the echo server at `addr` from `endpoint`, using the newest
+/// protocol version both sides support
+///
+/// A server picks the first ALPN of its own list that the client offers, and
+/// that list sorts older versions first. So each version is offered alone,
+/// newest first, moving on only when the server rejects it.
+pub async fn dial(endpoint: &Endpoint, addr: EndpointAddr) -> Result<Connection> {
+    let mut alpns = supported_alpns().into_iter().peekable();
+    while let Some(alpn) = alpns.next() {
+        let connecting = endpoint
+            .connect_with_opts(addr.clone(), &alpn, ConnectOptions::new())
+            .await?;
+        match connecting.await {
+            Ok(conn) => return Ok(conn),
+            Err(e) if is_alpn_mismatch(&e) && alpns.peek().is_some() => continue,
+            Err(e) => {
+                return Err(e).with_context(|_| {
+                    format!(
+                        "handshake failed, the server may not speak echo protocol versions {}..={}",
+                        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    )
+                });
+            }
+        }
+    }
+    unreachable!("at least one protocol version is supported")
+}
+
+/// Connect to the echo server at `addr` from `endpoint` speaking the current
+/// protocol version, sending `msg` as 0-RTT data if `endpoint` can resume an
+/// earlier session with the server
+///
+/// `msg` travels on a framed stream of its own, so its answer comes back on
+/// that stream. Returns the connection along with that answer, which is
+/// `None` if there was no session to resume or the server rejected the 0-RTT
+/// data; `msg` then never reached the server. Only send what
+/// [`Message::is_replay_safe`] allows.
+pub(crate) async fn dial_early<C: Codec>(
+    endpoint: &Endpoint,
+    addr: EndpointAddr,
+    codec: &C,
+    config: &ProtocolConfig,
+    msg: &Message,
+) -> Result<(Connection, Option<Message>)> {
+    let alpn = alpn_for_version(PROTOCOL_VERSION);
+    let connecting = endpoint
+        .connect_with_opts(addr, &alpn, ConnectOptions::new())
+        .await?;
+    let early = match connecting.into_0rtt() {
+        Ok(early) => early,
+        Err(connecting) => return Ok((connecting.await?, None)),
+    };
+    let exchange = async {
+        let (send, recv) = early.open_bi().await.anyerr()?;
+        let mut framed = FramedConnection::from_streams(send, recv, codec.clone())
+            .with_max_frame_size(config.max_message_size);
+        let mut request = MessageEnvelope::new(0, msg.clone());
+        // Alone on the connection so far, any counter will do
+        request.counter = Some(MessageCounter::default().next());
+        framed
+            .send_raw(request.frame(codec, PROTOCOL_VERSION)?)
+            .await?;
+        let reply = framed
+            .recv_bytes()
+            .await?
+            .map(|bytes| decode_frame(codec, PROTOCOL_VERSION, &bytes))
+            .transpose()?;
+        framed.finish().ok();
+        Ok::<_, AnyError>(reply)
+    };
+    let (reply, status) = tokio::join!(exchange, early.handshake_completed());
+    match status.std_context("0-RTT handshake failed")? {
+        ZeroRttStatus::Accepted(conn) => {
+            let reply = match reply {
+                Ok(reply) => reply,
+                Err(e) => {
+                    debug!("0-RTT request failed: {:#}", e);
+                    None
+                }
+            };
+            let body = reply
+                .map(|reply| reply.body)
+                .filter(|body| config.check_shape(body).is_ok());
+            Ok((conn, body))
+        }
+        ZeroRttStatus::Rejected(conn) => {
+            debug!("server rejected 0-RTT data");
+            Ok((conn, None))
+        }
+    }
+}
+
+/// Whether the server aborted the handshake for not speaking the offered ALPN
+fn is_alpn_mismatch(e: &ConnectingError) -> bool {
+    /// TLS alert `no_application_protocol`
+    const NO_APPLICATION_PROTOCOL: u8 = 120;
+    matches!(
+        e,
+        ConnectingError::ConnectionError {
+            source: ConnectionError::ConnectionClosed(close),
+            ..
+        } if close.error_code == TransportErrorCode::crypto(NO_APPLICATION_PROTOCOL)
+    )
+}
+
+/// Connect to the echo server named by a ticket string, speaking its codec
+pub async fn connect_by_ticket(ticket: &str) -> Result<Client<CodecKind>> {
+    let ticket: EchoTicket = ticket.parse().std_context("invalid echo ticket")?;
+    Client::connect_with_codec(ticket.addr, ticket.codec).await
+}
+
+/// Connect to the echo server with `id`, looking its address up through n0's
+/// DNS server
+///
+/// This only finds servers that publish there, which servers bound with the
+/// default [`AddrLookup`](crate::AddrLookup) do.
+pub async fn connect_by_id(id: EndpointId) -> Result<Client> {
+    Client::connect(id.into()).await
+}
+
+/// Bind a fresh endpoint and connect to `addr` speaking `alpn`
+pub async fn connect_with_alpn(addr: EndpointAddr, alpn: &[u8]) -> Result<Connection> {
+    let endpoint = Endpoint::bind().await?;
+    let conn = endpoint.connect(addr, alpn).await?;
+    Ok(conn)
+}
+
+type PendingMap = Arc<Mutex<HashMap<u64, oneshot::Sender<Message>>>>;
+
+/// A request waiting in a [`PendingMap`], removed from it on drop
+struct Pending<'a> {
+    pending: &'a PendingMap,
+    id: u64,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().expect("poisoned").remove(&self.id);
+    }
+}
+
+/// No response to a request arrived in time
+#[stack_error(derive, add_meta)]
+#[error("no response within {timeout:?}")]
+pub struct RequestTimedOut {
+    pub timeout: Duration,
+}
+
+/// How many unread server pushes a subscriber may fall behind by
+const PUSH_CAPACITY: usize = 256;
+
+/// Where traffic is captured to, set after the dispatch task started
+type CaptureSlot = Arc<Mutex<Option<Capture>>>;
+
+/// The interceptors of a client, shared with its [`Responder`]s
+type InterceptorSlot = Arc<Mutex<Vec<Arc<dyn Interceptor>>>>;
+
+/// The keys a client signs and checks messages with, shared with its
+/// [`Responder`]s
+type SigningSlot = Arc<Mutex<Option<Arc<dyn KeyProvider>>>>;
+
+/// Where [`Client::incoming`] receives from, if anyone is listening
+type IncomingSlot<C> = Arc<Mutex<Option<mpsc::Sender<(Message, Responder<C>)>>>>;
+
+/// Answers a message the server sent on its own initiative, see
+/// [`Client::incoming`]
+///
+/// Dropping it unanswered finishes the stream with no response, which the
+/// server sees as a failed request.
+#[derive(Debug)]
+pub struct Responder<C = Bincode> {
+    send: Option<SendStream>,
+    id: u64,
+    peer: EndpointId,
+    codec: C,
+    config: ProtocolConfig,
+    version: u32,
+    interceptors: InterceptorSlot,
+    signing: SigningSlot,
+    counter: MessageCounter,
+}
+
+impl<C: Codec> Responder<C> {
+    /// Whether the server waits for an answer, rather than having pushed the
+    /// message
+    pub fn expects_reply(&self) -> bool {
+        self.send.is_some()
+    }
+
+    /// Answer the server with `msg`
+    ///
+    /// Fails for a push, which takes no answer.
+    pub async fn respond(mut self, msg: Message) -> Result<()> {
+        let Some(mut send) = self.send.take() else {
+            return Err(anyerr!("a push takes no reply"));
+        };
+        let mut envelope = MessageEnvelope::new(self.id, msg);
+        intercepted(
+            &self.interceptors,
+            &self.signing,
+            &self.counter,
+            self.peer,
+            &mut envelope,
+        );
+        let encoded = self.config.encode(&self.codec, self.version, &envelope)?;
+        send.write_chunk(encoded).await.anyerr()?;
+        send.finish().anyerr()?;
+        Ok(())
+    }
+}
+
+/// Client side of the echo protocol
+///
+/// Every request is sent in a [`MessageEnvelope`] with a fresh id. Responses are
+/// routed back to the waiting caller through a map of pending requests, so
+/// concurrent requests resolve correctly whatever order their streams arrive in.
+#[derive(Debug)]
+pub struct Client<C = Bincode> {
+    conn: Connection,
+    codec: C,
+    config: ProtocolConfig,
+    version: u32,
+    next_id: Arc<AtomicU64>,
+    pending: PendingMap,
+    liveness: Liveness,
+    pushes: broadcast::Sender<Message>,
+    presence: broadcast::Sender<Presence>,
+    incoming: IncomingSlot<C>,
+    chaos: ChaosSlot,
+    capture: CaptureSlot,
+    interceptors: InterceptorSlot,
+    signing: SigningSlot,
+    counter: MessageCounter,
+    events: Events,
+    queue: SendQueue,
+    coalescer: Option<Coalescer>,
+    streams: Option<StreamPool<C>>,
+    responses: JoinHandle<()>,
+    heartbeat: Option<JoinHandle<()>>,
+    idle: Option<JoinHandle<()>>,
+    qlog: Option<JoinHandle<()>>,
+}
+
+impl Client {
+    /// Connect to the echo server at `addr` using the default codec
+    pub async fn connect(addr: EndpointAddr) -> Result<Self> {
+        Self::connect_with_codec(addr, Bincode).await
+    }
+}
+
+impl<C: Codec> Client<C> {
+    /// Connect to the echo server at `addr`, speaking `codec`
+    pub async fn connect_with_codec(addr: EndpointAddr, codec: C) -> Result<Self> {
+        Ok(Self::new(connect(addr).await?, codec))
+    }
+
+    /// Wrap an established connection and start dispatching responses
+    pub fn new(conn: Connection, codec: C) -> Self {
+        Self::with_config(conn, codec, ProtocolConfig::default())
+    }
+
+    /// Like [`Client::new`], enforcing the size limits of `config` on both
+    /// requests and responses
+    pub fn with_config(conn: Connection, codec: C, config: ProtocolConfig) -> Self {
+        Self::with_events(conn, codec, config, Events::default())
+    }
+
+    /// Like [`Client::with_config`], reporting to an existing event channel
+    pub(crate) fn with_events(
+        conn: Connection,
+        codec: C,
+        config: ProtocolConfig,
+        events: Events,
+    ) -> Self {
+        let version = connection_version(&conn);
+        events.emit(ConnEvent::Connected {
+            peer: conn.remote_id(),
+        });
+        let dispatch = Dispatch {
+            conn: conn.clone(),
+            codec: codec.clone(),
+            config: config.clone(),
+            version,
+            pending: PendingMap::default(),
+            liveness: Liveness::default(),
+            pushes: broadcast::channel(PUSH_CAPACITY).0,
+            presence: broadcast::channel(PUSH_CAPACITY).0,
+            incoming: IncomingSlot::default(),
+            chaos: ChaosSlot::default(),
+            capture: CaptureSlot::default(),
+            interceptors: InterceptorSlot::default(),
+            signing: SigningSlot::default(),
+            counter: MessageCounter::default(),
+            replay: Arc::default(),
+            events: events.clone(),
+        };
+        let Dispatch {
+            pending,
+            liveness,
+            pushes,
+            presence,
+            incoming,
+            chaos,
+            capture,
+            interceptors,
+            signing,
+            counter,
+            ..
+        } = dispatch.clone();
+        let span = info_span!("client", remote = %conn.remote_id().fmt_short());
+        let responses = tokio::spawn(dispatch.run().instrument(span));
+        Self {
+            queue: SendQueue::new(conn.clone(), SEND_QUEUE_CAPACITY),
+            conn,
+            codec,
+            config,
+            version,
+            next_id: Arc::new(AtomicU64::new(0)),
+            pending,
+            liveness,
+            pushes,
+            presence,
+            incoming,
+            chaos,
+            capture,
+            interceptors,
+            signing,
+            counter,
+            events,
+            coalescer: None,
+            streams: None,
+            responses,
+            heartbeat: None,
+            idle: None,
+            qlog: None,
+        }
+    }
+
+    /// Let up to `capacity` outgoing messages wait for a stream, instead of
+    /// [`SEND_QUEUE_CAPACITY`]
+    pub fn with_send_queue(mut self, capacity: usize) -> Self {
+        self.queue = SendQueue::new(self.conn.clone(), capacity);
+        if let Some(old) = self.coalescer.take() {
+            self.coalescer = Some(self.coalescer(old.window(), old.max_batch()));
+        }
+        self
+    }
+
+    /// Coalesce messages sent within `window` of each other into one
+    /// [`Message::Batch`], of up to [`MAX_BATCH`] messages, see [`Coalescer`]
+    ///
+    /// Only [`send`](Self::send) and [`try_send`](Self::try_send) coalesce,
+    /// and the server's answers to a batch are dropped like those to single
+    /// sends. Each message passes the interceptors and the capture on its
+    /// own, but the size limits apply to the batch as a whole, and only the
+    /// batch is numbered and signed.
+    pub fn with_coalescing(mut self, window: Duration) -> Self {
+        self.coalescer = Some(self.coalescer(window, MAX_BATCH));
+        self
+    }
+
+    fn coalescer(&self, window: Duration, max_batch: usize) -> Coalescer {
+        let (codec, config, version) = (self.codec.clone(), self.config.clone(), self.version);
+        let (queue, next_id) = (self.queue.clone(), self.next_id.clone());
+        let (signing, counter) = (self.signing.clone(), self.counter.clone());
+        Coalescer::new(window, max_batch, move |msg| {
+            let id = next_id.fetch_add(1, Ordering::Relaxed);
+            let mut envelope = MessageEnvelope::new(id, msg);
+            stamped(&signing, &counter, &mut envelope);
+            let encoded = config.encode(&codec, version, &envelope);
+            let queue = queue.clone();
+            async move { queue.send(encoded?).await }
+        })
+    }
+
+    /// Send requests on a pool of up to `size` reused bidirectional streams,
+    /// see [`StreamPool`], instead of a new stream each way
+    ///
+    /// The streams are opened right away. Only [`request`](Self::request)
+    /// and the calls built on it use the pool; one-way sends still go through
+    /// the send queue. Each pooled stream takes up a stream handler of the
+    /// server for as long as the pool keeps it.
+    pub fn with_stream_pool(mut self, size: usize) -> Self {
+        let streams = StreamPool::new(self.conn.clone(), self.codec.clone())
+            .with_max_frame_size(self.config.max_message_size)
+            .with_max_idle(size);
+        let prewarm = streams.clone();
+        tokio::spawn(async move {
+            if let Err(e) = prewarm.prewarm().await {
+                debug!("error opening pooled streams: {:#}", e);
+            }
+        });
+        self.streams = Some(streams);
+        self
+    }
+
+    /// Ping the server every interval and close the connection once it stops
+    /// answering, failing all pending requests
+    pub fn with_heartbeat(mut self, config: HeartbeatConfig) -> Self {
+        if let Some(old) = self.heartbeat.take() {
+            old.abort();
+        }
+        self.heartbeat = Some(tokio::spawn(run_heartbeat(
+            self.conn.clone(),
+            self.codec.clone(),
+            config,
+            self.liveness.clone(),
+        )));
+        self
+    }
+
+    /// Close the connection with [`IDLE_TIMEOUT`](crate::heartbeat::IDLE_TIMEOUT)
+    /// once nothing but heartbeats went either way for `timeout`
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        if let Some(old) = self.idle.take() {
+            old.abort();
+        }
+        self.idle = Some(tokio::spawn(run_idle_timeout(
+            self.conn.clone(),
+            timeout,
+            self.liveness.clone(),
+        )));
+        self
+    }
+
+    /// Write a qlog trace of the connection, see [`Qlog`]
+    ///
+    /// The trace ends with the close unless the client is dropped first.
+    pub fn with_qlog(mut self, qlog: Qlog) -> Self {
+        if let Some(old) = self.qlog.take() {
+            old.abort();
+        }
+        self.qlog = Some(tokio::spawn(qlog.trace(self.conn.clone(), Side::Client)));
+        self
+    }
+
+    /// Inject the faults of `chaos` into every message received from the
+    /// server, see [`ChaosConfig`]
+    pub fn with_chaos(self, chaos: ChaosConfig) -> Self {
+        *self.chaos.lock().expect("poisoned") = Some(chaos);
+        self
+    }
+
+    /// Sign every outbound message with the key of `keys`, if it has one, and
+    /// refuse inbound messages not signed by a key it trusts, see
+    /// [`KeyProvider`]
+    ///
+    /// A refused answer fails its request with
+    /// [`ErrorCode::Unauthorized`], a refused server request is answered
+    /// with it, and a refused push is dropped. Heartbeats and datagrams are
+    /// neither signed nor checked.
+    pub fn with_signing(self, keys: impl KeyProvider) -> Self {
+        self.with_signing_keys(Arc::new(keys))
+    }
+
+    /// Like [`with_signing`](Self::with_signing), for keys shared with other
+    /// clients
+    pub(crate) fn with_signing_keys(self, keys: Arc<dyn KeyProvider>) -> Self {
+        *self.signing.lock().expect("poisoned") = Some(keys);
+        self
+    }
+
+    /// Record every envelope sent or received to `capture`, for replaying
+    /// later with [`replay`](crate::capture::replay)
+    pub fn with_capture(self, capture: Capture) -> Self {
+        *self.capture.lock().expect("poisoned") = Some(capture);
+        self
+    }
+
+    /// Run `interceptor` on every outbound message, answers to the server
+    /// included, after the interceptors added before it, see [`Interceptor`]
+    pub fn with_interceptor(self, interceptor: impl Interceptor) -> Self {
+        self.with_interceptors([Arc::new(interceptor) as Arc<dyn Interceptor>])
+    }
+
+    /// Like [`with_interceptor`](Self::with_interceptor), for interceptors
+    /// shared with other clients
+    pub(crate) fn with_interceptors(
+        self,
+        interceptors: impl IntoIterator<Item = Arc<dyn Interceptor>>,
+    ) -> Self {
+        self.interceptors
+            .lock()
+            .expect("poisoned")
+            .extend(interceptors);
+        self
+    }
+
+    /// When the server was last heard from
+    pub fn last_seen(&self) -> Instant {
+        self.liveness.last_seen()
+    }
+
+    /// How the server closed the connection, once it did
+    pub fn remote_close(&self) -> Option<RemoteClose> {
+        RemoteClose::of(&self.conn.close_reason()?)
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// The echo protocol version negotiated with the server
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    pub fn config(&self) -> &ProtocolConfig {
+        &self.config
+    }
+
+    /// Messages the server pushes without a request, such as broadcasts
+    pub fn subscribe_pushes(&self) -> broadcast::Receiver<Message> {
+        self.pushes.subscribe()
+    }
+
+    /// Presence changes of the peers watched with
+    /// [`watch_presence`](Self::watch_presence)
+    ///
+    /// They are also part of [`subscribe_pushes`](Self::subscribe_pushes).
+    pub fn subscribe_presence(&self) -> broadcast::Receiver<Presence> {
+        self.presence.subscribe()
+    }
+
+    /// Everything the server sends on its own initiative: its pushes, and
+    /// requests it expects an answer to through the [`Responder`]
+    ///
+    /// Only the stream returned last receives anything. Pushes are dropped
+    /// once it falls too far behind, like for
+    /// [`subscribe_pushes`](Self::subscribe_pushes), while server requests
+    /// wait for it. With no stream around, server requests are refused
+    /// with [`ErrorCode::NotFound`].
+    pub fn incoming(&self) -> impl Stream<Item = (Message, Responder<C>)> + use<C> {
+        let (tx, rx) = mpsc::channel(PUSH_CAPACITY);
+        *self.incoming.lock().expect("poisoned") = Some(tx);
+        stream::unfold(rx, |mut rx| async move {
+            let item = rx.recv().await?;
+            Some((item, rx))
+        })
+    }
+
+    /// What happens on the connection
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ConnEvent> {
+        self.events.subscribe()
+    }
+
+    /// Send `msg` without waiting for a response
+    ///
+    /// Waits while the send queue is full. Fails with
+    /// [`MessageTooLarge`](crate::protocol::MessageTooLarge) before anything is
+    /// sent if `msg` exceeds the configured limits.
+    pub async fn send(&self, msg: Message) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut envelope = MessageEnvelope::new(id, msg);
+        if let Some(coalescer) = &self.coalescer {
+            self.intercept_unstamped(&mut envelope);
+            record(&self.capture, Direction::Sent, &envelope);
+            return coalescer.send(envelope.body).await;
+        }
+        self.intercept(&mut envelope);
+        let encoded = self.config.encode(&self.codec, self.version, &envelope)?;
+        record(&self.capture, Direction::Sent, &envelope);
+        self.queue.send(encoded).await
+    }
+
+    /// Queue `msg` without waiting for room or a response
+    ///
+    /// Fails with [`QueueFull`](crate::queue::QueueFull) if the send queue has
+    /// no room, for callers that would rather drop or coalesce messages than
+    /// slow down.
+    pub fn try_send(&self, msg: Message) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut envelope = MessageEnvelope::new(id, msg);
+        if let Some(coalescer) = &self.coalescer {
+            self.intercept_unstamped(&mut envelope);
+            record(&self.capture, Direction::Sent, &envelope);
+            return coalescer.try_send(envelope.body);
+        }
+        self.intercept(&mut envelope);
+        let encoded = self.config.encode(&self.codec, self.version, &envelope)?;
+        record(&self.capture, Direction::Sent, &envelope);
+        self.queue.try_send(encoded)
+    }
+
+    /// Send `msg` and wait for the response carrying the same id
+    ///
+    /// A [`Message::Error`] response is returned as a [`RemoteError`].
+    pub async fn request(&self, msg: Message) -> Result<Message> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.call(MessageEnvelope::new(id, msg)).await
+    }
+
+    /// Like [`request`](Self::request), but give up if no response arrives
+    /// within `timeout`
+    ///
+    /// The deadline travels with the request, so the server skips it if it
+    /// only gets to it once the client stopped waiting. Fails with
+    /// [`RequestTimedOut`]; a response arriving later is dropped.
+    pub async fn call_with_timeout(&self, msg: Message, timeout: Duration) -> Result<Message> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut envelope = MessageEnvelope::new(id, msg);
+        envelope.deadline = Some(timeout.as_millis().try_into().unwrap_or(u64::MAX));
+        match tokio::time::timeout(timeout, self.call(envelope)).await {
+            Ok(reply) => reply,
+            Err(_) => Err(RequestTimedOut::new(timeout).into()),
+        }
+    }
+
+    async fn call(&self, envelope: MessageEnvelope) -> Result<Message> {
+        // The span the server continues, covering the whole round trip
+        let span = info_span!("request", id = envelope.id, kind = ?envelope.body.kind());
+        self.round_trip(envelope).instrument(span).await
+    }
+
+    async fn round_trip(&self, mut envelope: MessageEnvelope) -> Result<Message> {
+        self.intercept(&mut envelope);
+        if let Some(streams) = &self.streams {
+            return self.pooled_round_trip(streams, envelope).await;
+        }
+        let encoded = self.config.encode(&self.codec, self.version, &envelope)?;
+        record(&self.capture, Direction::Sent, &envelope);
+
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("poisoned")
+            .insert(envelope.id, tx);
+        // Forget the request however the wait ends, including being cancelled
+        let _pending = Pending {
+            pending: &self.pending,
+            id: envelope.id,
+        };
+        self.queue.send(encoded).await?;
+
+        match rx
+            .await
+            .std_context("connection closed before response arrived")?
+        {
+            Message::Error { code, detail } => Err(RemoteError::new(code, detail).into()),
+            reply => Ok(reply),
+        }
+    }
+
+    async fn pooled_round_trip(
+        &self,
+        streams: &StreamPool<C>,
+        envelope: MessageEnvelope,
+    ) -> Result<Message> {
+        // Frames carry envelopes behind their preamble, uncompressed
+        let encoded = envelope.frame(&self.codec, self.version)?;
+        self.config.check(envelope.body.kind(), encoded.len())?;
+        record(&self.capture, Direction::Sent, &envelope);
+
+        let bytes = streams.send_raw(encoded).await?;
+        let mut reply = decode_frame(&self.codec, self.version, &bytes)?;
+        self.config.check_shape(&reply.body)?;
+        if let Some(refusal) = refusal(&self.signing, &reply) {
+            reply.body = refusal;
+        }
+        self.liveness.active();
+        self.events.emit(ConnEvent::MessageReceived {
+            peer: self.conn.remote_id(),
+            kind: reply.body.kind(),
+            size: bytes.len(),
+        });
+        record(&self.capture, Direction::Received, &reply);
+        match reply.body {
+            Message::Error { code, detail } => Err(RemoteError::new(code, detail).into()),
+            reply => Ok(reply),
+        }
+    }
+
+    /// Ask for every broadcast retained from `from_offset` on, see
+    /// [`Message::Resume`]
+    ///
+    /// Returns the replayed broadcasts with their offsets, and the offset to
+    /// resume from next time. A first returned offset above `from_offset`
+    /// means older broadcasts were already evicted.
+    pub async fn resume(&self, from_offset: u64) -> Result<(Vec<(u64, Message)>, u64)> {
+        match self.request(Message::Resume { from_offset }).await? {
+            Message::Replay {
+                entries,
+                next_offset,
+            } => Ok((entries, next_offset)),
+            other => Err(anyerr!("expected a replay, got {:?}", other.kind())),
+        }
+    }
+
+    /// Subscribe to the topics `filter` matches, see [`Message::Subscribe`]
+    ///
+    /// Publishes on those topics arrive through
+    /// [`subscribe_pushes`](Self::subscribe_pushes).
+    pub async fn subscribe(&self, filter: impl Into<String>) -> Result<()> {
+        self.acked(Message::Subscribe(filter.into())).await
+    }
+
+    /// Drop the subscription made with exactly `filter`
+    pub async fn unsubscribe(&self, filter: impl Into<String>) -> Result<()> {
+        self.acked(Message::Unsubscribe(filter.into())).await
+    }
+
+    /// Publish `payload` on `topic` to the other subscribed peers
+    pub async fn publish(&self, topic: impl Into<String>, payload: Vec<u8>) -> Result<()> {
+        let topic = topic.into();
+        self.acked(Message::Publish { topic, payload }).await
+    }
+
+    /// Publish `payload` on `topic` sealed by `sealer`, so only `recipients`
+    /// can read it and the server routing it cannot
+    ///
+    /// It is sealed to the session keys the recipients offered on their
+    /// connections to the server, so each must be connected, and by the one
+    /// `sealer` offered on this connection. Recipients open it with
+    /// [`Sealer::open`].
+    pub async fn publish_sealed(
+        &self,
+        sealer: &Sealer,
+        topic: impl Into<String>,
+        recipients: &[PublicKey],
+        payload: &[u8],
+    ) -> Result<()> {
+        let topic = topic.into();
+        let keys = self.session_keys(recipients).await?;
+        for recipient in recipients {
+            if !keys.iter().any(|key| key.owner == *recipient.as_bytes()) {
+                return Err(anyerr!(
+                    "{} offered no session key to seal to",
+                    recipient.fmt_short()
+                ));
+            }
+        }
+        let payload = sealer.seal(&topic, &keys, payload)?;
+        self.publish(topic, payload).await
+    }
+
+    /// The session keys the peers holding `owners` offered on their
+    /// connections to the server, see [`Message::FindSessionKeys`]
+    pub async fn session_keys(&self, owners: &[PublicKey]) -> Result<Vec<SessionKey>> {
+        match self
+            .request(Message::FindSessionKeys(owners.to_vec()))
+            .await?
+        {
+            Message::SessionKeys(mut keys) => {
+                // Only those asked for, whatever the server sent
+                keys.retain(|key| owners.iter().any(|owner| *owner.as_bytes() == key.owner));
+                Ok(keys)
+            }
+            other => Err(anyerr!("expected session keys, got {:?}", other.kind())),
+        }
+    }
+
+    /// Tell the server how other peers can reach this one directly, usually
+    /// the local endpoint's [`Endpoint::addr`](iroh::Endpoint::addr)
+    pub async fn announce(&self, addr: EndpointAddr) -> Result<()> {
+        self.acked(Message::Announce(addr)).await
+    }
+
+    /// The other peers connected to the server, see [`Message::PeerList`]
+    pub async fn peers(&self) -> Result<Vec<EndpointAddr>> {
+        match self.request(Message::ListPeers).await? {
+            Message::PeerList(peers) => Ok(peers),
+            other => Err(anyerr!("expected a peer list, got {:?}", other.kind())),
+        }
+    }
+
+    /// Ask the server to introduce this client to the connected peer `peer`
+    ///
+    /// Returns the address to dial `peer` at directly; `peer` in turn gets
+    /// this client's address pushed as a [`Message::Introduce`]. Fails with
+    /// [`ErrorCode::NotFound`](crate::protocol::ErrorCode::NotFound) if `peer`
+    /// is not connected.
+    pub async fn introduce(&self, peer: EndpointId) -> Result<EndpointAddr> {
+        let intro = Message::Introduce {
+            peer: EndpointAddr::new(peer),
+        };
+        match self.request(intro).await? {
+            Message::Introduce { peer } => Ok(peer),
+            other => Err(anyerr!("expected an introduction, got {:?}", other.kind())),
+        }
+    }
+
+    /// Send `request` to the server's lobby and wait for its answer
+    ///
+    /// A refusal is returned as a [`RoomRefused`] error. News about the room,
+    /// such as other members joining, arrives as [`Message::Room`] through
+    /// [`subscribe_pushes`](Self::subscribe_pushes).
+    pub async fn lobby(&self, request: LobbyRequest) -> Result<RoomEvent> {
+        match self.request(Message::Lobby(request)).await? {
+            Message::Room(RoomEvent::Refused { reason }) => Err(RoomRefused::new(reason).into()),
+            Message::Room(event) => Ok(event),
+            other => Err(anyerr!("expected a room event, got {:?}", other.kind())),
+        }
+    }
+
+    /// Wait in the server's queue for a match of `party_size` clients
+    /// playing `mode`, see [`Message::JoinQueue`]
+    ///
+    /// Returns once queued; the match arrives as [`Message::MatchFound`]
+    /// through [`subscribe_pushes`](Self::subscribe_pushes).
+    pub async fn join_queue(
+        &self,
+        mode: impl Into<String>,
+        party_size: usize,
+        rating: Option<u32>,
+    ) -> Result<()> {
+        let mode = mode.into();
+        self.acked(Message::JoinQueue {
+            mode,
+            party_size,
+            rating,
+        })
+        .await
+    }
+
+    pub async fn leave_queue(&self) -> Result<()> {
+        self.acked(Message::LeaveQueue).await
+    }
+
+    /// Start a session on the server as the player `name`
+    pub async fn login(&self, name: impl Into<String>) -> Result<Presence> {
+        self.presence_of(Message::Login { name: name.into() }).await
+    }
+
+    /// Change the status of this client's session
+    pub async fn set_status(&self, status: Status) -> Result<Presence> {
+        self.presence_of(Message::SetStatus(status)).await
+    }
+
+    /// Watch the presence of `peers`, returning their current presence
+    ///
+    /// Later changes arrive through
+    /// [`subscribe_presence`](Self::subscribe_presence).
+    pub async fn watch_presence(&self, peers: Vec<EndpointId>) -> Result<Vec<Presence>> {
+        match self.request(Message::WatchPresence(peers)).await? {
+            Message::PresenceList(list) => Ok(list),
+            other => Err(anyerr!("expected a presence list, got {:?}", other.kind())),
+        }
+    }
+
+    /// The statistics of this client's connection, as the server sees it
+    ///
+    /// [`Connection::stats`] has the same from this side.
+    pub async fn remote_stats(&self) -> Result<ConnectionStats> {
+        match self.request(Message::StatsRequest).await? {
+            Message::StatsResponse(stats) => Ok(stats),
+            other => Err(anyerr!("expected connection stats, got {:?}", other.kind())),
+        }
+    }
+
+    async fn presence_of(&self, msg: Message) -> Result<Presence> {
+        match self.request(msg).await? {
+            Message::Presence(presence) => Ok(presence),
+            other => Err(anyerr!("expected a presence, got {:?}", other.kind())),
+        }
+    }
+
+    /// Send `msg` and wait for the server to echo back the same kind
+    async fn acked(&self, msg: Message) -> Result<()> {
+        let kind = msg.kind();
+        let reply = self.request(msg).await?;
+        if reply.kind() != kind {
+            return Err(anyerr!("expected {:?} back, got {:?}", kind, reply.kind()));
+        }
+        Ok(())
+    }
+
+    /// Send `msg` as an unreliable datagram, see [`datagram::send_datagram`]
+    ///
+    /// An echo server answers with a datagram of its own, if neither gets
+    /// lost on the way.
+    pub fn send_datagram(&self, msg: &Message) -> Result<()> {
+        let mut envelope = MessageEnvelope::new(PUSH_ID, msg.clone());
+        self.intercept(&mut envelope);
+        datagram::send_datagram(&self.conn, &self.codec, self.version, &envelope.body)
+    }
+
+    /// Tag `envelope` with the current trace, run it through the
+    /// interceptors, then number and sign it, as every message this client
+    /// sends, for sending it some other way such as on a framed stream
+    pub fn intercept(&self, envelope: &mut MessageEnvelope) {
+        self.intercept_unstamped(envelope);
+        stamped(&self.signing, &self.counter, envelope);
+    }
+
+    /// Like [`intercept`](Self::intercept), leaving numbering and signing to
+    /// the batch a coalesced message goes out in
+    fn intercept_unstamped(&self, envelope: &mut MessageEnvelope) {
+        // Every outgoing message but heartbeats and answers passes here
+        self.liveness.active();
+        traced(&self.interceptors, self.conn.remote_id(), envelope);
+    }
+
+    /// What numbers the messages this client sends, for anything else
+    /// sending on its connection to share, see
+    /// [`MessageConnection::with_counter`](crate::MessageConnection::with_counter)
+    pub fn counter(&self) -> &MessageCounter {
+        &self.counter
+    }
+
+    /// The datagrams the server sends, see [`datagram::recv_datagrams`]
+    pub fn recv_datagrams(&self) -> impl Stream<Item = Result<Message>> + use<C> {
+        datagram::recv_datagrams(self.conn.clone(), self.codec.clone(), self.version)
+    }
+}
+
+impl<C> Drop for Client<C> {
+    fn drop(&mut self) {
+        self.responses.abort();
+        if let Some(heartbeat) = &self.heartbeat {
+            heartbeat.abort();
+        }
+        if let Some(idle) = &self.idle {
+            idle.abort();
+        }
+        if let Some(qlog) = &self.qlog {
+            qlog.abort();
+        }
+    }
+}
+
+/// Tag `envelope` with the current trace, run it through `interceptors`, then
+/// number it with `counter` and sign it with the key of `signing`
+fn intercepted(
+    interceptors: &InterceptorSlot,
+    signing: &SigningSlot,
+    counter: &MessageCounter,
+    peer: EndpointId,
+    envelope: &mut MessageEnvelope,
+) {
+    traced(interceptors, peer, envelope);
+    stamped(signing, counter, envelope);
+}
+
+/// Tag `envelope` with the current trace and run it through `interceptors`
+fn traced(interceptors: &InterceptorSlot, peer: EndpointId, envelope: &mut MessageEnvelope) {
+    envelope.trace = TraceContext::current();
+    intercept(&interceptors.lock().expect("poisoned"), peer, envelope);
+}
+
+/// Number `envelope` with `counter` and sign it with the key of `signing`
+fn stamped(signing: &SigningSlot, counter: &MessageCounter, envelope: &mut MessageEnvelope) {
+    replay::stamp(envelope, || counter.next());
+    let keys = signing.lock().expect("poisoned").clone();
+    signing::sign_outgoing(keys.as_deref(), envelope);
+}
+
+/// The answer to `envelope` if its signature is not one `signing` accepts
+fn refusal(signing: &SigningSlot, envelope: &MessageEnvelope) -> Option<Message> {
+    let keys = signing.lock().expect("poisoned").clone();
+    signing::refusal(keys.as_deref(), envelope)
+}
+
+fn record(capture: &CaptureSlot, direction: Direction, envelope: &MessageEnvelope) {
+    if let Some(capture) = &*capture.lock().expect("poisoned") {
+        capture.record(direction, envelope);
+    }
+}
+
+/// Everything the background task needs to route incoming streams
+#[derive(Debug, Clone)]
+struct Dispatch<C> {
+    conn: Connection,
+    codec: C,
+    config: ProtocolConfig,
+    version: u32,
+    pending: PendingMap,
+    liveness: Liveness,
+    pushes: broadcast::Sender<Message>,
+    presence: broadcast::Sender<Presence>,
+    incoming: IncomingSlot<C>,
+    chaos: ChaosSlot,
+    capture: CaptureSlot,
+    interceptors: InterceptorSlot,
+    signing: SigningSlot,
+    counter: MessageCounter,
+    /// Counters of the messages the server sent on this connection
+    replay: Arc<ReplayWindow>,
+    events: Events,
+}
+
+impl<C: Codec> Dispatch<C> {
+    /// Accept response streams and hand each one to the request waiting on its id
+    ///
+    /// Bidirectional streams carry requests of the server, whose answer goes
+    /// back on the same stream.
+    async fn run(self) {
+        let peer = self.conn.remote_id();
+        loop {
+            let accepted = tokio::select! {
+                uni = self.conn.accept_uni() => uni.map(|recv| (None, recv)),
+                bi = self.conn.accept_bi() => bi.map(|(send, recv)| (Some(send), recv)),
+            };
+            let Ok((send, mut recv)) = accepted else {
+                break;
+            };
+            self.events.emit(ConnEvent::StreamOpened {
+                peer,
+                stream: recv.id(),
+            });
+            let span = debug_span!("stream", id = %recv.id());
+            let this = self.clone();
+            let handler = async move {
+                match chaos::strike(&this.chaos, &this.conn).await {
+                    Strike::Deliver => {}
+                    Strike::Drop => return,
+                    Strike::Reset => {
+                        recv.stop(CHAOS_RESET).ok();
+                        return;
+                    }
+                }
+                let envelope = this
+                    .config
+                    .read_message(&mut recv)
+                    .await
+                    .and_then(|bytes| this.config.decode(&this.codec, this.version, &bytes));
+                match envelope {
+                    Ok((envelope, size)) => {
+                        this.events.emit(ConnEvent::MessageReceived {
+                            peer,
+                            kind: envelope.body.kind(),
+                            size,
+                        });
+                        if !is_heartbeat(&envelope)
+                            && let Some(refusal) = this.refusal(&envelope)
+                        {
+                            return this.refuse(envelope, refusal, send).await;
+                        }
+                        match send {
+                            Some(send) => this.answer(envelope, send).await,
+                            None => this.route(envelope).await,
+                        }
+                    }
+                    Err(e) => warn!("error receiving response: {:#}", e),
+                }
+            };
+            tokio::spawn(handler.instrument(span));
+        }
+        // Connection is gone: fail everything still waiting
+        self.pending.lock().expect("poisoned").clear();
+        self.events.emit(ConnEvent::Disconnected {
+            peer,
+            reason: self.conn.closed().await,
+        });
+    }
+
+    async fn route(&self, envelope: MessageEnvelope) {
+        match is_heartbeat(&envelope) {
+            true => self.liveness.touch(),
+            false => self.liveness.active(),
+        }
+        record(&self.capture, Direction::Received, &envelope);
+        if is_heartbeat(&envelope) {
+            if let Message::Ping { .. } = &envelope.body {
+                let pong = MessageEnvelope::new(envelope.id, envelope.body.reply());
+                if let Ok(pong) = pong.wire(&self.codec, self.version) {
+                    send_message(&self.conn, &self.codec, &pong).await.ok();
+                }
+            }
+        } else if envelope.id == PUSH_ID {
+            // Nobody subscribed is fine, the push is simply dropped
+            if let Message::Presence(presence) = &envelope.body {
+                self.presence.send(presence.clone()).ok();
+            }
+            self.pushes.send(envelope.body.clone()).ok();
+            let incoming = self.incoming.lock().expect("poisoned").clone();
+            if let Some(incoming) = incoming
+                && incoming
+                    .try_send((envelope.body, self.responder(None, PUSH_ID)))
+                    .is_err()
+            {
+                debug!("incoming stream is behind or gone, dropping push");
+            }
+        } else {
+            // Responses to one-way sends have nobody waiting for them
+            let waiter = self.pending.lock().expect("poisoned").remove(&envelope.id);
+            if let Some(tx) = waiter {
+                tx.send(envelope.body).ok();
+            }
+        }
+    }
+
+    /// The answer to `envelope` if its signature is not one this client
+    /// accepts or it was seen before
+    fn refusal(&self, envelope: &MessageEnvelope) -> Option<Message> {
+        refusal(&self.signing, envelope).or_else(|| {
+            // Relayed messages keep their authors' counters, so they are
+            // counted by the key they name even where it goes unchecked: only
+            // the server this client chose sends here, while a server hears
+            // from anyone and counts by verified keys alone
+            let signer = envelope.signature.as_ref().map(|signature| &signature.key);
+            self.replay.refusal(self.version, envelope, signer)
+        })
+    }
+
+    /// Answer a server request refused for its signature or as a replay with
+    /// `refusal`, fail the request waiting for a refused answer, and drop a
+    /// refused push
+    async fn refuse(&self, envelope: MessageEnvelope, refusal: Message, send: Option<SendStream>) {
+        if let Message::Error { detail, .. } = &refusal {
+            warn!(id = envelope.id, kind = ?envelope.body.kind(), "refusing message: {}", detail);
+        }
+        match send {
+            Some(send) => {
+                if let Err(e) = self
+                    .responder(Some(send), envelope.id)
+                    .respond(refusal)
+                    .await
+                {
+                    debug!("error refusing server request: {:#}", e);
+                }
+            }
+            None => {
+                let waiter = self.pending.lock().expect("poisoned").remove(&envelope.id);
+                if let Some(tx) = waiter {
+                    tx.send(refusal).ok();
+                }
+            }
+        }
+    }
+
+    /// Hand a request of the server to [`Client::incoming`], or refuse it if
+    /// nobody listens
+    async fn answer(&self, envelope: MessageEnvelope, send: SendStream) {
+        self.liveness.active();
+        let responder = self.responder(Some(send), envelope.id);
+        let incoming = self.incoming.lock().expect("poisoned").clone();
+        let unhandled = match incoming {
+            Some(incoming) => match incoming.send((envelope.body, responder)).await {
+                Ok(()) => return,
+                Err(mpsc::error::SendError((_, responder))) => responder,
+            },
+            None => responder,
+        };
+        let refusal = Message::Error {
+            code: ErrorCode::NotFound,
+            detail: "client does not take requests".to_string(),
+        };
+        if let Err(e) = unhandled.respond(refusal).await {
+            debug!("error refusing server request: {:#}", e);
+        }
+    }
+
+    fn responder(&self, send: Option<SendStream>, id: u64) -> Responder<C> {
+        Responder {
+            send,
+            id,
+            peer: self.conn.remote_id(),
+            codec: self.codec.clone(),
+            config: self.config.clone(),
+            version: self.version,
+            interceptors: self.interceptors.clone(),
+            signing: self.signing.clone(),
+            counter: self.counter.clone(),
+        }
+    }
+}
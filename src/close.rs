@@ -0,0 +1,157 @@
+use std::fmt;
+
+use iroh::endpoint::{Connection, ConnectionError, VarInt};
+
+// ====================
+// Close Reasons
+// ====================
+
+/// Why a connection was closed, carried to the peer as its application close
+/// code
+///
+/// The codes are part of the protocol and never change meaning, so peers of
+/// different versions agree on them; [`CloseReason::from_code`] knows them
+/// all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    /// Done, with nothing wrong
+    Normal,
+    /// The peer stopped answering heartbeats, see
+    /// [`run_heartbeat`](crate::heartbeat::run_heartbeat)
+    HeartbeatTimeout,
+    /// The peer is not allowed in, see [`AccessPolicy`](crate::access::AccessPolicy)
+    AccessDenied,
+    /// The peer failed to authenticate, see [`authenticate`](crate::auth::authenticate)
+    AuthFailed,
+    /// The closing side is going away, as a server does on shutdown
+    GoingAway,
+    /// An operator kicked the peer, see [`Echo::kick`](crate::server::Echo::kick)
+    Kicked,
+    /// Injected by [`ChaosConfig`](crate::chaos::ChaosConfig)
+    Chaos,
+    /// The server is at capacity, see
+    /// [`ConnectionLimits`](crate::limits::ConnectionLimits)
+    Busy,
+    /// Nothing but heartbeats went over the connection for too long, see
+    /// [`run_idle_timeout`](crate::heartbeat::run_idle_timeout)
+    Idle,
+    /// The peer broke the protocol
+    ProtocolError,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 10] = [
+        CloseReason::Normal,
+        CloseReason::HeartbeatTimeout,
+        CloseReason::AccessDenied,
+        CloseReason::AuthFailed,
+        CloseReason::GoingAway,
+        CloseReason::Kicked,
+        CloseReason::Chaos,
+        CloseReason::Busy,
+        CloseReason::Idle,
+        CloseReason::ProtocolError,
+    ];
+
+    /// The application close code this travels as
+    pub const fn code(self) -> VarInt {
+        VarInt::from_u32(match self {
+            CloseReason::Normal => 0,
+            CloseReason::HeartbeatTimeout => 1,
+            CloseReason::AccessDenied => 2,
+            CloseReason::AuthFailed => 3,
+            CloseReason::GoingAway => 4,
+            CloseReason::Kicked => 5,
+            CloseReason::Chaos => 6,
+            CloseReason::Busy => 7,
+            CloseReason::Idle => 8,
+            CloseReason::ProtocolError => 9,
+        })
+    }
+
+    /// The reason closing with `code` stands for, if it is one of ours
+    pub fn from_code(code: VarInt) -> Option<Self> {
+        Self::ALL.into_iter().find(|reason| reason.code() == code)
+    }
+
+    /// Close `conn` for this reason, telling the peer `detail`
+    pub fn close(self, conn: &Connection, detail: &str) {
+        conn.close(self.code(), detail.as_bytes());
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CloseReason::Normal => "normal",
+            CloseReason::HeartbeatTimeout => "heartbeat timeout",
+            CloseReason::AccessDenied => "access denied",
+            CloseReason::AuthFailed => "authentication failed",
+            CloseReason::GoingAway => "going away",
+            CloseReason::Kicked => "kicked",
+            CloseReason::Chaos => "chaos",
+            CloseReason::Busy => "busy",
+            CloseReason::Idle => "idle",
+            CloseReason::ProtocolError => "protocol error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// ====================
+// Remote Closes
+// ====================
+
+/// How the peer closed a connection, as learned from the error the
+/// connection ended with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteClose {
+    /// `None` for a code that is not a [`CloseReason`], e.g. of another
+    /// application sharing the transport
+    pub reason: Option<CloseReason>,
+    pub code: VarInt,
+    /// What the peer said along with the code, lossily decoded
+    pub detail: String,
+}
+
+impl RemoteClose {
+    /// The peer's close, if `error` says the peer closed the connection
+    ///
+    /// Connections closed locally, lost or timed out have none.
+    pub fn of(error: &ConnectionError) -> Option<Self> {
+        match error {
+            ConnectionError::ApplicationClosed(close) => Some(Self {
+                reason: CloseReason::from_code(close.error_code),
+                code: close.error_code,
+                detail: String::from_utf8_lossy(&close.reason).into_owned(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether the peer closed for `reason`
+    pub fn is(&self, reason: CloseReason) -> bool {
+        self.reason == Some(reason)
+    }
+}
+
+impl fmt::Display for RemoteClose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Some(reason) => write!(f, "{reason}")?,
+            None => write!(f, "code {}", self.code)?,
+        }
+        // Most details just repeat the reason
+        if !self.detail.is_empty()
+            && self
+                .reason
+                .is_none_or(|reason| reason.name() != self.detail)
+        {
+            write!(f, ": {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
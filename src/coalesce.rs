@@ -0,0 +1,133 @@
+use std::{future::Future, time::Duration};
+
+use n0_error::{Result, StdResultExt, anyerr};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::debug;
+
+use crate::{protocol::Message, queue::QueueFull};
+
+/// How long a [`Coalescer`] holds a message for others to join it by default
+pub const COALESCE_WINDOW: Duration = Duration::from_millis(1);
+
+/// Most messages a [`Coalescer`] puts in one batch by default
+pub const MAX_BATCH: usize = 64;
+
+/// A message waiting for its batch, and who to tell how sending it went
+type Queued = (Message, Option<oneshot::Sender<Result<()>>>);
+
+// ====================
+// Coalescer
+// ====================
+
+/// Groups messages sent close together into one [`Message::Batch`], so they
+/// share a stream instead of each opening their own
+///
+/// The first message of a batch waits up to the window for more; the batch
+/// is handed on once the window closes or it is full. A message that stays
+/// alone is handed on as it is. Batches are handed on as soon as they are
+/// complete, without waiting for the one before to be sent.
+#[derive(Debug)]
+pub struct Coalescer {
+    tx: mpsc::Sender<Queued>,
+    task: JoinHandle<()>,
+    window: Duration,
+    max_batch: usize,
+}
+
+impl Coalescer {
+    /// Coalesce messages sent within `window` of each other into batches of
+    /// up to `max_batch`, sending each with `flush`
+    pub fn new<F, Fut>(window: Duration, max_batch: usize, flush: F) -> Self
+    where
+        F: FnMut(Message) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let max_batch = max_batch.max(1);
+        let (tx, rx) = mpsc::channel(max_batch);
+        let task = tokio::spawn(coalesce(rx, window, max_batch, flush));
+        Self {
+            tx,
+            task,
+            window,
+            max_batch,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn max_batch(&self) -> usize {
+        self.max_batch
+    }
+
+    /// Add `msg` to the current batch and wait until the batch has been sent
+    pub async fn send(&self, msg: Message) -> Result<()> {
+        let (done, sent) = oneshot::channel();
+        self.tx
+            .send((msg, Some(done)))
+            .await
+            .map_err(|_| anyerr!("coalescer closed"))?;
+        sent.await.std_context("coalescer closed")?
+    }
+
+    /// Add `msg` to the current batch without waiting, failing with
+    /// [`QueueFull`] if the batch cannot take it yet
+    ///
+    /// Errors sending it are only logged.
+    pub fn try_send(&self, msg: Message) -> Result<()> {
+        self.tx.try_send((msg, None)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => QueueFull::new().into(),
+            mpsc::error::TrySendError::Closed(_) => anyerr!("coalescer closed"),
+        })
+    }
+}
+
+impl Drop for Coalescer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn coalesce<F, Fut>(
+    mut rx: mpsc::Receiver<Queued>,
+    window: Duration,
+    max_batch: usize,
+    mut flush: F,
+) where
+    F: FnMut(Message) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    while let Some(first) = rx.recv().await {
+        let closes = Instant::now() + window;
+        let mut batch = vec![first];
+        while batch.len() < max_batch {
+            match tokio::time::timeout_at(closes, rx.recv()).await {
+                Ok(Some(queued)) => batch.push(queued),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let (mut messages, waiters): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        let msg = match messages.len() {
+            1 => messages.pop().expect("one message"),
+            _ => Message::Batch(messages),
+        };
+        let sent = flush(msg);
+        tokio::spawn(async move {
+            // Every waiter learns of the same failure
+            let result = sent.await.map_err(|e| format!("{e:#}"));
+            if let Err(e) = &result
+                && waiters.iter().all(Option::is_none)
+            {
+                debug!("error sending coalesced messages: {}", e);
+            }
+            for done in waiters.into_iter().flatten() {
+                done.send(result.clone().map_err(|e| anyerr!("{e}"))).ok();
+            }
+        });
+    }
+}
@@ -0,0 +1,305 @@
+use std::{fmt, str::FromStr};
+
+use bytes::{BufMut, BytesMut};
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::protocol::MAX_MESSAGE_SIZE;
+
+/// The [`Codec::wire_id`] of codecs that do not name themselves, whose frames
+/// receivers take on trust
+pub const UNNAMED_CODEC: u8 = 0;
+
+/// Turns values into wire bytes and back
+///
+/// Every transport helper is generic over the codec, so peers only need to
+/// agree on the codec to interoperate. [`Bincode`] is the default.
+pub trait Codec: fmt::Debug + Clone + Send + Sync + 'static {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+
+    /// The byte naming this codec in the preamble of every frame, so a peer
+    /// encoding with another codec is told apart from a corrupt one, see
+    /// [`strip_preamble`](crate::protocol::strip_preamble)
+    fn wire_id(&self) -> u8 {
+        UNNAMED_CODEC
+    }
+
+    /// Append the encoding of `value` to `buf`
+    ///
+    /// Encodes to a fresh `Vec` unless overridden; the built-in codecs write
+    /// straight into `buf`.
+    fn encode_into<T: Serialize>(&self, value: &T, buf: &mut BytesMut) -> Result<()> {
+        buf.extend_from_slice(&self.encode(value)?);
+        Ok(())
+    }
+}
+
+/// Compact binary encoding, the fast default for Rust peers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn wire_id(&self) -> u8 {
+        1
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serde::encode_to_vec(value, bincode::config::standard()).anyerr()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        // Without a limit, bincode allocates whatever length a peer claims
+        // before finding out the bytes are not there
+        let config = bincode::config::standard().with_limit::<MAX_MESSAGE_SIZE>();
+        let (value, _) = bincode::serde::decode_from_slice(bytes, config).anyerr()?;
+        Ok(value)
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buf: &mut BytesMut) -> Result<()> {
+        let config = bincode::config::standard();
+        bincode::serde::encode_into_std_write(value, &mut buf.writer(), config).anyerr()?;
+        Ok(())
+    }
+}
+
+/// JSON, for talking to peers that are not written in Rust
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn wire_id(&self) -> u8 {
+        2
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).anyerr()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).anyerr()
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buf: &mut BytesMut) -> Result<()> {
+        serde_json::to_writer(buf.writer(), value).anyerr()
+    }
+}
+
+/// Postcard, a compact format suited to embedded peers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+impl Codec for Postcard {
+    fn wire_id(&self) -> u8 {
+        3
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        postcard::to_stdvec(value).anyerr()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        postcard::from_bytes(bytes).anyerr()
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buf: &mut BytesMut) -> Result<()> {
+        postcard::to_io(value, buf.writer()).anyerr()?;
+        Ok(())
+    }
+}
+
+/// CBOR, a self-describing binary format with wide language support
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn wire_id(&self) -> u8 {
+        4
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(value, &mut buf).anyerr()?;
+        Ok(buf)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        ciborium::from_reader(bytes).anyerr()
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buf: &mut BytesMut) -> Result<()> {
+        ciborium::into_writer(value, buf.writer()).anyerr()
+    }
+}
+
+/// MessagePack, a compact self-describing format with wide language support
+///
+/// Structs are encoded as maps keyed by field name, as most MessagePack
+/// libraries outside Rust expect.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn wire_id(&self) -> u8 {
+        5
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).anyerr()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(bytes).anyerr()
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buf: &mut BytesMut) -> Result<()> {
+        rmp_serde::encode::write_named(&mut buf.writer(), value).anyerr()
+    }
+}
+
+/// Protocol Buffers, matching the schema in `proto/echo.proto` so peers can
+/// generate their types from it
+///
+/// See [`protobuf`](crate::protobuf) for how values map onto messages.
+/// Protobuf cannot tell an empty list from an absent one, so an
+/// `Option<Vec<_>>` of `Some(vec![])` decodes as `None`.
+#[cfg(feature = "protobuf")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Protobuf;
+
+#[cfg(feature = "protobuf")]
+impl Codec for Protobuf {
+    fn wire_id(&self) -> u8 {
+        6
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        crate::protobuf::to_vec(value).anyerr()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        crate::protobuf::from_slice(bytes).anyerr()
+    }
+}
+
+/// Any of the built-in codecs, chosen at runtime
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CodecKind {
+    #[default]
+    Bincode,
+    Json,
+    Postcard,
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl CodecKind {
+    /// Every codec this build has
+    pub const ALL: &[CodecKind] = &[
+        CodecKind::Bincode,
+        CodecKind::Json,
+        CodecKind::Postcard,
+        CodecKind::Cbor,
+        #[cfg(feature = "msgpack")]
+        CodecKind::MessagePack,
+        #[cfg(feature = "protobuf")]
+        CodecKind::Protobuf,
+    ];
+
+    /// The codec of this build named by `id` in a frame's preamble
+    pub fn from_wire_id(id: u8) -> Option<Self> {
+        CodecKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.wire_id() == id)
+    }
+}
+
+impl Codec for CodecKind {
+    fn wire_id(&self) -> u8 {
+        match self {
+            CodecKind::Bincode => Bincode.wire_id(),
+            CodecKind::Json => Json.wire_id(),
+            CodecKind::Postcard => Postcard.wire_id(),
+            CodecKind::Cbor => Cbor.wire_id(),
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack => MessagePack.wire_id(),
+            #[cfg(feature = "protobuf")]
+            CodecKind::Protobuf => Protobuf.wire_id(),
+        }
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            CodecKind::Bincode => Bincode.encode(value),
+            CodecKind::Json => Json.encode(value),
+            CodecKind::Postcard => Postcard.encode(value),
+            CodecKind::Cbor => Cbor.encode(value),
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack => MessagePack.encode(value),
+            #[cfg(feature = "protobuf")]
+            CodecKind::Protobuf => Protobuf.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            CodecKind::Bincode => Bincode.decode(bytes),
+            CodecKind::Json => Json.decode(bytes),
+            CodecKind::Postcard => Postcard.decode(bytes),
+            CodecKind::Cbor => Cbor.decode(bytes),
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack => MessagePack.decode(bytes),
+            #[cfg(feature = "protobuf")]
+            CodecKind::Protobuf => Protobuf.decode(bytes),
+        }
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buf: &mut BytesMut) -> Result<()> {
+        match self {
+            CodecKind::Bincode => Bincode.encode_into(value, buf),
+            CodecKind::Json => Json.encode_into(value, buf),
+            CodecKind::Postcard => Postcard.encode_into(value, buf),
+            CodecKind::Cbor => Cbor.encode_into(value, buf),
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack => MessagePack.encode_into(value, buf),
+            #[cfg(feature = "protobuf")]
+            CodecKind::Protobuf => Protobuf.encode_into(value, buf),
+        }
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = n0_error::AnyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        CodecKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.to_string() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = CodecKind::ALL.iter().map(CodecKind::to_string).collect();
+                anyerr!("unknown codec {s:?}, expected one of {}", names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for CodecKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CodecKind::Bincode => "bincode",
+            CodecKind::Json => "json",
+            CodecKind::Postcard => "postcard",
+            CodecKind::Cbor => "cbor",
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack => "msgpack",
+            #[cfg(feature = "protobuf")]
+            CodecKind::Protobuf => "protobuf",
+        })
+    }
+}
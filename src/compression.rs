@@ -0,0 +1,138 @@
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use bytes::{BufMut, BytesMut};
+use n0_error::{Result, StdResultExt, anyerr};
+
+use crate::protocol::MessageTooLarge;
+
+/// Encoded messages shorter than this are never worth compressing
+pub const COMPRESSION_THRESHOLD: usize = 512;
+
+const HEADER_NONE: u8 = 0;
+const HEADER_LZ4: u8 = 1;
+const HEADER_ZSTD: u8 = 2;
+
+/// How message bytes are compressed on the wire
+///
+/// Every compressed message starts with a one-byte header naming the algorithm,
+/// so a receiver decodes whatever the sender picked and the two sides need not
+/// use the same setting. Messages below [`COMPRESSION_THRESHOLD`], or that do
+/// not shrink, are sent with the uncompressed header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Very fast, modest ratio
+    Lz4,
+    /// Better ratio at higher cost, `level` as understood by zstd (1..=22)
+    Zstd { level: i32 },
+}
+
+impl Compression {
+    /// Prefix `bytes` with a header and compress them if that pays off
+    pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let (header, body) = match self.compressed(bytes)? {
+            Some((header, body)) => (header, body),
+            None => (HEADER_NONE, bytes.to_vec()),
+        };
+        Ok([&[header], body.as_slice()].concat())
+    }
+
+    /// Start `buf` with the uncompressed header, for a message to be written
+    /// after it and then passed to [`compress_in_place`](Self::compress_in_place)
+    pub(crate) fn put_header(buf: &mut BytesMut) {
+        buf.put_u8(HEADER_NONE);
+    }
+
+    /// Compress the message following the header [`put_header`](Self::put_header)
+    /// wrote to `buf`, if that pays off
+    pub(crate) fn compress_in_place(&self, buf: &mut BytesMut) -> Result<()> {
+        if let Some((header, body)) = self.compressed(&buf[1..])? {
+            buf.truncate(1);
+            buf[0] = header;
+            buf.extend_from_slice(&body);
+        }
+        Ok(())
+    }
+
+    /// The header and body of `bytes` compressed, unless that does not make
+    /// them smaller
+    fn compressed(&self, bytes: &[u8]) -> Result<Option<(u8, Vec<u8>)>> {
+        let compressed = match self {
+            _ if bytes.len() < COMPRESSION_THRESHOLD => None,
+            Compression::None => None,
+            Compression::Lz4 => Some((HEADER_LZ4, lz4_flex::compress_prepend_size(bytes))),
+            Compression::Zstd { level } => {
+                Some((HEADER_ZSTD, zstd::bulk::compress(bytes, *level).anyerr()?))
+            }
+        };
+        Ok(compressed.filter(|(_, body)| body.len() < bytes.len()))
+    }
+
+    /// Strip the header from `bytes` and undo whatever compression it names,
+    /// refusing to produce more than `limit` bytes
+    pub fn decompress(bytes: &[u8], limit: usize) -> Result<Vec<u8>> {
+        Self::decompressed(bytes, limit).map(Cow::into_owned)
+    }
+
+    /// Like [`decompress`](Self::decompress), borrowing uncompressed bodies
+    /// instead of copying them
+    pub(crate) fn decompressed(bytes: &[u8], limit: usize) -> Result<Cow<'_, [u8]>> {
+        let Some((&header, body)) = bytes.split_first() else {
+            return Err(anyerr!("message is missing its compression header"));
+        };
+        let too_large = |size| MessageTooLarge::new(None, size, limit);
+        match header {
+            HEADER_NONE => Ok(Cow::Borrowed(body)),
+            HEADER_LZ4 => {
+                let (size, _) = lz4_flex::block::uncompressed_size(body).anyerr()?;
+                if size > limit {
+                    return Err(too_large(size).into());
+                }
+                lz4_flex::decompress_size_prepended(body)
+                    .map(Cow::Owned)
+                    .anyerr()
+            }
+            HEADER_ZSTD => {
+                if let Ok(Some(size)) = zstd::zstd_safe::get_frame_content_size(body)
+                    && size > limit as u64
+                {
+                    return Err(too_large(size as usize).into());
+                }
+                zstd::bulk::decompress(body, limit).map(Cow::Owned).anyerr()
+            }
+            other => Err(anyerr!("unknown compression header {other}")),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = n0_error::AnyError;
+
+    /// Parses `none`, `lz4`, `zstd` or `zstd:<level>`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(Compression::None),
+            None if s == "lz4" => Ok(Compression::Lz4),
+            None if s == "zstd" => Ok(Compression::Zstd {
+                level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            }),
+            Some(("zstd", level)) => Ok(Compression::Zstd {
+                level: level.parse().anyerr()?,
+            }),
+            _ => Err(anyerr!(
+                "unknown compression {s:?}, expected none, lz4, zstd or zstd:<level>"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Lz4 => f.write_str("lz4"),
+            Compression::Zstd { level } => write!(f, "zstd:{level}"),
+        }
+    }
+}
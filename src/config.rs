@@ -0,0 +1,103 @@
+use std::{env::VarError, fmt::Display, fs, path::Path, str::FromStr};
+
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Deserialize, Deserializer};
+
+use crate::{codec::CodecKind, protocol::ProtocolConfig, relay::Relays};
+
+/// File [`Config::load_or_default`] is usually pointed at, in the working
+/// directory
+pub const CONFIG_FILE: &str = "wstest.toml";
+
+// ====================
+// Configuration
+// ====================
+
+/// Tunables read from a TOML file and `WSTEST_*` environment variables
+///
+/// Every setting is optional, unset ones keeping the built-in default. The
+/// keys are the field names; each variable is `WSTEST_` and the field name in
+/// upper case, e.g. `WSTEST_MAX_MESSAGE_SIZE`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// UDP port servers bind to
+    pub port: Option<u16>,
+    /// `default`, `disabled` or the URL of a relay of your own
+    #[serde(deserialize_with = "parsed")]
+    pub relay: Option<Relays>,
+    /// `bincode`, `json`, `postcard` or `cbor`
+    #[serde(deserialize_with = "parsed")]
+    pub codec: Option<CodecKind>,
+    pub max_message_size: Option<usize>,
+    pub max_string_len: Option<usize>,
+    pub max_collection_len: Option<usize>,
+    /// Log filter, in `RUST_LOG` syntax
+    pub log: Option<String>,
+}
+
+impl Config {
+    /// Read the TOML file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path)
+            .with_std_context(|_| format!("reading config file {}", path.display()))?;
+        toml::from_str(&toml)
+            .with_std_context(|_| format!("invalid config file {}", path.display()))
+    }
+
+    /// Like [`Config::load`], but with nothing set if there is no file
+    pub fn load_or_default(path: impl AsRef<Path>) -> Result<Self> {
+        match path.as_ref().try_exists() {
+            Ok(false) => Ok(Self::default()),
+            _ => Self::load(path),
+        }
+    }
+
+    /// Override these settings with any `WSTEST_*` variable that is set
+    pub fn with_env(self) -> Result<Self> {
+        Ok(Self {
+            port: env("WSTEST_PORT")?.or(self.port),
+            relay: env("WSTEST_RELAY")?.or(self.relay),
+            codec: env("WSTEST_CODEC")?.or(self.codec),
+            max_message_size: env("WSTEST_MAX_MESSAGE_SIZE")?.or(self.max_message_size),
+            max_string_len: env("WSTEST_MAX_STRING_LEN")?.or(self.max_string_len),
+            max_collection_len: env("WSTEST_MAX_COLLECTION_LEN")?.or(self.max_collection_len),
+            log: env("WSTEST_LOG")?.or(self.log),
+        })
+    }
+
+    /// `config` with the limits set here
+    pub fn apply(&self, config: ProtocolConfig) -> ProtocolConfig {
+        ProtocolConfig {
+            max_message_size: self.max_message_size.unwrap_or(config.max_message_size),
+            max_string_len: self.max_string_len.unwrap_or(config.max_string_len),
+            max_collection_len: self.max_collection_len.unwrap_or(config.max_collection_len),
+            ..config
+        }
+    }
+}
+
+/// The value of variable `name` parsed, if it is set
+fn env<T: FromStr<Err: Display>>(name: &str) -> Result<Option<T>> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyerr!("invalid {name}={value:?}: {e:#}")),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => Err(anyerr!("{name} is not valid unicode")),
+    }
+}
+
+/// Deserialize a setting written as a string in its `FromStr` syntax
+fn parsed<'de, D: Deserializer<'de>, T: FromStr<Err: Display>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    let Some(s) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    s.parse()
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("{s:?}: {e:#}")))
+}
@@ -0,0 +1,145 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{
+    Sink, Stream, StreamExt,
+    stream::{self, BoxStream},
+};
+use iroh::endpoint::Connection;
+use n0_error::{AnyError, Result};
+
+use crate::{
+    codec::{Bincode, Codec},
+    duplex::DuplexSink,
+    framed::{FrameReceiver, FramedConnection},
+    protocol::{
+        Message, MessageEnvelope, WireEnvelope, connection_version, decode_frame, put_preamble,
+    },
+    replay::MessageCounter,
+};
+
+// ====================
+// Message Sink and Stream
+// ====================
+
+/// A framed echo session exposed as a [`Sink`] of messages and a [`Stream`]
+/// of their responses
+///
+/// Messages go out in envelopes with increasing ids, and the server answers
+/// them in order on the same stream, so this composes with [`SinkExt`],
+/// [`StreamExt`] and `select!` like any other channel. Messages the server
+/// drops without an answer leave no trace in the stream.
+///
+/// [`SinkExt`]: futures::SinkExt
+pub struct MessageConnection<C = Bincode> {
+    sink: DuplexSink<WireEnvelope<'static>, C>,
+    stream: BoxStream<'static, Result<Message>>,
+    codec: C,
+    version: u32,
+    next_id: u64,
+    counter: MessageCounter,
+}
+
+// No field is ever pinned
+impl<C> Unpin for MessageConnection<C> {}
+
+impl<C> std::fmt::Debug for MessageConnection<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MessageConnection")
+            .field("sink", &self.sink)
+            .field("version", &self.version)
+            .field("next_id", &self.next_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: Codec> MessageConnection<C> {
+    /// Open a framed session on a connection to an echo server
+    ///
+    /// The server only observes the session once the first message is sent.
+    pub async fn open(conn: &Connection, codec: C) -> Result<Self> {
+        let framed = FramedConnection::open(conn, codec).await?;
+        Ok(Self::from_framed(framed, connection_version(conn)))
+    }
+
+    /// Wrap an opened framed session with a server speaking `version`
+    pub fn from_framed(framed: FramedConnection<C>, version: u32) -> Self {
+        let (sender, receiver) = framed.split();
+        let mut preamble = Vec::new();
+        put_preamble(sender.codec(), version, &mut preamble);
+        Self {
+            codec: sender.codec().clone(),
+            sink: DuplexSink::new(sender).with_preamble(preamble),
+            stream: responses(receiver, version),
+            version,
+            next_id: 0,
+            counter: MessageCounter::default(),
+        }
+    }
+
+    /// Number messages with `counter`, shared with whatever else sends on
+    /// the same connection, such as [`Client::counter`](crate::Client::counter)
+    ///
+    /// The server keeps one [`ReplayWindow`](crate::ReplayWindow) for the
+    /// whole connection, and refuses messages numbered too far below the
+    /// highest it saw.
+    pub fn with_counter(mut self, counter: MessageCounter) -> Self {
+        self.counter = counter;
+        self
+    }
+}
+
+/// Decode each frame as an envelope, ending after the first error
+fn responses<C: Codec>(
+    receiver: FrameReceiver<C>,
+    version: u32,
+) -> BoxStream<'static, Result<Message>> {
+    stream::unfold(Some(receiver), move |receiver| async move {
+        let mut receiver = receiver?;
+        let frame = receiver.recv_bytes().await.and_then(|bytes| {
+            bytes
+                .map(|bytes| decode_frame(receiver.codec(), version, &bytes))
+                .transpose()
+        });
+        match frame {
+            Ok(Some(envelope)) => Some((Ok(envelope.body), Some(receiver))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+    .boxed()
+}
+
+impl<C: Codec> Sink<Message> for MessageConnection<C> {
+    type Error = AnyError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.sink).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> Result<()> {
+        let mut envelope = MessageEnvelope::new(self.next_id, msg);
+        envelope.counter = Some(self.counter.next());
+        let wire = envelope.into_wire(&self.codec, self.version)?;
+        self.next_id += 1;
+        Pin::new(&mut self.sink).start_send(wire)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.sink).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.sink).poll_close(cx)
+    }
+}
+
+impl<C> Stream for MessageConnection<C> {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Message>>> {
+        self.stream.poll_next_unpin(cx)
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Write as _},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use iroh::EndpointId;
+use n0_error::{Result, StdResultExt};
+use ratatui::{
+    Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, Paragraph, Row, Table},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{Event as TraceEvent, Level, Subscriber, field::Field};
+use tracing_subscriber::{Layer, field::Visit, layer};
+
+use crate::{close::RemoteClose, codec::Codec, events::ConnEvent, server::Echo};
+
+/// How many lines each scrolling pane of the [`Dashboard`] keeps
+const KEEP_LINES: usize = 500;
+
+/// How often the [`Dashboard`] redraws, and checks for keys in between
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often the [`Dashboard`] recomputes message rates
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+// ====================
+// Log Buffer
+// ====================
+
+/// A tracing layer keeping the latest log lines in memory, for a
+/// [`Dashboard`] to show instead of them going to a terminal it draws on
+///
+/// Clones share the same lines.
+#[derive(Debug, Clone, Default)]
+pub struct LogBuffer(Arc<Mutex<VecDeque<LogLine>>>);
+
+#[derive(Debug, Clone)]
+struct LogLine {
+    at: String,
+    level: Level,
+    target: String,
+    text: String,
+}
+
+impl LogBuffer {
+    fn push(&self, line: LogLine) {
+        let mut lines = self.0.lock().expect("poisoned");
+        if lines.len() == KEEP_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// The latest lines at `level` or more severe, oldest first
+    fn lines(&self, level: Level) -> Vec<LogLine> {
+        let lines = self.0.lock().expect("poisoned");
+        lines
+            .iter()
+            .filter(|line| line.level <= level)
+            .cloned()
+            .collect()
+    }
+}
+
+impl<S: Subscriber> Layer<S> for LogBuffer {
+    fn on_event(&self, event: &TraceEvent<'_>, _ctx: layer::Context<'_, S>) {
+        let mut text = Text::default();
+        event.record(&mut text);
+        let meta = event.metadata();
+        self.push(LogLine {
+            at: clock(),
+            level: *meta.level(),
+            target: meta.target().to_string(),
+            text: text.0,
+        });
+    }
+}
+
+/// An event's message followed by its other fields, as the fmt layer writes
+/// them
+#[derive(Default)]
+struct Text(String);
+
+impl Visit for Text {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        match field.name() {
+            "message" => write!(self.0, "{value:?}"),
+            name => write!(self.0, "{name}={value:?}"),
+        }
+        .ok();
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.record_debug(field, &format_args!("{value}")),
+            _ => self.record_debug(field, &value),
+        }
+    }
+}
+
+// ====================
+// Dashboard
+// ====================
+
+/// A live terminal view of an [`Echo`] handler: its connected peers with
+/// their message rates and RTTs, recent warnings and errors, and every
+/// message and connection as it happens
+///
+/// It takes over the terminal until `q`, `Esc` or Ctrl-C is pressed.
+#[derive(Debug)]
+pub struct Dashboard<C: Codec> {
+    echo: Echo<C>,
+    title: String,
+    logs: LogBuffer,
+}
+
+/// What the dashboard saw of the traffic, updated from the handler's events
+#[derive(Debug, Default)]
+struct Activity {
+    peers: HashMap<EndpointId, PeerActivity>,
+    messages: VecDeque<String>,
+    /// Events missed for falling behind
+    missed: u64,
+}
+
+#[derive(Debug, Default)]
+struct PeerActivity {
+    received: u64,
+    bytes: u64,
+    /// Messages per second over the last [`RATE_INTERVAL`]
+    rate: f64,
+    counted: u64,
+}
+
+impl<C: Codec> Dashboard<C> {
+    /// Watch `echo`, headed by `title`, e.g. the server's ticket
+    pub fn new(echo: Echo<C>, title: impl Into<String>) -> Self {
+        Self {
+            echo,
+            title: title.into(),
+            logs: LogBuffer::default(),
+        }
+    }
+
+    /// Show the warnings and errors collected by `logs`
+    pub fn with_logs(mut self, logs: LogBuffer) -> Self {
+        self.logs = logs;
+        self
+    }
+
+    /// Draw until the user quits
+    pub async fn run(self) -> Result<()> {
+        self.run_until(std::future::pending()).await
+    }
+
+    /// Draw until the user quits or `stop` completes, restoring the terminal
+    /// either way
+    pub async fn run_until(self, stop: impl Future<Output = ()>) -> Result<()> {
+        let activity = Arc::new(Mutex::new(Activity::default()));
+        let mut events = self.echo.subscribe_events();
+        let watcher = tokio::spawn({
+            let activity = activity.clone();
+            async move {
+                loop {
+                    let event = match events.recv().await {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(missed)) => {
+                            activity.lock().expect("poisoned").missed += missed;
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    activity.lock().expect("poisoned").record(event);
+                }
+            }
+        });
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut drawn = tokio::task::spawn_blocking({
+            let stopped = stopped.clone();
+            move || self.draw_until_quit(&activity, &stopped)
+        });
+        let drawn = tokio::select! {
+            drawn = &mut drawn => drawn,
+            () = stop => {
+                stopped.store(true, Ordering::Relaxed);
+                drawn.await
+            }
+        };
+        watcher.abort();
+        drawn.anyerr()?
+    }
+
+    fn draw_until_quit(&self, activity: &Mutex<Activity>, stopped: &AtomicBool) -> Result<()> {
+        let mut terminal = ratatui::try_init().std_context("taking over the terminal")?;
+        let started = Instant::now();
+        let mut rated = Instant::now();
+        let result = loop {
+            if rated.elapsed() >= RATE_INTERVAL {
+                activity.lock().expect("poisoned").rate(rated.elapsed());
+                rated = Instant::now();
+            }
+            let drawn = terminal.draw(|frame| {
+                let activity = activity.lock().expect("poisoned");
+                self.draw(frame, &activity, started.elapsed());
+            });
+            if let Err(e) = drawn {
+                break Err(e).std_context("drawing the dashboard");
+            }
+            match quit_requested() {
+                Ok(false) if stopped.load(Ordering::Relaxed) => break Ok(()),
+                Ok(false) => {}
+                Ok(true) => break Ok(()),
+                Err(e) => break Err(e).std_context("reading the terminal"),
+            }
+        };
+        ratatui::try_restore().std_context("restoring the terminal")?;
+        result
+    }
+
+    fn draw(&self, frame: &mut Frame<'_>, activity: &Activity, uptime: Duration) {
+        let [header, peers, logs, footer] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Percentage(40),
+            Constraint::Min(6),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [errors, messages] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(logs);
+
+        self.draw_header(frame, header, activity, uptime);
+        self.draw_peers(frame, peers, activity);
+        let errors_lines = self.logs.lines(Level::WARN);
+        let lines = errors_lines.iter().map(|line| {
+            let style = match line.level {
+                Level::ERROR => Style::new().fg(Color::Red),
+                _ => Style::new().fg(Color::Yellow),
+            };
+            Line::from(vec![
+                Span::raw(format!("{} ", line.at)),
+                Span::styled(format!("{:<5} ", line.level), style),
+                Span::styled(
+                    format!("{}: ", line.target),
+                    Style::new().fg(Color::DarkGray),
+                ),
+                Span::raw(line.text.clone()),
+            ])
+        });
+        draw_tail(frame, errors, "Recent errors", lines);
+        let lines = activity.messages.iter().map(|line| Line::raw(line.clone()));
+        draw_tail(frame, messages, "Messages", lines);
+        frame.render_widget(
+            Paragraph::new("q quit").style(Style::new().fg(Color::DarkGray)),
+            footer,
+        );
+    }
+
+    fn draw_header(
+        &self,
+        frame: &mut Frame<'_>,
+        area: Rect,
+        activity: &Activity,
+        uptime: Duration,
+    ) {
+        let stats = self.echo.stats();
+        let uptime = Duration::from_secs(uptime.as_secs());
+        let mut summary = format!(
+            "up {}  connections {}  in flight {}  received {} B  sent {} B",
+            humantime::format_duration(uptime),
+            stats.connections,
+            stats.in_flight,
+            stats.bytes_received,
+            stats.bytes_sent,
+        );
+        if activity.missed > 0 {
+            write!(summary, "  missed {} events", activity.missed).ok();
+        }
+        let text = vec![Line::raw(self.title.as_str()), Line::raw(summary)];
+        let block = Block::bordered().title(" wstest server ");
+        frame.render_widget(Paragraph::new(text).block(block), area);
+    }
+
+    fn draw_peers(&self, frame: &mut Frame<'_>, area: Rect, activity: &Activity) {
+        let mut connections = self.echo.connections();
+        connections.sort_by_key(|info| info.peer);
+        let rows = connections.iter().map(|info| {
+            let seen = activity.peers.get(&info.peer);
+            Row::new(vec![
+                info.peer.fmt_short().to_string(),
+                info.name.clone().unwrap_or_default(),
+                seen.map_or(0, |seen| seen.received).to_string(),
+                format!("{:.1}", seen.map_or(0.0, |seen| seen.rate)),
+                seen.map_or(0, |seen| seen.bytes).to_string(),
+                format!("{:.1} ms", info.rtt.as_secs_f64() * 1000.0),
+                format!("{:.1} s", info.idle.as_secs_f64()),
+            ])
+        });
+        let header = Row::new(["peer", "name", "messages", "msg/s", "bytes", "rtt", "idle"])
+            .style(Style::new().add_modifier(Modifier::BOLD));
+        let widths = [
+            Constraint::Length(12),
+            Constraint::Min(8),
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Length(12),
+            Constraint::Length(10),
+            Constraint::Length(8),
+        ];
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(Block::bordered().title(" Peers "));
+        frame.render_widget(table, area);
+    }
+}
+
+impl Activity {
+    fn record(&mut self, event: ConnEvent) {
+        let line = match event {
+            ConnEvent::Connected { peer } => format!("{} connected", peer.fmt_short()),
+            ConnEvent::Disconnected { peer, reason } => {
+                self.peers.remove(&peer);
+                match RemoteClose::of(&reason) {
+                    Some(close) => format!("{} disconnected: {}", peer.fmt_short(), close),
+                    None => format!("{} disconnected: {}", peer.fmt_short(), reason),
+                }
+            }
+            ConnEvent::StreamOpened { .. } => return,
+            ConnEvent::MessageReceived { peer, kind, size } => {
+                let seen = self.peers.entry(peer).or_default();
+                seen.received += 1;
+                seen.bytes += size as u64;
+                format!("{} {:?} {} B", peer.fmt_short(), kind, size)
+            }
+        };
+        if self.messages.len() == KEEP_LINES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(format!("{} {}", clock(), line));
+    }
+
+    /// Update every peer's rate, `elapsed` after the last update
+    fn rate(&mut self, elapsed: Duration) {
+        for seen in self.peers.values_mut() {
+            seen.rate = (seen.received - seen.counted) as f64 / elapsed.as_secs_f64();
+            seen.counted = seen.received;
+        }
+    }
+}
+
+/// Draw the last `lines` that fit in `area`, in a box titled `title`
+fn draw_tail<'a>(
+    frame: &mut Frame<'_>,
+    area: Rect,
+    title: &str,
+    lines: impl DoubleEndedIterator<Item = Line<'a>>,
+) {
+    // Less the borders
+    let fits = area.height.saturating_sub(2) as usize;
+    let mut tail: Vec<ListItem> = lines.rev().take(fits).map(ListItem::new).collect();
+    tail.reverse();
+    let list = List::new(tail).block(Block::bordered().title(format!(" {title} ")));
+    frame.render_widget(list, area);
+}
+
+/// Whether a quitting key was pressed, waiting up to [`REDRAW_INTERVAL`]
+fn quit_requested() -> std::io::Result<bool> {
+    if !event::poll(REDRAW_INTERVAL)? {
+        return Ok(false);
+    }
+    let Event::Key(key) = event::read()? else {
+        return Ok(false);
+    };
+    let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+    let quit = matches!(key.code, KeyCode::Char('q') | KeyCode::Esc);
+    Ok(key.kind == KeyEventKind::Press && (quit || ctrl_c))
+}
+
+/// The time of day, UTC, to the second
+fn clock() -> String {
+    let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    now[11..19].to_string()
+}
@@ -0,0 +1,86 @@
+use bytes::Bytes;
+use futures::{Stream, stream};
+use iroh::endpoint::Connection;
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    buffer::BufferPool,
+    codec::Codec,
+    protocol::{put_preamble, strip_preamble},
+};
+
+/// Send `msg` as one unreliable, unordered QUIC datagram on a connection
+/// speaking `version`
+///
+/// The datagram may be lost, duplicated or overtaken by later ones, and is
+/// never retransmitted. From protocol version 8 on it starts with the same
+/// preamble as a frame, see [`strip_preamble`]. Fails if the encoding does
+/// not fit in a single datagram of the current path, see
+/// [`Connection::max_datagram_size`].
+pub fn send_datagram<C: Codec, T: Serialize>(
+    conn: &Connection,
+    codec: &C,
+    version: u32,
+    msg: &T,
+) -> Result<()> {
+    send_encoded_datagram(conn, encode_datagram(codec, version, msg)?)
+}
+
+/// Encode `msg` for a datagram on a connection speaking `version`, behind
+/// its preamble if the version has one
+pub(crate) fn encode_datagram<C: Codec, T: Serialize>(
+    codec: &C,
+    version: u32,
+    msg: &T,
+) -> Result<Bytes> {
+    BufferPool::shared().fill(|buf| {
+        put_preamble(codec, version, buf);
+        codec.encode_into(msg, buf)
+    })
+}
+
+/// Decode a datagram received on a connection speaking `version`, failing
+/// with [`IncompatibleProtocol`](crate::protocol::IncompatibleProtocol) if
+/// its preamble does not match
+pub(crate) fn decode_datagram<C: Codec, T: DeserializeOwned>(
+    codec: &C,
+    version: u32,
+    datagram: &[u8],
+) -> Result<T> {
+    codec.decode(strip_preamble(codec, version, datagram)?)
+}
+
+/// Like [`send_datagram`], for an already encoded value
+pub(crate) fn send_encoded_datagram(conn: &Connection, encoded: Bytes) -> Result<()> {
+    let max = conn
+        .max_datagram_size()
+        .std_context("peer does not accept datagrams")?;
+    if encoded.len() > max {
+        return Err(anyerr!(
+            "datagram of {} bytes exceeds the limit of {} bytes",
+            encoded.len(),
+            max
+        ));
+    }
+    conn.send_datagram(encoded).anyerr()?;
+    Ok(())
+}
+
+/// Every datagram arriving on `conn`, speaking `version`, decoded until the
+/// connection closes
+///
+/// A datagram that fails to decode is yielded as an error and the stream
+/// carries on. Each datagram goes to exactly one reader, so concurrent
+/// streams on the same connection split the traffic between them.
+pub fn recv_datagrams<C: Codec, T: DeserializeOwned>(
+    conn: Connection,
+    codec: C,
+    version: u32,
+) -> impl Stream<Item = Result<T>> {
+    stream::unfold((conn, codec), move |(conn, codec)| async move {
+        let datagram = conn.read_datagram().await.ok()?;
+        let msg = decode_datagram(&codec, version, &datagram);
+        Some((msg, (conn, codec)))
+    })
+}
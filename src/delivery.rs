@@ -0,0 +1,288 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use iroh::EndpointId;
+use n0_error::{Result, anyerr};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::debug;
+
+use crate::{
+    codec::{Bincode, Codec},
+    protocol::Message,
+    reconnect::ReconnectingClient,
+    replay::MessageCounter,
+};
+
+/// Default time after which an unacknowledged message is sent again
+pub const RETRANSMIT_AFTER: Duration = Duration::from_secs(2);
+
+/// How far beyond the first of its messages yet to arrive a peer may number
+/// a [`Message::Reliable`] before the server refuses it, for a later
+/// retransmission to try again
+pub const RELIABLE_WINDOW: u64 = 1024;
+
+/// How long a server remembers the reliable messages of a peer that
+/// disconnected, for it to reconnect without having them acted on twice
+pub const RECEIPTS_KEPT: Duration = Duration::from_secs(60);
+
+/// Delivery guarantee of a message sent through a [`ReliableClient`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QoS {
+    /// Sent once; lost if the stream or connection fails
+    #[default]
+    AtMostOnce,
+    /// Numbered and sent again until the server acknowledges it
+    AtLeastOnce,
+}
+
+// ====================
+// Sending Side
+// ====================
+
+#[derive(Debug)]
+struct Outbox {
+    next_seq: u64,
+    /// Messages not yet acknowledged, with when they were last sent
+    unacked: BTreeMap<u64, (Message, Instant)>,
+}
+
+impl Outbox {
+    /// Numbering starts where a [`MessageCounter`] does, so a new client
+    /// with the identity of an earlier one carries on above its numbers
+    fn new() -> Self {
+        Self {
+            next_seq: MessageCounter::default().next(),
+            unacked: BTreeMap::new(),
+        }
+    }
+
+    /// Every seq below this has been acknowledged
+    fn acked(&self) -> u64 {
+        self.unacked.keys().next().copied().unwrap_or(self.next_seq)
+    }
+}
+
+/// A [`ReconnectingClient`] that can guarantee one-way messages arrive
+///
+/// An [`QoS::AtLeastOnce`] message goes out as [`Message::Reliable`] carrying
+/// a sequence number, and is retransmitted on a fresh stream until the server
+/// answers with a cumulative [`Message::Ack`]. Since the client re-dials with
+/// the same identity, unacknowledged messages survive connection drops.
+///
+/// Each message also tells the server which ones were acknowledged already,
+/// so it can pick up the numbering of a new client, or of one it forgot.
+pub struct ReliableClient<C = Bincode> {
+    client: Arc<ReconnectingClient<C>>,
+    outbox: Arc<Mutex<Outbox>>,
+    pending: watch::Sender<usize>,
+    retransmit: JoinHandle<()>,
+}
+
+impl<C: Codec> fmt::Debug for ReliableClient<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReliableClient")
+            .field("client", &self.client)
+            .field("unacked", &self.unacked())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: Codec> ReliableClient<C> {
+    /// Wrap `client`, retransmitting after [`RETRANSMIT_AFTER`]
+    pub fn new(client: ReconnectingClient<C>) -> Self {
+        Self::with_retransmit_after(client, RETRANSMIT_AFTER)
+    }
+
+    pub fn with_retransmit_after(client: ReconnectingClient<C>, after: Duration) -> Self {
+        let client = Arc::new(client);
+        let outbox = Arc::new(Mutex::new(Outbox::new()));
+        let pending = watch::Sender::new(0);
+        let retransmit = tokio::spawn(retransmit(
+            client.clone(),
+            outbox.clone(),
+            pending.clone(),
+            after,
+        ));
+        Self {
+            client,
+            outbox,
+            pending,
+            retransmit,
+        }
+    }
+
+    pub fn client(&self) -> &ReconnectingClient<C> {
+        &self.client
+    }
+
+    /// Send `msg` with the guarantee `qos`
+    ///
+    /// At most once, this is a single attempt on the current connection. At
+    /// least once, this returns as soon as the message is queued; it keeps
+    /// being sent in the background until acknowledged.
+    pub async fn send(&self, msg: Message, qos: QoS) -> Result<()> {
+        match qos {
+            QoS::AtMostOnce => self.client.client().await?.send(msg).await,
+            QoS::AtLeastOnce => {
+                let seq = {
+                    let mut outbox = self.outbox.lock().expect("poisoned");
+                    let seq = outbox.next_seq;
+                    outbox.next_seq += 1;
+                    outbox.unacked.insert(seq, (msg.clone(), Instant::now()));
+                    self.pending.send_replace(outbox.unacked.len());
+                    seq
+                };
+                let (client, outbox, pending) = (
+                    self.client.clone(),
+                    self.outbox.clone(),
+                    self.pending.clone(),
+                );
+                tokio::spawn(async move { attempt(&client, &outbox, &pending, seq, msg).await });
+                Ok(())
+            }
+        }
+    }
+
+    /// Number of messages sent at least once that are not yet acknowledged
+    pub fn unacked(&self) -> usize {
+        *self.pending.borrow()
+    }
+
+    /// Wait until every message sent at least once has been acknowledged
+    pub async fn flush(&self) -> Result<()> {
+        self.pending
+            .subscribe()
+            .wait_for(|pending| *pending == 0)
+            .await
+            .map_err(|_| anyerr!("client dropped"))?;
+        Ok(())
+    }
+}
+
+impl<C> Drop for ReliableClient<C> {
+    fn drop(&mut self) {
+        self.retransmit.abort();
+    }
+}
+
+/// Send message `seq` once, applying the ack it is answered with
+async fn attempt<C: Codec>(
+    client: &ReconnectingClient<C>,
+    outbox: &Mutex<Outbox>,
+    pending: &watch::Sender<usize>,
+    seq: u64,
+    msg: Message,
+) {
+    let acked = outbox.lock().expect("poisoned").acked();
+    let reliable = Message::Reliable {
+        seq,
+        body: Box::new(msg),
+        acked,
+    };
+    match client.request(reliable).await {
+        Ok(Message::Ack { next }) => {
+            let mut outbox = outbox.lock().expect("poisoned");
+            outbox.unacked = outbox.unacked.split_off(&next);
+            pending.send_replace(outbox.unacked.len());
+        }
+        Ok(other) => debug!(seq, "expected an ack, got {:?}", other.kind()),
+        Err(e) => debug!(seq, "delivery attempt failed: {:#}", e),
+    }
+}
+
+/// Resend every message unacknowledged for longer than `after`
+async fn retransmit<C: Codec>(
+    client: Arc<ReconnectingClient<C>>,
+    outbox: Arc<Mutex<Outbox>>,
+    pending: watch::Sender<usize>,
+    after: Duration,
+) {
+    let mut ticker = tokio::time::interval(after / 2);
+    loop {
+        ticker.tick().await;
+        let due: Vec<_> = {
+            let mut outbox = outbox.lock().expect("poisoned");
+            outbox
+                .unacked
+                .iter_mut()
+                .filter(|(_, (_, sent))| sent.elapsed() >= after)
+                .map(|(seq, (msg, sent))| {
+                    *sent = Instant::now();
+                    (*seq, msg.clone())
+                })
+                .collect()
+        };
+        for (seq, msg) in due {
+            let (client, outbox, pending) = (client.clone(), outbox.clone(), pending.clone());
+            tokio::spawn(async move { attempt(&client, &outbox, &pending, seq, msg).await });
+        }
+    }
+}
+
+// ====================
+// Receiving Side
+// ====================
+
+/// Which sequence numbers one peer's reliable messages have arrived with
+#[derive(Debug)]
+struct Window {
+    /// Every seq below this has arrived
+    next: u64,
+    /// Seqs above `next` that arrived early, less than [`RELIABLE_WINDOW`]
+    /// beyond it
+    ahead: BTreeSet<u64>,
+    /// When the peer disconnected, unless it is connected
+    gone: Option<Instant>,
+}
+
+/// The reliable messages each peer has delivered, kept across reconnects
+///
+/// A peer gone for longer than [`RECEIPTS_KEPT`] is forgotten.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Receipts(Arc<Mutex<HashMap<EndpointId, Window>>>);
+
+impl Receipts {
+    /// Note that `seq` arrived from `peer`, which saw every seq below `acked`
+    /// acknowledged
+    ///
+    /// Returns the cumulative ack to answer with, and whether this is the
+    /// first time `seq` arrived rather than a retransmission or a seq too far
+    /// ahead to accept yet.
+    pub(crate) fn record(&self, peer: EndpointId, seq: u64, acked: u64) -> (u64, bool) {
+        let mut peers = self.0.lock().expect("poisoned");
+        let window = peers.entry(peer).or_insert_with(|| Window {
+            next: acked,
+            ahead: BTreeSet::new(),
+            gone: None,
+        });
+        window.gone = None;
+        if acked > window.next {
+            window.next = acked;
+            window.ahead = window.ahead.split_off(&acked);
+        }
+        let fresh =
+            seq >= window.next && seq - window.next < RELIABLE_WINDOW && window.ahead.insert(seq);
+        while window.ahead.remove(&window.next) {
+            window.next += 1;
+        }
+        (window.next, fresh)
+    }
+
+    /// Note that `peer` disconnected, and forget the peers gone for longer
+    /// than [`RECEIPTS_KEPT`]
+    pub(crate) fn disconnected(&self, peer: EndpointId) {
+        let mut peers = self.0.lock().expect("poisoned");
+        if let Some(window) = peers.get_mut(&peer) {
+            window.gone = Some(Instant::now());
+        }
+        peers.retain(|_, window| {
+            window
+                .gone
+                .is_none_or(|gone| gone.elapsed() < RECEIPTS_KEPT)
+        });
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use iroh::{Endpoint, EndpointAddr, RelayUrl, Watcher, endpoint::ConnectionType};
+use n0_error::{Result, anyerr};
+
+use crate::{
+    client::{Client, dial},
+    codec::Codec,
+    protocol::Message,
+};
+
+// ====================
+// Path Diagnostics
+// ====================
+
+/// How a connection that started out relayed fared at switching to a direct
+/// path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Holepunch {
+    /// The path was direct from the start
+    NotNeeded,
+    /// The path went direct this long after the handshake
+    Succeeded(Duration),
+    /// The path was still relayed when the diagnosis ended
+    Failed,
+}
+
+/// What [`diagnose`] found out about the path to a server
+#[derive(Debug, Clone)]
+pub struct Diagnosis {
+    /// Time from dialing until the handshake completed
+    pub handshake: Duration,
+    /// Time for the first echo request to be answered
+    pub first_round_trip: Duration,
+    /// Path right after the handshake
+    pub initial_path: ConnectionType,
+    /// Path when the diagnosis ended
+    pub path: ConnectionType,
+    pub holepunch: Holepunch,
+    /// Round-trip time estimate of the connection when the diagnosis ended
+    pub rtt: Duration,
+    /// Relay this endpoint is reachable through, if it found one
+    pub home_relay: Option<RelayUrl>,
+    /// Direct addresses this endpoint knows for itself, including those
+    /// observed by relays
+    pub local_addrs: Vec<SocketAddr>,
+}
+
+impl Diagnosis {
+    /// The relay the connection goes through, if it is not direct
+    pub fn relay(&self) -> Option<&RelayUrl> {
+        match &self.path {
+            ConnectionType::Relay(url) | ConnectionType::Mixed(_, url) => Some(url),
+            ConnectionType::Direct(_) | ConnectionType::None => None,
+        }
+    }
+
+    /// Whether the connection ended up on a direct path
+    pub fn is_direct(&self) -> bool {
+        matches!(self.path, ConnectionType::Direct(_))
+    }
+}
+
+/// Connect to the server at `addr` from `endpoint` and watch the path for up
+/// to `window`
+///
+/// A relayed path is watched until hole punching makes it direct or the
+/// window runs out; waiting for the endpoint's own relay shares the same
+/// window.
+pub async fn diagnose<C: Codec>(
+    endpoint: &Endpoint,
+    addr: EndpointAddr,
+    codec: C,
+    window: Duration,
+) -> Result<Diagnosis> {
+    let dialing = Instant::now();
+    let conn = dial(endpoint, addr).await?;
+    let handshake = dialing.elapsed();
+    let connected = Instant::now();
+    let peer = conn.remote_id();
+
+    let mut path = endpoint
+        .conn_type(peer)
+        .ok_or_else(|| anyerr!("no path information for {}", peer.fmt_short()))?;
+    let initial_path = path.get();
+
+    let client = Client::new(conn.clone(), codec);
+    let sent = Instant::now();
+    client.request(Message::Echo).await?;
+    let first_round_trip = sent.elapsed();
+
+    let holepunch = match initial_path {
+        ConnectionType::Direct(_) => Holepunch::NotNeeded,
+        _ => {
+            let punched = async {
+                while let Ok(current) = path.updated().await {
+                    if matches!(current, ConnectionType::Direct(_)) {
+                        return connected.elapsed();
+                    }
+                }
+                std::future::pending().await
+            };
+            match tokio::time::timeout_at((connected + window).into(), punched).await {
+                Ok(after) => Holepunch::Succeeded(after),
+                Err(_) => Holepunch::Failed,
+            }
+        }
+    };
+
+    // Gives up at once if the window is already used up
+    tokio::time::timeout_at((connected + window).into(), endpoint.online())
+        .await
+        .ok();
+    let own = endpoint.addr();
+    Ok(Diagnosis {
+        handshake,
+        first_round_trip,
+        initial_path,
+        path: path.get(),
+        holepunch,
+        rtt: conn.rtt(),
+        home_relay: own.relay_urls().next().cloned(),
+        local_addrs: own.ip_addrs().copied().collect(),
+    })
+}
@@ -0,0 +1,210 @@
+use std::{
+    fmt,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use bytes::BytesMut;
+use futures::{
+    FutureExt, Sink, Stream, StreamExt,
+    future::BoxFuture,
+    stream::{self, BoxStream},
+};
+use iroh::endpoint::Connection;
+use n0_error::{AnyError, Result, anyerr};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    codec::{Bincode, Codec},
+    framed::{FrameReceiver, FrameSender, FramedConnection},
+};
+
+// ====================
+// Full-Duplex Stream
+// ====================
+
+/// Open a full-duplex stream on `conn` that sends `Out` and receives `In`
+///
+/// Each direction is framed on its own, so either side sends whenever it
+/// likes, and closing the sink leaves the other direction open. The peer
+/// only observes the stream once the first message is sent, and takes it
+/// with [`accept_duplex`] with the types swapped.
+///
+/// Meant for connections on an application ALPN: an echo server reads every
+/// bidirectional stream as a framed echo session.
+pub async fn open_duplex<Out, In, C>(
+    conn: &Connection,
+    codec: C,
+) -> Result<(DuplexSink<Out, C>, DuplexStream<In>)>
+where
+    Out: Serialize,
+    In: DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    Ok(split(FramedConnection::open(conn, codec).await?))
+}
+
+/// Accept the full-duplex stream the peer opened with [`open_duplex`]
+pub async fn accept_duplex<Out, In, C>(
+    conn: &Connection,
+    codec: C,
+) -> Result<(DuplexSink<Out, C>, DuplexStream<In>)>
+where
+    Out: Serialize,
+    In: DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    Ok(split(FramedConnection::accept(conn, codec).await?))
+}
+
+fn split<Out, In, C>(framed: FramedConnection<C>) -> (DuplexSink<Out, C>, DuplexStream<In>)
+where
+    In: DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    let (sender, receiver) = framed.split();
+    (DuplexSink::new(sender), DuplexStream::new(receiver))
+}
+
+enum SinkState<C> {
+    Idle(FrameSender<C>),
+    Sending(BoxFuture<'static, (FrameSender<C>, Result<()>)>),
+    Closed,
+}
+
+/// The sending direction of a full-duplex stream, see [`open_duplex`]
+///
+/// Closing the sink finishes its direction of the stream.
+pub struct DuplexSink<T, C = Bincode> {
+    state: SinkState<C>,
+    /// Written in front of every message, see [`with_preamble`](Self::with_preamble)
+    preamble: Vec<u8>,
+    item: PhantomData<fn(T)>,
+}
+
+impl<T, C> fmt::Debug for DuplexSink<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            SinkState::Idle(_) => "idle",
+            SinkState::Sending(_) => "sending",
+            SinkState::Closed => "closed",
+        };
+        f.debug_struct("DuplexSink").field("state", &state).finish()
+    }
+}
+
+// No field is ever pinned
+impl<T, C> Unpin for DuplexSink<T, C> {}
+
+impl<T, C: Codec> DuplexSink<T, C> {
+    pub(crate) fn new(sender: FrameSender<C>) -> Self {
+        Self {
+            state: SinkState::Idle(sender),
+            preamble: Vec::new(),
+            item: PhantomData,
+        }
+    }
+
+    /// Start every frame with `preamble`, as the echo protocol's frames do
+    pub(crate) fn with_preamble(mut self, preamble: Vec<u8>) -> Self {
+        self.preamble = preamble;
+        self
+    }
+
+    /// Wait for the message being sent, if any, to be written
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &mut self.state {
+            SinkState::Idle(_) => Poll::Ready(Ok(())),
+            SinkState::Sending(sending) => {
+                let (sender, result) = ready!(sending.poll_unpin(cx));
+                self.state = SinkState::Idle(sender);
+                Poll::Ready(result)
+            }
+            SinkState::Closed => Poll::Ready(Err(anyerr!("duplex sink is closed"))),
+        }
+    }
+}
+
+impl<T: Serialize, C: Codec> Sink<T> for DuplexSink<T, C> {
+    type Error = AnyError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_idle(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<()> {
+        let this = self.get_mut();
+        let SinkState::Idle(mut sender) = std::mem::replace(&mut this.state, SinkState::Closed)
+        else {
+            return Err(anyerr!("duplex sink is not ready"));
+        };
+        let mut encoded = BytesMut::from(this.preamble.as_slice());
+        if let Err(e) = sender.codec().encode_into(&item, &mut encoded) {
+            this.state = SinkState::Idle(sender);
+            return Err(e);
+        }
+        this.state = SinkState::Sending(
+            async move {
+                let result = sender.send_raw(encoded.freeze()).await;
+                (sender, result)
+            }
+            .boxed(),
+        );
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_idle(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if let SinkState::Closed = this.state {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(this.poll_idle(cx))?;
+        let SinkState::Idle(mut sender) = std::mem::replace(&mut this.state, SinkState::Closed)
+        else {
+            unreachable!("idle after polling");
+        };
+        Poll::Ready(sender.finish())
+    }
+}
+
+/// The receiving direction of a full-duplex stream, see [`open_duplex`]
+///
+/// Ends once the peer closes its sink, or after the first error.
+pub struct DuplexStream<T> {
+    inner: BoxStream<'static, Result<T>>,
+}
+
+impl<T> fmt::Debug for DuplexStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DuplexStream").finish_non_exhaustive()
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> DuplexStream<T> {
+    fn new<C: Codec>(receiver: FrameReceiver<C>) -> Self {
+        let inner = stream::unfold(Some(receiver), |receiver| async move {
+            let mut receiver = receiver?;
+            match receiver.recv().await {
+                Ok(Some(msg)) => Some((Ok(msg), Some(receiver))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        Self {
+            inner: inner.boxed(),
+        }
+    }
+}
+
+impl<T> Stream for DuplexStream<T> {
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<T>>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
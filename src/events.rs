@@ -0,0 +1,58 @@
+use iroh::{
+    EndpointId,
+    endpoint::{ConnectionError, StreamId},
+};
+use tokio::sync::broadcast;
+
+use crate::protocol::MessageKind;
+
+/// How many unread events a subscriber may fall behind by
+const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened on a connection, as seen by a server or client
+#[derive(Debug, Clone)]
+pub enum ConnEvent {
+    /// A connection was established and admitted
+    Connected { peer: EndpointId },
+    /// An established connection was lost, see [`RemoteClose::of`] for why
+    /// the peer closed it
+    ///
+    /// [`RemoteClose::of`]: crate::close::RemoteClose::of
+    Disconnected {
+        peer: EndpointId,
+        reason: ConnectionError,
+    },
+    /// The remote side opened a stream
+    StreamOpened { peer: EndpointId, stream: StreamId },
+    /// A message arrived, encoded in `size` bytes
+    MessageReceived {
+        peer: EndpointId,
+        kind: MessageKind,
+        size: usize,
+    },
+}
+
+/// Fans [`ConnEvent`]s out to any number of subscribers
+///
+/// Emitting never waits; a subscriber that falls too far behind sees
+/// [`RecvError::Lagged`](broadcast::error::RecvError::Lagged) and misses the
+/// oldest events.
+#[derive(Debug, Clone)]
+pub(crate) struct Events(broadcast::Sender<ConnEvent>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(EVENT_CAPACITY).0)
+    }
+}
+
+impl Events {
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<ConnEvent> {
+        self.0.subscribe()
+    }
+
+    pub(crate) fn emit(&self, event: ConnEvent) {
+        // Nobody subscribed is fine, the event is simply dropped
+        self.0.send(event).ok();
+    }
+}
@@ -0,0 +1,386 @@
+use bytes::{BufMut, Bytes};
+use iroh::endpoint::{Connection, ReadExactError, RecvStream, SendStream, VarInt};
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+    buffer::BufferPool,
+    codec::{Bincode, Codec},
+    protocol::MAX_MESSAGE_SIZE,
+    throttle::Throttle,
+};
+
+/// Frames a [`FramePipeline`] holds before senders wait, unless told
+/// otherwise
+pub const PIPELINE_DEPTH: usize = 64;
+
+// ====================
+// Persistent Framed Stream Solution
+// ====================
+
+/// Size of the length prefix in front of every frame
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// The payload length announced by a frame header, refusing frames larger
+/// than `max_frame_size`
+pub fn frame_len(header: [u8; FRAME_HEADER_LEN], max_frame_size: usize) -> Result<usize> {
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_frame_size {
+        return Err(anyerr!("frame of {} bytes exceeds limit", len));
+    }
+    Ok(len)
+}
+
+/// Encode `msg` as a whole frame, header included, into a buffer of the
+/// shared [`BufferPool`]
+pub fn encode_frame<C: Codec, T: Serialize>(
+    codec: &C,
+    msg: &T,
+    max_frame_size: usize,
+) -> Result<Bytes> {
+    BufferPool::shared().fill(|buf| {
+        buf.put_u32(0);
+        codec.encode_into(msg, buf)?;
+        let len = checked_len(buf.len() - FRAME_HEADER_LEN, max_frame_size)?;
+        buf[..FRAME_HEADER_LEN].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    })
+}
+
+/// The header of a frame with `len` bytes of payload
+fn header(len: usize, max_frame_size: usize) -> Result<Bytes> {
+    let len = checked_len(len, max_frame_size)?;
+    BufferPool::shared().fill(|buf| {
+        buf.put_u32(len);
+        Ok(())
+    })
+}
+
+fn checked_len(len: usize, max_frame_size: usize) -> Result<u32> {
+    u32::try_from(len)
+        .ok()
+        .filter(|len| *len as usize <= max_frame_size)
+        .ok_or_else(|| anyerr!("frame of {} bytes exceeds limit", len))
+}
+
+/// Split the first frame off `buf`, returning its payload and the number of
+/// bytes it took up
+///
+/// Returns `None` while `buf` does not hold a whole frame yet. A pure function
+/// of untrusted bytes, for fuzzing the framing outside of any stream.
+pub fn parse_frame(buf: &[u8], max_frame_size: usize) -> Result<Option<(&[u8], usize)>> {
+    let Some((header, rest)) = buf.split_first_chunk::<FRAME_HEADER_LEN>() else {
+        return Ok(None);
+    };
+    let len = frame_len(*header, max_frame_size)?;
+    Ok(rest
+        .get(..len)
+        .map(|payload| (payload, FRAME_HEADER_LEN + len)))
+}
+
+/// A single long-lived bidirectional stream carrying length-prefixed frames
+///
+/// Each frame is a big-endian `u32` length followed by that many bytes of an
+/// encoded value, usually a [`MessageEnvelope`](crate::MessageEnvelope). Compared to [`send_message`](crate::send_message)
+/// this pays the stream setup cost once, at the price of head-of-line blocking
+/// between messages.
+#[derive(Debug)]
+pub struct FramedConnection<C = Bincode> {
+    sender: FrameSender<C>,
+    receiver: FrameReceiver<C>,
+}
+
+impl<C: Codec> FramedConnection<C> {
+    /// Open the framed stream on the dialing side
+    ///
+    /// The peer only observes the stream once the first frame is sent.
+    pub async fn open(conn: &Connection, codec: C) -> Result<Self> {
+        let (send, recv) = conn.open_bi().await.anyerr()?;
+        Ok(Self::from_streams(send, recv, codec))
+    }
+
+    /// Accept the framed stream on the listening side
+    pub async fn accept(conn: &Connection, codec: C) -> Result<Self> {
+        let (send, recv) = conn.accept_bi().await.anyerr()?;
+        Ok(Self::from_streams(send, recv, codec))
+    }
+
+    /// Wrap an already accepted or opened pair of streams
+    pub fn from_streams(send: SendStream, recv: RecvStream, codec: C) -> Self {
+        Self {
+            sender: FrameSender {
+                send,
+                codec: codec.clone(),
+                max_frame_size: MAX_MESSAGE_SIZE,
+                throttle: None,
+            },
+            receiver: FrameReceiver {
+                recv,
+                codec,
+                max_frame_size: MAX_MESSAGE_SIZE,
+                throttle: None,
+            },
+        }
+    }
+
+    /// Refuse to send or receive frames larger than `max_frame_size` bytes
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.sender.max_frame_size = max_frame_size;
+        self.receiver.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Pace sending through `egress` and reading through `ingress`, see
+    /// [`Throttle`]
+    ///
+    /// Frame headers count towards the rate.
+    pub fn with_throttles(mut self, egress: Option<Throttle>, ingress: Option<Throttle>) -> Self {
+        self.sender.throttle = egress;
+        self.receiver.throttle = ingress;
+        self
+    }
+
+    /// Write one frame
+    pub async fn send<T: Serialize>(&mut self, msg: &T) -> Result<()> {
+        self.sender.send(msg).await
+    }
+
+    /// Write one frame that is already encoded
+    pub async fn send_bytes(&mut self, encoded: &[u8]) -> Result<()> {
+        self.sender.send_bytes(encoded).await
+    }
+
+    /// Write one frame that is already encoded, without copying it
+    pub async fn send_raw(&mut self, encoded: Bytes) -> Result<()> {
+        self.sender.send_raw(encoded).await
+    }
+
+    /// Read one frame, returning `None` once the peer has finished the stream
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        self.receiver.recv().await
+    }
+
+    /// Read one frame without decoding it
+    pub async fn recv_bytes(&mut self) -> Result<Option<Vec<u8>>> {
+        self.receiver.recv_bytes().await
+    }
+
+    /// Give up the framing and return the underlying streams, for protocols
+    /// that switch to another encoding after a framed header
+    pub fn into_streams(self) -> (SendStream, RecvStream) {
+        (self.sender.send, self.receiver.recv)
+    }
+
+    /// Split into halves that send and receive independently, for instance
+    /// from different tasks
+    pub fn split(self) -> (FrameSender<C>, FrameReceiver<C>) {
+        (self.sender, self.receiver)
+    }
+
+    /// Stop the receiving half with `code`, telling the peer that no more
+    /// frames will be read
+    pub fn stop(&mut self, code: VarInt) -> Result<()> {
+        self.receiver.stop(code)
+    }
+
+    /// Wait for the peer to stop reading, returning its code
+    ///
+    /// Resolves with `None` if the peer read every frame after
+    /// [`finish`](Self::finish).
+    pub async fn stopped(&mut self) -> Result<Option<VarInt>> {
+        self.sender.stopped().await
+    }
+
+    /// Finish the sending half, signalling the peer that no more frames follow
+    pub fn finish(&mut self) -> Result<()> {
+        self.sender.finish()
+    }
+}
+
+/// The sending half of a [`FramedConnection`]
+#[derive(Debug)]
+pub struct FrameSender<C = Bincode> {
+    send: SendStream,
+    codec: C,
+    max_frame_size: usize,
+    throttle: Option<Throttle>,
+}
+
+impl<C: Codec> FrameSender<C> {
+    /// The codec frames are encoded with
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Write one frame
+    pub async fn send<T: Serialize>(&mut self, msg: &T) -> Result<()> {
+        let frame = encode_frame(&self.codec, msg, self.max_frame_size)?;
+        self.write_chunks(&mut [frame]).await
+    }
+
+    /// Write one frame that is already encoded
+    pub async fn send_bytes(&mut self, encoded: &[u8]) -> Result<()> {
+        self.send_raw(Bytes::copy_from_slice(encoded)).await
+    }
+
+    /// Write one frame that is already encoded, without copying it
+    pub async fn send_raw(&mut self, encoded: Bytes) -> Result<()> {
+        let header = header(encoded.len(), self.max_frame_size)?;
+        self.write_chunks(&mut [header, encoded]).await
+    }
+
+    /// Write frames that are already encoded in one vectored write
+    pub async fn send_all(&mut self, encoded: impl IntoIterator<Item = Bytes>) -> Result<()> {
+        let mut chunks = Vec::new();
+        for payload in encoded {
+            chunks.push(header(payload.len(), self.max_frame_size)?);
+            chunks.push(payload);
+        }
+        self.write_chunks(&mut chunks).await
+    }
+
+    /// Write frames from a background task, so that sending one only waits
+    /// for room among the `depth` frames waiting to be written, see
+    /// [`FramePipeline`]
+    pub fn pipelined(self, depth: usize) -> FramePipeline<C> {
+        let (tx, rx) = mpsc::channel(depth.max(1));
+        let (codec, max_frame_size) = (self.codec.clone(), self.max_frame_size);
+        FramePipeline {
+            tx,
+            writer: tokio::spawn(write_pipelined(self, rx, depth.max(1))),
+            codec,
+            max_frame_size,
+        }
+    }
+
+    async fn write_chunks(&mut self, chunks: &mut [Bytes]) -> Result<()> {
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire(chunks.iter().map(Bytes::len).sum()).await;
+        }
+        self.send.write_all_chunks(chunks).await.anyerr()
+    }
+
+    /// Wait for the peer to stop reading, see [`FramedConnection::stopped`]
+    pub async fn stopped(&mut self) -> Result<Option<VarInt>> {
+        self.send.stopped().await.anyerr()
+    }
+
+    /// Signal the peer that no more frames follow
+    pub fn finish(&mut self) -> Result<()> {
+        self.send.finish().anyerr()?;
+        Ok(())
+    }
+}
+
+/// A whole frame, or a header and the payload following it
+type Chunks = (Bytes, Option<Bytes>);
+
+/// The sending half of a [`FramedConnection`] writing frames in the
+/// background, so several can be in flight without waiting for each write
+///
+/// Whatever frames are waiting when the writer gets to them go out in one
+/// vectored write, in the order they were sent. A failed write closes the
+/// pipeline: later sends fail, and [`finish`](Self::finish) returns the
+/// error.
+#[derive(Debug)]
+pub struct FramePipeline<C = Bincode> {
+    tx: mpsc::Sender<Chunks>,
+    writer: JoinHandle<Result<FrameSender<C>>>,
+    codec: C,
+    max_frame_size: usize,
+}
+
+impl<C: Codec> FramePipeline<C> {
+    /// Queue one frame, waiting only while the pipeline is full
+    pub async fn send<T: Serialize>(&self, msg: &T) -> Result<()> {
+        let frame = encode_frame(&self.codec, msg, self.max_frame_size)?;
+        self.queue((frame, None)).await
+    }
+
+    /// Queue one frame that is already encoded, without copying it
+    pub async fn send_raw(&self, encoded: Bytes) -> Result<()> {
+        let header = header(encoded.len(), self.max_frame_size)?;
+        self.queue((header, Some(encoded))).await
+    }
+
+    async fn queue(&self, chunks: Chunks) -> Result<()> {
+        self.tx
+            .send(chunks)
+            .await
+            .map_err(|_| anyerr!("frame pipeline closed"))
+    }
+
+    /// Wait until every queued frame is written, and return the sender
+    pub async fn finish(self) -> Result<FrameSender<C>> {
+        drop(self.tx);
+        self.writer.await.anyerr()?
+    }
+}
+
+async fn write_pipelined<C: Codec>(
+    mut sender: FrameSender<C>,
+    mut rx: mpsc::Receiver<Chunks>,
+    depth: usize,
+) -> Result<FrameSender<C>> {
+    let (mut waiting, mut chunks) = (Vec::with_capacity(depth), Vec::new());
+    while rx.recv_many(&mut waiting, depth).await > 0 {
+        for (first, rest) in waiting.drain(..) {
+            chunks.push(first);
+            chunks.extend(rest);
+        }
+        sender.write_chunks(&mut chunks).await?;
+        chunks.clear();
+    }
+    Ok(sender)
+}
+
+/// The receiving half of a [`FramedConnection`]
+#[derive(Debug)]
+pub struct FrameReceiver<C = Bincode> {
+    recv: RecvStream,
+    codec: C,
+    max_frame_size: usize,
+    throttle: Option<Throttle>,
+}
+
+impl<C: Codec> FrameReceiver<C> {
+    /// The codec frames are decoded with
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Read one frame, returning `None` once the peer has finished the stream
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        match self.recv_bytes().await? {
+            Some(buf) => self.codec.decode(&buf).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Read one frame without decoding it
+    pub async fn recv_bytes(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut len = [0u8; FRAME_HEADER_LEN];
+        match self.recv.read_exact(&mut len).await {
+            Ok(()) => {}
+            Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+            Err(e) => return Err(e).anyerr(),
+        }
+
+        let len = frame_len(len, self.max_frame_size)?;
+
+        let mut buf = vec![0u8; len];
+        self.recv.read_exact(&mut buf).await.anyerr()?;
+        // Waiting before the next read leaves the peer's data unread
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire(FRAME_HEADER_LEN + len).await;
+        }
+        Ok(Some(buf))
+    }
+
+    /// Stop reading with `code`, see [`FramedConnection::stop`]
+    pub fn stop(&mut self, code: VarInt) -> Result<()> {
+        self.recv.stop(code).anyerr()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use futures::TryStreamExt;
+use iroh::{
+    Endpoint, EndpointAddr, EndpointId, discovery::static_provider::StaticProvider,
+    protocol::RouterBuilder,
+};
+use iroh_gossip::{
+    TopicId,
+    api::{Event, GossipReceiver, GossipSender},
+    net::Gossip,
+};
+use n0_error::{Result, StdResultExt};
+use tracing::warn;
+
+use crate::{
+    codec::{Bincode, Codec},
+    protocol::Message,
+};
+
+/// The gossip topic id for a human-readable topic `name`
+pub fn topic_id(name: &str) -> TopicId {
+    TopicId::from_bytes(*blake3::hash(name.as_bytes()).as_bytes())
+}
+
+/// Many-to-many broadcast of [`Message`]s over named topics
+///
+/// Unlike the echo protocol there is no server: every member of a topic relays
+/// what it receives to its neighbors, so a message published once reaches
+/// the whole swarm. Messages travel encoded with the node's codec, so all
+/// members of a topic must agree on it.
+#[derive(Debug, Clone)]
+pub struct GossipNode<C = Bincode> {
+    gossip: Gossip,
+    peers: StaticProvider,
+    codec: C,
+}
+
+impl<C: Codec> GossipNode<C> {
+    pub fn new(endpoint: &Endpoint, codec: C) -> Self {
+        let peers = StaticProvider::new();
+        endpoint.discovery().add(peers.clone());
+        Self {
+            gossip: Gossip::builder().spawn(endpoint.clone()),
+            peers,
+            codec,
+        }
+    }
+
+    /// Accept gossip connections on the router being built
+    pub fn register(&self, builder: RouterBuilder) -> RouterBuilder {
+        builder.accept(iroh_gossip::ALPN, self.gossip.clone())
+    }
+
+    /// Remember how to reach a peer, for bootstrapping by id
+    pub fn add_addr(&self, addr: EndpointAddr) {
+        self.peers.add_endpoint_info(addr);
+    }
+
+    /// Join the topic `name`, using `bootstrap` as first contacts
+    ///
+    /// Returns immediately; the first node of a topic has nobody to join, so
+    /// use [`Topic::joined`] to wait for a neighbor when that matters.
+    pub async fn join(&self, name: &str, bootstrap: Vec<EndpointId>) -> Result<Topic<C>> {
+        let topic = self
+            .gossip
+            .subscribe(topic_id(name), bootstrap)
+            .await
+            .anyerr()?;
+        let (sender, receiver) = topic.split();
+        Ok(Topic {
+            name: name.to_string(),
+            sender,
+            receiver,
+            codec: self.codec.clone(),
+        })
+    }
+}
+
+/// Membership in one gossip topic
+#[derive(Debug)]
+pub struct Topic<C = Bincode> {
+    name: String,
+    sender: GossipSender,
+    receiver: GossipReceiver,
+    codec: C,
+}
+
+impl<C: Codec> Topic<C> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Our current direct neighbors in the topic's swarm
+    pub fn neighbors(&self) -> Vec<EndpointId> {
+        self.receiver.neighbors().collect()
+    }
+
+    /// Wait until connected to at least one other member
+    pub async fn joined(&mut self) -> Result<()> {
+        self.receiver.joined().await.anyerr()
+    }
+
+    /// Broadcast `msg` to every member of the topic
+    pub async fn publish(&self, msg: &Message) -> Result<()> {
+        let encoded = self.codec.encode(msg)?;
+        self.sender.broadcast(encoded.into()).await.anyerr()
+    }
+
+    /// The next message published by another member, with the neighbor that
+    /// delivered it, or `None` once the topic is closed
+    pub async fn recv(&mut self) -> Result<Option<(EndpointId, Message)>> {
+        while let Some(event) = self.receiver.try_next().await.anyerr()? {
+            match event {
+                Event::Received(received) => {
+                    let msg = self.codec.decode(&received.content)?;
+                    return Ok(Some((received.delivered_from, msg)));
+                }
+                Event::Lagged => warn!(topic = %self.name, "missed messages"),
+                Event::NeighborUp(_) | Event::NeighborDown(_) => {}
+            }
+        }
+        Ok(None)
+    }
+}
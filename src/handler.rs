@@ -0,0 +1,141 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use futures::{FutureExt, future::BoxFuture};
+use iroh::EndpointId;
+
+use crate::{middleware::Context, protocol::Message, signing::Signed};
+
+// ====================
+// Connection State
+// ====================
+
+/// Values an [`AppHandler`] keeps for one connection, at most one of each
+/// type
+///
+/// A fresh one is made for every accepted connection and dropped, with all
+/// it holds, once the connection closed and its last message was handled.
+#[derive(Clone, Default)]
+pub struct ConnState(Arc<Mutex<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>>);
+
+impl ConnState {
+    /// The `T` of this connection, starting out as `T::default()`
+    ///
+    /// Messages of a connection may be handled concurrently, so anything
+    /// that changes goes in a `Mutex` or an atomic.
+    pub fn get<T: Default + Send + Sync + 'static>(&self) -> Arc<T> {
+        let mut values = self.0.lock().expect("poisoned");
+        let value = values
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Arc::new(T::default()));
+        value.clone().downcast().expect("keyed by type")
+    }
+
+    /// Set the `T` of this connection, returning the one it replaced
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) -> Option<Arc<T>> {
+        let mut values = self.0.lock().expect("poisoned");
+        let old = values.insert(TypeId::of::<T>(), Arc::new(value))?;
+        Some(old.downcast().expect("keyed by type"))
+    }
+
+    /// Drop the `T` of this connection, returning it
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let mut values = self.0.lock().expect("poisoned");
+        let old = values.remove(&TypeId::of::<T>())?;
+        Some(old.downcast().expect("keyed by type"))
+    }
+}
+
+impl fmt::Debug for ConnState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.0.lock().expect("poisoned").len();
+        f.debug_struct("ConnState").field("values", &len).finish()
+    }
+}
+
+// ====================
+// App Handlers
+// ====================
+
+/// What an [`AppHandler`] knows about a message besides its body
+#[derive(Debug, Clone)]
+pub struct PeerCtx {
+    /// The peer the message came from
+    pub peer: EndpointId,
+    /// Size of the message as it arrived, in bytes
+    pub size: usize,
+    /// When the sender stops waiting for a response, if it said so
+    pub deadline: Option<Instant>,
+    /// The signature the message came with, for relaying it as its author
+    /// signed it
+    pub signed: Option<Signed>,
+    conn: ConnState,
+}
+
+impl PeerCtx {
+    pub(crate) fn new(ctx: Context, conn: ConnState) -> Self {
+        Self {
+            peer: ctx.peer,
+            size: ctx.size,
+            deadline: ctx.deadline,
+            signed: ctx.signed,
+            conn,
+        }
+    }
+
+    /// The `T` kept for the connection the message came on, see
+    /// [`ConnState::get`]
+    pub fn state<T: Default + Send + Sync + 'static>(&self) -> Arc<T> {
+        self.conn.get()
+    }
+
+    /// Everything kept for the connection the message came on
+    pub fn conn_state(&self) -> &ConnState {
+        &self.conn
+    }
+
+    /// Whether the sender has stopped waiting for a response
+    pub fn expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// The business logic of a server: the answer to every message a peer sends
+///
+/// An [`Echo`](crate::server::Echo) handler does the rest for it. It accepts
+/// connections and streams, decodes, enforces access, limits and middleware,
+/// answers what fails to decode, and sends back what this returns, on
+/// whichever transport the message came. Without an app of its own it runs
+/// the built-in echo behaviour, which is just another implementation.
+pub trait AppHandler: fmt::Debug + Send + Sync + 'static {
+    /// The answer to `msg`, or `None` to send none
+    fn handle(&self, ctx: PeerCtx, msg: Message) -> impl Future<Output = Option<Message>> + Send;
+
+    /// Forget whatever is kept about `peer`, whose last connection closed
+    fn disconnected(&self, peer: EndpointId) {
+        let _ = peer;
+    }
+}
+
+/// [`AppHandler`] boxed, so it can be stored without a type parameter
+pub(crate) trait DynAppHandler: fmt::Debug + Send + Sync + 'static {
+    fn handle(&self, ctx: PeerCtx, msg: Message) -> BoxFuture<'_, Option<Message>>;
+    fn disconnected(&self, peer: EndpointId);
+}
+
+impl<H: AppHandler> DynAppHandler for H {
+    fn handle(&self, ctx: PeerCtx, msg: Message) -> BoxFuture<'_, Option<Message>> {
+        AppHandler::handle(self, ctx, msg).boxed()
+    }
+
+    fn disconnected(&self, peer: EndpointId) {
+        AppHandler::disconnected(self, peer)
+    }
+}
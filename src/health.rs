@@ -0,0 +1,93 @@
+use std::{net::SocketAddr, time::Duration};
+
+use n0_error::{Result, StdResultExt, anyerr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::debug;
+
+use crate::{codec::Codec, server::Server};
+
+/// Address the health endpoint of `wstest server --daemon` listens on unless
+/// told otherwise
+pub const DEFAULT_HEALTH_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 8081);
+
+/// How long a health check may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request a health check may send, headers included
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+// ====================
+// Health Endpoint
+// ====================
+
+/// Answer health checks about `server` over HTTP on `listener`
+///
+/// `GET /healthz` is 200 for as long as the server runs, and `GET /readyz`
+/// is 200 while it takes new connections, see [`Server::is_ready`]; either
+/// is 503 otherwise. Runs until the listener fails.
+pub async fn serve_health<C: Codec>(listener: TcpListener, server: Server<C>) -> Result<()> {
+    loop {
+        let (stream, from) = listener
+            .accept()
+            .await
+            .std_context("health endpoint failed")?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &server).await {
+                debug!(%from, "error answering health check: {:#}", e);
+            }
+        });
+    }
+}
+
+/// Read one request from `stream` and answer it
+async fn answer<C: Codec>(mut stream: TcpStream, server: &Server<C>) -> Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .std_context("health check timed out")??;
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET" | "HEAD"), Some("/healthz")) => match server.router().is_shutdown() {
+            false => ("200 OK", "ok"),
+            true => ("503 Service Unavailable", "shut down"),
+        },
+        (Some("GET" | "HEAD"), Some("/readyz")) => match server.is_ready() {
+            true => ("200 OK", "ready"),
+            false => ("503 Service Unavailable", "not ready"),
+        },
+        (Some("GET" | "HEAD"), Some(_)) => ("404 Not Found", "not found"),
+        _ => ("405 Method Not Allowed", "method not allowed"),
+    };
+    let body = match request.starts_with("HEAD") {
+        true => String::new(),
+        false => format!("{body}\n"),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: text/plain\r\ncontent-length: {}\r\n\
+         connection: close\r\n\r\n{body}",
+        body.len(),
+    );
+    stream.write_all(response.as_bytes()).await.anyerr()?;
+    stream.shutdown().await.anyerr()
+}
+
+/// The request line and headers, up to the blank line ending them
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|end| end == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_LEN {
+            return Err(anyerr!("health check request too long"));
+        }
+        let n = stream.read(&mut buf).await.anyerr()?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(head).std_context("health check request is not UTF-8")
+}
@@ -0,0 +1,163 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use iroh::endpoint::{Connection, VarInt};
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use crate::{
+    close::CloseReason,
+    codec::Codec,
+    protocol::{Message, MessageEnvelope, connection_version, send_message},
+};
+
+/// Envelope id reserved for heartbeat pings and their pongs
+pub const HEARTBEAT_ID: u64 = u64::MAX;
+
+/// Application close code used when a peer misses too many heartbeats
+pub const HEARTBEAT_TIMEOUT: VarInt = CloseReason::HeartbeatTimeout.code();
+
+/// Application close code used when a connection carried nothing but
+/// heartbeats for too long, see [`run_idle_timeout`]
+pub const IDLE_TIMEOUT: VarInt = CloseReason::Idle.code();
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    /// Time between two pings
+    pub interval: Duration,
+    /// Consecutive intervals without any traffic before the peer is considered dead
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            max_missed: 3,
+        }
+    }
+}
+
+/// When a peer was last heard from, and when the connection last carried an
+/// application message
+///
+/// Any incoming stream counts as hearing from the peer, not only pongs, so a
+/// busy connection never trips the heartbeat timeout. Heartbeats in turn do
+/// not count as application messages, so they cannot keep an abandoned
+/// connection from its idle timeout.
+#[derive(Debug, Clone)]
+pub struct Liveness(Arc<Mutex<Seen>>);
+
+#[derive(Debug, Clone, Copy)]
+struct Seen {
+    any: Instant,
+    active: Instant,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self(Arc::new(Mutex::new(Seen {
+            any: now,
+            active: now,
+        })))
+    }
+}
+
+impl Liveness {
+    /// Note hearing from the peer
+    pub fn touch(&self) {
+        self.0.lock().expect("poisoned").any = Instant::now();
+    }
+
+    /// Note an application message, sent or received
+    pub fn active(&self) {
+        let now = Instant::now();
+        *self.0.lock().expect("poisoned") = Seen {
+            any: now,
+            active: now,
+        };
+    }
+
+    pub fn last_seen(&self) -> Instant {
+        self.0.lock().expect("poisoned").any
+    }
+
+    pub fn last_active(&self) -> Instant {
+        self.0.lock().expect("poisoned").active
+    }
+}
+
+/// Whether `msg` is heartbeat traffic rather than an application message
+pub fn is_heartbeat(msg: &MessageEnvelope) -> bool {
+    msg.id == HEARTBEAT_ID
+}
+
+/// Ping the peer every interval, closing the connection once it has been silent
+/// for `max_missed` intervals
+///
+/// Returns when the connection is closed, by either side.
+pub async fn run_heartbeat<C: Codec>(
+    conn: Connection,
+    codec: C,
+    config: HeartbeatConfig,
+    liveness: Liveness,
+) {
+    let started = Instant::now();
+    let mut ticker = tokio::time::interval(config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    for seq in 0.. {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = conn.closed() => return,
+        }
+
+        if liveness.last_seen().elapsed() > config.interval * config.max_missed {
+            warn!(
+                remote = %conn.remote_id().fmt_short(),
+                missed = config.max_missed,
+                "peer stopped answering heartbeats, closing"
+            );
+            CloseReason::HeartbeatTimeout.close(&conn, "missed heartbeats");
+            return;
+        }
+
+        let ping = Message::Ping {
+            seq,
+            timestamp: started.elapsed().as_micros() as u64,
+        };
+        let ping = MessageEnvelope::new(HEARTBEAT_ID, ping);
+        let Ok(ping) = ping.wire(&codec, connection_version(&conn)) else {
+            return;
+        };
+        if send_message(&conn, &codec, &ping).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Close `conn` with [`IDLE_TIMEOUT`] once it carried no application message
+/// for `timeout`, as noted in `liveness`
+///
+/// Returns when the connection is closed, by either side.
+pub async fn run_idle_timeout(conn: Connection, timeout: Duration, liveness: Liveness) {
+    loop {
+        let deadline = liveness.last_active() + timeout;
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline.into()) => {}
+            _ = conn.closed() => return,
+        }
+        if liveness.last_active().elapsed() >= timeout {
+            info!(
+                remote = %conn.remote_id().fmt_short(),
+                idle = ?timeout,
+                "connection idle, closing"
+            );
+            CloseReason::Idle.close(&conn, "idle");
+            return;
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::protocol::Message;
+
+/// Default number of broadcasts a [`History`] keeps
+pub const HISTORY_CAPACITY: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Log {
+    next_offset: u64,
+    entries: VecDeque<(u64, Message)>,
+}
+
+/// The most recent broadcasts of a server, numbered with increasing offsets
+///
+/// Once full, every new broadcast evicts the oldest one. Clones share the
+/// same log.
+#[derive(Debug, Clone)]
+pub struct History {
+    capacity: usize,
+    log: Arc<Mutex<Log>>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(HISTORY_CAPACITY)
+    }
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            log: Arc::default(),
+        }
+    }
+
+    /// Append `msg`, returning its offset
+    pub fn record(&self, msg: Message) -> u64 {
+        let mut log = self.log.lock().expect("poisoned");
+        let offset = log.next_offset;
+        log.next_offset += 1;
+        if log.entries.len() == self.capacity {
+            log.entries.pop_front();
+        }
+        log.entries.push_back((offset, msg));
+        offset
+    }
+
+    /// Every retained broadcast from `from_offset` on, oldest first, and the
+    /// offset the next broadcast will get
+    ///
+    /// Broadcasts already evicted are skipped, which a client notices as a
+    /// gap before the first returned offset.
+    pub fn since(&self, from_offset: u64) -> (Vec<(u64, Message)>, u64) {
+        let log = self.log.lock().expect("poisoned");
+        let entries = log
+            .entries
+            .iter()
+            .filter(|(offset, _)| *offset >= from_offset)
+            .cloned()
+            .collect();
+        (entries, log.next_offset)
+    }
+
+    /// The offset the next broadcast will get
+    pub fn next_offset(&self) -> u64 {
+        self.log.lock().expect("poisoned").next_offset
+    }
+}
@@ -0,0 +1,36 @@
+use std::{fs, io::Write, path::Path};
+
+use iroh::SecretKey;
+use n0_error::{Result, StdResultExt};
+
+/// Load the secret key stored at `path`, or generate one and store it there
+///
+/// The key is kept as hex on a single line. Reusing it keeps the endpoint's
+/// [`EndpointId`](iroh::EndpointId) stable across restarts, so peers can keep
+/// dialing the same id.
+pub fn load_or_create_secret_key(path: impl AsRef<Path>) -> Result<SecretKey> {
+    let path = path.as_ref();
+    if path.exists() {
+        let hex = fs::read_to_string(path)
+            .with_std_context(|_| format!("reading key file {}", path.display()))?;
+        return hex
+            .trim()
+            .parse()
+            .with_std_context(|_| format!("invalid key in {}", path.display()));
+    }
+
+    let key = SecretKey::generate(&mut rand::rng());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).anyerr()?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_std_context(|_| format!("creating key file {}", path.display()))?;
+    let hex: String = key.to_bytes().iter().map(|b| format!("{b:02x}")).collect();
+    writeln!(file, "{hex}").anyerr()?;
+    Ok(key)
+}
@@ -0,0 +1,170 @@
+//! Echo protocol over iroh, with either one unidirectional stream per message
+//! or a persistent framed stream.
+
+pub mod access;
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod bench;
+pub mod bind;
+#[cfg(feature = "blobs")]
+pub mod blobs;
+pub mod blocking;
+#[cfg(feature = "websocket")]
+pub mod bridge;
+pub mod buffer;
+pub mod capture;
+pub mod channel;
+pub mod chaos;
+pub mod chunked;
+pub mod client;
+pub mod close;
+pub mod coalesce;
+pub mod codec;
+pub mod compression;
+pub mod config;
+pub mod connection;
+#[cfg(feature = "tui")]
+pub mod dashboard;
+pub mod datagram;
+pub mod delivery;
+pub mod doctor;
+pub mod duplex;
+pub mod events;
+pub mod framed;
+#[cfg(feature = "gossip")]
+pub mod gossip;
+pub mod handler;
+pub mod health;
+pub mod heartbeat;
+pub mod history;
+pub mod identity;
+pub mod limits;
+pub mod load;
+pub mod lobby;
+#[cfg(feature = "local-discovery")]
+pub mod local;
+pub mod lookup;
+pub mod matchmaking;
+pub mod memory;
+pub mod metrics;
+pub mod middleware;
+pub mod outbox;
+pub mod peers;
+pub mod permissions;
+pub mod pool;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+pub mod protocol;
+pub mod pubsub;
+pub mod qlog;
+pub mod queue;
+pub mod reconnect;
+pub mod registry;
+pub mod relay;
+pub mod rendezvous;
+pub mod replay;
+pub mod rpc;
+pub mod rtt;
+pub mod sealing;
+pub mod server;
+pub mod session;
+pub mod signing;
+pub mod soak;
+pub mod stats;
+pub mod streams;
+pub mod telemetry;
+pub mod testkit;
+pub mod throttle;
+pub mod ticket;
+pub mod transfer;
+pub mod tuning;
+
+pub use access::AccessPolicy;
+pub use admin::Bans;
+pub use audit::{AuditLog, AuditRecord, Outcome};
+pub use auth::{
+    AuthProvider, AuthVerifier, Authenticated, KeyCredential, SharedToken, TrustedKeys,
+};
+pub use bench::{BenchConfig, BenchReport, run_bench};
+pub use bind::BindAddr;
+#[cfg(feature = "blobs")]
+pub use blobs::BlobShare;
+pub use blocking::BlockingClient;
+#[cfg(feature = "websocket")]
+pub use bridge::WsBridge;
+pub use buffer::BufferPool;
+pub use capture::{Capture, ReplayReport, read_capture, replay};
+pub use channel::{Channel, ChannelConfig, Channels, Reliability};
+pub use chaos::ChaosConfig;
+pub use chunked::{recv_stream, send_stream};
+pub use client::{Client, RequestTimedOut, Responder};
+pub use close::{CloseReason, RemoteClose};
+pub use coalesce::Coalescer;
+#[cfg(feature = "msgpack")]
+pub use codec::MessagePack;
+#[cfg(feature = "protobuf")]
+pub use codec::Protobuf;
+pub use codec::{Bincode, Cbor, Codec, CodecKind, Json, Postcard};
+pub use compression::Compression;
+pub use config::Config;
+pub use connection::MessageConnection;
+#[cfg(feature = "tui")]
+pub use dashboard::{Dashboard, LogBuffer};
+pub use datagram::{recv_datagrams, send_datagram};
+pub use delivery::{QoS, ReliableClient};
+pub use doctor::{Diagnosis, Holepunch, diagnose};
+pub use duplex::{DuplexSink, DuplexStream, accept_duplex, open_duplex};
+pub use events::ConnEvent;
+pub use framed::{
+    FramePipeline, FrameReceiver, FrameSender, FramedConnection, encode_frame, parse_frame,
+};
+#[cfg(feature = "gossip")]
+pub use gossip::{GossipNode, Topic};
+pub use handler::{AppHandler, ConnState, PeerCtx};
+pub use health::serve_health;
+pub use heartbeat::HeartbeatConfig;
+pub use history::History;
+pub use identity::load_or_create_secret_key;
+pub use limits::{ConnectionLimits, HandlerLimits};
+pub use load::{LoadReport, run_load};
+pub use lobby::{Lobby, LobbyConfig, LobbyRequest, RoomEvent, RoomRefused};
+#[cfg(feature = "local-discovery")]
+pub use local::LocalPeer;
+pub use lookup::AddrLookup;
+pub use matchmaking::{FirstCome, MatchStrategy, Matchmaker};
+pub use memory::{MemoryTransport, TestHarness};
+pub use metrics::Metrics;
+pub use middleware::{Interceptor, Middleware, Verdict};
+pub use outbox::Outbox;
+pub use peers::{AddressBook, Contact};
+pub use permissions::{Capabilities, Capability, Permissions};
+pub use pool::PeerPool;
+pub use protocol::{
+    ALPN, DecodeLimitExceeded, ErrorCode, IncompatibleProtocol, MAX_MESSAGE_SIZE, Message,
+    MessageEnvelope, MessageKind, MessageTooLarge, PROTOCOL_VERSION, ProtocolConfig, RemoteError,
+    Timeout, WireEnvelope, decode_message, encode_message, recv_message, recv_message_within,
+    send_compressed, send_message, send_raw,
+};
+pub use qlog::Qlog;
+pub use queue::{QueueFull, SendQueue};
+pub use reconnect::{Backoff, ReconnectingClient};
+pub use registry::Registry;
+pub use relay::Relays;
+pub use replay::{MessageCounter, ReplayWindow};
+pub use rpc::{CallHandle, Rpc, RpcClient, RpcServer};
+pub use rtt::{RttStats, measure_rtt};
+pub use sealing::{Opened, Sealer, SessionKey};
+pub use session::{Presence, SessionManager, Status};
+pub use signing::{KeyProvider, Keyring, MessageSignature, Signed};
+pub use soak::{Snapshot, SoakConfig, run_soak};
+pub use stats::ConnectionStats;
+pub use streams::{PooledStream, StreamPool};
+#[cfg(feature = "otel")]
+pub use telemetry::Otlp;
+pub use telemetry::TraceContext;
+pub use testkit::{Step, TestNet, Topology};
+pub use throttle::{RateLimit, Throttle};
+pub use ticket::EchoTicket;
+pub use transfer::{FileTransfer, send_file};
+pub use tuning::{Congestion, TransportTuning};
@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use iroh::{
+    EndpointId,
+    endpoint::{Connection, VarInt},
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
+use tracing::Instrument;
+
+use crate::{close::CloseReason, soak::resident_memory};
+
+/// Application code for closing a connection the server has no room for, see
+/// [`ConnectionLimits`]
+pub const BUSY: VarInt = CloseReason::Busy.code();
+
+/// Default cap on handlers running at once for a single connection
+pub const MAX_HANDLERS_PER_CONNECTION: usize = 64;
+
+/// Default cap on handlers running at once across all connections
+pub const MAX_HANDLERS: usize = 1024;
+
+/// How long a shutting down server waits for in-flight handlers before its
+/// connections are torn down
+pub(crate) const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Caps how many stream handlers run at once, per connection and overall
+///
+/// A server waits for room before accepting the next stream, so a peer
+/// opening streams faster than they are served is held back by QUIC flow
+/// control instead of growing the server's memory. Clones share the overall
+/// limit.
+#[derive(Debug, Clone)]
+pub struct HandlerLimits {
+    per_connection: usize,
+    total: usize,
+    global: Arc<Semaphore>,
+}
+
+impl Default for HandlerLimits {
+    fn default() -> Self {
+        Self::new(MAX_HANDLERS_PER_CONNECTION, MAX_HANDLERS)
+    }
+}
+
+impl HandlerLimits {
+    pub fn new(per_connection: usize, total: usize) -> Self {
+        Self {
+            per_connection,
+            total,
+            global: Arc::new(Semaphore::new(total)),
+        }
+    }
+
+    pub fn per_connection(&self) -> usize {
+        self.per_connection
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Number of handlers currently running under these limits
+    pub fn in_flight(&self) -> usize {
+        self.total - self.global.available_permits()
+    }
+
+    /// Wait until no handler is running, giving up after `timeout`
+    ///
+    /// Returns whether all handlers finished in time.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let all = self.global.acquire_many(self.total as u32);
+        tokio::time::timeout(timeout, all).await.is_ok()
+    }
+
+    /// Start tracking the handlers of one connection
+    pub(crate) fn connection(&self) -> Handlers {
+        Handlers {
+            local: Arc::new(Semaphore::new(self.per_connection)),
+            global: self.global.clone(),
+            tasks: JoinSet::new(),
+        }
+    }
+}
+
+/// Room for one handler, released when the handler finishes
+#[derive(Debug)]
+pub(crate) struct HandlerPermit {
+    _local: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+/// The running handlers of one connection
+#[derive(Debug)]
+pub(crate) struct Handlers {
+    local: Arc<Semaphore>,
+    global: Arc<Semaphore>,
+    tasks: JoinSet<()>,
+}
+
+impl Handlers {
+    /// Wait for room for one more handler, or `None` once `conn` closes
+    pub(crate) async fn reserve(&self, conn: &Connection) -> Option<HandlerPermit> {
+        let acquire = async {
+            let local = self.local.clone().acquire_owned().await;
+            let global = self.global.clone().acquire_owned().await;
+            HandlerPermit {
+                _local: local.expect("never closed"),
+                _global: global.expect("never closed"),
+            }
+        };
+        tokio::select! {
+            permit = acquire => Some(permit),
+            _ = conn.closed() => None,
+        }
+    }
+
+    /// Run `handler`, holding `permit` until it completes
+    pub(crate) fn spawn(
+        &mut self,
+        permit: HandlerPermit,
+        handler: impl Future<Output = ()> + Send + 'static,
+    ) {
+        // Drop the results of handlers that already finished
+        while self.tasks.try_join_next().is_some() {}
+        self.tasks.spawn(
+            async move {
+                handler.await;
+                drop(permit);
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Wait for every handler of the connection to complete
+    pub(crate) async fn join(mut self) {
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
+
+// ====================
+// Connection Limits
+// ====================
+
+/// Caps on the connections a server admits, and when it sheds load by
+/// refusing new ones
+///
+/// A connection over any of them is closed with [`BUSY`] before
+/// authentication. Connections already admitted are never closed for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Connections served at once, across all peers
+    pub max_connections: Option<usize>,
+    /// Connections served at once for any one [`EndpointId`]
+    pub max_per_peer: Option<usize>,
+    /// Refuse new connections while this many stream handlers are running
+    pub shed_in_flight: Option<usize>,
+    /// Refuse new connections while the process holds this many bytes of
+    /// resident memory; only checked where that is known, on Linux
+    pub shed_rss_bytes: Option<u64>,
+}
+
+impl ConnectionLimits {
+    /// Why a new connection from `peer` is refused, if it is, given the
+    /// connections `admitted` so far and the stream handlers under `handlers`
+    fn refusal(
+        &self,
+        peer: &EndpointId,
+        admitted: &Admitted,
+        handlers: &HandlerLimits,
+    ) -> Option<&'static str> {
+        if self
+            .max_connections
+            .is_some_and(|max| admitted.total >= max)
+        {
+            return Some("too many connections");
+        }
+        let from_peer = admitted.per_peer.get(peer).copied().unwrap_or(0);
+        if self.max_per_peer.is_some_and(|max| from_peer >= max) {
+            return Some("too many connections from this peer");
+        }
+        if self
+            .shed_in_flight
+            .is_some_and(|max| handlers.in_flight() >= max)
+        {
+            return Some("server busy");
+        }
+        if let Some(max) = self.shed_rss_bytes
+            && resident_memory().is_some_and(|rss| rss >= max)
+        {
+            return Some("server low on memory");
+        }
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+struct Admitted {
+    total: usize,
+    per_peer: HashMap<EndpointId, usize>,
+}
+
+/// The connections admitted under [`ConnectionLimits`], shared by a handler
+/// and its clones
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Admissions(Arc<Mutex<Admitted>>);
+
+impl Admissions {
+    /// Count a new connection from `peer`, or say why `limits` refuse it
+    pub(crate) fn admit(
+        &self,
+        peer: EndpointId,
+        limits: &ConnectionLimits,
+        handlers: &HandlerLimits,
+    ) -> Result<Admission, &'static str> {
+        let mut admitted = self.0.lock().expect("poisoned");
+        if let Some(reason) = limits.refusal(&peer, &admitted, handlers) {
+            return Err(reason);
+        }
+        admitted.total += 1;
+        *admitted.per_peer.entry(peer).or_default() += 1;
+        Ok(Admission {
+            admissions: self.clone(),
+            peer,
+        })
+    }
+}
+
+/// One admitted connection, counted until dropped
+#[derive(Debug)]
+pub(crate) struct Admission {
+    admissions: Admissions,
+    peer: EndpointId,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut admitted = self.admissions.0.lock().expect("poisoned");
+        admitted.total -= 1;
+        if let Some(count) = admitted.per_peer.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                admitted.per_peer.remove(&self.peer);
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+
+use crate::{codec::Codec, protocol::Message, reconnect::ReconnectingClient, rtt::percentile};
+
+/// Message count, duration and latency of some requests
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub messages: u64,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Stats {
+    fn new(mut latencies: Vec<Duration>, elapsed: Duration) -> Self {
+        if latencies.is_empty() {
+            return Self {
+                elapsed,
+                ..Default::default()
+            };
+        }
+        latencies.sort_unstable();
+        Self {
+            messages: latencies.len() as u64,
+            elapsed,
+            p50: percentile(&latencies, 50),
+            p99: percentile(&latencies, 99),
+            max: latencies[latencies.len() - 1],
+        }
+    }
+
+    pub fn messages_per_sec(&self) -> f64 {
+        self.messages as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// How one client of a load test fared
+#[derive(Debug, Clone)]
+pub struct ClientStats {
+    pub stats: Stats,
+    /// Why the client stopped early, if it did
+    pub error: Option<String>,
+}
+
+/// Per-client and overall results of [`run_load`]
+#[derive(Debug, Clone)]
+pub struct LoadReport {
+    pub clients: Vec<ClientStats>,
+    pub total: Stats,
+}
+
+/// Run every client at once, each sending `count` requests cycling through
+/// `mix`, one request in flight per client
+///
+/// Each client runs in its own task, so with clients on separate endpoints
+/// the server sees as many truly concurrent connections. A client stops at
+/// its first failed request; the others carry on.
+pub async fn run_load<C: Codec>(
+    clients: Vec<ReconnectingClient<C>>,
+    count: u64,
+    mix: &[Message],
+) -> LoadReport {
+    let mix: Arc<[Message]> = mix.into();
+    let started = Instant::now();
+    let tasks = clients.into_iter().map(|client| {
+        let mix = mix.clone();
+        tokio::spawn(async move { run_client(&client, count, &mix).await })
+    });
+    let results = join_all(tasks).await;
+    let elapsed = started.elapsed();
+
+    let mut all = Vec::new();
+    let clients = results
+        .into_iter()
+        .map(|result| match result {
+            Ok((latencies, stats)) => {
+                all.extend(latencies);
+                stats
+            }
+            Err(e) => ClientStats {
+                stats: Stats::default(),
+                error: Some(format!("client task failed: {e}")),
+            },
+        })
+        .collect();
+    LoadReport {
+        clients,
+        total: Stats::new(all, elapsed),
+    }
+}
+
+async fn run_client<C: Codec>(
+    client: &ReconnectingClient<C>,
+    count: u64,
+    mix: &[Message],
+) -> (Vec<Duration>, ClientStats) {
+    let started = Instant::now();
+    let mut latencies = Vec::new();
+    let mut error = None;
+    for msg in mix.iter().cycle().take(count as usize) {
+        let sent = Instant::now();
+        match client.request(msg.clone()).await {
+            Ok(_) => latencies.push(sent.elapsed()),
+            Err(e) => {
+                error = Some(format!("{e:#}"));
+                break;
+            }
+        }
+    }
+    let stats = Stats::new(latencies.clone(), started.elapsed());
+    (latencies, ClientStats { stats, error })
+}
@@ -0,0 +1,354 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use bincode::{Decode, Encode};
+use iroh::EndpointId;
+use n0_error::stack_error;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Default largest number of rooms a [`Lobby`] holds at once
+pub const MAX_ROOMS: usize = 1024;
+/// Default largest number of members a room can be created for
+pub const MAX_ROOM_SIZE: usize = 64;
+
+/// Letters room codes are made of, leaving out look-alikes such as `0` and `O`
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 6;
+
+/// What a client asks of the lobby, sent as [`Message::Lobby`](crate::Message::Lobby)
+///
+/// Every request is answered with a [`RoomEvent`].
+#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+pub enum LobbyRequest {
+    /// Open a room for up to `capacity` members, the server's limit if 0,
+    /// and join it
+    Create { capacity: usize },
+    /// Join the room with the code `code`
+    Join { code: String },
+    /// Who is in the room with the code `code`
+    Members { code: String },
+    /// Leave the current room
+    Leave,
+    /// Send `payload` to every other member of the current room
+    Send { payload: Vec<u8> },
+}
+
+/// Why the lobby turned a request down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub enum Refusal {
+    /// No room has this code
+    NotFound,
+    /// The room has no place left
+    Full,
+    /// The server holds as many rooms as it allows
+    TooManyRooms,
+    /// Create and join require leaving the current room first
+    AlreadyInRoom,
+    /// Leave and send require being in a room
+    NotInRoom,
+}
+
+/// A lobby request was turned down by the server
+#[stack_error(derive, add_meta)]
+#[error("lobby refused the request: {reason:?}")]
+pub struct RoomRefused {
+    pub reason: Refusal,
+}
+
+/// The lobby's answer to a [`LobbyRequest`], or news pushed to the members of
+/// a room, sent as [`Message::Room`](crate::Message::Room)
+#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+pub enum RoomEvent {
+    /// The room was created or joined; lists every member, including the
+    /// one who joined, in order of arrival
+    Joined {
+        code: String,
+        capacity: usize,
+        #[bincode(with_serde)]
+        members: Vec<EndpointId>,
+    },
+    /// The members of a room, answering [`LobbyRequest::Members`]
+    Members {
+        code: String,
+        capacity: usize,
+        #[bincode(with_serde)]
+        members: Vec<EndpointId>,
+    },
+    /// The room was left, answering [`LobbyRequest::Leave`]
+    Left { code: String },
+    /// The request was turned down
+    Refused { reason: Refusal },
+    /// Pushed to the other members when `peer` joins
+    MemberJoined {
+        code: String,
+        #[bincode(with_serde)]
+        peer: EndpointId,
+    },
+    /// Pushed to the other members when `peer` leaves or disconnects
+    MemberLeft {
+        code: String,
+        #[bincode(with_serde)]
+        peer: EndpointId,
+    },
+    /// `payload` sent to the room by `from`; pushed to the other members, and
+    /// returned to the sender as acknowledgement
+    Message {
+        code: String,
+        #[bincode(with_serde)]
+        from: EndpointId,
+        payload: Vec<u8>,
+    },
+}
+
+/// Limits of a [`Lobby`]
+#[derive(Debug, Clone, Copy)]
+pub struct LobbyConfig {
+    /// Largest number of rooms open at once
+    pub max_rooms: usize,
+    /// Largest capacity a room can be created with
+    pub max_room_size: usize,
+}
+
+impl Default for LobbyConfig {
+    fn default() -> Self {
+        Self {
+            max_rooms: MAX_ROOMS,
+            max_room_size: MAX_ROOM_SIZE,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Room {
+    capacity: usize,
+    members: Vec<EndpointId>,
+}
+
+#[derive(Debug, Default)]
+struct Rooms {
+    rooms: HashMap<String, Room>,
+    /// The room each member is in; a peer is in at most one room
+    member_of: HashMap<EndpointId, String>,
+}
+
+impl Rooms {
+    /// Take `peer` out of its room, closing the room once empty
+    ///
+    /// Returns the room's code and the members left in it.
+    fn leave(&mut self, peer: &EndpointId) -> Option<(String, Vec<EndpointId>)> {
+        let code = self.member_of.remove(peer)?;
+        let room = self.rooms.get_mut(&code)?;
+        room.members.retain(|member| member != peer);
+        let others = room.members.clone();
+        if others.is_empty() {
+            self.rooms.remove(&code);
+        }
+        Some((code, others))
+    }
+
+    fn unused_code(&self) -> String {
+        let mut rng = rand::rng();
+        loop {
+            let code: String = (0..CODE_LEN)
+                .map(|_| CODE_ALPHABET[rng.random_range(0..CODE_ALPHABET.len())] as char)
+                .collect();
+            if !self.rooms.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+}
+
+/// A [`RoomEvent`] and the peers to push it to
+pub type Notice = (Vec<EndpointId>, RoomEvent);
+
+/// The answer to a lobby request, and the event to push to other peers
+#[derive(Debug)]
+pub struct Outcome {
+    pub reply: RoomEvent,
+    /// The peers to push `event` to; empty if there is nothing to tell
+    pub notify: Vec<EndpointId>,
+    pub event: Option<RoomEvent>,
+}
+
+impl Outcome {
+    fn reply(reply: RoomEvent) -> Self {
+        Self {
+            reply,
+            notify: Vec::new(),
+            event: None,
+        }
+    }
+
+    fn refused(reason: Refusal) -> Self {
+        Self::reply(RoomEvent::Refused { reason })
+    }
+}
+
+/// Rooms that clients create and join by code, managed by the server
+///
+/// Clones share the same rooms.
+#[derive(Debug, Clone, Default)]
+pub struct Lobby {
+    config: LobbyConfig,
+    rooms: Arc<Mutex<Rooms>>,
+}
+
+impl Lobby {
+    pub fn new(config: LobbyConfig) -> Self {
+        Self {
+            config,
+            rooms: Arc::default(),
+        }
+    }
+
+    /// Apply `request` from `peer`
+    pub fn handle(&self, peer: EndpointId, request: LobbyRequest) -> Outcome {
+        let mut rooms = self.rooms.lock().expect("poisoned");
+        match request {
+            LobbyRequest::Create { capacity } => {
+                if rooms.member_of.contains_key(&peer) {
+                    return Outcome::refused(Refusal::AlreadyInRoom);
+                }
+                if rooms.rooms.len() >= self.config.max_rooms {
+                    return Outcome::refused(Refusal::TooManyRooms);
+                }
+                let capacity = match capacity {
+                    0 => self.config.max_room_size,
+                    capacity => capacity.min(self.config.max_room_size),
+                };
+                let code = rooms.unused_code();
+                let members = vec![peer];
+                rooms.rooms.insert(
+                    code.clone(),
+                    Room {
+                        capacity,
+                        members: members.clone(),
+                    },
+                );
+                rooms.member_of.insert(peer, code.clone());
+                Outcome::reply(RoomEvent::Joined {
+                    code,
+                    capacity,
+                    members,
+                })
+            }
+            LobbyRequest::Join { code } => {
+                if rooms.member_of.contains_key(&peer) {
+                    return Outcome::refused(Refusal::AlreadyInRoom);
+                }
+                let Some(room) = rooms.rooms.get_mut(&code) else {
+                    return Outcome::refused(Refusal::NotFound);
+                };
+                if room.members.len() >= room.capacity {
+                    return Outcome::refused(Refusal::Full);
+                }
+                let notify = room.members.clone();
+                room.members.push(peer);
+                let reply = RoomEvent::Joined {
+                    code: code.clone(),
+                    capacity: room.capacity,
+                    members: room.members.clone(),
+                };
+                rooms.member_of.insert(peer, code.clone());
+                Outcome {
+                    reply,
+                    notify,
+                    event: Some(RoomEvent::MemberJoined { code, peer }),
+                }
+            }
+            LobbyRequest::Members { code } => match rooms.rooms.get(&code) {
+                Some(room) => Outcome::reply(RoomEvent::Members {
+                    capacity: room.capacity,
+                    members: room.members.clone(),
+                    code,
+                }),
+                None => Outcome::refused(Refusal::NotFound),
+            },
+            LobbyRequest::Leave => match rooms.leave(&peer) {
+                Some((code, notify)) => Outcome {
+                    reply: RoomEvent::Left { code: code.clone() },
+                    notify,
+                    event: Some(RoomEvent::MemberLeft { code, peer }),
+                },
+                None => Outcome::refused(Refusal::NotInRoom),
+            },
+            LobbyRequest::Send { payload } => {
+                let Some(code) = rooms.member_of.get(&peer) else {
+                    return Outcome::refused(Refusal::NotInRoom);
+                };
+                let mut notify = rooms.rooms[code].members.clone();
+                notify.retain(|member| *member != peer);
+                let msg = RoomEvent::Message {
+                    code: code.clone(),
+                    from: peer,
+                    payload,
+                };
+                Outcome {
+                    reply: msg.clone(),
+                    notify,
+                    event: Some(msg),
+                }
+            }
+        }
+    }
+
+    /// Open a room holding exactly `members`, as the server does for a match
+    ///
+    /// Members already in a room leave it first. Returns the new room's code
+    /// and the [`RoomEvent::MemberLeft`] news for the rooms they left, or
+    /// `None` if the lobby holds as many rooms as it allows.
+    pub fn open(&self, members: &[EndpointId]) -> Option<(String, Vec<Notice>)> {
+        let mut rooms = self.rooms.lock().expect("poisoned");
+        if rooms.rooms.len() >= self.config.max_rooms {
+            return None;
+        }
+        let left = members
+            .iter()
+            .filter_map(|peer| {
+                let (code, others) = rooms.leave(peer)?;
+                Some((others, RoomEvent::MemberLeft { code, peer: *peer }))
+            })
+            .collect();
+        let code = rooms.unused_code();
+        rooms.rooms.insert(
+            code.clone(),
+            Room {
+                capacity: members.len(),
+                members: members.to_vec(),
+            },
+        );
+        for peer in members {
+            rooms.member_of.insert(*peer, code.clone());
+        }
+        Some((code, left))
+    }
+
+    /// Take the disconnected `peer` out of its room
+    ///
+    /// Returns the remaining members and the [`RoomEvent::MemberLeft`] to
+    /// push to them, if `peer` was in a room.
+    pub fn remove_peer(&self, peer: &EndpointId) -> Option<Notice> {
+        let mut rooms = self.rooms.lock().expect("poisoned");
+        let (code, others) = rooms.leave(peer)?;
+        Some((others, RoomEvent::MemberLeft { code, peer: *peer }))
+    }
+
+    /// The code of the room `peer` is in
+    pub fn room_of(&self, peer: &EndpointId) -> Option<String> {
+        let rooms = self.rooms.lock().expect("poisoned");
+        rooms.member_of.get(peer).cloned()
+    }
+
+    /// Number of rooms open
+    pub fn len(&self) -> usize {
+        self.rooms.lock().expect("poisoned").rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
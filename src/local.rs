@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use iroh::{
+    Endpoint, EndpointAddr, SecretKey,
+    discovery::mdns::{DiscoveryEvent, MdnsDiscovery},
+};
+use n0_error::{Result, StdResultExt};
+
+use crate::codec::CodecKind;
+
+/// mDNS service echo servers advertise themselves under, so other iroh
+/// endpoints on the network are not listed
+pub const LOCAL_SERVICE: &str = "wstest-echo";
+
+// ====================
+// Local Network Discovery
+// ====================
+
+/// An echo server found on the local network
+#[derive(Debug, Clone)]
+pub struct LocalPeer {
+    pub addr: EndpointAddr,
+    /// Codec the server says it speaks, if it said anything readable
+    pub codec: Option<CodecKind>,
+}
+
+/// Advertise `endpoint` on the local network as an echo server speaking
+/// `codec`, for as long as it stays open
+///
+/// The endpoint can also dial peers it learns about this way by id alone.
+pub fn advertise(endpoint: &Endpoint, codec: CodecKind) -> Result<()> {
+    let mdns = MdnsDiscovery::builder()
+        .service_name(LOCAL_SERVICE)
+        .build(endpoint.id())?;
+    let user_data = codec.to_string().parse().anyerr()?;
+    // Set first, so the service publishes it as soon as it is added
+    endpoint.set_user_data_for_discovery(Some(user_data));
+    endpoint.discovery().add(mdns);
+    Ok(())
+}
+
+/// Listen on the local network for `wait` and return every echo server that
+/// advertised itself and did not expire meanwhile, ordered by id
+pub async fn discover(wait: Duration) -> Result<Vec<LocalPeer>> {
+    // Only used to leave ourselves out, and we do not advertise
+    let id = SecretKey::generate(&mut rand::rng()).public();
+    let mdns = MdnsDiscovery::builder()
+        .advertise(false)
+        .service_name(LOCAL_SERVICE)
+        .build(id)?;
+    let mut events = mdns.subscribe().await;
+    let mut peers: Vec<LocalPeer> = Vec::new();
+    let listen = async {
+        while let Some(event) = events.next().await {
+            match event {
+                DiscoveryEvent::Discovered { endpoint_info, .. } => {
+                    let codec = endpoint_info
+                        .data
+                        .user_data()
+                        .and_then(|data| data.as_ref().parse().ok());
+                    let peer = LocalPeer {
+                        addr: endpoint_info.into_endpoint_addr(),
+                        codec,
+                    };
+                    match peers.iter_mut().find(|known| known.addr.id == peer.addr.id) {
+                        Some(known) => *known = peer,
+                        None => peers.push(peer),
+                    }
+                }
+                DiscoveryEvent::Expired { endpoint_id } => {
+                    peers.retain(|known| known.addr.id != endpoint_id);
+                }
+            }
+        }
+    };
+    tokio::time::timeout(wait, listen).await.ok();
+    peers.sort_by_key(|peer| peer.addr.id);
+    Ok(peers)
+}
@@ -0,0 +1,58 @@
+use iroh::{
+    Endpoint,
+    discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher},
+    endpoint,
+};
+use n0_error::Result;
+use url::Url;
+
+use crate::relay::Relays;
+
+// ====================
+// Address Lookup
+// ====================
+
+/// Where an endpoint publishes its address, and looks up the addresses of
+/// peers dialed by [`EndpointId`](iroh::EndpointId) alone
+///
+/// What gets published is signed by the endpoint's key and holds its home
+/// relay, which is enough for a peer to reach it and punch a direct path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AddrLookup {
+    /// n0's public DNS server
+    #[default]
+    N0,
+    /// An iroh-dns-server of your own: `pkarr_relay` takes the signed
+    /// records, which are then served as DNS under `origin`
+    Custom { pkarr_relay: Url, origin: String },
+    /// Neither publish nor look up, so peers can only be dialed with a full
+    /// address or ticket
+    Disabled,
+}
+
+impl AddrLookup {
+    /// Configure `builder` to publish and look up addresses here
+    ///
+    /// Discovery added to `builder` earlier is replaced, unless this is
+    /// [`AddrLookup::N0`], which builders start out with.
+    pub fn apply(&self, builder: endpoint::Builder) -> endpoint::Builder {
+        match self {
+            AddrLookup::N0 => builder,
+            AddrLookup::Custom {
+                pkarr_relay,
+                origin,
+            } => builder
+                .clear_discovery()
+                .discovery(PkarrPublisher::builder(pkarr_relay.clone()))
+                .discovery(DnsDiscovery::builder(origin.clone())),
+            AddrLookup::Disabled => builder.clear_discovery(),
+        }
+    }
+
+    /// Bind a fresh client endpoint looking peers up here and reaching them
+    /// through `relays`
+    pub async fn bind(&self, relays: &Relays) -> Result<Endpoint> {
+        let endpoint = self.apply(relays.apply(Endpoint::builder())).bind().await?;
+        Ok(endpoint)
+    }
+}
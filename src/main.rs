@@ -1,47 +1,899 @@
-use bincode::{Decode, Encode};
-use iroh::{
-    Endpoint, EndpointAddr,
-    endpoint::Connection,
-    protocol::{AcceptError, ProtocolHandler, Router},
+use std::{
+    io::IsTerminal,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
-use n0_error::{Result, StdResultExt};
-use serde::{Deserialize, Serialize};
 
-const ALPN: &[u8] = b"iroh-example/echo/0";
-const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024; // 10MB limit
+use clap::{
+    ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+    parser::ValueSource,
+};
+use futures::{StreamExt, future::try_join_all};
+use iroh::{EndpointAddr, EndpointId, PublicKey, RelayUrl, SecretKey};
+use n0_error::{AnyError, Result, StdResultExt, anyerr};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use wstest::{
+    AccessPolicy, AddrLookup, AddressBook, AuditLog, Backoff, BenchConfig, BindAddr, Capabilities,
+    Capture, ChaosConfig, Client, CloseReason, CodecKind, Compression, Config, Congestion,
+    ConnectionLimits, Contact, EchoTicket, FramedConnection, HeartbeatConfig, Holepunch, Keyring,
+    Message, MessageEnvelope, ProtocolConfig, Qlog, RateLimit, ReconnectingClient, Relays,
+    RpcClient, Sealer, SharedToken, SoakConfig, TransportTuning,
+    audit::{AUDIT_KEEP, AUDIT_ROTATE_SIZE},
+    client::connect_with_alpn,
+    health::DEFAULT_HEALTH_ADDR,
+    load_or_create_secret_key,
+    protocol::decode_frame,
+    rpc::EchoRpc,
+    sealing,
+    server::{self, Echo, Server},
+    transfer::{FileTransfer, Progress, TRANSFER_ALPN},
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
-enum Message {
-    Echo,
-    Ping,
-    Pong,
+#[derive(Debug, Parser)]
+#[command(version, about = "Echo protocol test tool over iroh")]
+struct Cli {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[command(subcommand)]
+    command: Command,
 }
 
-// ====================
-// Unidirectional Stream Solution
-// ====================
+/// Options shared by the server and client sides
+#[derive(Debug, Clone, Args)]
+struct CommonArgs {
+    /// Wire codec: bincode, json, postcard or cbor, or msgpack and protobuf
+    /// when built with those features
+    #[arg(long, global = true, default_value_t = CodecKind::Bincode)]
+    codec: CodecKind,
+    /// Ping the peer at this interval and drop it after three silent intervals
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    heartbeat: Option<Duration>,
+    /// Close connections that carried nothing but heartbeats for this long
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    idle_timeout: Option<Duration>,
+    /// Reconnect with replay-safe requests sent as 0-RTT data
+    #[arg(long, global = true)]
+    zero_rtt: bool,
+    /// Batch one-way messages sent within this long of each other, e.g. `1ms`
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    coalesce: Option<Duration>,
+    /// Send requests on this many reused streams instead of a new stream each
+    #[arg(long, global = true)]
+    stream_pool: Option<usize>,
+    /// Reject messages whose encoding exceeds this many bytes
+    #[arg(long, global = true, default_value_t = wstest::MAX_MESSAGE_SIZE)]
+    max_message_size: usize,
+    /// Reject messages holding a string longer than this many bytes
+    #[arg(long, global = true, default_value_t = wstest::protocol::MAX_STRING_LEN)]
+    max_string_len: usize,
+    /// Reject messages holding a list longer than this many elements
+    #[arg(long, global = true, default_value_t = wstest::protocol::MAX_COLLECTION_LEN)]
+    max_collection_len: usize,
+    /// Compress large outgoing messages: none, lz4, zstd or zstd:<level>
+    #[arg(long, global = true, default_value_t = Compression::None)]
+    compression: Compression,
+    /// Pre-shared token: servers require clients to prove they know it,
+    /// clients answer the challenge with it
+    #[arg(long, global = true)]
+    token: Option<String>,
+    /// What clients knowing the token may do: `all`, `none` or a comma
+    /// separated list of broadcast, upload and admin
+    #[arg(long, global = true, requires = "token")]
+    grant: Option<Capabilities>,
+    /// Sign messages with the application key in this file, creating it if
+    /// missing; peers pass its public key to `--trust-signer`
+    #[arg(long, global = true, value_parser = load_app_key)]
+    signing_key: Option<SecretKey>,
+    /// Refuse messages not signed by this application key; repeat to trust
+    /// several
+    #[arg(long, global = true)]
+    trust_signer: Vec<PublicKey>,
+    /// Open publishes sealed to the application key in this file, creating
+    /// it if missing; peers pass its public key to `--seal-to`. Keys to seal
+    /// to are offered in the auth handshake, so this takes `--token`
+    #[arg(long, global = true, value_parser = load_app_key, requires = "token")]
+    sealing_key: Option<SecretKey>,
+    /// Seal interactive publishes so only the holder of this application key
+    /// can read them, not the server; repeat to seal to several
+    #[arg(long, global = true, requires = "sealing_key")]
+    seal_to: Vec<PublicKey>,
+    /// Drop this fraction of received messages, from 0 to 1
+    #[arg(long, global = true, default_value_t = 0.0)]
+    chaos_drop: f64,
+    /// Delay every received message by this much
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    chaos_latency: Option<Duration>,
+    /// Delay received messages by up to this much more, at random
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    chaos_jitter: Option<Duration>,
+    /// Reset the stream of this fraction of received messages, from 0 to 1
+    #[arg(long, global = true, default_value_t = 0.0)]
+    chaos_reset: f64,
+    /// Close the connection on this fraction of received messages, from 0 to 1
+    #[arg(long, global = true, default_value_t = 0.0)]
+    chaos_close: f64,
+    /// Cap framed sessions at sending this many bytes per second
+    #[arg(long, global = true)]
+    egress_limit: Option<u64>,
+    /// Cap framed sessions at receiving this many bytes per second
+    #[arg(long, global = true)]
+    ingress_limit: Option<u64>,
+    /// Reach peers through this relay instead of n0's public ones
+    #[arg(long, global = true)]
+    relay: Option<RelayUrl>,
+    /// Use no relay at all, so peers must be reachable directly
+    #[arg(long, global = true, conflicts_with = "relay")]
+    no_relay: bool,
+    /// Send everything through the relay, never attempting a direct path
+    #[cfg(feature = "relay-only")]
+    #[arg(long, global = true, conflicts_with = "no_relay")]
+    relay_only: bool,
+    /// Publish and look up addresses through the iroh-dns-server taking pkarr
+    /// records at this URL, instead of n0's
+    #[arg(long, global = true, requires = "dns_origin")]
+    pkarr_relay: Option<url::Url>,
+    /// Domain the `--pkarr-relay` server answers DNS queries under
+    #[arg(long, global = true, requires = "pkarr_relay")]
+    dns_origin: Option<String>,
+    /// Neither publish this endpoint's address nor look up peers by id, so
+    /// servers must be given by ticket
+    #[arg(long, global = true, conflicts_with = "pkarr_relay")]
+    no_lookup: bool,
+    /// Start the QUIC transport from this preset: default, low-latency or
+    /// bulk-transfer
+    #[arg(long, global = true)]
+    tuning: Option<TransportTuning>,
+    /// QUIC congestion controller: cubic, new-reno or bbr
+    #[arg(long, global = true)]
+    congestion: Option<Congestion>,
+    /// Let peers send this much on one stream before it is read, e.g. `4m`
+    #[arg(long, global = true, value_parser = parse_size)]
+    stream_window: Option<usize>,
+    /// Let peers send this much on all streams together before they are read
+    #[arg(long, global = true, value_parser = parse_size)]
+    receive_window: Option<usize>,
+    /// Send this much ahead of acknowledgements, across all streams
+    #[arg(long, global = true, value_parser = parse_size)]
+    send_window: Option<usize>,
+    /// Let peers open this many streams of each direction at once
+    #[arg(long, global = true)]
+    max_streams: Option<u32>,
+    /// Send a QUIC keep-alive on quiet connections at this interval
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    keep_alive: Option<Duration>,
+    /// Drop connections QUIC heard nothing on for this long
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    max_idle: Option<Duration>,
+    /// Log TLS secrets to the file `SSLKEYLOGFILE` names, so captured
+    /// traffic can be decrypted
+    #[arg(long, global = true)]
+    keylog: bool,
+    /// Write a qlog trace of every connection to this directory
+    #[arg(long, global = true)]
+    qlog_dir: Option<PathBuf>,
+    /// Address book naming servers for `@name`, instead of
+    /// `~/.config/wstest/peers.json`
+    #[arg(long, global = true)]
+    peers_file: Option<PathBuf>,
+    /// Read defaults for these options from this TOML file instead of
+    /// `wstest.toml`; `WSTEST_*` variables override it, flags override both
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Export traces to the OpenTelemetry collector taking OTLP/HTTP at this
+    /// URL, e.g. http://localhost:4318
+    #[cfg(feature = "otel")]
+    #[arg(long, global = true)]
+    otlp: Option<url::Url>,
+}
 
-/// Send one message on a new unidirectional stream
-async fn send_one_way(conn: &Connection, msg: &Message) -> Result<()> {
-    let mut send = conn.open_uni().await.anyerr()?;
+impl CommonArgs {
+    fn heartbeat(&self) -> Option<HeartbeatConfig> {
+        self.heartbeat.map(|interval| HeartbeatConfig {
+            interval,
+            ..Default::default()
+        })
+    }
 
-    let encoded = bincode::encode_to_vec(msg, bincode::config::standard())
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    fn token(&self) -> Option<Arc<SharedToken>> {
+        self.token.as_ref().map(|token| {
+            let token = SharedToken::new(token);
+            Arc::new(token.with_capabilities(self.grant.unwrap_or_default()))
+        })
+    }
 
-    send.write_all(&encoded).await.anyerr()?;
-    send.finish().anyerr()?;
+    /// The keys to sign and check messages with, if any were given
+    fn signing(&self) -> Option<Keyring> {
+        if self.signing_key.is_none() && self.trust_signer.is_empty() {
+            return None;
+        }
+        let keyring = self.signing_key.clone().map(Keyring::new);
+        Some(
+            keyring
+                .unwrap_or_default()
+                .with_trusted(self.trust_signer.iter().copied()),
+        )
+    }
 
-    Ok(())
+    /// The key to seal and open publishes with, if one was given
+    fn sealer(&self) -> Option<Sealer> {
+        self.sealing_key.clone().map(Sealer::new)
+    }
+
+    /// The faults to inject, if any were asked for
+    fn chaos(&self) -> Option<ChaosConfig> {
+        let chaos = ChaosConfig {
+            drop: self.chaos_drop,
+            latency: self.chaos_latency.unwrap_or_default(),
+            jitter: self.chaos_jitter.unwrap_or_default(),
+            reset: self.chaos_reset,
+            close: self.chaos_close,
+        };
+        chaos.is_active().then_some(chaos)
+    }
+
+    fn relays(&self) -> Relays {
+        #[cfg(feature = "relay-only")]
+        if self.relay_only {
+            return Relays::Only(self.relay.clone());
+        }
+        match &self.relay {
+            Some(url) => Relays::Custom(url.clone()),
+            None if self.no_relay => Relays::Disabled,
+            None => Relays::Default,
+        }
+    }
+
+    fn lookup(&self) -> AddrLookup {
+        match (&self.pkarr_relay, &self.dns_origin) {
+            (Some(pkarr_relay), Some(origin)) => AddrLookup::Custom {
+                pkarr_relay: pkarr_relay.clone(),
+                origin: origin.clone(),
+            },
+            _ if self.no_lookup => AddrLookup::Disabled,
+            _ => AddrLookup::N0,
+        }
+    }
+
+    /// The transport tuning preset with the individual settings on top
+    fn tuning(&self) -> TransportTuning {
+        let mut tuning = self.tuning.unwrap_or_default();
+        let size = |bytes: Option<usize>| bytes.map(|bytes| bytes as u64);
+        tuning.congestion = self.congestion.or(tuning.congestion);
+        tuning.stream_receive_window = size(self.stream_window).or(tuning.stream_receive_window);
+        tuning.receive_window = size(self.receive_window).or(tuning.receive_window);
+        tuning.send_window = size(self.send_window).or(tuning.send_window);
+        if let Some(max) = self.max_streams {
+            tuning.max_concurrent_bidi_streams = Some(max);
+            tuning.max_concurrent_uni_streams = Some(max);
+        }
+        tuning.keep_alive_interval = self.keep_alive.or(tuning.keep_alive_interval);
+        tuning.max_idle_timeout = self.max_idle.or(tuning.max_idle_timeout);
+        tuning.keylog = self.keylog;
+        tuning
+    }
+
+    /// A fresh client endpoint with these relay, lookup and tuning options
+    async fn bind(&self) -> Result<iroh::Endpoint> {
+        let builder = self
+            .lookup()
+            .apply(self.relays().apply(iroh::Endpoint::builder()));
+        Ok(self.tuning().apply(builder).bind().await?)
+    }
+
+    fn address_book(&self) -> Result<AddressBook> {
+        match &self.peers_file {
+            Some(path) => AddressBook::open(path),
+            None => AddressBook::open(AddressBook::default_path()?),
+        }
+    }
+
+    fn rate_limit(&self) -> RateLimit {
+        RateLimit {
+            egress: self.egress_limit,
+            ingress: self.ingress_limit,
+        }
+    }
+
+    /// A client for `addr` with these options applied
+    fn client(&self, addr: EndpointAddr) -> ReconnectingClient<CodecKind> {
+        let mut client = ReconnectingClient::new(addr, self.codec)
+            .with_config(self.protocol())
+            .with_relays(self.relays())
+            .with_lookup(self.lookup())
+            .with_tuning(self.tuning());
+        if let Some(config) = self.heartbeat() {
+            client = client.with_heartbeat(config);
+        }
+        if let Some(timeout) = self.idle_timeout {
+            client = client.with_idle_timeout(timeout);
+        }
+        if self.zero_rtt {
+            client = client.with_zero_rtt();
+        }
+        if let Some(window) = self.coalesce {
+            client = client.with_coalescing(window);
+        }
+        if let Some(size) = self.stream_pool {
+            client = client.with_stream_pool(size);
+        }
+        if let Some(dir) = &self.qlog_dir {
+            client = client.with_qlog(Qlog::new(dir));
+        }
+        if let Some(chaos) = self.chaos() {
+            client = client.with_chaos(chaos);
+        }
+        if let Some(token) = self.token() {
+            client = client.with_auth(token);
+        }
+        if let Some(keyring) = self.signing() {
+            client = client.with_signing(Arc::new(keyring));
+        }
+        if let Some(sealer) = self.sealer() {
+            client = client.with_sealer(sealer);
+        }
+        client
+    }
+
+    fn protocol(&self) -> ProtocolConfig {
+        ProtocolConfig {
+            max_message_size: self.max_message_size,
+            compression: self.compression,
+            ..Default::default()
+        }
+        .with_decode_limits(self.max_string_len, self.max_collection_len)
+    }
+
+    /// The config file and environment, layered
+    fn config(&self) -> Result<Config> {
+        let config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::load_or_default(wstest::config::CONFIG_FILE)?,
+        };
+        config.with_env()
+    }
+}
+
+impl Cli {
+    /// Parse the command line, taking what it leaves out from the config file
+    /// and environment
+    fn load() -> Result<(Self, Config)> {
+        let matches = Cli::command().get_matches();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        let config = cli.common.config()?;
+        cli.apply(&config, &matches);
+        Ok((cli, config))
+    }
+
+    fn apply(&mut self, config: &Config, matches: &ArgMatches) {
+        let given = |matches: &ArgMatches, id: &str| {
+            matches.value_source(id) == Some(ValueSource::CommandLine)
+        };
+        let common = &mut self.common;
+        if let Some(codec) = config.codec
+            && !given(matches, "codec")
+        {
+            common.codec = codec;
+        }
+        if let Some(size) = config.max_message_size
+            && !given(matches, "max_message_size")
+        {
+            common.max_message_size = size;
+        }
+        if let Some(len) = config.max_string_len
+            && !given(matches, "max_string_len")
+        {
+            common.max_string_len = len;
+        }
+        if let Some(len) = config.max_collection_len
+            && !given(matches, "max_collection_len")
+        {
+            common.max_collection_len = len;
+        }
+        #[cfg(feature = "relay-only")]
+        let relay_only = given(matches, "relay_only");
+        #[cfg(not(feature = "relay-only"))]
+        let relay_only = false;
+        if let Some(relays) = &config.relay
+            && !(given(matches, "relay") || given(matches, "no_relay") || relay_only)
+        {
+            match relays {
+                Relays::Default => {}
+                Relays::Custom(url) => common.relay = Some(url.clone()),
+                Relays::Disabled => common.no_relay = true,
+                #[cfg(feature = "relay-only")]
+                Relays::Only(url) => {
+                    common.relay_only = true;
+                    common.relay = url.clone();
+                }
+            }
+        }
+        if let Some(port) = config.port
+            && let Some((_, sub)) = matches.subcommand()
+            && sub.try_contains_id("port").is_ok()
+            && !given(sub, "port")
+            && let Some(server) = self.command.server_args_mut()
+        {
+            server.port = port;
+        }
+    }
+}
+
+/// A server given as an `echo…` ticket, a bare EndpointId or `@` and its name
+/// in the address book
+#[derive(Debug, Clone)]
+enum Target {
+    Contact(Contact),
+    Name(String),
+}
+
+impl FromStr for Target {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix('@') {
+            Some(name) => Ok(Target::Name(name.to_string())),
+            None => s.parse().map(Target::Contact),
+        }
+    }
+}
+
+impl Target {
+    /// The server's address, with the options adjusted to what a ticket says
+    /// the server speaks
+    fn resolve(self, common: CommonArgs) -> Result<(EndpointAddr, CommonArgs)> {
+        let contact = match self {
+            Target::Contact(contact) => contact,
+            Target::Name(name) => {
+                let book = common.address_book()?;
+                book.get(&name)
+                    .cloned()
+                    .ok_or_else(|| anyerr!("no peer named {name:?} in {}", book.path().display()))?
+            }
+        };
+        Ok(match contact {
+            Contact::Ticket(ticket) => (
+                ticket.addr,
+                CommonArgs {
+                    codec: ticket.codec,
+                    ..common
+                },
+            ),
+            Contact::Id(id) => (id.into(), common),
+        })
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run an echo server until Ctrl-C or SIGTERM
+    Server {
+        #[command(flatten)]
+        server: ServerArgs,
+        /// Show a live dashboard of peers, traffic and errors instead of
+        /// logging, until `q` is pressed
+        #[cfg(feature = "tui")]
+        #[arg(long)]
+        tui: bool,
+        /// Run supervised, by systemd or in a container: log what would be
+        /// printed, and serve health checks on `--health-addr`, by default
+        /// 127.0.0.1:8081
+        #[arg(long)]
+        #[cfg_attr(feature = "tui", arg(conflicts_with = "tui"))]
+        daemon: bool,
+        /// Answer HTTP health checks on this address, at `/healthz` while
+        /// running and `/readyz` while taking connections
+        #[arg(long)]
+        health_addr: Option<std::net::SocketAddr>,
+        /// Write the process id to this file, deleting it again on exit
+        #[arg(long)]
+        pid_file: Option<PathBuf>,
+    },
+    /// Connect to a running echo server and stream messages at it
+    Client {
+        /// Ticket, EndpointId or `@name` of the server to connect to
+        addr: Target,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Run a server and a client in the same process
+    Singleplayer {
+        #[command(flatten)]
+        server: ServerArgs,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Measure the round-trip time to a running echo server
+    Ping {
+        /// Ticket, EndpointId or `@name` of the server to ping
+        addr: Target,
+        /// Number of pings to time
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+    },
+    /// Connect to a running echo server and report how the path to it came
+    /// about: direct or relayed, hole punching and handshake timing
+    Doctor {
+        /// Ticket, EndpointId or `@name` of the server to diagnose
+        addr: Target,
+        /// How long to wait for a relayed path to go direct
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+        window: Duration,
+    },
+    /// List the echo servers advertising themselves on the local network and
+    /// connect to one of them
+    #[cfg(feature = "local-discovery")]
+    Discover {
+        /// How long to listen for servers
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "3s")]
+        wait: Duration,
+        /// Connect to the server with this index in the list without asking
+        #[arg(long)]
+        connect: Option<usize>,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Re-send the messages of a trace written with `client --capture`
+    Replay {
+        /// Ticket, EndpointId or `@name` of the server to replay against
+        addr: Target,
+        trace: PathBuf,
+        /// Replay this many times faster than captured, `inf` for no pauses
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Flood a running echo server and report throughput and latency
+    Bench {
+        /// Ticket, EndpointId or `@name` of the server to benchmark
+        addr: Target,
+        /// Payload size of each message, e.g. `512`, `1k` or `4m`
+        #[arg(long, value_parser = parse_size, default_value = "1k")]
+        size: usize,
+        /// Number of messages to send
+        #[arg(long, default_value_t = 100_000)]
+        count: u64,
+        /// Requests kept in flight at once
+        #[arg(long, default_value_t = 8)]
+        streams: usize,
+        /// Pipeline the requests over one framed stream instead of a stream
+        /// each
+        #[arg(long)]
+        framed: bool,
+        /// Print the results as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run a server and many concurrent clients in the same process
+    Load {
+        #[command(flatten)]
+        server: ServerArgs,
+        /// Number of clients, each on its own endpoint
+        #[arg(long, default_value_t = 16)]
+        clients: usize,
+        /// Requests sent by every client
+        #[arg(long, default_value_t = 1000)]
+        count: u64,
+        /// Message kinds each client cycles through: echo, ping, chat or
+        /// data[:<size>]
+        #[arg(long, value_parser = parse_mix, value_delimiter = ',', default_value = "echo,ping,data")]
+        mix: Vec<Message>,
+    },
+    /// Run a server and clients at a steady rate for a long time, reporting
+    /// memory, tasks and message counts along the way
+    Soak {
+        #[command(flatten)]
+        server: ServerArgs,
+        /// How long to run, e.g. `30m` or `8h`
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "1h")]
+        duration: Duration,
+        /// Number of clients, each on its own endpoint
+        #[arg(long, default_value_t = 4)]
+        clients: usize,
+        /// Requests per second sent by each client
+        #[arg(long, default_value_t = 10.0)]
+        rate: f64,
+        /// Time between two reports
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+        report_every: Duration,
+        /// Message kinds each client cycles through, as for `load`
+        #[arg(long, value_parser = parse_mix, value_delimiter = ',', default_value = "echo,ping,data")]
+        mix: Vec<Message>,
+    },
+    /// Manage a server started with `--operator` while it runs
+    Admin {
+        /// Ticket, EndpointId or `@name` of the server to manage
+        addr: Target,
+        /// Secret key identifying this operator, created if missing
+        #[arg(long)]
+        key_file: PathBuf,
+        #[command(subcommand)]
+        action: AdminAction,
+    },
+    /// Name servers in the address book, to connect to them as `@name`
+    Peers {
+        #[command(subcommand)]
+        action: PeersAction,
+    },
+    /// Send a file to a server started with `--receive-dir`
+    SendFile {
+        /// Ticket, EndpointId or `@name` of the server to send to
+        addr: Target,
+        path: PathBuf,
+    },
+    /// Serve a file by content hash, alongside the echo server, until Ctrl-C
+    #[cfg(feature = "blobs")]
+    Share {
+        path: PathBuf,
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+    /// Download a file shared with `share` and verify it against its hash
+    #[cfg(feature = "blobs")]
+    Fetch {
+        ticket: wstest::blobs::BlobTicket,
+        dest: PathBuf,
+    },
+    /// Join a gossip topic, publishing stdin lines as chat and printing what arrives
+    #[cfg(feature = "gossip")]
+    Gossip {
+        /// Name of the topic to join
+        topic: String,
+        /// EndpointId of a topic member to bootstrap from, may be repeated
+        #[arg(long)]
+        peer: Vec<EndpointId>,
+    },
+    /// Let WebSocket clients talk to an echo server through this node
+    #[cfg(feature = "websocket")]
+    Bridge {
+        /// Ticket, EndpointId or `@name` of the server to forward to
+        addr: Target,
+        /// TCP address to accept WebSocket connections on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: std::net::SocketAddr,
+    },
+}
+
+impl Command {
+    /// The options of the server this command runs, if it runs one
+    fn server_args_mut(&mut self) -> Option<&mut ServerArgs> {
+        match self {
+            Command::Server { server, .. }
+            | Command::Singleplayer { server, .. }
+            | Command::Load { server, .. }
+            | Command::Soak { server, .. } => Some(server),
+            #[cfg(feature = "blobs")]
+            Command::Share { server, .. } => Some(server),
+            _ => None,
+        }
+    }
+
+    /// Whether this command draws a dashboard on the terminal
+    fn tui(&self) -> bool {
+        #[cfg(feature = "tui")]
+        if let Command::Server { tui, .. } = self {
+            return *tui;
+        }
+        false
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum PeersAction {
+    /// Save a server under a name, replacing whatever had that name
+    Add {
+        name: String,
+        /// Ticket or EndpointId of the server
+        contact: Contact,
+    },
+    /// List the saved servers
+    List,
+    /// Forget a saved server
+    Remove { name: String },
 }
 
-/// Receive one message from a unidirectional stream
-async fn recv_one_way(mut recv: iroh::endpoint::RecvStream) -> Result<Message> {
-    let bytes = recv.read_to_end(MAX_MESSAGE_SIZE).await.anyerr()?;
+#[derive(Debug, Subcommand)]
+enum AdminAction {
+    /// List the connected peers
+    Connections,
+    /// Close the connection of a peer
+    Kick { peer: EndpointId },
+    /// Refuse a peer, kicking it if connected
+    Ban {
+        peer: EndpointId,
+        /// How long the ban lasts, e.g. `1h`; for good if unset
+        #[arg(long = "for", value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// Lift the ban on a peer
+    Unban { peer: EndpointId },
+    /// List the bans in force
+    Bans,
+    /// Show what the server is doing
+    Stats,
+}
+
+#[derive(Debug, Args)]
+struct ServerArgs {
+    /// UDP port to bind the server endpoint to (0 picks any); a taken port
+    /// is an error rather than replaced by another
+    #[arg(long, default_value_t = 0)]
+    port: u16,
+    /// Bind IPv4 to the interface with this address only
+    #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED)]
+    bind_ipv4: Ipv4Addr,
+    /// Bind IPv6 to the interface with this address only
+    #[arg(long, default_value_t = Ipv6Addr::UNSPECIFIED)]
+    bind_ipv6: Ipv6Addr,
+    /// Reach peers over IPv4 only
+    #[arg(long, conflicts_with = "bind_ipv6")]
+    no_ipv6: bool,
+    /// Load the server's secret key from this file, creating it if missing, so
+    /// its EndpointId survives restarts
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+    /// Only serve these peers, may be repeated
+    #[arg(long, conflicts_with = "deny")]
+    allow: Vec<EndpointId>,
+    /// Refuse these peers, may be repeated
+    #[arg(long)]
+    deny: Vec<EndpointId>,
+    /// Let this peer use the admin control channel, may be repeated
+    #[arg(long)]
+    operator: Vec<EndpointId>,
+    /// Also accept file transfers, storing received files in this directory
+    #[arg(long)]
+    receive_dir: Option<PathBuf>,
+    /// On Ctrl-C, give in-flight requests this long to finish
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5s")]
+    shutdown_grace: Duration,
+    /// Serve at most this many connections at once
+    #[arg(long)]
+    max_connections: Option<usize>,
+    /// Serve at most this many connections at once from any one peer
+    #[arg(long)]
+    max_connections_per_peer: Option<usize>,
+    /// Refuse new connections while this many stream handlers are running
+    #[arg(long)]
+    shed_in_flight: Option<usize>,
+    /// Refuse new connections while the server uses this much memory, e.g.
+    /// `512m`; Linux only
+    #[arg(long, value_parser = parse_size)]
+    shed_memory: Option<usize>,
+    /// Record every message received or sent to this file, as JSON lines
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+    /// Start a new audit log once it holds this many bytes
+    #[arg(long, requires = "audit_log", default_value_t = AUDIT_ROTATE_SIZE)]
+    audit_rotate_size: u64,
+    /// Keep this many full audit logs, as `<audit-log>.1` and up
+    #[arg(long, requires = "audit_log", default_value_t = AUDIT_KEEP)]
+    audit_keep: usize,
+    /// Answer messages whose handler panicked with an internal error
+    #[arg(long)]
+    report_panics: bool,
+    /// Serve Prometheus metrics over HTTP on this address
+    #[cfg(feature = "prometheus")]
+    #[arg(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+    /// Advertise the server on the local network, for `discover` to find
+    #[cfg(feature = "local-discovery")]
+    #[arg(long)]
+    advertise: bool,
+}
+
+impl ServerArgs {
+    fn access(&self) -> AccessPolicy {
+        if !self.allow.is_empty() {
+            AccessPolicy::allow_list(self.allow.iter().copied())
+        } else if !self.deny.is_empty() {
+            AccessPolicy::deny_list(self.deny.iter().copied())
+        } else {
+            AccessPolicy::AllowAll
+        }
+    }
+
+    /// The server's echo handler with these options and `common` applied
+    fn echo(&self, common: &CommonArgs) -> Result<Echo<CodecKind>> {
+        let mut echo = Echo::new(common.codec)
+            .with_config(common.protocol())
+            .with_access(self.access())
+            .with_connection_limits(ConnectionLimits {
+                max_connections: self.max_connections,
+                max_per_peer: self.max_connections_per_peer,
+                shed_in_flight: self.shed_in_flight,
+                shed_rss_bytes: self.shed_memory.map(|bytes| bytes as u64),
+            })
+            .with_rate_limit(common.rate_limit());
+        if let Some(config) = common.heartbeat() {
+            echo = echo.with_heartbeat(config);
+        }
+        if let Some(timeout) = common.idle_timeout {
+            echo = echo.with_idle_timeout(timeout);
+        }
+        if let Some(dir) = &common.qlog_dir {
+            echo = echo.with_qlog(Qlog::new(dir));
+        }
+        if let Some(token) = common.token() {
+            echo = echo.with_auth(token);
+        }
+        if let Some(keyring) = common.signing() {
+            echo = echo.with_signing(keyring);
+        }
+        if let Some(chaos) = common.chaos() {
+            echo = echo.with_chaos(chaos);
+        }
+
+        if !self.operator.is_empty() {
+            echo = echo.with_operators(AccessPolicy::allow_list(self.operator.iter().copied()));
+        }
+        if let Some(path) = &self.audit_log {
+            let audit =
+                AuditLog::open(path)?.with_rotation(self.audit_rotate_size, self.audit_keep);
+            echo = echo.with_audit(audit);
+        }
+        if self.report_panics {
+            echo = echo.with_panic_reports();
+        }
+        Ok(echo)
+    }
+
+    async fn bind(&self, common: &CommonArgs) -> Result<iroh::Endpoint> {
+        let secret_key = match &self.key_file {
+            Some(path) => Some(load_or_create_secret_key(path)?),
+            None => None,
+        };
+        let addr = self.bind_addr();
+        let (relays, lookup, tuning) = (common.relays(), common.lookup(), common.tuning());
+        server::bind_with(&addr, secret_key, &relays, &lookup, &tuning).await
+    }
+
+    fn bind_addr(&self) -> BindAddr {
+        let addr = BindAddr::port(self.port).with_ipv4(self.bind_ipv4);
+        if self.no_ipv6 {
+            addr.without_ipv6()
+        } else {
+            addr.with_ipv6(self.bind_ipv6)
+        }
+    }
+}
 
-    let (msg, _) = bincode::decode_from_slice(&bytes, bincode::config::standard())
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+#[derive(Debug, Args)]
+struct RunArgs {
+    /// Stop after sending this many messages (runs forever if unset)
+    #[arg(long)]
+    count: Option<u64>,
+    /// Stop after this much time has passed, e.g. `30s` or `5m`
+    #[arg(long, value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+    /// How messages are carried to the server
+    #[arg(long, value_enum, default_value_t = Transport::Streams)]
+    transport: Transport,
+    /// Give up reconnecting after this many failed attempts (retries forever if unset)
+    #[arg(long)]
+    retries: Option<u32>,
+    /// Write every message sent and received to this file, as JSON lines
+    #[arg(long)]
+    capture: Option<PathBuf>,
+    /// Read commands from a prompt instead, printing what the server pushes
+    /// as it arrives; `help` lists them
+    #[arg(long, conflicts_with_all = ["count", "timeout", "transport"])]
+    interactive: bool,
+}
 
-    Ok(msg)
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Transport {
+    /// One unidirectional stream per message, responses matched by id
+    Streams,
+    /// One persistent length-prefixed bidirectional stream
+    Framed,
+    /// Typed RPC calls, one bidirectional stream per call
+    Rpc,
+    /// Unreliable datagrams, counting those that get no answer as lost
+    Datagrams,
 }
 
 // ====================
@@ -50,103 +902,1199 @@ async fn recv_one_way(mut recv: iroh::endpoint::RecvStream) -> Result<Message> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    run_singleplayer().await?;
+    let (cli, config) = Cli::load()?;
+    let telemetry = init_tracing(config.log.as_deref(), &cli.common, cli.command.tui())?;
+    if let Some(key) = &cli.common.signing_key {
+        info!(key = %key.public(), "signing messages");
+    }
+    if let Some(key) = &cli.common.sealing_key {
+        info!(key = %key.public(), "opening publishes sealed to this key");
+    }
+    let result = run(cli, &telemetry).await;
+    telemetry.shutdown();
+    result
+}
+
+/// Carry out the command `cli` asks for
+async fn run(cli: Cli, telemetry: &Telemetry) -> Result<()> {
+    match cli.command {
+        Command::Server {
+            server: args,
+            daemon,
+            health_addr,
+            pid_file,
+            ..
+        } => {
+            let server = run_server_internal(&args, &cli.common, daemon).await?;
+            let _pid_file = pid_file.map(PidFile::create).transpose()?;
+            if let Some(addr) = health_addr.or(daemon.then_some(DEFAULT_HEALTH_ADDR)) {
+                let listener = tokio::net::TcpListener::bind(addr)
+                    .await
+                    .with_std_context(|_| format!("binding health endpoint to {addr}"))?;
+                announce(daemon, format!("Health checks at http://{addr}/healthz"));
+                let server = server.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = wstest::serve_health(listener, server).await {
+                        error!("{:#}", e);
+                    }
+                });
+            }
+            match telemetry.dashboard_logs() {
+                #[cfg(feature = "tui")]
+                Some(logs) => {
+                    let ticket = EchoTicket::new(server.endpoint().addr(), cli.common.codec);
+                    let signal = async {
+                        if let Err(e) = shutdown_signal().await {
+                            warn!("{:#}", e);
+                        }
+                    };
+                    wstest::Dashboard::new(server.echo().clone(), ticket.to_string())
+                        .with_logs(logs.clone())
+                        .run_until(signal)
+                        .await?;
+                }
+                _ => shutdown_signal().await?,
+            }
+            info!("shutting down, press Ctrl-C again to force");
+            tokio::select! {
+                result = server.shutdown(args.shutdown_grace) => result?,
+                _ = shutdown_signal() => {}
+            }
+        }
+        Command::Client { addr, run } => {
+            let (addr, common) = addr.resolve(cli.common)?;
+            run_client_internal(addr, &run, &common).await?;
+        }
+        Command::Singleplayer { server, run } => {
+            run_singleplayer(&server, &run, &cli.common).await?;
+        }
+        Command::Ping { addr, count } => {
+            let (addr, common) = addr.resolve(cli.common)?;
+            run_ping(addr, count, &common).await?;
+        }
+        #[cfg(feature = "local-discovery")]
+        Command::Discover { wait, connect, run } => {
+            run_discover(wait, connect, &run, cli.common).await?;
+        }
+        Command::Doctor { addr, window } => {
+            let (addr, common) = addr.resolve(cli.common)?;
+            run_doctor(addr, window, &common).await?;
+        }
+        Command::Replay { addr, trace, speed } => {
+            let (addr, common) = addr.resolve(cli.common)?;
+            run_replay(addr, &trace, speed, &common).await?;
+        }
+        Command::Bench {
+            addr,
+            size,
+            count,
+            streams,
+            framed,
+            json,
+        } => {
+            let (addr, common) = addr.resolve(cli.common)?;
+            let config = BenchConfig {
+                size,
+                count,
+                streams,
+                framed,
+            };
+            run_bench(addr, config, json, &common).await?;
+        }
+        Command::Load {
+            server,
+            clients,
+            count,
+            mix,
+        } => {
+            run_load(&server, clients, count, &mix, &cli.common).await?;
+        }
+        Command::Soak {
+            server,
+            duration,
+            clients,
+            rate,
+            report_every,
+            mix,
+        } => {
+            if !(rate > 0.0 && rate.is_finite()) {
+                return Err(anyerr!("rate must be a positive number"));
+            }
+            let config = SoakConfig {
+                duration,
+                rate,
+                report_every,
+            };
+            run_soak(&server, clients, config, &mix, &cli.common).await?;
+        }
+        Command::Admin {
+            addr,
+            key_file,
+            action,
+        } => {
+            let (addr, common) = addr.resolve(cli.common)?;
+            run_admin(addr, &key_file, action, &common).await?;
+        }
+        Command::Peers { action } => {
+            run_peers(action, &cli.common)?;
+        }
+        Command::SendFile { addr, path } => {
+            let (addr, common) = addr.resolve(cli.common)?;
+            send_file(addr, &path, &common).await?;
+        }
+        #[cfg(feature = "blobs")]
+        Command::Share {
+            path,
+            server: server_args,
+        } => {
+            let endpoint = server_args.bind(&cli.common).await?;
+            let share = wstest::BlobShare::new(endpoint.clone());
+            let ticket = share.share_file(&path).await?;
+            let server = Server::builder(endpoint, server_args.echo(&cli.common)?)
+                .register(|router| share.register(router))
+                .spawn()?;
+            println!("Sharing {}", path.display());
+            println!("Fetch with: wstest fetch {} <dest>", ticket);
+            shutdown_signal().await?;
+            server.shutdown(server_args.shutdown_grace).await?;
+        }
+        #[cfg(feature = "blobs")]
+        Command::Fetch { ticket, dest } => {
+            let share = wstest::BlobShare::new(cli.common.bind().await?);
+            let size = share.fetch(&ticket, &dest).await?;
+            println!("Fetched {} bytes to {}", size, dest.display());
+        }
+        #[cfg(feature = "gossip")]
+        Command::Gossip { topic, peer } => {
+            run_gossip(&topic, peer, &cli.common).await?;
+        }
+        #[cfg(feature = "websocket")]
+        Command::Bridge { addr, listen } => {
+            let endpoint = cli.common.bind().await?;
+            let bridge = {
+                let (addr, common) = addr.resolve(cli.common)?;
+                wstest::WsBridge::new(endpoint, addr, common.codec)
+            };
+            bridge.serve(listen).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Where traces go besides the log, to be flushed before exiting
+struct Telemetry {
+    #[cfg(feature = "otel")]
+    otlp: Option<wstest::Otlp>,
+    /// Log lines held for a dashboard instead of going to stderr
+    #[cfg(feature = "tui")]
+    logs: Option<wstest::LogBuffer>,
+}
+
+impl Telemetry {
+    #[cfg(feature = "tui")]
+    fn dashboard_logs(&self) -> Option<&wstest::LogBuffer> {
+        self.logs.as_ref()
+    }
+
+    #[cfg(not(feature = "tui"))]
+    fn dashboard_logs(&self) -> Option<std::convert::Infallible> {
+        None
+    }
+
+    fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Some(otlp) = self.otlp
+            && let Err(e) = otlp.shutdown()
+        {
+            warn!("{:#}", e);
+        }
+    }
+}
+
+/// Log to stderr, or for a dashboard if `tui`, filtered by `RUST_LOG`, else
+/// by `filter`, else showing this crate's info events, and export spans if
+/// `--otlp` says where to
+fn init_tracing(filter: Option<&str>, common: &CommonArgs, tui: bool) -> Result<Telemetry> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(filter.unwrap_or("wstest=info")));
+    let log = (!tui).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+    });
+    let subscriber = tracing_subscriber::registry().with(filter).with(log);
+    #[cfg(feature = "tui")]
+    let logs = tui.then(wstest::LogBuffer::default);
+    #[cfg(feature = "tui")]
+    let subscriber = subscriber.with(logs.clone());
+    #[cfg(feature = "otel")]
+    let otlp = common
+        .otlp
+        .as_ref()
+        .map(|url| wstest::Otlp::new(url.as_str(), "wstest"))
+        .transpose()?;
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(otlp.as_ref().map(|otlp| otlp.layer()));
+    #[cfg(not(feature = "otel"))]
+    let _ = common;
+    subscriber.init();
+    Ok(Telemetry {
+        #[cfg(feature = "otel")]
+        otlp,
+        #[cfg(feature = "tui")]
+        logs,
+    })
+}
+
+/// Start a server as `args` and `common` say, telling how to reach it on
+/// stdout, or in the log if `daemon`
+async fn run_server_internal(
+    args: &ServerArgs,
+    common: &CommonArgs,
+    daemon: bool,
+) -> Result<Server<CodecKind>> {
+    let endpoint = args.bind(common).await?;
+    let mut builder = Server::builder(endpoint, args.echo(common)?);
+    if let Some(dir) = &args.receive_dir {
+        builder = builder.accept(TRANSFER_ALPN, FileTransfer::new(common.codec, dir));
+    }
+    let server = builder.spawn()?;
+    #[cfg(feature = "prometheus")]
+    if let Some(addr) = args.metrics_addr {
+        let mut registry = server.echo().metrics().registry();
+        registry.register_all_prefixed(server.endpoint().metrics());
+        tokio::spawn(async move {
+            if let Err(e) = wstest::metrics::serve_metrics(addr, registry).await {
+                error!("{:#}", e);
+            }
+        });
+        announce(daemon, format!("Metrics at http://{}/metrics", addr));
+    }
+    #[cfg(feature = "local-discovery")]
+    if args.advertise {
+        wstest::local::advertise(server.endpoint(), common.codec)?;
+        announce(daemon, "Advertising on the local network");
+    }
+    let ticket = EchoTicket::new(server.endpoint().addr(), common.codec);
+    announce(
+        daemon,
+        format!("Server started as {}", server.endpoint().id()),
+    );
+    announce(daemon, format!("Connect with: wstest client {}", ticket));
+    if common.lookup() != AddrLookup::Disabled {
+        announce(
+            daemon,
+            format!(
+                "Or, once its address is published: wstest client {}",
+                server.endpoint().id()
+            ),
+        );
+    }
+    Ok(server)
+}
+
+/// Tell the user `line` on stdout, or as a daemon in the log
+fn announce(daemon: bool, line: impl std::fmt::Display) {
+    match daemon {
+        true => info!("{}", line),
+        false => println!("{}", line),
+    }
+}
+
+/// A file holding the id of this process, deleted again when dropped
+struct PidFile(PathBuf);
+
+impl PidFile {
+    fn create(path: PathBuf) -> Result<Self> {
+        std::fs::write(&path, format!("{}\n", std::process::id()))
+            .with_std_context(|_| format!("writing PID file {}", path.display()))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("error deleting PID file {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+static MESSAGES: [Message; 4] = [
+    Message::Echo,
+    Message::Ping {
+        seq: 0,
+        timestamp: 0,
+    },
+    Message::Pong {
+        seq: 0,
+        timestamp: 0,
+    },
+    Message::Data(Vec::new()),
+];
+
+async fn run_client_internal(
+    addr: EndpointAddr,
+    args: &RunArgs,
+    common: &CommonArgs,
+) -> Result<()> {
+    let codec = common.codec;
+    let mut client = common
+        .client(addr.clone())
+        .with_backoff(Backoff {
+            max_retries: args.retries,
+            ..Default::default()
+        })
+        .on_connect(|conn| info!(remote = %conn.remote_id(), "connected"))
+        .on_disconnect(|reason| info!("disconnected: {}", reason));
+    if let Some(path) = &args.capture {
+        client = client.with_capture(Capture::create(path)?);
+    }
+    if args.interactive {
+        let result = run_interactive(&client, addr, common).await;
+        client.close().await;
+        return result;
+    }
+
+    let run = async {
+        match args.transport {
+            Transport::Streams => Ok(run_streams(&client, args.count).await),
+            Transport::Framed => {
+                let client = client.client().await?;
+                Ok(run_framed(&client, args.count, common.rate_limit()).await)
+            }
+            Transport::Datagrams => {
+                let client = client.client().await?;
+                Ok(run_datagrams(&client, args.count).await)
+            }
+            Transport::Rpc => {
+                let client = match common.token() {
+                    Some(token) => {
+                        RpcClient::connect_with_auth(addr.clone(), codec, token.as_ref()).await?
+                    }
+                    None => RpcClient::connect(addr.clone(), codec).await?,
+                };
+                Ok::<_, AnyError>(run_rpc(&client, args.count).await)
+            }
+        }
+    };
+
+    let run = async {
+        match args.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, run).await {
+                Ok(count) => count.map(Some),
+                Err(_) => {
+                    info!("timeout of {} reached", humantime::format_duration(timeout));
+                    Ok(None)
+                }
+            },
+            None => run.await.map(Some),
+        }
+    };
+    let message_count = tokio::select! {
+        count = run => count?,
+        signal = shutdown_signal() => {
+            signal?;
+            info!("interrupted, closing the connection");
+            None
+        }
+    };
+    client.close().await;
+    if let Some(message_count) = message_count {
+        println!("Finished after {} messages", message_count);
+    }
+
     Ok(())
 }
 
-async fn run_server_internal() -> Result<Router> {
-    let endpoint = Endpoint::bind().await?;
-    let router = Router::builder(endpoint).accept(ALPN, Echo).spawn();
-    println!("Server started at {:#?}", router.endpoint().addr());
-    Ok(router)
+/// Wait for Ctrl-C, or on Unix also for SIGTERM, which supervisors send to
+/// stop a process
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate()).anyerr()?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.anyerr(),
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.anyerr()
 }
 
-async fn run_client_internal(addr: EndpointAddr) -> Result<()> {
-    let endpoint = Endpoint::bind().await?;
-    let conn = endpoint.connect(addr, ALPN).await?;
+/// Stress test: keep one request of each kind in flight at a time, letting the
+/// responses come back in whatever order their streams complete
+async fn run_streams(client: &ReconnectingClient<CodecKind>, count: Option<u64>) -> u64 {
+    let mut message_count = 0u64;
+
+    while count.is_none_or(|count| message_count < count) {
+        let remaining = count.map_or(u64::MAX, |count| count - message_count);
+        let batch = MESSAGES
+            .iter()
+            .take(remaining.min(MESSAGES.len() as u64) as usize);
 
-    // Infinite stress test: send a message every 100ms
+        match try_join_all(batch.map(|msg| client.request(msg.clone()))).await {
+            Ok(responses) => {
+                for response in &responses {
+                    message_count += 1;
+                    if message_count.is_multiple_of(10) {
+                        info!("received {} responses, last {:?}", message_count, response);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("error sending message: {:#}", e);
+                break;
+            }
+        }
+    }
+    message_count
+}
+
+/// Stress test over a single framed stream, where responses arrive in order
+async fn run_framed(client: &Client<CodecKind>, count: Option<u64>, rate_limit: RateLimit) -> u64 {
+    let (egress, ingress) = rate_limit.throttles();
+    let mut framed = match FramedConnection::open(client.connection(), *client.codec()).await {
+        Ok(framed) => framed
+            .with_max_frame_size(client.config().max_message_size)
+            .with_throttles(egress, ingress),
+        Err(e) => {
+            error!("error opening framed stream: {:#}", e);
+            return 0;
+        }
+    };
     let mut message_count = 0u64;
-    let messages = vec![Message::Echo, Message::Ping, Message::Pong];
 
-    loop {
-        let msg = &messages[message_count as usize % messages.len()];
+    while count.is_none_or(|count| message_count < count) {
+        let mut msg = MessageEnvelope::new(
+            message_count,
+            MESSAGES[message_count as usize % MESSAGES.len()].clone(),
+        );
+        client.intercept(&mut msg);
+        let sent = match msg.frame(client.codec(), client.protocol_version()) {
+            Ok(frame) => framed.send_raw(frame).await,
+            Err(e) => Err(e),
+        };
+        let response = match sent {
+            Ok(()) => framed.recv_bytes().await.and_then(|bytes| {
+                bytes
+                    .map(|bytes| decode_frame(client.codec(), client.protocol_version(), &bytes))
+                    .transpose()
+            }),
+            Err(e) => Err(e),
+        };
+        match response {
+            Ok(Some(response)) => {
+                message_count += 1;
+                if message_count.is_multiple_of(10) {
+                    info!(
+                        "received {} frames, last {:?}",
+                        message_count, response.body
+                    );
+                }
+            }
+            Ok(None) => {
+                warn!("server finished the framed stream");
+                break;
+            }
+            Err(e) => {
+                error!("error sending frame: {:#}", e);
+                break;
+            }
+        }
+    }
+    framed.finish().ok();
+    message_count
+}
+
+/// Send datagrams one at a time, waiting briefly for each answer before
+/// counting it as lost
+async fn run_datagrams(client: &Client<CodecKind>, count: Option<u64>) -> u64 {
+    const ANSWER_TIMEOUT: Duration = Duration::from_millis(500);
+    let mut answers = std::pin::pin!(client.recv_datagrams());
+    let (mut message_count, mut lost) = (0u64, 0u64);
+
+    while count.is_none_or(|count| message_count < count) {
+        let msg = &MESSAGES[message_count as usize % MESSAGES.len()];
+        if let Err(e) = client.send_datagram(msg) {
+            error!("error sending datagram: {:#}", e);
+            break;
+        }
+        message_count += 1;
+        match tokio::time::timeout(ANSWER_TIMEOUT, answers.next()).await {
+            Ok(Some(Ok(_))) => {}
+            Ok(Some(Err(e))) => warn!("error decoding datagram: {:#}", e),
+            Ok(None) => {
+                warn!("connection closed");
+                break;
+            }
+            Err(_) => lost += 1,
+        }
+        if message_count.is_multiple_of(10) {
+            info!("sent {} datagrams, {} lost", message_count, lost);
+        }
+    }
+    message_count
+}
 
-        match send_one_way(&conn, msg).await {
-            Ok(_) => {
+/// Stress test through the typed RPC layer, one call at a time
+async fn run_rpc(client: &RpcClient<CodecKind>, count: Option<u64>) -> u64 {
+    let mut message_count = 0u64;
+
+    while count.is_none_or(|count| message_count < count) {
+        let msg = MESSAGES[message_count as usize % MESSAGES.len()].clone();
+        match client.call::<EchoRpc>(msg).await {
+            Ok(response) => {
                 message_count += 1;
-                if message_count % 10 == 0 {
-                    println!("Sent {} messages", message_count);
+                if message_count.is_multiple_of(10) {
+                    info!("completed {} calls, last {:?}", message_count, response);
                 }
             }
             Err(e) => {
-                eprintln!("Error sending message: {}", e);
+                error!("error calling rpc: {:#}", e);
                 break;
             }
         }
+    }
+    message_count
+}
+
+async fn run_ping(addr: EndpointAddr, count: usize, common: &CommonArgs) -> Result<()> {
+    let client = common.client(addr);
+    let stats = wstest::measure_rtt(&*client.client().await?, count).await?;
+    println!(
+        "{} pings: min {:?}, avg {:?}, p99 {:?}, max {:?}, jitter {:?}",
+        stats.samples, stats.min, stats.avg, stats.p99, stats.max, stats.jitter
+    );
+    Ok(())
+}
+
+/// What `client --interactive` understands, as `help` lists it
+const INTERACTIVE_HELP: &str = "\
+ping                     time a round trip
+echo <text>              send text and print what comes back
+chat <text>              send a chat line to every other peer, as $USER
+subscribe <filter>       be pushed what is published to matching topics
+publish <topic> <text>   publish text to a topic, sealed to --seal-to keys
+send-file <path>         transfer a file, if the server accepts transfers
+stats                    the server's statistics of this connection
+help                     show this list
+quit                     disconnect and exit";
+
+/// Run commands typed at a prompt against the server, printing its pushes
+/// as they arrive, until `quit`, end of input or Ctrl-C
+async fn run_interactive(
+    client: &ReconnectingClient<CodecKind>,
+    addr: EndpointAddr,
+    common: &CommonArgs,
+) -> Result<()> {
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        sync::broadcast::error::RecvError,
+    };
+
+    let sealer = client.sealer().cloned();
+    let client = client.client().await?;
+    let mut pushes = client.subscribe_pushes();
+    println!(
+        "Connected to {}, type help for commands",
+        client.connection().remote_id()
+    );
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    prompt();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line.anyerr()? else {
+                    break;
+                };
+                match run_command(&client, &addr, line.trim(), common, sealer.as_ref()).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => match client.remote_close() {
+                        Some(close) => {
+                            println!("server closed the connection: {}", close);
+                            break;
+                        }
+                        None => println!("error: {:#}", e),
+                    },
+                }
+                prompt();
+            }
+            push = pushes.recv() => {
+                match push {
+                    Ok(msg) => println!("\rpushed: {}", describe(&msg, sealer.as_ref())),
+                    Err(RecvError::Lagged(missed)) => println!("\rmissed {} pushes", missed),
+                    Err(RecvError::Closed) => break,
+                }
+                prompt();
+            }
+            _ = shutdown_signal() => break,
+        }
+    }
+    Ok(())
+}
+
+/// Carry out one line typed at the interactive prompt, returning whether to
+/// go on
+async fn run_command(
+    client: &Client<CodecKind>,
+    addr: &EndpointAddr,
+    line: &str,
+    common: &CommonArgs,
+    sealer: Option<&Sealer>,
+) -> Result<bool> {
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    match command {
+        "" => {}
+        "ping" => {
+            let stats = wstest::measure_rtt(client, 1).await?;
+            println!("pong after {:?}", stats.avg);
+        }
+        "echo" => {
+            let reply = client.request(Message::Data(rest.into())).await?;
+            println!("{}", describe(&reply, sealer));
+        }
+        "chat" => {
+            let from = std::env::var("USER").unwrap_or_else(|_| "anonymous".to_string());
+            let text = rest.to_string();
+            client.send(Message::Chat { from, text }).await?;
+        }
+        "subscribe" if !rest.is_empty() => {
+            client.subscribe(rest).await?;
+            println!("subscribed to {}", rest);
+        }
+        "publish" if rest.contains(' ') => {
+            let (topic, text) = rest.split_once(' ').expect("checked");
+            match sealer {
+                Some(sealer) if !common.seal_to.is_empty() => {
+                    client
+                        .publish_sealed(sealer, topic, &common.seal_to, text.as_bytes())
+                        .await?
+                }
+                _ => client.publish(topic, text.into()).await?,
+            }
+        }
+        "send-file" if !rest.is_empty() => {
+            send_file(addr.clone(), Path::new(rest), common).await?;
+        }
+        "stats" => {
+            let stats = client.remote_stats().await?;
+            println!(
+                "rtt {:?}, cwnd {} B, sent {} B in {} packets ({} lost), received {} B, \
+                 {} streams opened",
+                stats.rtt,
+                stats.cwnd,
+                stats.bytes_sent,
+                stats.packets_sent,
+                stats.packets_lost,
+                stats.bytes_received,
+                stats.streams_opened,
+            );
+        }
+        "help" => println!("{}", INTERACTIVE_HELP),
+        "quit" | "exit" => return Ok(false),
+        "subscribe" | "publish" | "send-file" => {
+            return Err(anyerr!("{} needs more arguments, see help", command));
+        }
+        _ => return Err(anyerr!("unknown command {:?}, see help", command)),
+    }
+    Ok(true)
+}
+
+/// Prompt for the next interactive command, if someone is typing them
+fn prompt() {
+    use std::io::Write;
+
+    if std::io::stdin().is_terminal() {
+        print!("> ");
+        std::io::stdout().flush().ok();
+    }
+}
+
+/// `msg` for printing, with text sent as bytes shown as text, and publishes
+/// sealed to `sealer` opened
+fn describe(msg: &Message, sealer: Option<&Sealer>) -> String {
+    match msg {
+        Message::Data(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        Message::Chat { from, text } => format!("{}: {}", from, text),
+        Message::Publish { topic, payload } if sealing::is_sealed(payload) => {
+            match sealer.map(|sealer| sealer.open(topic, payload)) {
+                Some(Ok(opened)) => format!(
+                    "{} on {}, sealed by {}",
+                    String::from_utf8_lossy(&opened.payload),
+                    topic,
+                    opened.sender.fmt_short()
+                ),
+                Some(Err(e)) => format!("sealed payload on {} ({:#})", topic, e),
+                None => format!("sealed payload on {}", topic),
+            }
+        }
+        Message::Publish { topic, payload } => {
+            format!("{} on {}", String::from_utf8_lossy(payload), topic)
+        }
+        Message::Unknown { tag, bytes } => {
+            format!("unknown message (tag {}, {} bytes)", tag, bytes.len())
+        }
+        other => format!("{:?}", other),
+    }
+}
+
+async fn run_doctor(addr: EndpointAddr, window: Duration, common: &CommonArgs) -> Result<()> {
+    let endpoint = common.bind().await?;
+    let diagnosis = wstest::diagnose(&endpoint, addr, common.codec, window).await?;
+    let path = if diagnosis.is_direct() {
+        "direct"
+    } else {
+        "relayed"
+    };
+    println!("path:          {} ({})", path, diagnosis.path);
+    println!("initial path:  {}", diagnosis.initial_path);
+    match diagnosis.relay() {
+        Some(relay) => println!("relay:         {relay}"),
+        None => println!("relay:         none"),
+    }
+    let holepunch = match diagnosis.holepunch {
+        Holepunch::NotNeeded => "not needed, direct from the start".to_string(),
+        Holepunch::Succeeded(after) => format!("succeeded after {after:?}"),
+        Holepunch::Failed => format!("no direct path within {window:?}"),
+    };
+    println!("hole punching: {holepunch}");
+    println!("handshake:     {:?}", diagnosis.handshake);
+    println!("first echo:    {:?}", diagnosis.first_round_trip);
+    println!("rtt:           {:?}", diagnosis.rtt);
+    match &diagnosis.home_relay {
+        Some(relay) => println!("home relay:    {relay}"),
+        None => println!("home relay:    none found"),
+    }
+    if diagnosis.local_addrs.is_empty() {
+        println!("own addresses: none found");
+    }
+    for addr in &diagnosis.local_addrs {
+        println!("own address:   {addr}");
+    }
+    endpoint.close().await;
+    Ok(())
+}
 
-        //tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+#[cfg(feature = "local-discovery")]
+async fn run_discover(
+    wait: Duration,
+    connect: Option<usize>,
+    run: &RunArgs,
+    common: CommonArgs,
+) -> Result<()> {
+    let peers = wstest::local::discover(wait).await?;
+    if peers.is_empty() {
+        println!("No echo servers found on the local network within {wait:?}");
+        return Ok(());
     }
+    for (i, peer) in peers.iter().enumerate() {
+        let codec = peer
+            .codec
+            .map_or_else(|| "unknown codec".to_string(), |codec| codec.to_string());
+        let addrs: Vec<_> = peer.addr.ip_addrs().map(ToString::to_string).collect();
+        println!("[{i}] {} ({codec}) {}", peer.addr.id, addrs.join(", "));
+    }
+    let index = match connect {
+        Some(index) => index,
+        None if std::io::stdin().is_terminal() => {
+            print!("Connect to: ");
+            std::io::Write::flush(&mut std::io::stdout()).anyerr()?;
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line).anyerr()?;
+            let line = line.trim();
+            if line.is_empty() {
+                return Ok(());
+            }
+            line.parse()
+                .with_std_context(|_| format!("invalid index {line:?}"))?
+        }
+        None => return Ok(()),
+    };
+    let peer = peers
+        .get(index)
+        .ok_or_else(|| anyerr!("no server with index {index}"))?;
+    let common = CommonArgs {
+        codec: peer.codec.unwrap_or(common.codec),
+        ..common
+    };
+    run_client_internal(peer.addr.clone(), run, &common).await
+}
 
+fn run_peers(action: PeersAction, common: &CommonArgs) -> Result<()> {
+    let mut book = common.address_book()?;
+    match action {
+        PeersAction::Add { name, contact } => {
+            match book.insert(&name, contact)? {
+                Some(old) => println!("Replaced @{name}, was {old}"),
+                None => println!("Added @{name}"),
+            }
+            book.save()?;
+        }
+        PeersAction::List => {
+            for (name, contact) in book.iter() {
+                println!("@{name}\t{contact}");
+            }
+        }
+        PeersAction::Remove { name } => {
+            book.remove(&name)
+                .ok_or_else(|| anyerr!("no peer named {name:?} in {}", book.path().display()))?;
+            book.save()?;
+            println!("Removed @{name}");
+        }
+    }
     Ok(())
 }
 
-async fn run_singleplayer() -> Result<()> {
-    let router = run_server_internal().await?;
-    router.endpoint().online().await;
-    let server_addr = router.endpoint().addr();
+async fn run_replay(
+    addr: EndpointAddr,
+    trace: &Path,
+    speed: f64,
+    common: &CommonArgs,
+) -> Result<()> {
+    let trace = wstest::read_capture(trace)?;
+    let client = common.client(addr);
+    let report = wstest::replay(&*client.client().await?, &trace, speed).await?;
+    println!(
+        "replayed {} requests in {:?}: {} answered, {} failed",
+        report.sent, report.elapsed, report.answered, report.failed
+    );
+    Ok(())
+}
 
-    // Give server time to start
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+async fn run_bench(
+    addr: EndpointAddr,
+    config: BenchConfig,
+    json: bool,
+    common: &CommonArgs,
+) -> Result<()> {
+    let client = common.client(addr);
+    let report = wstest::run_bench(&*client.client().await?, config).await?;
+    if json {
+        let report = serde_json::json!({
+            "size": config.size,
+            "streams": config.streams,
+            "framed": config.framed,
+            "messages": report.messages,
+            "bytes": report.bytes,
+            "elapsed_secs": report.elapsed.as_secs_f64(),
+            "messages_per_sec": report.messages_per_sec(),
+            "mb_per_sec": report.mb_per_sec(),
+            "latency_ms": {
+                "p50": report.p50.as_secs_f64() * 1e3,
+                "p90": report.p90.as_secs_f64() * 1e3,
+                "p99": report.p99.as_secs_f64() * 1e3,
+                "max": report.max.as_secs_f64() * 1e3,
+            },
+        });
+        println!("{}", report);
+    } else {
+        let rows = [
+            ("messages", report.messages.to_string()),
+            ("elapsed", format!("{:.2?}", report.elapsed)),
+            ("messages/sec", format!("{:.0}", report.messages_per_sec())),
+            ("MB/sec", format!("{:.2}", report.mb_per_sec())),
+            ("latency p50", format!("{:.2?}", report.p50)),
+            ("latency p90", format!("{:.2?}", report.p90)),
+            ("latency p99", format!("{:.2?}", report.p99)),
+            ("latency max", format!("{:.2?}", report.max)),
+        ];
+        for (name, value) in rows {
+            println!("{:<14}{:>12}", name, value);
+        }
+    }
+    Ok(())
+}
 
-    // Run client (will run infinitely)
-    run_client_internal(server_addr).await?;
+async fn run_load(
+    server_args: &ServerArgs,
+    clients: usize,
+    count: u64,
+    mix: &[Message],
+    common: &CommonArgs,
+) -> Result<()> {
+    let server = run_server_internal(server_args, common, false).await?;
+    server.endpoint().online().await;
+    let addr = server.endpoint().addr();
+    let clients = (0..clients).map(|_| common.client(addr.clone())).collect();
 
+    let report = tokio::select! {
+        report = wstest::run_load(clients, count, mix) => report,
+        signal = shutdown_signal() => {
+            signal?;
+            info!("interrupted, shutting down");
+            return server.shutdown(server_args.shutdown_grace).await;
+        }
+    };
+    println!(
+        "{:>6} {:>10} {:>12} {:>10} {:>10} {:>10}",
+        "client", "messages", "messages/s", "p50", "p99", "max"
+    );
+    let rows = report
+        .clients
+        .iter()
+        .enumerate()
+        .map(|(i, client)| (i.to_string(), &client.stats));
+    for (name, stats) in rows.chain([("total".to_string(), &report.total)]) {
+        println!(
+            "{:>6} {:>10} {:>12.0} {:>10.2?} {:>10.2?} {:>10.2?}",
+            name,
+            stats.messages,
+            stats.messages_per_sec(),
+            stats.p50,
+            stats.p99,
+            stats.max
+        );
+    }
+    for (i, client) in report.clients.iter().enumerate() {
+        if let Some(e) = &client.error {
+            error!(client = i, "stopped early: {}", e);
+        }
+    }
+    server.shutdown(server_args.shutdown_grace).await?;
     Ok(())
 }
 
-#[derive(Debug, Clone)]
-struct Echo;
-
-impl ProtocolHandler for Echo {
-    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
-        let endpoint_id = connection.remote_id();
-        println!("Accepted connection from {}", endpoint_id);
-
-        let mut receive_count = 0u64;
-
-        // Accept unidirectional streams in a loop
-        loop {
-            match connection.accept_uni().await {
-                Ok(recv) => {
-                    // Spawn a task to handle each stream independently
-                    tokio::spawn(async move {
-                        match recv_one_way(recv).await {
-                            Ok(msg) => {
-                                // Just log occasionally to avoid spam
-                                if receive_count % 10 == 0 {
-                                    println!(
-                                        "Server received message #{}: {:?}",
-                                        receive_count, msg
-                                    );
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Error receiving message: {}", e);
-                            }
-                        }
-                    });
+async fn run_soak(
+    server_args: &ServerArgs,
+    clients: usize,
+    config: SoakConfig,
+    mix: &[Message],
+    common: &CommonArgs,
+) -> Result<()> {
+    let server = run_server_internal(server_args, common, false).await?;
+    server.endpoint().online().await;
+    let addr = server.endpoint().addr();
+    let clients = (0..clients).map(|_| common.client(addr.clone())).collect();
+
+    let metrics = server.echo().metrics().clone();
+    let print = |snapshot: &wstest::Snapshot| {
+        let rss = snapshot.rss_bytes.map_or("?".to_string(), |rss| {
+            format!("{:.1}MiB", rss as f64 / (1 << 20) as f64)
+        });
+        println!(
+            "[{}] rss {} tasks {} connections {} sent {} answered {} failed {}",
+            humantime::format_duration(Duration::from_secs(snapshot.elapsed.as_secs())),
+            rss,
+            snapshot.tasks,
+            metrics.echo().connections_active.get(),
+            snapshot.sent,
+            snapshot.answered,
+            snapshot.failed
+        );
+    };
+    tokio::select! {
+        last = wstest::run_soak(clients, config, mix, print) => print(&last),
+        signal = shutdown_signal() => {
+            signal?;
+            info!("interrupted, shutting down");
+        }
+    }
+    server.shutdown(server_args.shutdown_grace).await?;
+    Ok(())
+}
+
+/// Parse one entry of a load test message mix
+fn parse_mix(s: &str) -> Result<Message> {
+    let (kind, arg) = s.split_once(':').unwrap_or((s, ""));
+    match (kind, arg) {
+        ("echo", "") => Ok(Message::Echo),
+        ("ping", "") => Ok(Message::Ping {
+            seq: 0,
+            timestamp: 0,
+        }),
+        ("chat", "") => Ok(Message::Chat {
+            from: "load".to_string(),
+            text: "hello".to_string(),
+        }),
+        ("data", "") => Ok(Message::Data(vec![0; 1024])),
+        ("data", size) => Ok(Message::Data(vec![0; parse_size(size)?])),
+        _ => Err(anyerr!("unknown message kind {}", s)),
+    }
+}
+
+/// The application key in the file at `path`, created if missing
+fn load_app_key(path: &str) -> Result<SecretKey> {
+    load_or_create_secret_key(path)
+}
 
-                    receive_count += 1;
+/// Parse a byte count with an optional binary suffix: `k`, `m` or `g`
+fn parse_size(s: &str) -> Result<usize> {
+    let lower = s.trim().to_ascii_lowercase();
+    let (digits, multiplier) = match lower.trim_end_matches('b').trim_end_matches('i') {
+        n if n.ends_with('k') => (&n[..n.len() - 1], 1 << 10),
+        n if n.ends_with('m') => (&n[..n.len() - 1], 1 << 20),
+        n if n.ends_with('g') => (&n[..n.len() - 1], 1 << 30),
+        n => (n, 1),
+    };
+    let n: usize = digits.parse().std_context("invalid size")?;
+    n.checked_mul(multiplier)
+        .ok_or_else(|| anyerr!("size {} is too large", s))
+}
+
+async fn run_admin(
+    addr: EndpointAddr,
+    key_file: &Path,
+    action: AdminAction,
+    common: &CommonArgs,
+) -> Result<()> {
+    use wstest::admin::{BanPeer, BanRequest, Kick, ListBans, ListConnections, Stats, Unban};
+
+    let builder = common.relays().apply(iroh::Endpoint::builder());
+    let endpoint = common
+        .lookup()
+        .apply(builder)
+        .secret_key(load_or_create_secret_key(key_file)?)
+        .bind()
+        .await?;
+    info!(operator = %endpoint.id(), "connecting to control channel");
+    let admin = wstest::admin::connect(&endpoint, addr, common.codec).await?;
+    match action {
+        AdminAction::Connections => {
+            let connections = admin.call::<ListConnections>(()).await?;
+            println!("{:<12}{:<16}{:>10}{:>10}", "peer", "name", "rtt", "idle");
+            for conn in &connections {
+                println!(
+                    "{:<12}{:<16}{:>10}{:>10}",
+                    conn.peer.fmt_short(),
+                    conn.name.as_deref().unwrap_or("-"),
+                    format!("{:.1?}", conn.rtt),
+                    format!("{:.1?}", conn.idle),
+                );
+            }
+            println!("{} connected", connections.len());
+        }
+        AdminAction::Kick { peer } => match admin.call::<Kick>(peer).await? {
+            true => println!("Kicked {}", peer),
+            false => println!("{} is not connected", peer),
+        },
+        AdminAction::Ban { peer, duration } => {
+            admin.call::<BanPeer>(BanRequest { peer, duration }).await?;
+            match duration {
+                Some(duration) => {
+                    println!(
+                        "Banned {} for {}",
+                        peer,
+                        humantime::format_duration(duration)
+                    )
                 }
-                Err(_) => {
-                    // Connection closed
-                    println!("Connection closed after {} messages", receive_count);
-                    break;
+                None => println!("Banned {}", peer),
+            }
+        }
+        AdminAction::Unban { peer } => match admin.call::<Unban>(peer).await? {
+            true => println!("Unbanned {}", peer),
+            false => println!("{} is not banned", peer),
+        },
+        AdminAction::Bans => {
+            for ban in admin.call::<ListBans>(()).await? {
+                match ban.until {
+                    Some(until) => println!(
+                        "{} until {}",
+                        ban.peer,
+                        humantime::format_rfc3339_seconds(until)
+                    ),
+                    None => println!("{} for good", ban.peer),
                 }
             }
         }
+        AdminAction::Stats => {
+            let stats = admin.call::<Stats>(()).await?;
+            let rows = [
+                ("connections", stats.connections.to_string()),
+                ("in flight", stats.in_flight.to_string()),
+                ("received", format!("{} B", stats.bytes_received)),
+                ("sent", format!("{} B", stats.bytes_sent)),
+                ("rooms", stats.rooms.to_string()),
+                ("players", stats.players.to_string()),
+                ("queued", stats.queued.to_string()),
+                ("bans", stats.bans.to_string()),
+            ];
+            for (name, value) in rows {
+                println!("{:<14}{:>12}", name, value);
+            }
+        }
+    }
+    CloseReason::Normal.close(admin.connection(), "done");
+    Ok(())
+}
+
+async fn send_file(addr: EndpointAddr, path: &Path, common: &CommonArgs) -> Result<()> {
+    let conn = connect_with_alpn(addr, TRANSFER_ALPN).await?;
+    let (progress, mut updates) = watch::channel(Progress::default());
+    let report = tokio::spawn(async move {
+        let mut reported = 0;
+        while updates.changed().await.is_ok() {
+            let progress = updates.borrow_and_update().clone();
+            let percent = (progress.bytes * 100)
+                .checked_div(progress.total)
+                .unwrap_or(100);
+            if percent >= reported + 10 {
+                reported = percent - percent % 10;
+                println!("Sent {}% of {}", reported, progress.name);
+            }
+        }
+    });
+
+    let meta = wstest::send_file(&conn, &common.codec, path, &progress).await?;
+    drop(progress);
+    report.await.ok();
+    println!("Transferred {} ({} bytes)", meta.name, meta.size);
+    CloseReason::Normal.close(&conn, "done");
+    Ok(())
+}
+
+#[cfg(feature = "gossip")]
+async fn run_gossip(name: &str, bootstrap: Vec<EndpointId>, common: &CommonArgs) -> Result<()> {
+    use iroh::protocol::Router;
+    use tokio::io::{AsyncBufReadExt, BufReader};
 
-        Ok(())
+    let endpoint = common.bind().await?;
+    let node = wstest::GossipNode::new(&endpoint, common.codec);
+    let router = node.register(Router::builder(endpoint.clone())).spawn();
+    let mut topic = node.join(name, bootstrap).await?;
+    println!("Joined topic {} as {}", name, endpoint.id());
+
+    let me = endpoint.id().fmt_short().to_string();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line.anyerr()? {
+                Some(text) => {
+                    let chat = Message::Chat { from: me.clone(), text };
+                    if let Err(e) = topic.publish(&chat).await {
+                        warn!("error publishing: {:#}", e);
+                    }
+                }
+                None => break,
+            },
+            msg = topic.recv() => match msg? {
+                Some((_, Message::Chat { from, text })) => println!("{}: {}", from, text),
+                Some((via, other)) => println!("{:?} via {}", other, via),
+                None => break,
+            },
+            _ = shutdown_signal() => break,
+        }
     }
+    router.shutdown().await.anyerr()?;
+    Ok(())
+}
+
+async fn run_singleplayer(
+    server_args: &ServerArgs,
+    run: &RunArgs,
+    common: &CommonArgs,
+) -> Result<()> {
+    let server = run_server_internal(server_args, common, false).await?;
+    server.endpoint().online().await;
+    server.ready().await?;
+    let server_addr = server.endpoint().addr();
+
+    run_client_internal(server_addr, run, common).await?;
+    server.shutdown(server_args.shutdown_grace).await?;
+
+    Ok(())
 }
@@ -0,0 +1,133 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use iroh::EndpointId;
+
+/// A client waiting in a matchmaking queue
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub peer: EndpointId,
+    /// The skill rating the client joined with, if any
+    pub rating: Option<u32>,
+    /// When the client joined the queue
+    pub since: Instant,
+}
+
+/// How a [`Matchmaker`] picks the clients to play together
+pub trait MatchStrategy: fmt::Debug + Send + Sync + 'static {
+    /// Pick `party_size` entries of `waiting` to match together, by index
+    ///
+    /// `waiting` are the clients queued for one mode and party size, oldest
+    /// first. Returning `None` leaves everyone waiting for more clients.
+    fn find_match(&self, waiting: &[QueueEntry], party_size: usize) -> Option<Vec<usize>>;
+}
+
+/// Matches clients in the order they joined, ignoring ratings
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirstCome;
+
+impl MatchStrategy for FirstCome {
+    fn find_match(&self, waiting: &[QueueEntry], party_size: usize) -> Option<Vec<usize>> {
+        (waiting.len() >= party_size).then(|| (0..party_size).collect())
+    }
+}
+
+/// The waiting clients of each mode and party size
+type Queues = HashMap<(String, usize), Vec<QueueEntry>>;
+
+/// Clients waiting to be matched, queued by mode and party size
+///
+/// Clones share the same queues.
+#[derive(Debug, Clone)]
+pub struct Matchmaker {
+    strategy: Arc<dyn MatchStrategy>,
+    queues: Arc<Mutex<Queues>>,
+}
+
+impl Default for Matchmaker {
+    fn default() -> Self {
+        Self::new(FirstCome)
+    }
+}
+
+impl Matchmaker {
+    pub fn new(strategy: impl MatchStrategy) -> Self {
+        Self {
+            strategy: Arc::new(strategy),
+            queues: Arc::default(),
+        }
+    }
+
+    /// Queue `peer` for matches of `party_size` clients playing `mode`
+    ///
+    /// A peer waits in one queue at a time, so this takes it out of any
+    /// other. Returns every match that is complete now.
+    pub fn join(
+        &self,
+        peer: EndpointId,
+        mode: String,
+        party_size: usize,
+        rating: Option<u32>,
+    ) -> Vec<Vec<QueueEntry>> {
+        let party_size = party_size.max(1);
+        let mut queues = self.queues.lock().expect("poisoned");
+        remove_from(&mut queues, &peer);
+        let waiting = queues.entry((mode, party_size)).or_default();
+        waiting.push(QueueEntry {
+            peer,
+            rating,
+            since: Instant::now(),
+        });
+
+        let mut matches = Vec::new();
+        while let Some(mut picked) = self.strategy.find_match(waiting, party_size) {
+            picked.sort_unstable();
+            picked.dedup();
+            if picked.len() != party_size || picked.last().is_some_and(|i| *i >= waiting.len()) {
+                break;
+            }
+            // Remove from the back so the earlier indices stay valid
+            let mut players: Vec<_> = picked.iter().rev().map(|i| waiting.remove(*i)).collect();
+            players.reverse();
+            matches.push(players);
+        }
+        queues.retain(|_, waiting| !waiting.is_empty());
+        matches
+    }
+
+    /// Put the entries of a match back at the front of their queue, after
+    /// the match could not be set up
+    pub fn requeue(&self, mode: String, players: Vec<QueueEntry>) {
+        let mut queues = self.queues.lock().expect("poisoned");
+        let waiting = queues.entry((mode, players.len())).or_default();
+        waiting.splice(0..0, players);
+    }
+
+    /// Take `peer` out of the queue it waits in, returning false if none
+    pub fn leave(&self, peer: &EndpointId) -> bool {
+        let mut queues = self.queues.lock().expect("poisoned");
+        let removed = remove_from(&mut queues, peer);
+        queues.retain(|_, waiting| !waiting.is_empty());
+        removed
+    }
+
+    /// Number of clients waiting, over every queue
+    pub fn waiting(&self) -> usize {
+        let queues = self.queues.lock().expect("poisoned");
+        queues.values().map(Vec::len).sum()
+    }
+}
+
+fn remove_from(queues: &mut Queues, peer: &EndpointId) -> bool {
+    let mut removed = false;
+    for waiting in queues.values_mut() {
+        let before = waiting.len();
+        waiting.retain(|entry| entry.peer != *peer);
+        removed |= waiting.len() != before;
+    }
+    removed
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use iroh::{EndpointId, SecretKey};
+use n0_error::{Result, StdResultExt, anyerr};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{Instrument, warn};
+
+use crate::{
+    audit::Outcome,
+    capture::Direction,
+    codec::{Bincode, Codec},
+    handler::ConnState,
+    middleware::Context,
+    protocol::{Message, MessageEnvelope, PROTOCOL_VERSION, RemoteError, decode_frame},
+    server::{Echo, deadline_of},
+    signing::Signed,
+    telemetry::handler_span,
+};
+
+// ====================
+// In-Memory Transport
+// ====================
+
+/// How many sent messages may wait for the handler before sending waits
+const MEMORY_CAPACITY: usize = 64;
+
+type PendingMap = Arc<Mutex<HashMap<u64, oneshot::Sender<Message>>>>;
+
+/// A client wired straight to an [`Echo`] handler, without any network
+///
+/// Messages are still encoded and decoded with the codec, but the handler
+/// takes them one at a time in the order they were sent, so a test sees the
+/// same answers in the same order on every run. Only request and response
+/// are covered: nothing reaches the transport unprompted, since the handler
+/// cannot push to a peer without a connection.
+#[derive(Debug)]
+pub struct MemoryTransport<C = Bincode> {
+    peer: EndpointId,
+    codec: C,
+    next_id: AtomicU64,
+    pending: PendingMap,
+    to_handler: mpsc::Sender<Vec<u8>>,
+    replies: mpsc::UnboundedReceiver<Message>,
+    handler: JoinHandle<()>,
+}
+
+impl<C: Codec> MemoryTransport<C> {
+    /// Serve a new client identity with `echo`, on a task of its own
+    pub fn spawn(echo: Echo<C>) -> Self {
+        let peer = SecretKey::generate(&mut rand::rng()).public();
+        let codec = echo.codec().clone();
+        let pending = PendingMap::default();
+        let (to_handler, from_client) = mpsc::channel(MEMORY_CAPACITY);
+        let (to_client, replies) = mpsc::unbounded_channel();
+        let handler = tokio::spawn(serve(echo, peer, from_client, pending.clone(), to_client));
+        Self {
+            peer,
+            codec,
+            next_id: AtomicU64::new(0),
+            pending,
+            to_handler,
+            replies,
+            handler,
+        }
+    }
+
+    /// The identity the handler sees messages coming from
+    pub fn peer(&self) -> EndpointId {
+        self.peer
+    }
+
+    /// Send `msg` without waiting for its answer, which [`recv`](Self::recv)
+    /// returns
+    pub async fn send(&self, msg: Message) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.deliver(MessageEnvelope::new(id, msg)).await
+    }
+
+    /// Send `msg` and wait for its answer
+    ///
+    /// A [`Message::Error`] answer is returned as a [`RemoteError`].
+    pub async fn request(&self, msg: Message) -> Result<Message> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().expect("poisoned").insert(id, tx);
+        if let Err(e) = self.deliver(MessageEnvelope::new(id, msg)).await {
+            self.pending.lock().expect("poisoned").remove(&id);
+            return Err(e);
+        }
+        match rx.await.std_context("handler stopped before answering")? {
+            Message::Error { code, detail } => Err(RemoteError::new(code, detail).into()),
+            reply => Ok(reply),
+        }
+    }
+
+    /// The next answer to a message sent with [`send`](Self::send)
+    ///
+    /// Returns `None` once the handler stopped.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.replies.recv().await
+    }
+
+    async fn deliver(&self, mut envelope: MessageEnvelope) -> Result<()> {
+        // Ids count up from zero, so they double as counters
+        envelope.counter = Some(envelope.id);
+        let encoded = Vec::from(envelope.frame(&self.codec, PROTOCOL_VERSION)?);
+        self.to_handler
+            .send(encoded)
+            .await
+            .map_err(|_| anyerr!("handler stopped"))
+    }
+}
+
+impl<C> Drop for MemoryTransport<C> {
+    fn drop(&mut self) {
+        self.handler.abort();
+    }
+}
+
+/// Answer every message from the client in order, routing each answer to
+/// the request waiting for it or else to [`MemoryTransport::recv`]
+async fn serve<C: Codec>(
+    echo: Echo<C>,
+    peer: EndpointId,
+    mut from_client: mpsc::Receiver<Vec<u8>>,
+    pending: PendingMap,
+    to_client: mpsc::UnboundedSender<Message>,
+) {
+    // The client is one connection, lasting as long as this task
+    let state = ConnState::default();
+    while let Some(bytes) = from_client.recv().await {
+        let received = Instant::now();
+        let envelope = match decode_frame(echo.codec(), PROTOCOL_VERSION, &bytes) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("error decoding message: {:#}", e);
+                echo.audit(
+                    peer,
+                    Direction::Received,
+                    None,
+                    None,
+                    bytes.len(),
+                    Outcome::Malformed,
+                );
+                continue;
+            }
+        };
+        let kind = envelope.body.kind();
+        let ctx = Context {
+            peer,
+            size: bytes.len(),
+            deadline: deadline_of(&envelope, received),
+            signed: Signed::of(&envelope),
+        };
+        let span = handler_span(&envelope);
+        let answer = match echo.refusal(&state, PROTOCOL_VERSION, &envelope) {
+            Some(refusal) => Some(refusal),
+            None => {
+                let handled = echo.handle(ctx, &state, envelope.body);
+                handled.instrument(span.clone()).await
+            }
+        };
+        let outcome = Outcome::of_answer(&answer);
+        let id = Some(envelope.id);
+        echo.audit(
+            peer,
+            Direction::Received,
+            id,
+            Some(kind),
+            bytes.len(),
+            outcome,
+        );
+        let Some(body) = answer else {
+            continue;
+        };
+        // Round-trip the answer through the codec too, as the network would
+        let mut reply = MessageEnvelope::new(envelope.id, body);
+        span.in_scope(|| echo.intercept(peer, &mut reply));
+        let encoded = reply.frame(echo.codec(), PROTOCOL_VERSION);
+        let reply = match encoded.and_then(|encoded| {
+            let kind = Some(reply.body.kind());
+            echo.audit(
+                peer,
+                Direction::Sent,
+                id,
+                kind,
+                encoded.len(),
+                Outcome::Sent,
+            );
+            decode_frame(echo.codec(), PROTOCOL_VERSION, &encoded)
+        }) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("error encoding answer: {:#}", e);
+                continue;
+            }
+        };
+        let waiter = pending.lock().expect("poisoned").remove(&reply.id);
+        match waiter {
+            Some(tx) => {
+                tx.send(reply.body).ok();
+            }
+            None => {
+                to_client.send(reply.body).ok();
+            }
+        }
+    }
+}
+
+// ====================
+// Test Harness
+// ====================
+
+/// An [`Echo`] handler and the in-memory clients talking to it
+///
+/// Everything runs on the current Tokio runtime, with no endpoint bound, so
+/// tests work where UDP is unavailable.
+#[derive(Debug, Clone)]
+pub struct TestHarness<C = Bincode> {
+    echo: Echo<C>,
+}
+
+impl TestHarness {
+    /// A default echo handler and one client wired to it
+    pub fn spawn_pair() -> (Self, MemoryTransport) {
+        let harness = Self::new(Echo::new(Bincode));
+        let client = harness.connect();
+        (harness, client)
+    }
+}
+
+impl<C: Codec> TestHarness<C> {
+    pub fn new(echo: Echo<C>) -> Self {
+        Self { echo }
+    }
+
+    /// The handler every client is wired to
+    pub fn echo(&self) -> &Echo<C> {
+        &self.echo
+    }
+
+    /// Wire another client, with an identity of its own, to the handler
+    pub fn connect(&self) -> MemoryTransport<C> {
+        MemoryTransport::spawn(self.echo.clone())
+    }
+}
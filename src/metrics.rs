@@ -0,0 +1,134 @@
+use std::{sync::Arc, time::Duration};
+
+use iroh_metrics::{Counter, Gauge, Histogram, MetricsGroup, Registry};
+
+use crate::protocol::MessageKind;
+
+/// Bucket bounds, in seconds, shared by every timing histogram
+const BUCKETS: [f64; 8] = [0.000_01, 0.000_1, 0.001, 0.01, 0.1, 0.5, 1.0, 10.0];
+
+/// Connection, traffic and timing metrics of an echo server
+#[derive(Debug, MetricsGroup)]
+#[metrics(name = "echo", default)]
+pub struct EchoMetrics {
+    /// Connections admitted past access control and authentication
+    pub connections_opened: Counter,
+    /// Admitted connections that have since closed
+    pub connections_closed: Counter,
+    /// Connections currently being served
+    pub connections_active: Gauge,
+    /// Connections closed with `BUSY` for exceeding the connection limits
+    pub connections_refused: Counter,
+    /// Stream handlers, the app's included, that panicked
+    pub handler_panics: Counter,
+    /// Encoded bytes of all messages received
+    pub bytes_received: Counter,
+    /// Encoded bytes of all replies sent
+    pub bytes_sent: Counter,
+    /// Time spent decoding a message
+    #[default(Histogram::new(BUCKETS.to_vec()))]
+    pub decode_seconds: Histogram,
+    /// Time spent encoding a reply
+    #[default(Histogram::new(BUCKETS.to_vec()))]
+    pub encode_seconds: Histogram,
+    /// Time from a message being read to its reply being sent
+    #[default(Histogram::new(BUCKETS.to_vec()))]
+    pub handler_seconds: Histogram,
+}
+
+/// Message counts for one [`MessageKind`], labelled with the kind
+#[derive(Debug, MetricsGroup)]
+#[metrics(name = "echo_messages", default)]
+pub struct MessageMetrics {
+    /// Messages received
+    pub received: Counter,
+    /// Replies sent
+    pub sent: Counter,
+}
+
+/// The metrics of an echo server, shared by the handler and its clones
+///
+/// Clones record into the same counters. Use [`Metrics::register`] to
+/// expose them, for example through [`serve_metrics`].
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    echo: Arc<EchoMetrics>,
+    kinds: Arc<[Arc<MessageMetrics>; MessageKind::ALL.len()]>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            echo: Arc::default(),
+            kinds: Arc::new(std::array::from_fn(|_| Arc::default())),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn echo(&self) -> &EchoMetrics {
+        &self.echo
+    }
+
+    pub fn messages(&self, kind: MessageKind) -> &MessageMetrics {
+        &self.kinds[kind as usize]
+    }
+
+    /// Add every group to `registry`, the per-kind counts labelled `kind`
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(self.echo.clone());
+        for kind in MessageKind::ALL {
+            let label = format!("{kind:?}").to_lowercase();
+            registry
+                .sub_registry_with_label("kind", label)
+                .register(self.kinds[kind as usize].clone());
+        }
+    }
+
+    /// A fresh registry holding just these metrics
+    pub fn registry(&self) -> Registry {
+        let mut registry = Registry::default();
+        self.register(&mut registry);
+        registry
+    }
+
+    pub(crate) fn opened(&self) {
+        self.echo.connections_opened.inc();
+        self.echo.connections_active.inc();
+    }
+
+    pub(crate) fn closed(&self) {
+        self.echo.connections_closed.inc();
+        self.echo.connections_active.dec();
+    }
+
+    /// Record a message of `kind`, `size` bytes encoded, decoded in `took`
+    pub(crate) fn received(&self, kind: MessageKind, size: usize, took: Duration) {
+        self.messages(kind).received.inc();
+        self.echo.bytes_received.inc_by(size as u64);
+        self.echo.decode_seconds.observe(took.as_secs_f64());
+    }
+
+    /// Record a reply of `kind`, `size` bytes encoded, encoded in `took`
+    pub(crate) fn sent(&self, kind: MessageKind, size: usize, took: Duration) {
+        self.messages(kind).sent.inc();
+        self.echo.bytes_sent.inc_by(size as u64);
+        self.echo.encode_seconds.observe(took.as_secs_f64());
+    }
+
+    pub(crate) fn handled(&self, took: Duration) {
+        self.echo.handler_seconds.observe(took.as_secs_f64());
+    }
+}
+
+/// Serve `registry` in Prometheus text format over HTTP on `addr`
+///
+/// Runs until the listener fails.
+#[cfg(feature = "prometheus")]
+pub async fn serve_metrics(addr: std::net::SocketAddr, registry: Registry) -> n0_error::Result<()> {
+    use n0_error::StdResultExt;
+
+    iroh_metrics::service::start_metrics_server(addr, Arc::new(registry))
+        .await
+        .std_context("metrics server failed")
+}
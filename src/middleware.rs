@@ -0,0 +1,111 @@
+use std::{collections::HashSet, fmt, sync::Arc, time::Instant};
+
+use iroh::EndpointId;
+
+use crate::{
+    protocol::{Message, MessageEnvelope, MessageKind},
+    signing::Signed,
+};
+
+/// What a [`Middleware`] knows about an inbound message besides its body
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// The peer the message came from
+    pub peer: EndpointId,
+    /// Size of the message as it arrived, in bytes
+    pub size: usize,
+    /// When the sender stops waiting for a response, if it said so
+    pub deadline: Option<Instant>,
+    /// The signature the message came with, kept on it when relayed
+    ///
+    /// A middleware that changes the message breaks the signature for those
+    /// it is relayed to.
+    pub signed: Option<Signed>,
+}
+
+impl Context {
+    /// Whether the sender has stopped waiting for a response
+    pub fn expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// What happens to an inbound message after a [`Middleware`] looked at it
+#[derive(Debug)]
+pub enum Verdict {
+    /// Hand this message, unchanged or not, to the next middleware and
+    /// finally the handler
+    Continue(Message),
+    /// Answer with this message without running the rest of the chain or
+    /// the handler
+    Reply(Message),
+    /// Act on nothing and send no answer
+    ///
+    /// A request on its own stream then waits in vain, and a framed session
+    /// gets one answer fewer than it sent messages.
+    Drop,
+}
+
+/// A step every inbound message of an [`Echo`](crate::server::Echo) handler
+/// passes before being acted on
+///
+/// Middleware runs in the order it was added, on messages from every
+/// transport, before size limits are checked.
+pub trait Middleware: fmt::Debug + Send + Sync + 'static {
+    fn on_recv(&self, ctx: &Context, msg: Message) -> Verdict;
+}
+
+/// Drops every message of the given kinds
+#[derive(Debug, Clone, Default)]
+pub struct DenyKinds(pub HashSet<MessageKind>);
+
+impl DenyKinds {
+    pub fn new(kinds: impl IntoIterator<Item = MessageKind>) -> Self {
+        Self(kinds.into_iter().collect())
+    }
+}
+
+impl Middleware for DenyKinds {
+    fn on_recv(&self, ctx: &Context, msg: Message) -> Verdict {
+        if self.0.contains(&msg.kind()) {
+            tracing::debug!(peer = %ctx.peer.fmt_short(), kind = ?msg.kind(), "dropping denied message");
+            return Verdict::Drop;
+        }
+        Verdict::Continue(msg)
+    }
+}
+
+// ====================
+// Interceptors
+// ====================
+
+/// What an [`Interceptor`] knows about an outbound message besides its
+/// envelope
+#[derive(Debug, Clone, Copy)]
+pub struct SendCtx {
+    /// The peer the message goes to
+    pub peer: EndpointId,
+}
+
+/// A step every outbound message of a client or an
+/// [`Echo`](crate::server::Echo) handler passes right before it is encoded
+///
+/// Interceptors run in the order they were added and may change anything
+/// about the envelope, body included. Heartbeats and the authentication
+/// handshake are sent without them, and a datagram only keeps the body.
+pub trait Interceptor: fmt::Debug + Send + Sync + 'static {
+    fn on_send(&self, ctx: &SendCtx, envelope: &mut MessageEnvelope);
+}
+
+/// Run `envelope` for `peer` through every one of `interceptors`, in order
+pub(crate) fn intercept(
+    interceptors: &[Arc<dyn Interceptor>],
+    peer: EndpointId,
+    envelope: &mut MessageEnvelope,
+) {
+    let ctx = SendCtx { peer };
+    for interceptor in interceptors {
+        interceptor.on_send(&ctx, envelope);
+    }
+}
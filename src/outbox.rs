@@ -0,0 +1,190 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use iroh::EndpointId;
+use n0_error::{Result, StdResultExt};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use crate::codec::{Bincode, Codec};
+
+/// Messages waiting for peers that are offline, kept on disk
+///
+/// Each peer has an append-only file in the outbox directory holding
+/// length-prefixed encoded messages, so queued messages survive a restart.
+/// A record cut short by a crash is cut off when the outbox is opened, so
+/// the records queued after it stay readable. Clones share the same
+/// directory and lock.
+#[derive(Debug, Clone)]
+pub struct Outbox<C = Bincode> {
+    dir: PathBuf,
+    codec: C,
+    lock: Arc<Mutex<()>>,
+}
+
+impl<C: Codec> Outbox<C> {
+    /// Keep the outbox in `dir`, creating it if missing
+    ///
+    /// Files of peers holding a record cut short are truncated back to the
+    /// last complete one.
+    pub async fn open(dir: impl AsRef<Path>, codec: C) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .await
+            .with_std_context(|_| format!("creating outbox {}", dir.display()))?;
+        let outbox = Self {
+            dir,
+            codec,
+            lock: Arc::default(),
+        };
+        for peer in outbox.peers().await? {
+            truncate_torn(&outbox.path(&peer)).await?;
+        }
+        Ok(outbox)
+    }
+
+    fn path(&self, peer: &EndpointId) -> PathBuf {
+        self.dir.join(format!("{peer}.outbox"))
+    }
+
+    /// Queue `msg` for `peer`
+    pub async fn push<T: Serialize>(&self, peer: &EndpointId, msg: &T) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        self.append_to(&self.path(peer), &[self.codec.encode(msg)?])
+            .await
+    }
+
+    /// Remove and return everything queued for `peer`, oldest first
+    ///
+    /// Bytes after the last complete record are left in the file.
+    pub async fn take<T: DeserializeOwned>(&self, peer: &EndpointId) -> Result<Vec<T>> {
+        let _lock = self.lock.lock().await;
+        let path = self.path(peer);
+        let bytes = match fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).anyerr(),
+        };
+        let msgs = records(&bytes)
+            .map(|record| self.codec.decode(record))
+            .collect::<Result<_>>()?;
+        let torn = &bytes[complete_len(&bytes)..];
+        if torn.is_empty() {
+            fs::remove_file(&path).await.anyerr()?;
+        } else {
+            self.replace(&path, &[], torn).await?;
+        }
+        Ok(msgs)
+    }
+
+    /// Put `msgs` back in front of anything queued for `peer` since they
+    /// were taken, after failing to deliver them
+    pub async fn restore<T: Serialize>(&self, peer: &EndpointId, msgs: &[T]) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        let path = self.path(peer);
+        let newer = match fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).anyerr(),
+        };
+        let mut encoded = msgs
+            .iter()
+            .map(|msg| self.codec.encode(msg))
+            .collect::<Result<Vec<_>>>()?;
+        encoded.extend(records(&newer).map(<[u8]>::to_vec));
+        self.replace(&path, &encoded, &newer[complete_len(&newer)..])
+            .await
+    }
+
+    /// Replace the file at `path` with `records`, followed by the bytes of a
+    /// `torn` record
+    async fn replace(&self, path: &Path, records: &[Vec<u8>], torn: &[u8]) -> Result<()> {
+        let part = path.with_extension("outbox.part");
+        fs::remove_file(&part).await.ok();
+        self.append_to(&part, records).await?;
+        if !torn.is_empty() {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(&part)
+                .await
+                .anyerr()?;
+            file.write_all(torn).await.anyerr()?;
+            file.sync_data().await.anyerr()?;
+        }
+        fs::rename(&part, path).await.anyerr()
+    }
+
+    /// The peers that have messages queued
+    pub async fn peers(&self) -> Result<Vec<EndpointId>> {
+        let mut peers = Vec::new();
+        let mut entries = fs::read_dir(&self.dir).await.anyerr()?;
+        while let Some(entry) = entries.next_entry().await.anyerr()? {
+            let name = entry.file_name();
+            let id = name.to_str().and_then(|name| name.strip_suffix(".outbox"));
+            if let Some(id) = id.and_then(|id| id.parse().ok()) {
+                peers.push(id);
+            }
+        }
+        Ok(peers)
+    }
+
+    async fn append_to(&self, path: &Path, records: &[Vec<u8>]) -> Result<()> {
+        let mut buf = Vec::new();
+        for record in records {
+            let len = u32::try_from(record.len()).std_context("message too large to queue")?;
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf.extend_from_slice(record);
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_std_context(|_| format!("opening outbox file {}", path.display()))?;
+        let len = file.metadata().await.anyerr()?.len();
+        let written = async {
+            file.write_all(&buf).await?;
+            file.sync_data().await
+        };
+        if let Err(e) = written.await {
+            // Leave no part of the records behind for later ones to follow
+            file.set_len(len).await.ok();
+            return Err(e).anyerr();
+        }
+        Ok(())
+    }
+}
+
+/// Cut the file at `path` back to its last complete record
+async fn truncate_torn(path: &Path) -> Result<()> {
+    let bytes = fs::read(path).await.anyerr()?;
+    let complete = complete_len(&bytes);
+    if complete < bytes.len() {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .await
+            .anyerr()?;
+        file.set_len(complete as u64).await.anyerr()?;
+        file.sync_data().await.anyerr()?;
+    }
+    Ok(())
+}
+
+/// How many leading bytes of `bytes` hold complete records
+fn complete_len(bytes: &[u8]) -> usize {
+    records(bytes).map(|record| 4 + record.len()).sum()
+}
+
+/// The complete length-prefixed records of `bytes`
+fn records(mut bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let (len, rest) = bytes.split_first_chunk::<4>()?;
+        let len = u32::from_be_bytes(*len) as usize;
+        let record = rest.get(..len)?;
+        bytes = &rest[len..];
+        Some(record)
+    })
+}
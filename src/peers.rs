@@ -0,0 +1,162 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use iroh::{EndpointAddr, EndpointId};
+use n0_error::{AnyError, Result, StdResultExt, anyerr};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+use crate::ticket::EchoTicket;
+
+// ====================
+// Contacts
+// ====================
+
+/// A saved way of reaching a server: a full ticket, or an id to look up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Contact {
+    Ticket(EchoTicket),
+    Id(EndpointId),
+}
+
+impl Contact {
+    pub fn addr(&self) -> EndpointAddr {
+        match self {
+            Contact::Ticket(ticket) => ticket.addr.clone(),
+            Contact::Id(id) => (*id).into(),
+        }
+    }
+}
+
+impl FromStr for Contact {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("echo") {
+            return s
+                .parse()
+                .map(Contact::Ticket)
+                .std_context("invalid echo ticket");
+        }
+        s.parse()
+            .map(Contact::Id)
+            .std_context("expected an echo ticket or EndpointId")
+    }
+}
+
+impl fmt::Display for Contact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Contact::Ticket(ticket) => ticket.fmt(f),
+            Contact::Id(id) => id.fmt(f),
+        }
+    }
+}
+
+impl Serialize for Contact {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Contact {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|e| de::Error::custom(format!("{e:#}")))
+    }
+}
+
+// ====================
+// Address Book
+// ====================
+
+/// Names for servers, kept in a file as one JSON object mapping each name to
+/// a ticket or EndpointId
+#[derive(Debug, Clone)]
+pub struct AddressBook {
+    path: PathBuf,
+    peers: BTreeMap<String, Contact>,
+}
+
+impl AddressBook {
+    /// `wstest/peers.json` in `$XDG_CONFIG_HOME`, or in `~/.config` without
+    /// it
+    pub fn default_path() -> Result<PathBuf> {
+        let config = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => {
+                let home = std::env::var_os("HOME")
+                    .ok_or_else(|| anyerr!("neither XDG_CONFIG_HOME nor HOME is set"))?;
+                PathBuf::from(home).join(".config")
+            }
+        };
+        Ok(config.join("wstest").join("peers.json"))
+    }
+
+    /// Read the book at `path`, empty if there is no file yet
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let peers = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .with_std_context(|_| format!("invalid address book {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_std_context(|_| format!("reading address book {}", path.display()));
+            }
+        };
+        Ok(Self { path, peers })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Contact> {
+        self.peers.get(name)
+    }
+
+    /// Save `contact` as `name`, returning what it replaced
+    ///
+    /// Names are letters, digits, `-`, `_` and `.`, so they can be typed
+    /// after an `@` without quoting.
+    pub fn insert(&mut self, name: &str, contact: Contact) -> Result<Option<Contact>> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(anyerr!("invalid peer name {name:?}"));
+        }
+        Ok(self.peers.insert(name.to_string(), contact))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Contact> {
+        self.peers.remove(name)
+    }
+
+    /// Every entry, ordered by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Contact)> {
+        self.peers
+            .iter()
+            .map(|(name, contact)| (name.as_str(), contact))
+    }
+
+    /// Write the book back to its file, creating its directory if needed
+    ///
+    /// The file is replaced in one rename, so a crash leaves either the old
+    /// book or the new one.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).with_std_context(|_| format!("creating {}", dir.display()))?;
+        }
+        let json = serde_json::to_string_pretty(&self.peers).anyerr()?;
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, json + "\n").with_std_context(|_| format!("writing {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .with_std_context(|_| format!("replacing address book {}", self.path.display()))
+    }
+}
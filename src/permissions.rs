@@ -0,0 +1,205 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use n0_error::{AnyError, Result, anyerr};
+
+use crate::{
+    lobby::LobbyRequest,
+    protocol::{ErrorCode, Message, MessageKind},
+};
+
+// ====================
+// Capabilities
+// ====================
+
+/// Something a peer may be allowed to do, required by [`Permissions`] of the
+/// messages that do it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Send messages the server passes on to other peers
+    Broadcast,
+    /// Hand the server application data
+    Upload,
+    /// Operate the server; not required of any built-in message, for
+    /// applications to require of their own
+    Admin,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Capability::Broadcast => "broadcast",
+            Capability::Upload => "upload",
+            Capability::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What the auth handshake allows the client of a connection to do, see
+/// [`AuthVerifier::capabilities`](crate::auth::AuthVerifier::capabilities)
+///
+/// A server keeps them in the connection's [`ConnState`], where handlers
+/// find them with [`PeerCtx::state`](crate::handler::PeerCtx::state). The
+/// default allows everything, as on a server that does not authenticate.
+///
+/// [`ConnState`]: crate::handler::ConnState
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub can_broadcast: bool,
+    pub can_upload: bool,
+    pub can_admin: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl Capabilities {
+    pub const fn all() -> Self {
+        Self {
+            can_broadcast: true,
+            can_upload: true,
+            can_admin: true,
+        }
+    }
+
+    pub const fn none() -> Self {
+        Self {
+            can_broadcast: false,
+            can_upload: false,
+            can_admin: false,
+        }
+    }
+
+    /// Also allow `capability`
+    pub fn with(mut self, capability: Capability) -> Self {
+        *self.flag(capability) = true;
+        self
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Broadcast => self.can_broadcast,
+            Capability::Upload => self.can_upload,
+            Capability::Admin => self.can_admin,
+        }
+    }
+
+    fn flag(&mut self, capability: Capability) -> &mut bool {
+        match capability {
+            Capability::Broadcast => &mut self.can_broadcast,
+            Capability::Upload => &mut self.can_upload,
+            Capability::Admin => &mut self.can_admin,
+        }
+    }
+}
+
+/// `all`, `none` or a comma separated list of `broadcast`, `upload` and
+/// `admin`
+impl FromStr for Capabilities {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => return Ok(Self::all()),
+            "none" => return Ok(Self::none()),
+            _ => {}
+        }
+        s.split(',').try_fold(Self::none(), |capabilities, name| {
+            let capability = match name.trim() {
+                "broadcast" => Capability::Broadcast,
+                "upload" => Capability::Upload,
+                "admin" => Capability::Admin,
+                other => return Err(anyerr!("unknown capability {:?}", other)),
+            };
+            Ok(capabilities.with(capability))
+        })
+    }
+}
+
+// ====================
+// Permissions
+// ====================
+
+/// The capability each kind of message requires of its sender, checked
+/// before the message reaches a handler
+///
+/// By default [`Message::Chat`], [`Message::Publish`],
+/// [`Message::Introduce`] and a [`LobbyRequest::Send`] to a room require
+/// [`Capability::Broadcast`] and [`Message::Data`] requires
+/// [`Capability::Upload`]; every other kind requires nothing. Messages
+/// wrapped in others, such as those of a [`Message::Batch`], are checked as
+/// if they came on their own.
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    required: HashMap<MessageKind, Capability>,
+    /// Required of [`LobbyRequest::Send`] on top of what
+    /// [`MessageKind::Lobby`] requires, since only sending reaches others
+    room_sends: Option<Capability>,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self::none()
+            .require(MessageKind::Chat, Capability::Broadcast)
+            .require(MessageKind::Publish, Capability::Broadcast)
+            .require(MessageKind::Introduce, Capability::Broadcast)
+            .require(MessageKind::Data, Capability::Upload)
+            .require_for_room_sends(Capability::Broadcast)
+    }
+}
+
+impl Permissions {
+    /// Require nothing of any message
+    pub fn none() -> Self {
+        Self {
+            required: HashMap::new(),
+            room_sends: None,
+        }
+    }
+
+    /// Require `capability` of peers sending `kind`, replacing what it
+    /// required before
+    pub fn require(mut self, kind: MessageKind, capability: Capability) -> Self {
+        self.required.insert(kind, capability);
+        self
+    }
+
+    /// Require `capability` of peers sending to the members of their lobby
+    /// room, replacing what it required before
+    pub fn require_for_room_sends(mut self, capability: Capability) -> Self {
+        self.room_sends = Some(capability);
+        self
+    }
+
+    /// The capability sending `msg` takes that `capabilities` lacks, if any
+    pub fn missing(&self, capabilities: &Capabilities, msg: &Message) -> Option<Capability> {
+        let required = self.required.get(&msg.kind()).copied();
+        if let Some(capability) = required.filter(|capability| !capabilities.has(*capability)) {
+            return Some(capability);
+        }
+        match msg {
+            Message::Lobby(LobbyRequest::Send { .. }) => self
+                .room_sends
+                .filter(|capability| !capabilities.has(*capability)),
+            Message::Batch(messages) => messages
+                .iter()
+                .find_map(|msg| self.missing(capabilities, msg)),
+            Message::Reliable { body, .. } | Message::Broadcast { body, .. } => {
+                self.missing(capabilities, body)
+            }
+            _ => None,
+        }
+    }
+
+    /// The answer to `msg` if `capabilities` do not allow sending it
+    pub(crate) fn refusal(&self, capabilities: &Capabilities, msg: &Message) -> Option<Message> {
+        let capability = self.missing(capabilities, msg)?;
+        Some(Message::Error {
+            code: ErrorCode::PermissionDenied,
+            detail: format!("{:?} needs the {} capability", msg.kind(), capability),
+        })
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use iroh::{Endpoint, EndpointAddr, EndpointId, endpoint::VarInt};
+use n0_error::Result;
+use tokio::sync::OnceCell;
+
+use crate::{
+    auth::{AuthProvider, authenticate},
+    client::{Client, dial},
+    close::CloseReason,
+    codec::{Bincode, Codec},
+    protocol::{Message, ProtocolConfig},
+};
+
+/// Application close code for connections the pool dropped for being idle
+pub const POOL_IDLE: VarInt = CloseReason::Normal.code();
+
+#[derive(Debug)]
+struct PoolEntry<C> {
+    client: Arc<OnceCell<Arc<Client<C>>>>,
+    last_used: Instant,
+}
+
+/// Connections to many peers, dialed on first use and shared afterwards
+///
+/// Peers are addressed by [`EndpointId`]. Addresses registered with
+/// [`add_addr`](Self::add_addr) are used for dialing, otherwise the endpoint's
+/// discovery has to resolve the id. Connections unused for longer than the idle
+/// timeout are closed on the next pool operation or [`evict_idle`](Self::evict_idle).
+#[derive(Debug)]
+pub struct PeerPool<C = Bincode> {
+    endpoint: Endpoint,
+    codec: C,
+    config: ProtocolConfig,
+    auth: Option<Arc<dyn AuthProvider>>,
+    idle_timeout: Duration,
+    addrs: Mutex<HashMap<EndpointId, EndpointAddr>>,
+    peers: Mutex<HashMap<EndpointId, PoolEntry<C>>>,
+}
+
+impl<C: Codec> PeerPool<C> {
+    /// Create a pool dialing from `endpoint`
+    pub fn new(endpoint: Endpoint, codec: C) -> Self {
+        Self {
+            endpoint,
+            codec,
+            config: ProtocolConfig::default(),
+            auth: None,
+            idle_timeout: Duration::from_secs(60),
+            addrs: Default::default(),
+            peers: Default::default(),
+        }
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Size limits for every pooled connection, see [`Client::with_config`]
+    pub fn with_config(mut self, config: ProtocolConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Answer every peer's auth challenge with `provider`
+    pub fn with_auth(mut self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.auth = Some(provider);
+        self
+    }
+
+    /// Remember how to reach a peer, for when it is first dialed
+    pub fn add_addr(&self, addr: EndpointAddr) {
+        self.addrs.lock().expect("poisoned").insert(addr.id, addr);
+    }
+
+    /// Number of peers with a live or pending connection
+    pub fn len(&self) -> usize {
+        self.peers.lock().expect("poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send `msg` to `peer` without waiting for a response
+    pub async fn send(&self, peer: EndpointId, msg: Message) -> Result<()> {
+        self.client(peer).await?.send(msg).await
+    }
+
+    /// Send `msg` to `peer` and wait for its response
+    pub async fn request(&self, peer: EndpointId, msg: Message) -> Result<Message> {
+        self.client(peer).await?.request(msg).await
+    }
+
+    /// Close and forget every connection idle for longer than the idle timeout,
+    /// returning how many were evicted
+    pub fn evict_idle(&self) -> usize {
+        let mut peers = self.peers.lock().expect("poisoned");
+        let before = peers.len();
+        peers.retain(|_, entry| {
+            if entry.last_used.elapsed() <= self.idle_timeout {
+                return true;
+            }
+            if let Some(client) = entry.client.get() {
+                CloseReason::Normal.close(client.connection(), "idle");
+            }
+            false
+        });
+        before - peers.len()
+    }
+
+    /// Close and forget the connection to `peer`, if any
+    pub fn remove(&self, peer: &EndpointId) {
+        let entry = self.peers.lock().expect("poisoned").remove(peer);
+        if let Some(client) = entry.as_ref().and_then(|entry| entry.client.get()) {
+            CloseReason::Normal.close(client.connection(), "removed");
+        }
+    }
+
+    /// The shared client for `peer`, dialing it if there is no live connection
+    async fn client(&self, peer: EndpointId) -> Result<Arc<Client<C>>> {
+        self.evict_idle();
+
+        let cell = {
+            let mut peers = self.peers.lock().expect("poisoned");
+            let entry = peers.entry(peer).or_insert_with(|| PoolEntry {
+                client: Default::default(),
+                last_used: Instant::now(),
+            });
+            // A dead connection is replaced by a fresh slot to dial into
+            let dead = entry
+                .client
+                .get()
+                .is_some_and(|client| client.connection().close_reason().is_some());
+            if dead {
+                entry.client = Default::default();
+            }
+            entry.last_used = Instant::now();
+            entry.client.clone()
+        };
+
+        let result = cell
+            .get_or_try_init(|| async {
+                let addr = self
+                    .addrs
+                    .lock()
+                    .expect("poisoned")
+                    .get(&peer)
+                    .cloned()
+                    .unwrap_or_else(|| peer.into());
+                let conn = dial(&self.endpoint, addr).await?;
+                if let Some(provider) = &self.auth {
+                    authenticate(&conn, &self.codec, provider.as_ref()).await?;
+                }
+                Ok::<_, n0_error::AnyError>(Arc::new(Client::with_config(
+                    conn,
+                    self.codec.clone(),
+                    self.config.clone(),
+                )))
+            })
+            .await;
+
+        match result {
+            Ok(client) => Ok(client.clone()),
+            Err(e) => {
+                // Don't keep a slot around for a peer we could not reach
+                let mut peers = self.peers.lock().expect("poisoned");
+                if peers
+                    .get(&peer)
+                    .is_some_and(|entry| Arc::ptr_eq(&entry.client, &cell))
+                {
+                    peers.remove(&peer);
+                }
+                Err(e)
+            }
+        }
+    }
+}
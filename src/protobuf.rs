@@ -0,0 +1,1575 @@
+use std::{collections::BTreeMap, fmt};
+
+use n0_error::stack_error;
+use prost::encoding::{WireType, decode_key, decode_varint, encode_key, encode_varint};
+use serde::{
+    Serialize,
+    de::{
+        self, DeserializeOwned, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess,
+        Visitor, value::U32Deserializer,
+    },
+    ser::{self, Impossible},
+};
+
+type Result<T, E = ProtobufError> = std::result::Result<T, E>;
+
+// ====================
+// Mapping
+// ====================
+//
+// Serde values map onto protobuf messages by position, as `proto/echo.proto`
+// spells out for the echo protocol, along with how the tests keep the two in
+// step:
+//
+// - a struct or tuple is a message whose fields are numbered from 1 in
+//   declaration order; a tuple starting with a `u8`, such as a key, holds only
+//   `u8`s and is `bytes`
+// - an enum is a message holding a `oneof` of its variants, numbered from 1;
+//   a unit variant is an empty message, a newtype variant holds its value,
+//   and the others a message of their fields
+// - unsigned integers and `bool` are varints, signed ones zigzag encoded
+//   `sint64`s, floats `fixed32` and `fixed64`
+// - a sequence is a repeated field, a sequence of `u8`s `bytes`, and a map
+//   a repeated entry message of key 1 and value 2, like a protobuf `map`
+// - `None` leaves its field out; an empty sequence inside `Some` reads back
+//   as `None`
+//
+// A sequence, map or option inside a sequence, or held by a newtype variant,
+// is wrapped in a message of its own, as field 1. The encoding as a whole is
+// a message: a struct, tuple or enum is that message, anything else its
+// field 1. Fields a reader does not expect are skipped and missing ones take
+// their default, as protobuf does.
+
+/// Encode `value` as protobuf, following the mapping above
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    value.serialize(Writer {
+        out: &mut out,
+        place: Place::Top,
+    })?;
+    Ok(out)
+}
+
+/// Decode a `T` encoded by [`to_vec`], or by any protobuf implementation of
+/// the matching `.proto` message
+pub fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    T::deserialize(Top(bytes))
+}
+
+/// A value protobuf cannot carry, or bytes that do not hold the value asked
+/// for
+#[stack_error(derive, add_meta)]
+#[error("{message}")]
+pub struct ProtobufError {
+    pub message: String,
+}
+
+impl ser::Error for ProtobufError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::new(msg.to_string())
+    }
+}
+
+impl de::Error for ProtobufError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::new(msg.to_string())
+    }
+}
+
+fn error(message: impl fmt::Display) -> ProtobufError {
+    ProtobufError::new(message.to_string())
+}
+
+// ====================
+// Encoding
+// ====================
+
+/// Where a value is written
+#[derive(Debug, Clone, Copy)]
+enum Place {
+    /// The whole encoding
+    Top,
+    /// Field `n` of a message
+    Field(u32),
+    /// One element of the repeated field `n`
+    Element(u32),
+}
+
+impl Place {
+    fn number(self) -> u32 {
+        match self {
+            Place::Top => 1,
+            Place::Field(number) | Place::Element(number) => number,
+        }
+    }
+}
+
+fn put_delimited(out: &mut Vec<u8>, number: u32, bytes: &[u8]) {
+    encode_key(number, WireType::LengthDelimited, out);
+    encode_varint(bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+struct Writer<'a> {
+    out: &'a mut Vec<u8>,
+    place: Place,
+}
+
+impl<'a> Writer<'a> {
+    fn varint(self, value: u64) -> Result<()> {
+        encode_key(self.place.number(), WireType::Varint, self.out);
+        encode_varint(value, self.out);
+        Ok(())
+    }
+
+    fn signed(self, value: i64) -> Result<()> {
+        self.varint(((value << 1) ^ (value >> 63)) as u64)
+    }
+
+    fn delimited(self, bytes: &[u8]) -> Result<()> {
+        put_delimited(self.out, self.place.number(), bytes);
+        Ok(())
+    }
+
+    fn message(self) -> MessageWriter<'a> {
+        MessageWriter {
+            out: self.out,
+            place: self.place,
+            body: Vec::new(),
+            next: 1,
+            raw: None,
+        }
+    }
+
+    /// A message holding variant `index` of an enum, `fields` its fields in
+    /// a message of their own
+    fn variant(self, index: u32) -> VariantWriter<'a> {
+        VariantWriter {
+            message: self.message(),
+            number: index + 1,
+            fields: Vec::new(),
+            next: 1,
+        }
+    }
+
+    fn seq(self) -> SeqWriter<'a> {
+        let (number, wrap) = match self.place {
+            Place::Element(number) => (1, Some(number)),
+            place => (place.number(), None),
+        };
+        SeqWriter {
+            out: self.out,
+            wrap,
+            number,
+            wrapped: Vec::new(),
+            bytes: None,
+            first: true,
+        }
+    }
+}
+
+impl<'a> ser::Serializer for Writer<'a> {
+    type Ok = ();
+    type Error = ProtobufError;
+    type SerializeSeq = SeqWriter<'a>;
+    type SerializeTuple = MessageWriter<'a>;
+    type SerializeTupleStruct = MessageWriter<'a>;
+    type SerializeTupleVariant = VariantWriter<'a>;
+    type SerializeMap = SeqWriter<'a>;
+    type SerializeStruct = MessageWriter<'a>;
+    type SerializeStructVariant = VariantWriter<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.varint(v as u64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.signed(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.signed(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.signed(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.signed(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.varint(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.varint(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.varint(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.varint(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        encode_key(self.place.number(), WireType::ThirtyTwoBit, self.out);
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        encode_key(self.place.number(), WireType::SixtyFourBit, self.out);
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.varint(u32::from(v).into())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.delimited(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.delimited(v)
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        match self.place {
+            Place::Element(_) => self.delimited(&[]),
+            Place::Top | Place::Field(_) => Ok(()),
+        }
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        match self.place {
+            Place::Top => value.serialize(Writer {
+                out: self.out,
+                place: Place::Field(1),
+            }),
+            Place::Field(_) => value.serialize(self),
+            Place::Element(number) => {
+                let mut body = Vec::new();
+                value.serialize(Writer {
+                    out: &mut body,
+                    place: Place::Field(1),
+                })?;
+                put_delimited(self.out, number, &body);
+                Ok(())
+            }
+        }
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        match self.place {
+            Place::Top => Ok(()),
+            Place::Field(_) | Place::Element(_) => self.delimited(&[]),
+        }
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+    ) -> Result<()> {
+        let mut message = self.message();
+        put_delimited(&mut message.body, index + 1, &[]);
+        ser::SerializeStruct::end(message)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        // A `oneof` field cannot repeat, nor be left out without losing the
+        // variant, so the value is written as an element would be
+        let mut message = self.message();
+        value.serialize(Writer {
+            out: &mut message.body,
+            place: Place::Element(index + 1),
+        })?;
+        ser::SerializeStruct::end(message)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqWriter<'a>> {
+        Ok(self.seq())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<MessageWriter<'a>> {
+        Ok(self.message())
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<MessageWriter<'a>> {
+        Ok(self.message())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<VariantWriter<'a>> {
+        Ok(self.variant(index))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SeqWriter<'a>> {
+        Ok(self.seq())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<MessageWriter<'a>> {
+        Ok(self.message())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<VariantWriter<'a>> {
+        Ok(self.variant(index))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// The fields of a struct or tuple, numbered from 1
+struct MessageWriter<'a> {
+    out: &'a mut Vec<u8>,
+    place: Place,
+    body: Vec<u8>,
+    next: u32,
+    /// The elements of a tuple of `u8`s
+    raw: Option<Vec<u8>>,
+}
+
+impl MessageWriter<'_> {
+    fn field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(Writer {
+            out: &mut self.body,
+            place: Place::Field(self.next),
+        })?;
+        self.next += 1;
+        Ok(())
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let byte = value.serialize(U8Probe).ok();
+        match (&mut self.raw, byte) {
+            (Some(raw), Some(byte)) => raw.push(byte),
+            (Some(_), None) => return Err(error("a tuple starting with a u8 must hold only u8s")),
+            (None, Some(byte)) if self.next == 1 => self.raw = Some(vec![byte]),
+            (None, _) => return self.field(value),
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        let body = self.raw.unwrap_or(self.body);
+        match self.place {
+            Place::Top => self.out.extend_from_slice(&body),
+            Place::Field(number) | Place::Element(number) => put_delimited(self.out, number, &body),
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for MessageWriter<'_> {
+    type Ok = ();
+    type Error = ProtobufError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.field(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for MessageWriter<'_> {
+    type Ok = ();
+    type Error = ProtobufError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for MessageWriter<'_> {
+    type Ok = ();
+    type Error = ProtobufError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// An enum's message, holding the fields of one tuple or struct variant
+struct VariantWriter<'a> {
+    message: MessageWriter<'a>,
+    number: u32,
+    fields: Vec<u8>,
+    next: u32,
+}
+
+impl VariantWriter<'_> {
+    fn field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(Writer {
+            out: &mut self.fields,
+            place: Place::Field(self.next),
+        })?;
+        self.next += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        put_delimited(&mut self.message.body, self.number, &self.fields);
+        self.message.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for VariantWriter<'_> {
+    type Ok = ();
+    type Error = ProtobufError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.field(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for VariantWriter<'_> {
+    type Ok = ();
+    type Error = ProtobufError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.field(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// The elements of a sequence, or entries of a map, as a repeated field
+struct SeqWriter<'a> {
+    out: &'a mut Vec<u8>,
+    /// The number of the field to wrap the sequence in, for one that is
+    /// itself an element
+    wrap: Option<u32>,
+    number: u32,
+    wrapped: Vec<u8>,
+    /// The elements of a sequence of `u8`s
+    bytes: Option<Vec<u8>>,
+    first: bool,
+}
+
+impl SeqWriter<'_> {
+    fn target(&mut self) -> &mut Vec<u8> {
+        match self.wrap {
+            Some(_) => &mut self.wrapped,
+            None => self.out,
+        }
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Some(bytes) = self.bytes.take() {
+            let number = self.number;
+            put_delimited(self.target(), number, &bytes);
+        }
+        if let Some(wrap) = self.wrap {
+            put_delimited(self.out, wrap, &self.wrapped);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SeqWriter<'_> {
+    type Ok = ();
+    type Error = ProtobufError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let first = std::mem::replace(&mut self.first, false);
+        if first || self.bytes.is_some() {
+            let byte = value.serialize(U8Probe).ok();
+            match (&mut self.bytes, byte) {
+                (Some(bytes), Some(byte)) => bytes.push(byte),
+                (Some(_), None) => return Err(error("a sequence of u8s must hold only u8s")),
+                (None, Some(byte)) => self.bytes = Some(vec![byte]),
+                (None, None) => {}
+            }
+            if self.bytes.is_some() {
+                return Ok(());
+            }
+        }
+        let number = self.number;
+        value.serialize(Writer {
+            out: self.target(),
+            place: Place::Element(number),
+        })
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for SeqWriter<'_> {
+    type Ok = ();
+    type Error = ProtobufError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        // The entry is put together in `wrapped`'s place until its value
+        // comes, unless the map is wrapped itself
+        let mut entry = Vec::new();
+        key.serialize(Writer {
+            out: &mut entry,
+            place: Place::Field(1),
+        })?;
+        self.bytes = Some(entry);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let mut entry = self.bytes.take().unwrap_or_default();
+        value.serialize(Writer {
+            out: &mut entry,
+            place: Place::Field(2),
+        })?;
+        let number = self.number;
+        put_delimited(self.target(), number, &entry);
+        Ok(())
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// The `u8` a value serializes as, if it is one, to tell sequences of bytes
+/// from others by their first element
+struct U8Probe;
+
+impl ser::Serializer for U8Probe {
+    type Ok = u8;
+    type Error = ProtobufError;
+    type SerializeSeq = Impossible<u8, ProtobufError>;
+    type SerializeTuple = Impossible<u8, ProtobufError>;
+    type SerializeTupleStruct = Impossible<u8, ProtobufError>;
+    type SerializeTupleVariant = Impossible<u8, ProtobufError>;
+    type SerializeMap = Impossible<u8, ProtobufError>;
+    type SerializeStruct = Impossible<u8, ProtobufError>;
+    type SerializeStructVariant = Impossible<u8, ProtobufError>;
+
+    fn serialize_u8(self, v: u8) -> Result<u8> {
+        Ok(v)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<u8> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_char(self, _v: char) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_str(self, _v: &str) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_none(self) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_unit(self) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+    ) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<u8> {
+        Err(not_u8())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(not_u8())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(not_u8())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(not_u8())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(not_u8())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(not_u8())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(not_u8())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(not_u8())
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+fn not_u8() -> ProtobufError {
+    error("not a u8")
+}
+
+// ====================
+// Decoding
+// ====================
+
+/// One value of a field, as it came on the wire
+#[derive(Debug, Clone, Copy)]
+enum Value<'de> {
+    Varint(u64),
+    Fixed32(u32),
+    Fixed64(u64),
+    Bytes(&'de [u8]),
+}
+
+/// The fields of a message, each with every value it was given
+#[derive(Debug, Default)]
+struct Fields<'de> {
+    values: BTreeMap<u32, Vec<Value<'de>>>,
+    /// The field that came last, telling the variant of an enum
+    last: Option<u32>,
+}
+
+impl<'de> Fields<'de> {
+    fn parse(mut bytes: &'de [u8]) -> Result<Self> {
+        let mut fields = Fields::default();
+        while !bytes.is_empty() {
+            let (number, wire_type) = decode_key(&mut bytes).map_err(error)?;
+            let value = match wire_type {
+                WireType::Varint => Value::Varint(decode_varint(&mut bytes).map_err(error)?),
+                WireType::ThirtyTwoBit => Value::Fixed32(u32::from_le_bytes(take(&mut bytes)?)),
+                WireType::SixtyFourBit => Value::Fixed64(u64::from_le_bytes(take(&mut bytes)?)),
+                WireType::LengthDelimited => {
+                    let len = decode_varint(&mut bytes).map_err(error)?;
+                    let len = usize::try_from(len)
+                        .ok()
+                        .filter(|len| *len <= bytes.len())
+                        .ok_or_else(|| error("field runs past the end of the message"))?;
+                    let (value, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    Value::Bytes(value)
+                }
+                WireType::StartGroup | WireType::EndGroup => {
+                    return Err(error("groups are not supported"));
+                }
+            };
+            fields.values.entry(number).or_default().push(value);
+            fields.last = Some(number);
+        }
+        Ok(fields)
+    }
+
+    fn take(&mut self, number: u32) -> ValueDe<'de> {
+        ValueDe {
+            values: self.values.remove(&number).unwrap_or_default(),
+            element: false,
+        }
+    }
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N]> {
+    let (value, rest) = bytes
+        .split_first_chunk()
+        .ok_or_else(|| error("field runs past the end of the message"))?;
+    *bytes = rest;
+    Ok(*value)
+}
+
+fn zigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+/// The encoding as a whole
+struct Top<'de>(&'de [u8]);
+
+impl<'de> Top<'de> {
+    fn message(self) -> ValueDe<'de> {
+        ValueDe {
+            values: vec![Value::Bytes(self.0)],
+            element: false,
+        }
+    }
+
+    fn field_one(self) -> Result<ValueDe<'de>> {
+        Ok(Fields::parse(self.0)?.take(1))
+    }
+}
+
+/// Forward each method to the deserializer `$to` returns
+macro_rules! forward {
+    ($to:ident: $($method:ident$(($($arg:ident: $ty:ty),*))?),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, $($($arg: $ty,)*)? visitor: V) -> Result<V::Value> {
+                self.$to()?.$method($($($arg,)*)? visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Top<'de> {
+    type Error = ProtobufError;
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        self.message().deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.message().deserialize_tuple_struct(name, len, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.message().deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.message().deserialize_enum(name, variants, visitor)
+    }
+
+    forward! {
+        field_one:
+        deserialize_any, deserialize_bool, deserialize_i8, deserialize_i16, deserialize_i32,
+        deserialize_i64, deserialize_u8, deserialize_u16, deserialize_u32, deserialize_u64,
+        deserialize_f32, deserialize_f64, deserialize_char, deserialize_str, deserialize_string,
+        deserialize_bytes, deserialize_byte_buf, deserialize_option, deserialize_seq,
+        deserialize_map, deserialize_identifier, deserialize_ignored_any,
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Every value one field was given, of which the last counts unless the
+/// field is repeated
+struct ValueDe<'de> {
+    values: Vec<Value<'de>>,
+    /// Whether the value is an element of a repeated field, where sequences,
+    /// maps and options come wrapped
+    element: bool,
+}
+
+impl<'de> ValueDe<'de> {
+    fn varint(&self) -> Result<u64> {
+        match self.values.last() {
+            None => Ok(0),
+            Some(Value::Varint(value)) => Ok(*value),
+            Some(_) => Err(error("expected a varint")),
+        }
+    }
+
+    fn bytes(&self) -> Result<&'de [u8]> {
+        match self.values.last() {
+            None => Ok(&[]),
+            Some(Value::Bytes(bytes)) => Ok(bytes),
+            Some(_) => Err(error("expected a length-delimited field")),
+        }
+    }
+
+    fn message(&self) -> Result<Fields<'de>> {
+        Fields::parse(self.bytes()?)
+    }
+
+    /// The value itself, taken out of the message an element wraps it in
+    fn unwrapped(self) -> Result<Self> {
+        match self.element {
+            true => Ok(self.message()?.take(1)),
+            false => Ok(self),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValueDe<'de> {
+    type Error = ProtobufError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        Err(error("protobuf is not self-describing"))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_bool(self.varint()? != 0)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i8(zigzag(self.varint()?).try_into().map_err(error)?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i16(zigzag(self.varint()?).try_into().map_err(error)?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i32(zigzag(self.varint()?).try_into().map_err(error)?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i64(zigzag(self.varint()?))
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u8(self.varint()?.try_into().map_err(error)?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u16(self.varint()?.try_into().map_err(error)?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u32(self.varint()?.try_into().map_err(error)?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u64(self.varint()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.values.last() {
+            None => visitor.visit_f32(0.0),
+            Some(Value::Fixed32(bits)) => visitor.visit_f32(f32::from_bits(*bits)),
+            Some(_) => Err(error("expected a fixed32")),
+        }
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.values.last() {
+            None => visitor.visit_f64(0.0),
+            Some(Value::Fixed64(bits)) => visitor.visit_f64(f64::from_bits(*bits)),
+            Some(_) => Err(error("expected a fixed64")),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let value = u32::try_from(self.varint()?).map_err(error)?;
+        visitor.visit_char(char::from_u32(value).ok_or_else(|| error("invalid char"))?)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_borrowed_str(std::str::from_utf8(self.bytes()?).map_err(error)?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_borrowed_bytes(self.bytes()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let value = self.unwrapped()?;
+        match value.values.is_empty() {
+            true => visitor.visit_none(),
+            false => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(SeqDe {
+            values: self.unwrapped()?.values,
+            index: 0,
+            offset: 0,
+            exhausted: false,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(TupleDe {
+            bytes: self.bytes()?,
+            fields: None,
+            raw: None,
+            index: 0,
+            len,
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(MapDe {
+            entries: self.unwrapped()?.values.into_iter(),
+            entry: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(StructDe {
+            fields: self.message()?,
+            next: 1,
+            len: fields.len() as u32,
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let mut fields = self.message()?;
+        let number = fields.last.ok_or_else(|| error("enum without a variant"))?;
+        visitor.visit_enum(EnumDe {
+            number,
+            value: fields.take(number),
+        })
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        Err(error("protobuf has no identifiers"))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// The fields of a struct, in order
+struct StructDe<'de> {
+    fields: Fields<'de>,
+    next: u32,
+    len: u32,
+}
+
+impl<'de> SeqAccess<'de> for StructDe<'de> {
+    type Error = ProtobufError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.next > self.len {
+            return Ok(None);
+        }
+        let value = self.fields.take(self.next);
+        self.next += 1;
+        seed.deserialize(value).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.len + 1 - self.next) as usize)
+    }
+}
+
+/// The elements of a tuple, either the fields of a message or, for a tuple
+/// starting with a `u8`, bytes
+struct TupleDe<'de> {
+    bytes: &'de [u8],
+    fields: Option<Fields<'de>>,
+    /// Whether the tuple is bytes, told by its first element
+    raw: Option<bool>,
+    index: usize,
+    len: usize,
+}
+
+impl<'de> SeqAccess<'de> for TupleDe<'de> {
+    type Error = ProtobufError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.index >= self.len {
+            return Ok(None);
+        }
+        let value = seed.deserialize(TupleElementDe { tuple: &mut *self })?;
+        self.index += 1;
+        Ok(Some(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.index)
+    }
+}
+
+struct TupleElementDe<'a, 'de> {
+    tuple: &'a mut TupleDe<'de>,
+}
+
+impl<'de> TupleElementDe<'_, 'de> {
+    fn field(self) -> Result<ValueDe<'de>> {
+        let tuple = self.tuple;
+        if tuple.raw == Some(true) {
+            return Err(error("a tuple starting with a u8 must hold only u8s"));
+        }
+        tuple.raw = Some(false);
+        let fields = match &mut tuple.fields {
+            Some(fields) => fields,
+            fields => fields.insert(Fields::parse(tuple.bytes)?),
+        };
+        Ok(fields.take(tuple.index as u32 + 1))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for TupleElementDe<'_, 'de> {
+    type Error = ProtobufError;
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let tuple = &mut *self.tuple;
+        if tuple.raw.is_none() {
+            tuple.raw = Some(true);
+        }
+        if tuple.raw == Some(false) {
+            return self.field()?.deserialize_u8(visitor);
+        }
+        let byte = tuple.bytes.get(tuple.index).copied();
+        visitor.visit_u8(byte.ok_or_else(|| error("too few bytes for the tuple"))?)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.field()?.deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.field()?.deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        self.field()?.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.field()?.deserialize_tuple_struct(name, len, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.field()?.deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.field()?.deserialize_enum(name, variants, visitor)
+    }
+
+    forward! {
+        field:
+        deserialize_any, deserialize_bool, deserialize_i8, deserialize_i16, deserialize_i32,
+        deserialize_i64, deserialize_u16, deserialize_u32, deserialize_u64, deserialize_f32,
+        deserialize_f64, deserialize_char, deserialize_str, deserialize_string, deserialize_bytes,
+        deserialize_byte_buf, deserialize_option, deserialize_unit, deserialize_seq,
+        deserialize_map, deserialize_identifier, deserialize_ignored_any,
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// The elements of a repeated field
+///
+/// Scalars may come packed, several to a length-delimited value as protobuf
+/// writes them by default, and `u8`s always do, as `bytes`.
+struct SeqDe<'de> {
+    values: Vec<Value<'de>>,
+    index: usize,
+    /// How far into the packed value at `index` the next element starts
+    offset: usize,
+    /// Whether a packed sequence turned out to hold no further element
+    exhausted: bool,
+}
+
+impl<'de> SeqDe<'de> {
+    /// The packed bytes left at `index`, if its value is packed
+    fn packed(&mut self) -> Option<&'de [u8]> {
+        while let Some(Value::Bytes(bytes)) = self.values.get(self.index) {
+            if self.offset < bytes.len() {
+                return Some(&bytes[self.offset..]);
+            }
+            self.index += 1;
+            self.offset = 0;
+        }
+        None
+    }
+
+    fn next(&mut self) -> Result<Value<'de>> {
+        let value = self.values.get(self.index).copied();
+        self.index += 1;
+        self.offset = 0;
+        value.ok_or_else(|| {
+            self.exhausted = true;
+            error("no element left")
+        })
+    }
+
+    fn next_varint(&mut self) -> Result<u64> {
+        if let Some(mut packed) = self.packed() {
+            let len = packed.len();
+            let value = decode_varint(&mut packed).map_err(error)?;
+            self.offset += len - packed.len();
+            return Ok(value);
+        }
+        match self.next()? {
+            Value::Varint(value) => Ok(value),
+            _ => Err(error("expected a varint")),
+        }
+    }
+
+    fn next_byte(&mut self) -> Result<u8> {
+        if let Some(packed) = self.packed() {
+            self.offset += 1;
+            return Ok(packed[0]);
+        }
+        match self.next()? {
+            Value::Varint(value) => value.try_into().map_err(error),
+            _ => Err(error("expected bytes")),
+        }
+    }
+
+    fn next_fixed<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if let Some(mut packed) = self.packed() {
+            let value = take(&mut packed)?;
+            self.offset += N;
+            return Ok(value);
+        }
+        match self.next()? {
+            Value::Fixed32(bits) if N == 4 => Ok(bits.to_le_bytes()[..N].try_into().unwrap()),
+            Value::Fixed64(bits) if N == 8 => Ok(bits.to_le_bytes()[..N].try_into().unwrap()),
+            _ => Err(error("expected a fixed-width value")),
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for SeqDe<'de> {
+    type Error = ProtobufError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.index >= self.values.len() {
+            return Ok(None);
+        }
+        match seed.deserialize(ElementDe { seq: &mut *self }) {
+            Err(_) if self.exhausted => Ok(None),
+            element => element.map(Some),
+        }
+    }
+}
+
+struct ElementDe<'a, 'de> {
+    seq: &'a mut SeqDe<'de>,
+}
+
+impl<'de> ElementDe<'_, 'de> {
+    fn element(self) -> Result<ValueDe<'de>> {
+        Ok(ValueDe {
+            values: vec![self.seq.next()?],
+            element: true,
+        })
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ElementDe<'_, 'de> {
+    type Error = ProtobufError;
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_bool(self.seq.next_varint()? != 0)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i8(zigzag(self.seq.next_varint()?).try_into().map_err(error)?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i16(zigzag(self.seq.next_varint()?).try_into().map_err(error)?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i32(zigzag(self.seq.next_varint()?).try_into().map_err(error)?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i64(zigzag(self.seq.next_varint()?))
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u8(self.seq.next_byte()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u16(self.seq.next_varint()?.try_into().map_err(error)?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u32(self.seq.next_varint()?.try_into().map_err(error)?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u64(self.seq.next_varint()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_f32(f32::from_le_bytes(self.seq.next_fixed()?))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_f64(f64::from_le_bytes(self.seq.next_fixed()?))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let value = u32::try_from(self.seq.next_varint()?).map_err(error)?;
+        visitor.visit_char(char::from_u32(value).ok_or_else(|| error("invalid char"))?)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.element()?.deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        self.element()?.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.element()?.deserialize_tuple_struct(name, len, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.element()?.deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.element()?.deserialize_enum(name, variants, visitor)
+    }
+
+    forward! {
+        element:
+        deserialize_any, deserialize_str, deserialize_string, deserialize_bytes,
+        deserialize_byte_buf, deserialize_option, deserialize_unit, deserialize_seq,
+        deserialize_map, deserialize_identifier, deserialize_ignored_any,
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// The entries of a map, each a message of its key and value
+struct MapDe<'de> {
+    entries: std::vec::IntoIter<Value<'de>>,
+    entry: Option<Fields<'de>>,
+}
+
+impl<'de> MapAccess<'de> for MapDe<'de> {
+    type Error = ProtobufError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        let Some(entry) = self.entries.next() else {
+            return Ok(None);
+        };
+        let entry = ValueDe {
+            values: vec![entry],
+            element: false,
+        };
+        let entry = self.entry.insert(entry.message()?);
+        seed.deserialize(entry.take(1)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let mut entry = self.entry.take().unwrap_or_default();
+        seed.deserialize(entry.take(2))
+    }
+}
+
+/// The variant of an enum, the number of its field less one
+struct EnumDe<'de> {
+    number: u32,
+    value: ValueDe<'de>,
+}
+
+impl<'de> EnumAccess<'de> for EnumDe<'de> {
+    type Error = ProtobufError;
+    type Variant = ValueDe<'de>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, ValueDe<'de>)> {
+        let index = U32Deserializer::<ProtobufError>::new(self.number - 1);
+        Ok((seed.deserialize(index)?, self.value))
+    }
+}
+
+impl<'de> VariantAccess<'de> for ValueDe<'de> {
+    type Error = ProtobufError;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(ValueDe {
+            element: true,
+            ..self
+        })
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_struct(self, "", fields, visitor)
+    }
+}
@@ -0,0 +1,1319 @@
+use bincode::{Decode, Encode};
+use std::{borrow::Cow, cell::Cell, collections::HashMap, time::Duration};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use iroh::{
+    EndpointAddr, EndpointId, PublicKey,
+    endpoint::{Connection, RecvStream, VarInt},
+};
+use n0_error::{Result, StdResultExt, anyerr, stack_error};
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
+
+use crate::{
+    buffer::BufferPool,
+    codec::{Bincode, Codec, CodecKind, UNNAMED_CODEC},
+    compression::Compression,
+    lobby::{LobbyRequest, RoomEvent},
+    sealing::SessionKey,
+    session::{Presence, Status},
+    signing::MessageSignature,
+    stats::ConnectionStats,
+    telemetry::TraceContext,
+};
+
+/// Version of the echo wire protocol spoken by this build
+///
+/// The version is part of the ALPN, so peers without a common version fail
+/// the QUIC handshake instead of misreading each other's bytes. Version 0 sent
+/// bare messages; version 1 wraps them in a [`MessageEnvelope`]; version 2
+/// prefixes every encoded message with a [`Compression`] header; version 3
+/// adds a deadline to the envelope; version 4 adds a trace context; version 5
+/// adds a signature; version 6 adds a replay counter; version 7 tags the body
+/// with its kind, see [`decode_envelope`]; version 8 starts every frame with
+/// a preamble naming the protocol and codec, see [`strip_preamble`].
+pub const PROTOCOL_VERSION: u32 = 8;
+/// Oldest protocol version this build still accepts and dials
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+const ALPN_PREFIX: &[u8] = b"iroh-example/echo/";
+
+/// ALPN of the current [`PROTOCOL_VERSION`]
+pub const ALPN: &[u8] = b"iroh-example/echo/8";
+
+/// First protocol version whose messages carry a compression header
+const COMPRESSION_VERSION: u32 = 2;
+
+/// First protocol version whose envelopes carry a deadline
+const DEADLINE_VERSION: u32 = 3;
+
+/// First protocol version whose envelopes carry a trace context
+const TRACE_VERSION: u32 = 4;
+
+/// First protocol version whose envelopes carry a signature
+const SIGNATURE_VERSION: u32 = 5;
+
+/// First protocol version whose envelopes carry a replay counter
+pub(crate) const COUNTER_VERSION: u32 = 6;
+
+/// First protocol version whose envelopes tag their body with its kind
+const TAGGED_VERSION: u32 = 7;
+
+/// First protocol version whose frames start with a preamble
+const PREAMBLE_VERSION: u32 = 8;
+
+/// First bytes of the preamble of every frame, see [`strip_preamble`]
+pub const FRAME_MAGIC: [u8; 4] = *b"ECHO";
+
+/// Size of the preamble: the [`FRAME_MAGIC`], then a byte each for the
+/// protocol version and the [`Codec::wire_id`]
+pub const PREAMBLE_LEN: usize = FRAME_MAGIC.len() + 2;
+
+/// The ALPN identifying `version` of the echo protocol
+pub fn alpn_for_version(version: u32) -> Vec<u8> {
+    [ALPN_PREFIX, version.to_string().as_bytes()].concat()
+}
+
+/// The echo protocol version named by `alpn`, if it is an echo ALPN at all
+pub fn version_from_alpn(alpn: &[u8]) -> Option<u32> {
+    let version = alpn.strip_prefix(ALPN_PREFIX)?;
+    std::str::from_utf8(version).ok()?.parse().ok()
+}
+
+/// The echo protocol version negotiated on `conn`
+///
+/// Connections on other ALPNs, such as application protocols reusing the
+/// transport helpers, are treated as speaking the current version.
+pub fn connection_version(conn: &Connection) -> u32 {
+    version_from_alpn(conn.alpn()).unwrap_or(PROTOCOL_VERSION)
+}
+
+/// ALPNs of every supported version, newest first
+pub fn supported_alpns() -> Vec<Vec<u8>> {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION)
+        .rev()
+        .map(alpn_for_version)
+        .collect()
+}
+pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024; // 10MB limit
+
+/// Default cap on the length of any string in a decoded message
+pub const MAX_STRING_LEN: usize = 64 * 1024;
+
+/// Default cap on the number of elements of any list in a decoded message
+pub const MAX_COLLECTION_LEN: usize = 4096;
+
+/// How deeply messages may be nested in one another, see [`Message::Reliable`]
+pub const MAX_NESTING: usize = 16;
+
+/// Default time a peer gets to send a whole message once it opened its stream
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default time a peer gets to take a whole message off our hands, stream
+/// setup included
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Envelope id for messages the server sends unprompted, such as broadcasts
+pub const PUSH_ID: u64 = u64::MAX - 1;
+
+/// Application code for stopping a stream whose message exceeds the size limit
+pub const STREAM_TOO_LARGE: VarInt = VarInt::from_u32(1);
+
+/// Application code for stopping a stream whose message did not arrive within
+/// the read timeout
+pub const STREAM_TIMED_OUT: VarInt = VarInt::from_u32(4);
+
+#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+pub enum Message {
+    Echo,
+    /// Asks for a [`Message::Pong`] carrying the same `seq` and `timestamp`
+    ///
+    /// `timestamp` is in microseconds on a clock of the sender's choosing;
+    /// only the sender interprets it, to time the round trip.
+    Ping {
+        seq: u64,
+        timestamp: u64,
+    },
+    /// Answer to a [`Message::Ping`], echoing its fields
+    Pong {
+        seq: u64,
+        timestamp: u64,
+    },
+    /// A chat line, relayed by the server to every other connected peer
+    Chat {
+        from: String,
+        text: String,
+    },
+    /// Opaque application bytes, echoed back unchanged
+    Data(Vec<u8>),
+    /// Reply to a request the server could not act on, see [`RemoteError`]
+    Error {
+        code: ErrorCode,
+        detail: String,
+    },
+    /// Pushed by a server that is shutting down; reconnect later or elsewhere
+    GoingAway,
+    /// `body`, numbered for at-least-once delivery; answered with [`Message::Ack`]
+    ///
+    /// Every message its sender numbered below `acked` has been acknowledged.
+    Reliable {
+        seq: u64,
+        #[serde(deserialize_with = "nested")]
+        body: Box<Message>,
+        acked: u64,
+    },
+    /// Every [`Message::Reliable`] numbered below `next` has arrived
+    Ack {
+        next: u64,
+    },
+    /// A broadcast pushed by a server that keeps a history, numbered so a
+    /// client can ask for what it missed with [`Message::Resume`]
+    Broadcast {
+        offset: u64,
+        #[serde(deserialize_with = "nested")]
+        body: Box<Message>,
+    },
+    /// Asks for every retained broadcast from `from_offset` on; answered with
+    /// [`Message::Replay`]
+    Resume {
+        from_offset: u64,
+    },
+    /// The broadcasts asked for by a [`Message::Resume`], with their offsets,
+    /// and the offset the next broadcast will get
+    Replay {
+        #[serde(deserialize_with = "nested")]
+        entries: Vec<(u64, Message)>,
+        next_offset: u64,
+    },
+    /// Subscribe to every topic the filter matches, see
+    /// [`pubsub::topic_matches`](crate::pubsub::topic_matches)
+    Subscribe(String),
+    /// Drop a subscription made with exactly this filter
+    Unsubscribe(String),
+    /// `payload` on `topic`, routed by the server to every other peer
+    /// subscribed to a matching filter
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+    },
+    /// Tells the server how other peers can reach the sender directly
+    Announce(#[bincode(with_serde)] EndpointAddr),
+    /// Asks for every other connected peer; answered with [`Message::PeerList`]
+    ListPeers,
+    /// Other connected peers, each with the address it announced or its id
+    /// alone
+    PeerList(#[bincode(with_serde)] Vec<EndpointAddr>),
+    /// Asks to meet the connected peer `peer`, of which only the id matters
+    ///
+    /// The server answers with that peer's address and pushes the sender's
+    /// address to it, so the two can connect directly. A peer that is not
+    /// connected is answered with an empty [`Message::PeerList`].
+    Introduce {
+        #[bincode(with_serde)]
+        peer: EndpointAddr,
+    },
+    /// A request to the server's room lobby; answered with [`Message::Room`]
+    Lobby(LobbyRequest),
+    /// The lobby's answer, or news pushed to the members of a room
+    Room(RoomEvent),
+    /// Wait for a match of `party_size` clients playing `mode`; a client
+    /// waits in one queue at a time
+    ///
+    /// `rating` is for strategies that match by skill, see
+    /// [`MatchStrategy`](crate::matchmaking::MatchStrategy).
+    JoinQueue {
+        mode: String,
+        party_size: usize,
+        rating: Option<u32>,
+    },
+    /// Stop waiting for a match
+    LeaveQueue,
+    /// Pushed to every player of a match: the lobby room opened for it, and
+    /// the addresses of the other players
+    MatchFound {
+        room: String,
+        #[bincode(with_serde)]
+        peers: Vec<EndpointAddr>,
+    },
+    /// Start a session as the player `name`; answered with the player's
+    /// [`Message::Presence`]
+    Login {
+        name: String,
+    },
+    /// Change the status of the current session; [`Status::Offline`] logs out
+    SetStatus(Status),
+    /// Be told about every presence change of these peers; answered with
+    /// their current [`Message::PresenceList`]
+    WatchPresence(#[bincode(with_serde)] Vec<EndpointId>),
+    /// The presence of one player, pushed to its watchers when it changes
+    Presence(Presence),
+    PresenceList(Vec<Presence>),
+    /// Asks for the statistics of the sender's own connection, as the server
+    /// sees it; answered with [`Message::StatsResponse`]
+    StatsRequest,
+    StatsResponse(ConnectionStats),
+    /// Several messages coalesced into one, each handled as if it had come on
+    /// its own and answered with a batch of the answers
+    Batch(#[serde(deserialize_with = "nested")] Vec<Message>),
+    /// A message this build cannot read, as a peer tagged and encoded it,
+    /// see [`decode_envelope`]
+    ///
+    /// It goes out again just as it came, so a node passing it on does not
+    /// need to know its kind, as long as the peer it goes to uses the same
+    /// codec.
+    Unknown {
+        tag: u32,
+        bytes: Vec<u8>,
+    },
+    /// Asks for the session keys that peers with these application keys
+    /// offered in their auth handshake; answered with [`Message::SessionKeys`]
+    FindSessionKeys(#[bincode(with_serde)] Vec<PublicKey>),
+    /// Every session key found, one for each connection of its owner, see
+    /// [`Sealer`](crate::Sealer)
+    SessionKeys(Vec<SessionKey>),
+}
+
+/// Why the server could not act on a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub enum ErrorCode {
+    /// The request could not be decoded
+    Malformed,
+    /// The request exceeded the size limit of its kind
+    TooLarge,
+    /// The client failed authentication
+    Unauthorized,
+    /// The request named something the server does not know of, such as an
+    /// RPC method or a peer that is not connected
+    NotFound,
+    /// Acting on the request failed on the server
+    Internal,
+    /// The sender lacks the capability the request takes, see
+    /// [`Permissions`](crate::permissions::Permissions)
+    PermissionDenied,
+    /// The request was not framed for the protocol version and codec of the
+    /// connection, see [`IncompatibleProtocol`]
+    IncompatibleProtocol,
+}
+
+/// The server answered a request with [`Message::Error`]
+#[stack_error(derive, add_meta)]
+#[error("server refused the request ({code:?}): {detail}")]
+pub struct RemoteError {
+    pub code: ErrorCode,
+    pub detail: String,
+}
+
+/// The variant of a [`Message`], without its payload
+///
+/// A kind's position is its [`tag`](Self::tag) on the wire, so new kinds are
+/// only ever added last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Encode, Decode)]
+pub enum MessageKind {
+    Echo,
+    Ping,
+    Pong,
+    Chat,
+    Data,
+    Error,
+    GoingAway,
+    Reliable,
+    Ack,
+    Broadcast,
+    Resume,
+    Replay,
+    Subscribe,
+    Unsubscribe,
+    Publish,
+    Announce,
+    ListPeers,
+    PeerList,
+    Introduce,
+    Lobby,
+    Room,
+    JoinQueue,
+    LeaveQueue,
+    MatchFound,
+    Login,
+    SetStatus,
+    WatchPresence,
+    Presence,
+    PresenceList,
+    StatsRequest,
+    StatsResponse,
+    Batch,
+    Unknown,
+    FindSessionKeys,
+    SessionKeys,
+}
+
+impl MessageKind {
+    /// Every kind, in declaration order
+    pub const ALL: [MessageKind; 35] = [
+        MessageKind::Echo,
+        MessageKind::Ping,
+        MessageKind::Pong,
+        MessageKind::Chat,
+        MessageKind::Data,
+        MessageKind::Error,
+        MessageKind::GoingAway,
+        MessageKind::Reliable,
+        MessageKind::Ack,
+        MessageKind::Broadcast,
+        MessageKind::Resume,
+        MessageKind::Replay,
+        MessageKind::Subscribe,
+        MessageKind::Unsubscribe,
+        MessageKind::Publish,
+        MessageKind::Announce,
+        MessageKind::ListPeers,
+        MessageKind::PeerList,
+        MessageKind::Introduce,
+        MessageKind::Lobby,
+        MessageKind::Room,
+        MessageKind::JoinQueue,
+        MessageKind::LeaveQueue,
+        MessageKind::MatchFound,
+        MessageKind::Login,
+        MessageKind::SetStatus,
+        MessageKind::WatchPresence,
+        MessageKind::Presence,
+        MessageKind::PresenceList,
+        MessageKind::StatsRequest,
+        MessageKind::StatsResponse,
+        MessageKind::Batch,
+        MessageKind::Unknown,
+        MessageKind::FindSessionKeys,
+        MessageKind::SessionKeys,
+    ];
+
+    /// The tag of envelopes whose body is of this kind, in protocol version
+    /// 7 and newer
+    pub fn tag(self) -> u32 {
+        self as u32
+    }
+
+    /// The kind tagged `tag`, if this build knows it
+    pub fn from_tag(tag: u32) -> Option<MessageKind> {
+        let kind = Self::ALL.get(usize::try_from(tag).ok()?).copied();
+        kind.filter(|kind| *kind != MessageKind::Unknown)
+    }
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::Echo => MessageKind::Echo,
+            Message::Ping { .. } => MessageKind::Ping,
+            Message::Pong { .. } => MessageKind::Pong,
+            Message::Chat { .. } => MessageKind::Chat,
+            Message::Data(_) => MessageKind::Data,
+            Message::Error { .. } => MessageKind::Error,
+            Message::GoingAway => MessageKind::GoingAway,
+            Message::Reliable { .. } => MessageKind::Reliable,
+            Message::Ack { .. } => MessageKind::Ack,
+            Message::Broadcast { .. } => MessageKind::Broadcast,
+            Message::Resume { .. } => MessageKind::Resume,
+            Message::Replay { .. } => MessageKind::Replay,
+            Message::Subscribe(_) => MessageKind::Subscribe,
+            Message::Unsubscribe(_) => MessageKind::Unsubscribe,
+            Message::Publish { .. } => MessageKind::Publish,
+            Message::Announce(_) => MessageKind::Announce,
+            Message::ListPeers => MessageKind::ListPeers,
+            Message::PeerList(_) => MessageKind::PeerList,
+            Message::Introduce { .. } => MessageKind::Introduce,
+            Message::Lobby(_) => MessageKind::Lobby,
+            Message::Room(_) => MessageKind::Room,
+            Message::JoinQueue { .. } => MessageKind::JoinQueue,
+            Message::LeaveQueue => MessageKind::LeaveQueue,
+            Message::MatchFound { .. } => MessageKind::MatchFound,
+            Message::Login { .. } => MessageKind::Login,
+            Message::SetStatus(_) => MessageKind::SetStatus,
+            Message::WatchPresence(_) => MessageKind::WatchPresence,
+            Message::Presence(_) => MessageKind::Presence,
+            Message::PresenceList(_) => MessageKind::PresenceList,
+            Message::StatsRequest => MessageKind::StatsRequest,
+            Message::StatsResponse(_) => MessageKind::StatsResponse,
+            Message::Batch(_) => MessageKind::Batch,
+            Message::Unknown { .. } => MessageKind::Unknown,
+            Message::FindSessionKeys(_) => MessageKind::FindSessionKeys,
+            Message::SessionKeys(_) => MessageKind::SessionKeys,
+        }
+    }
+
+    /// Whether handling the message any number of times does no more than
+    /// handling it once, so it may be sent as 0-RTT data, which anyone on the
+    /// path can replay
+    ///
+    /// Only requests that just read are, whatever app answers them.
+    pub fn is_replay_safe(&self) -> bool {
+        match self {
+            Message::Batch(messages) => messages.iter().all(Message::is_replay_safe),
+            other => matches!(
+                other,
+                Message::Echo
+                    | Message::Ping { .. }
+                    | Message::Resume { .. }
+                    | Message::ListPeers
+                    | Message::StatsRequest
+                    | Message::FindSessionKeys(_)
+            ),
+        }
+    }
+
+    /// The message the echo server answers with
+    pub fn reply(&self) -> Message {
+        match self {
+            Message::Ping { seq, timestamp } => Message::Pong {
+                seq: *seq,
+                timestamp: *timestamp,
+            },
+            Message::Batch(messages) => {
+                Message::Batch(messages.iter().map(Message::reply).collect())
+            }
+            other => other.clone(),
+        }
+    }
+}
+
+/// A message tagged with an id, so a response can be matched to the request
+/// that caused it regardless of the order streams arrive in
+///
+/// The body defaults to the echo protocol's [`Message`], but any serializable
+/// application type works with the transport helpers.
+#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+pub struct MessageEnvelope<T = Message> {
+    pub id: u64,
+    pub body: T,
+    /// How many milliseconds the sender still waits for a response, counted
+    /// from when it sent the message; `None` if it waits as long as it takes
+    ///
+    /// Trails the envelope, so peers older than protocol version 3 ignore it.
+    /// Those peers never send one.
+    #[serde(default)]
+    pub deadline: Option<u64>,
+    /// The span the message was sent from, for the peer to continue the
+    /// trace in; only set with the `otel` feature
+    ///
+    /// Trails the deadline, so peers older than protocol version 4 ignore it.
+    #[serde(default)]
+    pub trace: Option<TraceContext>,
+    /// The body's signature by the sender's application key, see
+    /// [`KeyProvider`](crate::signing::KeyProvider)
+    ///
+    /// Trails the trace context, so peers older than protocol version 5
+    /// ignore it.
+    #[serde(default)]
+    pub signature: Option<MessageSignature>,
+    /// Where the message stands among those its sender sent on this
+    /// connection, for the receiver to refuse a replay, see
+    /// [`ReplayWindow`](crate::replay::ReplayWindow)
+    ///
+    /// Trails the signature, so peers older than protocol version 6 ignore
+    /// it.
+    #[serde(default)]
+    pub counter: Option<u64>,
+}
+
+impl<T> MessageEnvelope<T> {
+    /// An envelope without a deadline
+    pub fn new(id: u64, body: T) -> Self {
+        Self {
+            id,
+            body,
+            deadline: None,
+            trace: None,
+            signature: None,
+            counter: None,
+        }
+    }
+}
+
+impl MessageEnvelope {
+    /// The envelope as a peer speaking `version` reads it, for sending with
+    /// any of the transport helpers
+    pub fn wire<C: Codec>(&self, codec: &C, version: u32) -> Result<WireEnvelope<'_>> {
+        if version < TAGGED_VERSION {
+            return Ok(WireEnvelope::Plain(Cow::Borrowed(self)));
+        }
+        let (tag, body) = match &self.body {
+            Message::Unknown { tag, bytes } => (*tag, Cow::Borrowed(bytes.as_slice())),
+            body => (body.kind().tag(), Cow::Owned(codec.encode(body)?)),
+        };
+        Ok(WireEnvelope::Tagged(TaggedEnvelope {
+            id: self.id,
+            tag,
+            body,
+            deadline: self.deadline,
+            trace: self.trace,
+            signature: self.signature.clone(),
+            counter: self.counter,
+        }))
+    }
+
+    /// The envelope as one frame of a framed stream speaking `version`,
+    /// preamble included, for [`decode_frame`] on the other end
+    ///
+    /// The bytes are written into a buffer of the shared [`BufferPool`].
+    pub fn frame<C: Codec>(&self, codec: &C, version: u32) -> Result<Bytes> {
+        let wire = self.wire(codec, version)?;
+        BufferPool::shared().fill(|buf| {
+            put_preamble(codec, version, buf);
+            codec.encode_into(&wire, buf)
+        })
+    }
+
+    /// Like [`wire`](Self::wire), for sinks that need to own what they send
+    pub fn into_wire<C: Codec>(self, codec: &C, version: u32) -> Result<WireEnvelope<'static>> {
+        Ok(match self.wire(codec, version)? {
+            WireEnvelope::Plain(_) => WireEnvelope::Plain(Cow::Owned(self)),
+            WireEnvelope::Tagged(tagged) => WireEnvelope::Tagged(TaggedEnvelope {
+                body: Cow::Owned(tagged.body.into_owned()),
+                ..tagged
+            }),
+        })
+    }
+}
+
+/// A [`MessageEnvelope`] encoded for a peer's protocol version, see
+/// [`MessageEnvelope::wire`]
+#[derive(Debug, Clone)]
+pub enum WireEnvelope<'a> {
+    /// As sent before protocol version 7
+    Plain(Cow<'a, MessageEnvelope>),
+    Tagged(TaggedEnvelope<Cow<'a, [u8]>>),
+}
+
+impl Serialize for WireEnvelope<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            WireEnvelope::Plain(envelope) => envelope.serialize(serializer),
+            WireEnvelope::Tagged(envelope) => envelope.serialize(serializer),
+        }
+    }
+}
+
+/// A [`MessageEnvelope`] as sent since protocol version 7, its body encoded
+/// on its own behind the [`tag`](MessageKind::tag) of its kind
+///
+/// A peer that does not know the kind, or cannot read a body of a kind it
+/// knows, still reads the rest of the envelope, see [`decode_envelope`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaggedEnvelope<B> {
+    pub id: u64,
+    pub tag: u32,
+    pub body: B,
+    pub deadline: Option<u64>,
+    pub trace: Option<TraceContext>,
+    pub signature: Option<MessageSignature>,
+    pub counter: Option<u64>,
+}
+
+/// A [`MessageEnvelope`] as sent before protocol version 3
+#[derive(Debug, Deserialize)]
+struct LegacyEnvelope {
+    id: u64,
+    body: Message,
+}
+
+/// A [`MessageEnvelope`] as sent in protocol version 3
+#[derive(Debug, Deserialize)]
+struct DeadlineEnvelope {
+    id: u64,
+    body: Message,
+    deadline: Option<u64>,
+}
+
+/// A [`MessageEnvelope`] as sent in protocol version 4
+#[derive(Debug, Deserialize)]
+struct TraceEnvelope {
+    id: u64,
+    body: Message,
+    deadline: Option<u64>,
+    trace: Option<TraceContext>,
+}
+
+/// A [`MessageEnvelope`] as sent in protocol version 5
+#[derive(Debug, Deserialize)]
+struct SignedEnvelope {
+    id: u64,
+    body: Message,
+    deadline: Option<u64>,
+    trace: Option<TraceContext>,
+    signature: Option<MessageSignature>,
+}
+
+/// Decode an uncompressed envelope sent by a peer speaking `version`
+///
+/// From protocol version 7 on, a body of a kind this build does not know
+/// comes back as [`Message::Unknown`] rather than failing the envelope, so
+/// peers of different builds can talk while a new message rolls out. A body
+/// of a known kind that does not decode, or decodes as another kind, is
+/// malformed. Fields a newer peer appended to a message it knows are
+/// skipped: self-describing codecs ignore fields they do not expect, and the
+/// others ignore bytes left over after the body. Only the body itself is
+/// tagged, though: a message wrapped in another, such as those of a
+/// [`Message::Batch`], fails the batch if its kind is unknown.
+pub fn decode_envelope<C: Codec>(
+    codec: &C,
+    version: u32,
+    encoded: &[u8],
+) -> Result<MessageEnvelope> {
+    if version < DEADLINE_VERSION {
+        let LegacyEnvelope { id, body } = codec.decode(encoded)?;
+        return Ok(MessageEnvelope::new(id, body));
+    }
+    if version < TRACE_VERSION {
+        let DeadlineEnvelope { id, body, deadline } = codec.decode(encoded)?;
+        return Ok(MessageEnvelope {
+            deadline,
+            ..MessageEnvelope::new(id, body)
+        });
+    }
+    if version < SIGNATURE_VERSION {
+        let TraceEnvelope {
+            id,
+            body,
+            deadline,
+            trace,
+        } = codec.decode(encoded)?;
+        return Ok(MessageEnvelope {
+            deadline,
+            trace,
+            ..MessageEnvelope::new(id, body)
+        });
+    }
+    if version < COUNTER_VERSION {
+        let SignedEnvelope {
+            id,
+            body,
+            deadline,
+            trace,
+            signature,
+        } = codec.decode(encoded)?;
+        return Ok(MessageEnvelope {
+            deadline,
+            trace,
+            signature,
+            ..MessageEnvelope::new(id, body)
+        });
+    }
+    if version < TAGGED_VERSION {
+        return codec.decode(encoded);
+    }
+    let TaggedEnvelope {
+        id,
+        tag,
+        body,
+        deadline,
+        trace,
+        signature,
+        counter,
+    } = codec.decode::<TaggedEnvelope<Vec<u8>>>(encoded)?;
+    let body = match MessageKind::from_tag(tag) {
+        Some(kind) => {
+            let msg: Message = codec.decode(&body)?;
+            if msg.kind() != kind {
+                return Err(anyerr!(
+                    "envelope tagged {:?} holds a {:?}",
+                    kind,
+                    msg.kind()
+                ));
+            }
+            msg
+        }
+        None => Message::Unknown { tag, bytes: body },
+    };
+    Ok(MessageEnvelope {
+        deadline,
+        trace,
+        signature,
+        counter,
+        ..MessageEnvelope::new(id, body)
+    })
+}
+
+/// Decode one frame of a framed stream speaking `version`, as
+/// [`MessageEnvelope::frame`] encodes it
+pub fn decode_frame<C: Codec>(codec: &C, version: u32, frame: &[u8]) -> Result<MessageEnvelope> {
+    decode_envelope(codec, version, strip_preamble(codec, version, frame)?)
+}
+
+// ====================
+// Preamble
+// ====================
+
+/// Start a frame for a connection speaking `version` with its preamble, if
+/// the version has one
+pub(crate) fn put_preamble<C: Codec>(codec: &C, version: u32, buf: &mut impl BufMut) {
+    if version >= PREAMBLE_VERSION {
+        buf.put_slice(&FRAME_MAGIC);
+        buf.put_u8(version as u8);
+        buf.put_u8(codec.wire_id());
+    }
+}
+
+/// Check the preamble of a frame read from a connection speaking `version`,
+/// returning the rest of the frame
+///
+/// From protocol version 8 on, every frame on a stream starts with the
+/// [`FRAME_MAGIC`], the protocol version and the [`Codec::wire_id`] of its
+/// sender's codec, so a peer that is not speaking the echo protocol, or
+/// encodes with another codec, is told apart from one sending corrupt
+/// messages. Frames of a codec without an id of its own are not checked for
+/// their codec. A pure function of untrusted bytes, like
+/// [`parse_frame`](crate::framed::parse_frame).
+pub fn strip_preamble<'a, C: Codec>(
+    codec: &C,
+    version: u32,
+    frame: &'a [u8],
+) -> Result<&'a [u8], IncompatibleProtocol> {
+    if version < PREAMBLE_VERSION {
+        return Ok(frame);
+    }
+    let preamble = frame
+        .split_first_chunk::<PREAMBLE_LEN>()
+        .filter(|(preamble, _)| preamble.starts_with(&FRAME_MAGIC));
+    let Some(([.., sent_version, sent_codec], rest)) = preamble else {
+        return Err(IncompatibleProtocol::new(
+            "frame does not start with the echo protocol's magic".to_string(),
+        ));
+    };
+    if u32::from(*sent_version) != version {
+        return Err(IncompatibleProtocol::new(format!(
+            "frame of protocol version {sent_version} on a connection speaking version {version}"
+        )));
+    }
+    let codec = codec.wire_id();
+    if codec != UNNAMED_CODEC && *sent_codec != UNNAMED_CODEC && *sent_codec != codec {
+        return Err(IncompatibleProtocol::new(format!(
+            "frame encoded with {}, expected {}",
+            codec_name(*sent_codec),
+            codec_name(codec)
+        )));
+    }
+    Ok(rest)
+}
+
+/// The name of the codec with `id`, for telling peers apart
+fn codec_name(id: u8) -> String {
+    CodecKind::from_wire_id(id).map_or_else(|| format!("codec {id}"), |kind| kind.to_string())
+}
+
+/// A frame that was not written for the protocol version and codec of the
+/// connection it came on, see [`strip_preamble`]
+#[stack_error(derive, add_meta)]
+#[error("incompatible protocol: {detail}")]
+pub struct IncompatibleProtocol {
+    pub detail: String,
+}
+
+/// Decode a bare [`Message`] encoded with the default [`Bincode`] codec
+///
+/// A pure function of untrusted bytes, for fuzzing: malformed input must come
+/// back as an error, never a panic.
+pub fn decode_message(bytes: &[u8]) -> Result<Message> {
+    Bincode.decode(bytes)
+}
+
+thread_local! {
+    /// How many messages the message being decoded on this thread is nested in
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Decode a field holding further messages, refusing to go deeper than
+/// [`MAX_NESTING`]
+///
+/// Checked while decoding rather than after, since a deep enough message
+/// overflows the stack before the decoder returns.
+fn nested<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> std::result::Result<T, D::Error> {
+    struct Level(usize);
+    impl Drop for Level {
+        fn drop(&mut self) {
+            NESTING.set(self.0);
+        }
+    }
+
+    let depth = NESTING.get();
+    if depth >= MAX_NESTING {
+        return Err(serde::de::Error::custom(format!(
+            "messages nested deeper than {MAX_NESTING}"
+        )));
+    }
+    let _level = Level(depth);
+    NESTING.set(depth + 1);
+    T::deserialize(deserializer)
+}
+
+/// Just the id of a [`MessageEnvelope`], for answering one whose body does not
+/// decode
+#[derive(Debug, Deserialize)]
+struct EnvelopeId {
+    id: u64,
+}
+
+/// Size limits and compression shared by client and server
+///
+/// `max_message_size` bounds every encoded envelope, compressed while reading
+/// and again once decompressed, so a small compressed message cannot expand
+/// past it. `kind_limits` optionally tighten that bound for individual message
+/// kinds; they are checked on the uncompressed envelope once its kind is
+/// known. `max_string_len` and `max_collection_len` bound every string and
+/// list inside a decoded message, byte payloads excepted. They are checked
+/// once the envelope decoded, not while decoding: what the decoder allocates
+/// meanwhile is bounded only by `max_message_size`, since none of the codecs
+/// allocates far beyond the bytes it was given. `read_timeout` and
+/// `write_timeout` bound how long a peer may take to send a message once its
+/// stream opened, and to accept one.
+/// `compression` only applies to outgoing messages on connections that
+/// negotiated protocol version 2 or newer.
+#[derive(Debug, Clone)]
+pub struct ProtocolConfig {
+    pub max_message_size: usize,
+    pub kind_limits: HashMap<MessageKind, usize>,
+    pub max_string_len: usize,
+    pub max_collection_len: usize,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub compression: Compression,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: MAX_MESSAGE_SIZE,
+            kind_limits: HashMap::from([(MessageKind::Ping, 128), (MessageKind::Pong, 128)]),
+            max_string_len: MAX_STRING_LEN,
+            max_collection_len: MAX_COLLECTION_LEN,
+            read_timeout: READ_TIMEOUT,
+            write_timeout: WRITE_TIMEOUT,
+            compression: Compression::None,
+        }
+    }
+}
+
+impl ProtocolConfig {
+    /// Limit encoded envelopes of `kind` to `limit` bytes
+    pub fn with_limit(mut self, kind: MessageKind, limit: usize) -> Self {
+        self.kind_limits.insert(kind, limit);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Bound every string in a decoded message to `max_string_len` bytes and
+    /// every list to `max_collection_len` elements
+    ///
+    /// Checked after decoding, so they bound what a message may carry, not
+    /// what decoding it allocates; `max_message_size` bounds that.
+    pub fn with_decode_limits(mut self, max_string_len: usize, max_collection_len: usize) -> Self {
+        self.max_string_len = max_string_len;
+        self.max_collection_len = max_collection_len;
+        self
+    }
+
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    pub fn with_write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    /// The effective limit for envelopes of `kind`
+    pub fn limit_for(&self, kind: MessageKind) -> usize {
+        self.kind_limits
+            .get(&kind)
+            .map_or(self.max_message_size, |limit| {
+                (*limit).min(self.max_message_size)
+            })
+    }
+
+    /// Check an encoded envelope of `kind` and `size` bytes against the limits
+    pub fn check(&self, kind: MessageKind, size: usize) -> Result<(), MessageTooLarge> {
+        let limit = self.limit_for(kind);
+        if size > limit {
+            return Err(MessageTooLarge::new(Some(kind), size, limit));
+        }
+        Ok(())
+    }
+
+    /// Encode `msg` for a connection speaking `version`, refusing to produce
+    /// bytes the peer would reject
+    ///
+    /// The bytes are written into a buffer of the shared [`BufferPool`].
+    pub fn encode<C: Codec>(
+        &self,
+        codec: &C,
+        version: u32,
+        msg: &MessageEnvelope,
+    ) -> Result<Bytes> {
+        let wire = msg.wire(codec, version)?;
+        let (encoded, size) = encode_pooled(codec, self.compression, version, &wire)?;
+        self.check(msg.body.kind(), size)?;
+        Ok(encoded)
+    }
+
+    /// Decode a message read from a connection speaking `version`, along with
+    /// its uncompressed size
+    pub fn decode<C: Codec>(
+        &self,
+        codec: &C,
+        version: u32,
+        bytes: &[u8],
+    ) -> Result<(MessageEnvelope, usize)> {
+        let encoded = decompress(codec, version, bytes, self.max_message_size)?;
+        let envelope = decode_envelope(codec, version, &encoded)?;
+        self.check_shape(&envelope.body)?;
+        Ok((envelope, encoded.len()))
+    }
+
+    /// Check every string and list inside `msg` against the decode limits
+    ///
+    /// Runs on the decoded message. Checking while decoding would need the
+    /// element type of every sequence, which serde does not pass on: byte
+    /// payloads arrive as sequences too, and are not bound by these limits.
+    pub fn check_shape(&self, msg: &Message) -> Result<(), DecodeLimitExceeded> {
+        let string = |s: &str| self.check_len("string", s.len(), self.max_string_len);
+        let list = |len| self.check_len("list", len, self.max_collection_len);
+        let addr = |addr: &EndpointAddr| list(addr.addrs.len());
+        let addrs = |addrs: &[EndpointAddr]| {
+            list(addrs.len())?;
+            addrs.iter().try_for_each(addr)
+        };
+        let presence = |presence: &Presence| string(&presence.name);
+        match msg {
+            Message::Echo
+            | Message::Ping { .. }
+            | Message::Pong { .. }
+            | Message::Data(_)
+            | Message::GoingAway
+            | Message::Ack { .. }
+            | Message::Resume { .. }
+            | Message::ListPeers
+            | Message::LeaveQueue
+            | Message::SetStatus(_)
+            | Message::StatsRequest
+            | Message::StatsResponse(_)
+            | Message::Unknown { .. } => Ok(()),
+            Message::Chat { from, text } => {
+                string(from)?;
+                string(text)
+            }
+            Message::Error { detail, .. } => string(detail),
+            Message::Reliable { body, .. } | Message::Broadcast { body, .. } => {
+                self.check_shape(body)
+            }
+            Message::Batch(messages) => {
+                list(messages.len())?;
+                messages.iter().try_for_each(|body| self.check_shape(body))
+            }
+            Message::Replay { entries, .. } => {
+                list(entries.len())?;
+                entries
+                    .iter()
+                    .try_for_each(|(_, body)| self.check_shape(body))
+            }
+            Message::Subscribe(topic) | Message::Unsubscribe(topic) => string(topic),
+            Message::Publish { topic, .. } => string(topic),
+            Message::Announce(peer) | Message::Introduce { peer } => addr(peer),
+            Message::PeerList(peers) => addrs(peers),
+            Message::FindSessionKeys(owners) => list(owners.len()),
+            Message::SessionKeys(keys) => list(keys.len()),
+            Message::Lobby(request) => match request {
+                LobbyRequest::Join { code } | LobbyRequest::Members { code } => string(code),
+                LobbyRequest::Create { .. } | LobbyRequest::Leave | LobbyRequest::Send { .. } => {
+                    Ok(())
+                }
+            },
+            Message::Room(event) => match event {
+                RoomEvent::Joined { code, members, .. }
+                | RoomEvent::Members { code, members, .. } => {
+                    string(code)?;
+                    list(members.len())
+                }
+                RoomEvent::Left { code }
+                | RoomEvent::MemberJoined { code, .. }
+                | RoomEvent::MemberLeft { code, .. }
+                | RoomEvent::Message { code, .. } => string(code),
+                RoomEvent::Refused { .. } => Ok(()),
+            },
+            Message::JoinQueue { mode, .. } => string(mode),
+            Message::MatchFound { room, peers } => {
+                string(room)?;
+                addrs(peers)
+            }
+            Message::Login { name } => string(name),
+            Message::WatchPresence(peers) => list(peers.len()),
+            Message::Presence(p) => presence(p),
+            Message::PresenceList(list_of) => {
+                list(list_of.len())?;
+                list_of.iter().try_for_each(presence)
+            }
+        }
+    }
+
+    fn check_len(
+        &self,
+        what: &'static str,
+        len: usize,
+        limit: usize,
+    ) -> Result<(), DecodeLimitExceeded> {
+        if len > limit {
+            return Err(DecodeLimitExceeded::new(what, len, limit));
+        }
+        Ok(())
+    }
+
+    /// Read a whole unidirectional stream under these limits
+    ///
+    /// A stream still incomplete after `read_timeout` is stopped with
+    /// [`STREAM_TIMED_OUT`] and fails with [`Timeout`], so a peer that opens a
+    /// stream and then stalls does not hold on to the reading task.
+    pub async fn read_message(&self, recv: &mut RecvStream) -> Result<Bytes> {
+        read_message_within(recv, self.max_message_size, self.read_timeout).await
+    }
+
+    /// Send already encoded bytes on a new unidirectional stream, failing with
+    /// [`Timeout`] if the peer does not take them within `write_timeout`
+    pub async fn send_bytes(&self, conn: &Connection, encoded: &[u8]) -> Result<()> {
+        send_bytes_within(conn, encoded, self.write_timeout).await
+    }
+
+    /// Like [`send_bytes`](Self::send_bytes), handing `encoded` to the
+    /// transport without copying it
+    pub async fn send_raw(&self, conn: &Connection, encoded: Bytes) -> Result<()> {
+        send_raw_within(conn, encoded, self.write_timeout).await
+    }
+
+    /// The id of the envelope in `bytes`, if at least that much of a message
+    /// that failed to [`decode`](Self::decode) is intact
+    pub fn decode_id<C: Codec>(&self, codec: &C, version: u32, bytes: &[u8]) -> Option<u64> {
+        let encoded = decompress(codec, version, bytes, self.max_message_size).ok()?;
+        envelope_id(codec, &encoded)
+    }
+}
+
+/// The id of the uncompressed envelope in `encoded`, even if its body does not
+/// decode
+pub fn envelope_id<C: Codec>(codec: &C, encoded: &[u8]) -> Option<u64> {
+    codec
+        .decode::<EnvelopeId>(encoded)
+        .ok()
+        .map(|envelope| envelope.id)
+}
+
+/// A string or list inside a decoded message exceeded its limit
+#[stack_error(derive, add_meta)]
+#[error("{what} of length {len} exceeds the limit of {limit}")]
+pub struct DecodeLimitExceeded {
+    /// `"string"` or `"list"`
+    pub what: &'static str,
+    pub len: usize,
+    pub limit: usize,
+}
+
+/// A stream operation did not complete before its deadline
+#[stack_error(derive, add_meta)]
+#[error("{op} timed out after {timeout:?}")]
+pub struct Timeout {
+    /// `"read"` or `"write"`
+    pub op: &'static str,
+    pub timeout: Duration,
+}
+
+/// A message exceeded the configured size limit
+#[stack_error(derive, add_meta)]
+#[error("{} message of {size} bytes exceeds the limit of {limit} bytes", kind.map_or("A".to_string(), |kind| format!("{kind:?}")))]
+pub struct MessageTooLarge {
+    /// Unknown when the limit was hit before the message could be decoded
+    pub kind: Option<MessageKind>,
+    pub size: usize,
+    pub limit: usize,
+}
+
+// ====================
+// Unidirectional Stream Solution
+// ====================
+
+/// Send one value on a new unidirectional stream, uncompressed
+pub async fn send_message<C: Codec, T: Serialize>(
+    conn: &Connection,
+    codec: &C,
+    msg: &T,
+) -> Result<()> {
+    send_compressed(conn, codec, Compression::None, msg).await
+}
+
+/// Send one value on a new unidirectional stream, compressed if the connection
+/// supports it and the value is large enough to benefit
+pub async fn send_compressed<C: Codec, T: Serialize>(
+    conn: &Connection,
+    codec: &C,
+    compression: Compression,
+    msg: &T,
+) -> Result<()> {
+    let encoded = encode_message(codec, compression, connection_version(conn), msg)?;
+    send_raw(conn, encoded).await
+}
+
+/// Encode one value for a unidirectional stream of a connection speaking
+/// `version`, ready for [`send_raw`] or a [`SendQueue`](crate::queue::SendQueue)
+pub fn encode_message<C: Codec, T: Serialize>(
+    codec: &C,
+    compression: Compression,
+    version: u32,
+    msg: &T,
+) -> Result<Bytes> {
+    let (encoded, _) = encode_pooled(codec, compression, version, msg)?;
+    Ok(encoded)
+}
+
+/// Send already encoded bytes on a new unidirectional stream, within
+/// [`WRITE_TIMEOUT`]
+pub async fn send_bytes(conn: &Connection, encoded: &[u8]) -> Result<()> {
+    send_bytes_within(conn, encoded, WRITE_TIMEOUT).await
+}
+
+/// Send already encoded bytes on a new unidirectional stream, failing with
+/// [`Timeout`] unless the peer takes them within `timeout`
+pub async fn send_bytes_within(conn: &Connection, encoded: &[u8], timeout: Duration) -> Result<()> {
+    send_raw_within(conn, Bytes::copy_from_slice(encoded), timeout).await
+}
+
+/// Send a pre-encoded message on a new unidirectional stream without copying
+/// it, within [`WRITE_TIMEOUT`]
+///
+/// `encoded` goes on the wire as is, so it must already carry the preamble
+/// and compression header if the connection's version has them.
+pub async fn send_raw(conn: &Connection, encoded: Bytes) -> Result<()> {
+    send_raw_within(conn, encoded, WRITE_TIMEOUT).await
+}
+
+/// Send a pre-encoded message like [`send_raw`], failing with [`Timeout`]
+/// unless the peer takes it within `timeout`
+///
+/// The deadline covers opening the stream too, which waits for the peer to
+/// allow another stream.
+pub async fn send_raw_within(conn: &Connection, encoded: Bytes, timeout: Duration) -> Result<()> {
+    let send = async {
+        let mut send = conn.open_uni().await.anyerr()?;
+        send.write_chunk(encoded).await.anyerr()?;
+        send.finish().anyerr()?;
+        Ok(())
+    };
+    match tokio::time::timeout(timeout, send).await {
+        Ok(sent) => sent,
+        Err(_) => Err(Timeout::new("write", timeout).into()),
+    }
+}
+
+/// Receive one value from a unidirectional stream of a current-version
+/// connection, decompressing it as needed, within [`READ_TIMEOUT`]
+pub async fn recv_message<C: Codec, T: DeserializeOwned>(codec: &C, recv: RecvStream) -> Result<T> {
+    recv_message_within(codec, recv, READ_TIMEOUT).await
+}
+
+/// Receive one value like [`recv_message`], failing with [`Timeout`] unless
+/// it arrives within `timeout`
+pub async fn recv_message_within<C: Codec, T: DeserializeOwned>(
+    codec: &C,
+    mut recv: RecvStream,
+    timeout: Duration,
+) -> Result<T> {
+    let bytes = read_message_within(&mut recv, MAX_MESSAGE_SIZE, timeout).await?;
+    let encoded = decompress(codec, PROTOCOL_VERSION, &bytes, MAX_MESSAGE_SIZE)?;
+
+    codec.decode(&encoded)
+}
+
+/// Encode `value` into a buffer of the shared [`BufferPool`], behind the
+/// preamble and compression header if `version` has them, along with its
+/// uncompressed size
+pub(crate) fn encode_pooled<C: Codec, T: Serialize>(
+    codec: &C,
+    compression: Compression,
+    version: u32,
+    value: &T,
+) -> Result<(Bytes, usize)> {
+    let mut size = 0;
+    let encoded = BufferPool::shared().fill(|buf| {
+        if version < COMPRESSION_VERSION {
+            codec.encode_into(value, buf)?;
+            size = buf.len();
+            return Ok(());
+        }
+        put_preamble(codec, version, buf);
+        let mut body = buf.split_off(buf.len());
+        Compression::put_header(&mut body);
+        codec.encode_into(value, &mut body)?;
+        size = body.len() - 1;
+        compression.compress_in_place(&mut body)?;
+        buf.unsplit(body);
+        Ok(())
+    })?;
+    Ok((encoded, size))
+}
+
+/// Strip the preamble and the compression header, if `version` has them
+fn decompress<'a, C: Codec>(
+    codec: &C,
+    version: u32,
+    bytes: &'a [u8],
+    limit: usize,
+) -> Result<Cow<'a, [u8]>> {
+    if version < COMPRESSION_VERSION {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    Compression::decompressed(strip_preamble(codec, version, bytes)?, limit)
+}
+
+/// Read a whole unidirectional stream of at most `limit` bytes
+///
+/// A message arriving in one piece is returned as received, without copying.
+/// A stream exceeding the limit is stopped with [`STREAM_TOO_LARGE`], so the
+/// sender learns why instead of waiting for a response that never comes.
+pub async fn read_message(recv: &mut RecvStream, limit: usize) -> Result<Bytes> {
+    let mut first = Bytes::new();
+    let mut joined: Option<BytesMut> = None;
+    while let Some(chunk) = recv.read_chunk(usize::MAX, true).await.anyerr()? {
+        let len = joined.as_ref().map_or(first.len(), BytesMut::len) + chunk.bytes.len();
+        if len > limit {
+            recv.stop(STREAM_TOO_LARGE).ok();
+            return Err(MessageTooLarge::new(None, len, limit).into());
+        }
+        match &mut joined {
+            Some(joined) => joined.extend_from_slice(&chunk.bytes),
+            None if first.is_empty() => first = chunk.bytes,
+            None => {
+                let mut buf = BytesMut::with_capacity(len);
+                buf.extend_from_slice(&first);
+                buf.extend_from_slice(&chunk.bytes);
+                joined = Some(buf);
+            }
+        }
+    }
+    Ok(joined.map_or(first, BytesMut::freeze))
+}
+
+/// Read a whole unidirectional stream like [`read_message`], stopping it with
+/// [`STREAM_TIMED_OUT`] and failing with [`Timeout`] unless it completes
+/// within `timeout`
+pub async fn read_message_within(
+    recv: &mut RecvStream,
+    limit: usize,
+    timeout: Duration,
+) -> Result<Bytes> {
+    match tokio::time::timeout(timeout, read_message(recv, limit)).await {
+        Ok(read) => read,
+        Err(_) => {
+            recv.stop(STREAM_TIMED_OUT).ok();
+            Err(Timeout::new("read", timeout).into())
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use iroh::EndpointId;
+
+/// Whether `filter` selects `topic`
+///
+/// Both are split into `/`-separated levels. A `+` level in the filter
+/// matches any one level, and a `#` as the last level matches every level
+/// that remains, including none. Any other level must match exactly, so
+/// `game/+/events` matches `game/42/events` but not `game/42/chat`, and
+/// `game/#` matches `game` and everything below it.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter = filter.split('/').peekable();
+    let mut topic = topic.split('/');
+    while let Some(level) = filter.next() {
+        if level == "#" && filter.peek().is_none() {
+            return true;
+        }
+        match topic.next() {
+            Some(actual) if level == "+" || level == actual => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+/// The topic filters each connected peer is subscribed to
+///
+/// Clones share the same table.
+#[derive(Debug, Clone, Default)]
+pub struct Subscriptions(Arc<Mutex<HashMap<EndpointId, HashSet<String>>>>);
+
+impl Subscriptions {
+    /// Subscribe `peer` to `filter`, returning false if it already was
+    pub fn subscribe(&self, peer: EndpointId, filter: String) -> bool {
+        let mut peers = self.0.lock().expect("poisoned");
+        peers.entry(peer).or_default().insert(filter)
+    }
+
+    /// Unsubscribe `peer` from `filter`, returning false if it was not
+    /// subscribed under exactly that filter
+    pub fn unsubscribe(&self, peer: EndpointId, filter: &str) -> bool {
+        let mut peers = self.0.lock().expect("poisoned");
+        let Some(filters) = peers.get_mut(&peer) else {
+            return false;
+        };
+        let removed = filters.remove(filter);
+        if filters.is_empty() {
+            peers.remove(&peer);
+        }
+        removed
+    }
+
+    /// Drop every subscription of `peer`
+    pub fn remove_peer(&self, peer: &EndpointId) {
+        self.0.lock().expect("poisoned").remove(peer);
+    }
+
+    /// The peers with at least one filter matching `topic`
+    pub fn subscribers(&self, topic: &str) -> Vec<EndpointId> {
+        let peers = self.0.lock().expect("poisoned");
+        peers
+            .iter()
+            .filter(|(_, filters)| filters.iter().any(|filter| topic_matches(filter, topic)))
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
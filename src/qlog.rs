@@ -0,0 +1,176 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use iroh::endpoint::{Connection, ConnectionError};
+use iroh_quinn_proto::Side;
+use n0_error::{Result, StdResultExt};
+use serde_json::{Value, json};
+use tokio::time::MissedTickBehavior;
+use tracing::warn;
+
+use crate::audit::now_ms;
+
+/// How often [`Qlog`] samples a connection unless told otherwise
+pub const QLOG_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Record separator starting every JSON-SEQ record
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+// ====================
+// Qlog Traces
+// ====================
+
+/// Writes a qlog trace of every connection it is given to a directory, for
+/// viewing in qvis
+///
+/// The QUIC implementation has no qlog support of its own, so traces are
+/// built from connection statistics sampled every [`QLOG_INTERVAL`]: the
+/// connection starting, the RTT and congestion window whenever they change,
+/// and the connection closing. Individual packets are not in the trace; for
+/// those, capture the traffic and decrypt it with a key log, see
+/// [`TransportTuning::keylog`](crate::tuning::TransportTuning::keylog).
+///
+/// Every connection gets its own `.sqlog` file in JSON-SEQ format, named
+/// after when it started, the peer and this end's side.
+#[derive(Debug, Clone)]
+pub struct Qlog {
+    dir: Arc<PathBuf>,
+    interval: Duration,
+}
+
+impl Qlog {
+    /// Write traces to `dir`, creating it when the first trace starts
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: Arc::new(dir.into()),
+            interval: QLOG_INTERVAL,
+        }
+    }
+
+    /// Sample connections every `interval` instead of [`QLOG_INTERVAL`]
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval.max(Duration::from_millis(1));
+        self
+    }
+
+    /// Trace `conn`, on whose `side` this end is, until it closes
+    ///
+    /// Failing to write the trace is logged rather than affecting the
+    /// connection.
+    pub async fn trace(self, conn: Connection, side: Side) {
+        if let Err(e) = self.write_trace(&conn, side).await {
+            warn!("error writing qlog trace: {:#}", e);
+        }
+    }
+
+    async fn write_trace(&self, conn: &Connection, side: Side) -> Result<()> {
+        let vantage = match side {
+            Side::Client => "client",
+            Side::Server => "server",
+        };
+        fs::create_dir_all(&*self.dir)
+            .with_std_context(|_| format!("creating qlog directory {}", self.dir.display()))?;
+        let started = now_ms();
+        let peer = conn.remote_id();
+        let path = self
+            .dir
+            .join(format!("{started}-{}-{vantage}.sqlog", peer.fmt_short()));
+        let file = File::create(&path)
+            .with_std_context(|_| format!("creating qlog trace {}", path.display()))?;
+        let mut trace = Trace {
+            file: BufWriter::new(file),
+            start: Instant::now(),
+        };
+        trace.header(vantage, started, &peer.to_string())?;
+        trace.event(
+            "connectivity:connection_started",
+            json!({ "protocol": "QUIC", "dst_id": peer.to_string() }),
+        )?;
+
+        let mut ticks = tokio::time::interval(self.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut last = None;
+        loop {
+            tokio::select! {
+                error = conn.closed() => {
+                    trace.metrics(conn, &mut last)?;
+                    return trace.event("connectivity:connection_closed", closed(&error));
+                }
+                _ = ticks.tick() => trace.metrics(conn, &mut last)?,
+            }
+        }
+    }
+}
+
+/// An open trace file
+struct Trace {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl Trace {
+    fn header(&mut self, vantage: &str, reference_time: u64, group: &str) -> Result<()> {
+        self.record(&json!({
+            "qlog_version": "0.3",
+            "qlog_format": "JSON-SEQ",
+            "title": "wstest",
+            "trace": {
+                "vantage_point": { "type": vantage },
+                "common_fields": {
+                    "time_format": "relative",
+                    "reference_time": reference_time,
+                    "group_id": group,
+                },
+            },
+        }))
+    }
+
+    fn event(&mut self, name: &str, data: Value) -> Result<()> {
+        let time = self.start.elapsed().as_secs_f64() * 1000.0;
+        self.record(&json!({ "time": time, "name": name, "data": data }))
+    }
+
+    /// A metrics update, if the RTT or congestion window changed since `last`
+    fn metrics(&mut self, conn: &Connection, last: &mut Option<(Duration, u64)>) -> Result<()> {
+        let path = conn.stats().path;
+        let now = (path.rtt, path.cwnd);
+        if *last == Some(now) {
+            return Ok(());
+        }
+        *last = Some(now);
+        self.event(
+            "recovery:metrics_updated",
+            json!({
+                "smoothed_rtt": path.rtt.as_secs_f64() * 1000.0,
+                "congestion_window": path.cwnd,
+            }),
+        )
+    }
+
+    fn record(&mut self, record: &Value) -> Result<()> {
+        self.file.write_all(&[RECORD_SEPARATOR]).anyerr()?;
+        serde_json::to_writer(&mut self.file, record).anyerr()?;
+        self.file.write_all(b"\n").anyerr()?;
+        self.file.flush().anyerr()
+    }
+}
+
+/// The data of a `connection_closed` event for a connection that ended with
+/// `error`
+fn closed(error: &ConnectionError) -> Value {
+    match error {
+        ConnectionError::ApplicationClosed(close) => json!({
+            "owner": "remote",
+            "application_code": close.error_code.into_inner(),
+            "reason": String::from_utf8_lossy(&close.reason),
+        }),
+        ConnectionError::LocallyClosed => json!({ "owner": "local" }),
+        ConnectionError::TimedOut => json!({ "trigger": "idle_timeout" }),
+        other => json!({ "reason": other.to_string() }),
+    }
+}
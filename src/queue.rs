@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use iroh::endpoint::Connection;
+use n0_error::{Result, StdResultExt, anyerr, stack_error};
+use tokio::{
+    sync::{Semaphore, mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{Instrument, debug, info_span};
+
+use crate::protocol::send_raw;
+
+/// Default number of messages a [`SendQueue`] holds before senders wait
+pub const SEND_QUEUE_CAPACITY: usize = 256;
+
+/// How many streams a [`SendQueue`] writes at once
+const MAX_STREAMS_IN_FLIGHT: usize = 32;
+
+/// A message was refused because the send queue had no room for it
+#[stack_error(derive, add_meta)]
+#[error("send queue is full")]
+pub struct QueueFull {}
+
+/// An encoded message waiting to be sent, and who to tell how it went
+type Outgoing = (Bytes, Option<oneshot::Sender<Result<()>>>);
+
+/// The outgoing messages of one connection, each written on its own
+/// unidirectional stream
+///
+/// At most a bounded number of streams are open at once and at most
+/// `capacity` messages wait behind them, so a producer faster than the peer
+/// is slowed down instead of opening ever more streams. Clones feed the same
+/// queue, which stops once the last of them is dropped.
+#[derive(Debug, Clone)]
+pub struct SendQueue {
+    tx: mpsc::Sender<Outgoing>,
+    _writer: Arc<Writer>,
+}
+
+/// The task writing a queue's messages, aborted when dropped
+#[derive(Debug)]
+struct Writer(JoinHandle<()>);
+
+impl SendQueue {
+    /// A queue writing to `conn`, holding up to `capacity` messages
+    pub fn new(conn: Connection, capacity: usize) -> Self {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        let span = info_span!("send_queue", remote = %conn.remote_id().fmt_short());
+        let writer = tokio::spawn(write_all(conn, rx).instrument(span));
+        Self {
+            tx,
+            _writer: Arc::new(Writer(writer)),
+        }
+    }
+
+    /// Queue `encoded`, waiting for room, and wait until it has been written
+    pub async fn send(&self, encoded: Bytes) -> Result<()> {
+        let (done, written) = oneshot::channel();
+        self.tx
+            .send((encoded, Some(done)))
+            .await
+            .map_err(|_| anyerr!("send queue closed"))?;
+        written.await.std_context("send queue closed")?
+    }
+
+    /// Queue `encoded` without waiting, failing with [`QueueFull`] if there
+    /// is no room
+    ///
+    /// Errors writing it are only logged.
+    pub fn try_send(&self, encoded: Bytes) -> Result<()> {
+        self.tx.try_send((encoded, None)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => QueueFull::new().into(),
+            mpsc::error::TrySendError::Closed(_) => anyerr!("send queue closed"),
+        })
+    }
+
+    /// Number of messages that can be queued before senders have to wait
+    pub fn room(&self) -> usize {
+        self.tx.capacity()
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn write_all(conn: Connection, mut rx: mpsc::Receiver<Outgoing>) {
+    let streams = Arc::new(Semaphore::new(MAX_STREAMS_IN_FLIGHT));
+    while let Some((encoded, done)) = rx.recv().await {
+        let permit = streams.clone().acquire_owned().await.expect("never closed");
+        let conn = conn.clone();
+        tokio::spawn(
+            async move {
+                let result = send_raw(&conn, encoded).await;
+                drop(permit);
+                match done {
+                    Some(done) => {
+                        done.send(result).ok();
+                    }
+                    None => {
+                        if let Err(e) = result {
+                            debug!("error sending queued message: {:#}", e);
+                        }
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
@@ -0,0 +1,500 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use iroh::{
+    Endpoint, EndpointAddr, SecretKey,
+    endpoint::{Connection, ConnectionError},
+};
+use n0_error::{Result, anyerr};
+use tokio::sync::{Mutex, OnceCell, broadcast};
+use tracing::{debug, warn};
+
+use crate::{
+    auth::{AuthProvider, authenticate, authenticate_sealing},
+    capture::Capture,
+    chaos::ChaosConfig,
+    client::{Client, dial, dial_early},
+    close::{CloseReason, RemoteClose},
+    codec::{Bincode, Codec},
+    events::{ConnEvent, Events},
+    heartbeat::HeartbeatConfig,
+    lookup::AddrLookup,
+    middleware::Interceptor,
+    protocol::{Message, ProtocolConfig, RemoteError},
+    qlog::Qlog,
+    relay::Relays,
+    sealing::Sealer,
+    signing::KeyProvider,
+    tuning::TransportTuning,
+};
+
+/// Exponential backoff between reconnection attempts
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// Delay before the first retry
+    pub initial: Duration,
+    /// Upper bound for any single delay
+    pub max: Duration,
+    /// Factor applied to the delay after every failed attempt
+    pub multiplier: f64,
+    /// Fraction of the delay randomly added or removed, in `0.0..=1.0`
+    pub jitter: f64,
+    /// Give up after this many failed attempts in a row, `None` retries forever
+    pub max_retries: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_retries: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// How long to wait before retry number `attempt`, counting from zero
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.initial.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let base = base.min(self.max.as_secs_f64());
+        let jitter = base * self.jitter * (rand::random::<f64>() * 2.0 - 1.0);
+        Duration::from_secs_f64((base + jitter).max(0.0))
+    }
+}
+
+type ConnectHook = Box<dyn Fn(&Connection) + Send + Sync>;
+type DisconnectHook = Box<dyn Fn(&ConnectionError) + Send + Sync>;
+
+/// A [`Client`] that re-dials the server whenever its connection drops
+///
+/// Requests that fail because the connection went away are retried on the
+/// next connection, so callers only see an error once reconnecting gives up.
+pub struct ReconnectingClient<C = Bincode> {
+    addr: EndpointAddr,
+    codec: C,
+    config: ProtocolConfig,
+    backoff: Backoff,
+    heartbeat: Option<HeartbeatConfig>,
+    idle_timeout: Option<Duration>,
+    zero_rtt: bool,
+    coalesce: Option<Duration>,
+    stream_pool: Option<usize>,
+    chaos: Option<ChaosConfig>,
+    capture: Option<Capture>,
+    qlog: Option<Qlog>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    auth: Option<Arc<dyn AuthProvider>>,
+    signing: Option<Arc<dyn KeyProvider>>,
+    sealer: Option<Sealer>,
+    relays: Relays,
+    lookup: AddrLookup,
+    tuning: TransportTuning,
+    secret_key: Option<SecretKey>,
+    endpoint: OnceCell<Endpoint>,
+    current: Mutex<Option<Arc<Client<C>>>>,
+    events: Events,
+    on_connect: Option<ConnectHook>,
+    on_disconnect: Option<DisconnectHook>,
+}
+
+impl<C: Codec> fmt::Debug for ReconnectingClient<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingClient")
+            .field("addr", &self.addr)
+            .field("codec", &self.codec)
+            .field("config", &self.config)
+            .field("backoff", &self.backoff)
+            .field("heartbeat", &self.heartbeat)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("zero_rtt", &self.zero_rtt)
+            .field("coalesce", &self.coalesce)
+            .field("stream_pool", &self.stream_pool)
+            .field("chaos", &self.chaos)
+            .field("qlog", &self.qlog)
+            .field("interceptors", &self.interceptors)
+            .field("auth", &self.auth)
+            .field("signing", &self.signing)
+            .field("sealer", &self.sealer)
+            .field("relays", &self.relays)
+            .field("lookup", &self.lookup)
+            .field("tuning", &self.tuning)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: Codec> ReconnectingClient<C> {
+    /// Create a client for `addr`; the first request dials the server
+    pub fn new(addr: EndpointAddr, codec: C) -> Self {
+        Self {
+            addr,
+            codec,
+            config: ProtocolConfig::default(),
+            backoff: Backoff::default(),
+            heartbeat: None,
+            idle_timeout: None,
+            zero_rtt: false,
+            coalesce: None,
+            stream_pool: None,
+            chaos: None,
+            capture: None,
+            qlog: None,
+            interceptors: Vec::new(),
+            auth: None,
+            signing: None,
+            sealer: None,
+            relays: Relays::default(),
+            lookup: AddrLookup::default(),
+            tuning: TransportTuning::default(),
+            secret_key: None,
+            endpoint: OnceCell::new(),
+            current: Mutex::new(None),
+            events: Events::default(),
+            on_connect: None,
+            on_disconnect: None,
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Size limits for every connection, see [`Client::with_config`]
+    pub fn with_config(mut self, config: ProtocolConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Run heartbeats on every connection, so a silently dead server is
+    /// detected and replaced instead of hanging requests
+    pub fn with_heartbeat(mut self, config: HeartbeatConfig) -> Self {
+        self.heartbeat = Some(config);
+        self
+    }
+
+    /// Let go of every connection idle for `timeout`, see
+    /// [`Client::with_idle_timeout`]; the next request dials again
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Send the request that reconnects as 0-RTT data, resuming the session of
+    /// an earlier connection to skip a handshake round trip
+    ///
+    /// 0-RTT data can be replayed by anyone on the path, and servers take it
+    /// like any other, so only requests [`Message::is_replay_safe`] allows are
+    /// sent this way. Other requests, the first connection and clients that
+    /// authenticate wait for the handshake as usual. Interceptors and
+    /// captures do not see the 0-RTT request.
+    pub fn with_zero_rtt(mut self) -> Self {
+        self.zero_rtt = true;
+        self
+    }
+
+    /// Coalesce the messages sent close together on every connection, see
+    /// [`Client::with_coalescing`]
+    pub fn with_coalescing(mut self, window: Duration) -> Self {
+        self.coalesce = Some(window);
+        self
+    }
+
+    /// Send requests on a pool of up to `size` reused streams on every
+    /// connection, see [`Client::with_stream_pool`]
+    pub fn with_stream_pool(mut self, size: usize) -> Self {
+        self.stream_pool = Some(size);
+        self
+    }
+
+    /// Inject faults into every connection, see [`Client::with_chaos`]
+    pub fn with_chaos(mut self, chaos: ChaosConfig) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Capture the traffic of every connection to one file, see
+    /// [`Client::with_capture`]
+    pub fn with_capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Write a qlog trace of every connection, see [`Client::with_qlog`]
+    pub fn with_qlog(mut self, qlog: Qlog) -> Self {
+        self.qlog = Some(qlog);
+        self
+    }
+
+    /// Run `interceptor` on every outbound message of every connection, see
+    /// [`Client::with_interceptor`]
+    pub fn with_interceptor(mut self, interceptor: impl Interceptor) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Answer the server's auth challenge with `provider` on every connection
+    pub fn with_auth(mut self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.auth = Some(provider);
+        self
+    }
+
+    /// Sign and check messages with `keys` on every connection, see
+    /// [`Client::with_signing`]
+    pub fn with_signing(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.signing = Some(keys);
+        self
+    }
+
+    /// Offer a fresh session key of `sealer` in the auth handshake of every
+    /// connection, for peers to seal payloads to
+    ///
+    /// Only takes effect along with [`with_auth`](Self::with_auth), as
+    /// session keys travel in the handshake.
+    pub fn with_sealer(mut self, sealer: Sealer) -> Self {
+        self.sealer = Some(sealer);
+        self
+    }
+
+    /// The sealer set with [`with_sealer`](Self::with_sealer), which opens
+    /// what peers sealed to its session keys
+    pub fn sealer(&self) -> Option<&Sealer> {
+        self.sealer.as_ref()
+    }
+
+    /// Reach the server through `relays` rather than n0's public ones
+    pub fn with_relays(mut self, relays: Relays) -> Self {
+        self.relays = relays;
+        self
+    }
+
+    /// Look the server's address up as `lookup` says, when it was given by
+    /// id alone
+    pub fn with_lookup(mut self, lookup: AddrLookup) -> Self {
+        self.lookup = lookup;
+        self
+    }
+
+    /// Tune the QUIC transport of every connection, see [`TransportTuning`]
+    pub fn with_tuning(mut self, tuning: TransportTuning) -> Self {
+        self.tuning = tuning;
+        self
+    }
+
+    /// Dial as `secret_key` rather than a fresh identity, so the server
+    /// recognizes this client as an earlier one with the same key
+    pub fn with_secret_key(mut self, secret_key: SecretKey) -> Self {
+        self.secret_key = Some(secret_key);
+        self
+    }
+
+    /// Called every time a connection is established
+    pub fn on_connect(mut self, hook: impl Fn(&Connection) + Send + Sync + 'static) -> Self {
+        self.on_connect = Some(Box::new(hook));
+        self
+    }
+
+    /// Called every time an established connection is lost
+    pub fn on_disconnect(
+        mut self,
+        hook: impl Fn(&ConnectionError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_disconnect = Some(Box::new(hook));
+        self
+    }
+
+    /// What happens on every connection this client makes, across reconnects
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ConnEvent> {
+        self.events.subscribe()
+    }
+
+    /// The current client, dialing a new connection first if there is none
+    pub async fn client(&self) -> Result<Arc<Client<C>>> {
+        let mut current = self.current.lock().await;
+        if let Some(client) = current.as_ref() {
+            match client.connection().close_reason() {
+                None => return Ok(client.clone()),
+                Some(reason) => self.disconnected(&reason),
+            }
+        }
+
+        let client = Arc::new(self.dial().await?);
+        *current = Some(client.clone());
+        Ok(client)
+    }
+
+    /// Close the current connection, if any, and wait for the endpoint to
+    /// close, so the server learns of it instead of timing out
+    pub async fn close(&self) {
+        if let Some(client) = self.current.lock().await.take() {
+            CloseReason::Normal.close(client.connection(), "client closing");
+        }
+        if let Some(endpoint) = self.endpoint.get() {
+            endpoint.close().await;
+        }
+    }
+
+    /// Send `msg` and wait for its response, reconnecting as often as needed
+    pub async fn request(&self, msg: Message) -> Result<Message> {
+        match self.request_early(&msg).await {
+            Some(Message::Error { code, detail }) => {
+                return Err(RemoteError::new(code, detail).into());
+            }
+            Some(response) => return Ok(response),
+            None => {}
+        }
+        loop {
+            let client = self.client().await?;
+            match client.request(msg.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(e) => match client.connection().close_reason() {
+                    Some(reason) => {
+                        self.invalidate(&client, &reason).await;
+                        if is_refusal(&reason) {
+                            return Err(anyerr!(e, "server refused the connection"));
+                        }
+                    }
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// The response to `msg`, sent as 0-RTT data on a new connection, if 0-RTT
+    /// is on and applies and there is no connection yet
+    ///
+    /// Does nothing on failure, to leave reconnecting to [`Self::request`].
+    async fn request_early(&self, msg: &Message) -> Option<Message> {
+        if !self.zero_rtt || self.auth.is_some() || !msg.is_replay_safe() {
+            return None;
+        }
+        let mut current = self.current.lock().await;
+        if let Some(client) = current.as_ref() {
+            match client.connection().close_reason() {
+                None => return None,
+                Some(reason) => self.disconnected(&reason),
+            }
+            *current = None;
+        }
+
+        let dialed = async {
+            let endpoint = self.endpoint().await?;
+            let (conn, response) =
+                dial_early(endpoint, self.addr.clone(), &self.codec, &self.config, msg).await?;
+            Ok::<_, n0_error::AnyError>((self.wrap(conn).await?, response))
+        };
+        match dialed.await {
+            Ok((client, response)) => {
+                *current = Some(Arc::new(client));
+                response
+            }
+            Err(e) => {
+                debug!("dialing with 0-RTT data failed: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Forget `client` if it is still the current one
+    async fn invalidate(&self, client: &Arc<Client<C>>, reason: &ConnectionError) {
+        let mut current = self.current.lock().await;
+        if current.as_ref().is_some_and(|c| Arc::ptr_eq(c, client)) {
+            *current = None;
+            self.disconnected(reason);
+        }
+    }
+
+    fn disconnected(&self, reason: &ConnectionError) {
+        if let Some(hook) = &self.on_disconnect {
+            hook(reason);
+        }
+    }
+
+    async fn endpoint(&self) -> Result<&Endpoint> {
+        self.endpoint
+            .get_or_try_init(|| async {
+                let mut builder = self.lookup.apply(self.relays.apply(Endpoint::builder()));
+                if let Some(secret_key) = &self.secret_key {
+                    builder = builder.secret_key(secret_key.clone());
+                }
+                let endpoint = self.tuning.apply(builder).bind().await?;
+                Ok(endpoint)
+            })
+            .await
+    }
+
+    async fn dial(&self) -> Result<Client<C>> {
+        let endpoint = self.endpoint().await?;
+
+        let mut attempt = 0;
+        let conn = loop {
+            match dial(endpoint, self.addr.clone()).await {
+                Ok(conn) => break conn,
+                Err(e) => {
+                    if self.backoff.max_retries.is_some_and(|max| attempt >= max) {
+                        return Err(anyerr!(e, "giving up after {} attempts", attempt + 1));
+                    }
+                    let delay = self.backoff.delay(attempt);
+                    warn!(attempt, ?delay, "connecting failed, retrying: {:#}", e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        };
+        self.wrap(conn).await
+    }
+
+    /// A client for the freshly dialed `conn`, authenticated and set up like
+    /// every other connection of this one
+    async fn wrap(&self, conn: Connection) -> Result<Client<C>> {
+        match (&self.auth, &self.sealer) {
+            (Some(provider), Some(sealer)) => {
+                authenticate_sealing(&conn, &self.codec, provider.as_ref(), sealer).await?
+            }
+            (Some(provider), None) => authenticate(&conn, &self.codec, provider.as_ref()).await?,
+            (None, _) => {}
+        }
+        if let Some(hook) = &self.on_connect {
+            hook(&conn);
+        }
+        let mut client = Client::with_events(
+            conn,
+            self.codec.clone(),
+            self.config.clone(),
+            self.events.clone(),
+        );
+        if let Some(config) = self.heartbeat {
+            client = client.with_heartbeat(config);
+        }
+        if let Some(timeout) = self.idle_timeout {
+            client = client.with_idle_timeout(timeout);
+        }
+        if let Some(window) = self.coalesce {
+            client = client.with_coalescing(window);
+        }
+        if let Some(size) = self.stream_pool {
+            client = client.with_stream_pool(size);
+        }
+        if let Some(chaos) = self.chaos {
+            client = client.with_chaos(chaos);
+        }
+        if let Some(capture) = &self.capture {
+            client = client.with_capture(capture.clone());
+        }
+        if let Some(qlog) = &self.qlog {
+            client = client.with_qlog(qlog.clone());
+        }
+        if let Some(keys) = &self.signing {
+            client = client.with_signing_keys(keys.clone());
+        }
+        Ok(client.with_interceptors(self.interceptors.iter().cloned()))
+    }
+}
+
+/// Whether the server closed the connection in a way that reconnecting cannot fix
+fn is_refusal(reason: &ConnectionError) -> bool {
+    RemoteClose::of(reason).is_some_and(|close| {
+        close.is(CloseReason::AccessDenied) || close.is(CloseReason::AuthFailed)
+    })
+}
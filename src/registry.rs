@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use futures::future::join_all;
+use iroh::{EndpointId, endpoint::Connection};
+use n0_error::Result;
+
+use crate::{
+    codec::Codec,
+    heartbeat::Liveness,
+    protocol::{Message, MessageEnvelope, PUSH_ID, connection_version, send_message},
+    replay::PeerCounters,
+    stats::ConnectionStats,
+};
+
+/// A connected peer as seen by the server
+#[derive(Debug, Clone)]
+pub struct PeerHandle {
+    pub conn: Connection,
+    pub liveness: Liveness,
+    /// Streams the peer opened so far, counted by the server
+    pub streams: Arc<AtomicU64>,
+}
+
+impl PeerHandle {
+    /// Transport statistics of the connection, as seen by the server
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats::of(&self.conn, self.streams.load(Ordering::Relaxed))
+    }
+}
+
+/// The live connections of a server, keyed by the remote [`EndpointId`]
+///
+/// A peer that reconnects replaces its previous entry; removing an entry only
+/// succeeds for the connection that inserted it.
+///
+/// The registry also numbers what the server sends each peer, for the peer's
+/// [`ReplayWindow`](crate::replay::ReplayWindow).
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    peers: Arc<Mutex<HashMap<EndpointId, PeerHandle>>>,
+    counters: PeerCounters,
+}
+
+impl Registry {
+    pub fn insert(&self, peer: PeerHandle) {
+        let id = peer.conn.remote_id();
+        self.peers.lock().expect("poisoned").insert(id, peer);
+    }
+
+    /// Remove `conn` if it is still the registered connection of its peer,
+    /// returning whether it was
+    pub fn remove(&self, conn: &Connection) -> bool {
+        let mut peers = self.peers.lock().expect("poisoned");
+        let id = conn.remote_id();
+        let current = peers
+            .get(&id)
+            .is_some_and(|peer| peer.conn.stable_id() == conn.stable_id());
+        if current {
+            peers.remove(&id);
+        }
+        current
+    }
+
+    pub fn get(&self, id: &EndpointId) -> Option<PeerHandle> {
+        self.peers.lock().expect("poisoned").get(id).cloned()
+    }
+
+    pub fn ids(&self) -> Vec<EndpointId> {
+        self.peers
+            .lock()
+            .expect("poisoned")
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    pub fn peers(&self) -> Vec<PeerHandle> {
+        self.peers
+            .lock()
+            .expect("poisoned")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.lock().expect("poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The counters of the messages sent to each peer
+    pub(crate) fn counters(&self) -> &PeerCounters {
+        &self.counters
+    }
+
+    /// Push `msg` to every connected peer on its own fresh stream
+    ///
+    /// All sends run concurrently and fail independently, so a slow or broken
+    /// peer only delays its own delivery. Returns the outcome for each peer.
+    pub async fn broadcast<C: Codec>(
+        &self,
+        codec: &C,
+        msg: &Message,
+    ) -> Vec<(EndpointId, Result<()>)> {
+        self.broadcast_to(codec, msg, self.peers()).await
+    }
+
+    /// Like [`broadcast`](Self::broadcast), skipping the peer `except`
+    pub async fn broadcast_except<C: Codec>(
+        &self,
+        codec: &C,
+        msg: &Message,
+        except: EndpointId,
+    ) -> Vec<(EndpointId, Result<()>)> {
+        let mut peers = self.peers();
+        peers.retain(|peer| peer.conn.remote_id() != except);
+        self.broadcast_to(codec, msg, peers).await
+    }
+
+    /// Like [`broadcast`](Self::broadcast), only to the connected peers
+    /// among `ids`
+    pub async fn multicast<C: Codec>(
+        &self,
+        codec: &C,
+        msg: &Message,
+        ids: &[EndpointId],
+    ) -> Vec<(EndpointId, Result<()>)> {
+        let peers = ids.iter().filter_map(|id| self.get(id)).collect();
+        self.broadcast_to(codec, msg, peers).await
+    }
+
+    async fn broadcast_to<C: Codec>(
+        &self,
+        codec: &C,
+        msg: &Message,
+        peers: Vec<PeerHandle>,
+    ) -> Vec<(EndpointId, Result<()>)> {
+        let sends = peers.into_iter().map(|peer| {
+            let mut envelope = MessageEnvelope::new(PUSH_ID, msg.clone());
+            envelope.counter = Some(self.counters.next(peer.conn.remote_id()));
+            async move {
+                let version = connection_version(&peer.conn);
+                let result = match envelope.wire(codec, version) {
+                    Ok(wire) => send_message(&peer.conn, codec, &wire).await,
+                    Err(e) => Err(e),
+                };
+                (peer.conn.remote_id(), result)
+            }
+        });
+        join_all(sends).await
+    }
+}
@@ -0,0 +1,65 @@
+use std::str::FromStr;
+
+use iroh::{Endpoint, RelayMap, RelayMode, RelayUrl, endpoint};
+use n0_error::{AnyError, Result, StdResultExt};
+
+// ====================
+// Relay Selection
+// ====================
+
+/// Which relays an endpoint uses to reach peers it has no direct path to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Relays {
+    /// The public relays run by n0
+    #[default]
+    Default,
+    /// A single relay of your own
+    Custom(RelayUrl),
+    /// No relay at all, so only peers reachable directly can be dialed
+    Disabled,
+    /// Relays only, never attempting a direct path: the given relay, or n0's
+    /// if `None`
+    #[cfg(feature = "relay-only")]
+    Only(Option<RelayUrl>),
+}
+
+impl Relays {
+    /// Configure `builder` to use these relays
+    pub fn apply(&self, builder: endpoint::Builder) -> endpoint::Builder {
+        match self {
+            Relays::Default => builder.relay_mode(RelayMode::Default),
+            Relays::Custom(url) => builder.relay_mode(custom(url)),
+            Relays::Disabled => builder.relay_mode(RelayMode::Disabled),
+            #[cfg(feature = "relay-only")]
+            Relays::Only(url) => builder
+                .relay_mode(url.as_ref().map_or(RelayMode::Default, custom))
+                .path_selection(endpoint::PathSelection::RelayOnly),
+        }
+    }
+
+    /// Bind a fresh client endpoint using these relays
+    pub async fn bind(&self) -> Result<Endpoint> {
+        let endpoint = self.apply(Endpoint::builder()).bind().await?;
+        Ok(endpoint)
+    }
+}
+
+/// `default`, `disabled` or the URL of a custom relay
+impl FromStr for Relays {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "default" => Ok(Relays::Default),
+            "disabled" => Ok(Relays::Disabled),
+            url => url
+                .parse()
+                .map(Relays::Custom)
+                .std_context("expected default, disabled or a relay URL"),
+        }
+    }
+}
+
+fn custom(url: &RelayUrl) -> RelayMode {
+    RelayMode::Custom(RelayMap::from(url.clone()))
+}
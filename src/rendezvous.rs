@@ -0,0 +1,41 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use iroh::{EndpointAddr, EndpointId};
+
+/// The addresses connected peers announced for reaching them directly
+///
+/// Used by the server to introduce peers to each other, see
+/// [`Message::Introduce`](crate::Message::Introduce). A peer that announced
+/// nothing is handed out by id alone, which is enough to dial it through
+/// discovery. Clones share the same directory.
+#[derive(Debug, Clone, Default)]
+pub struct Directory(Arc<Mutex<HashMap<EndpointId, EndpointAddr>>>);
+
+impl Directory {
+    /// Record the addresses `peer` can be reached at
+    ///
+    /// Only the transport addresses of `addr` are kept: a peer can only
+    /// announce itself.
+    pub fn announce(&self, peer: EndpointId, addr: &EndpointAddr) {
+        let addr = EndpointAddr::from_parts(peer, addr.addrs.iter().cloned());
+        self.0.lock().expect("poisoned").insert(peer, addr);
+    }
+
+    /// How to reach `peer`, as far as it told us
+    pub fn addr(&self, peer: EndpointId) -> EndpointAddr {
+        self.0
+            .lock()
+            .expect("poisoned")
+            .get(&peer)
+            .cloned()
+            .unwrap_or_else(|| EndpointAddr::new(peer))
+    }
+
+    /// Forget what `peer` announced
+    pub fn remove(&self, peer: &EndpointId) {
+        self.0.lock().expect("poisoned").remove(peer);
+    }
+}
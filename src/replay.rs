@@ -0,0 +1,187 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use iroh::EndpointId;
+
+use crate::{
+    heartbeat::is_heartbeat,
+    protocol::{COUNTER_VERSION, ErrorCode, Message, MessageEnvelope},
+};
+
+/// How far below the highest counter seen a [`ReplayWindow`] still accepts
+/// one it has not seen, for messages overtaken by later ones
+pub const REPLAY_WINDOW: u64 = 1024;
+
+/// Most signers a [`ReplayWindow`] keeps the counters of, see
+/// [`ReplayWindow::accept_from`]
+pub const MAX_SIGNERS: usize = 1024;
+
+// ====================
+// Counters
+// ====================
+
+/// Numbers the messages one end sends on a connection, for the peer's
+/// [`ReplayWindow`]
+///
+/// Counting starts at the current time in microseconds, so an author that
+/// reconnects carries on above the counters of its earlier connections,
+/// which the peers its messages are relayed to may still remember.
+#[derive(Debug, Clone)]
+pub struct MessageCounter(Arc<AtomicU64>);
+
+impl Default for MessageCounter {
+    fn default() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_micros() as u64)
+            .unwrap_or_default();
+        Self(Arc::new(AtomicU64::new(now)))
+    }
+}
+
+impl MessageCounter {
+    /// The counter of the next message
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// A [`MessageCounter`] for each peer a server sends to
+///
+/// A peer forgotten once it disconnects starts again above every counter
+/// handed out so far, so a connection it still has open never sees one go
+/// backwards.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerCounters(Arc<Mutex<Counters>>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    next: HashMap<EndpointId, u64>,
+    /// Above every counter handed out so far
+    floor: u64,
+}
+
+impl PeerCounters {
+    pub(crate) fn next(&self, peer: EndpointId) -> u64 {
+        let mut counters = self.0.lock().expect("poisoned");
+        let floor = counters.floor;
+        let next = counters.next.entry(peer).or_insert(floor);
+        let counter = *next;
+        *next += 1;
+        counters.floor = counters.floor.max(counter + 1);
+        counter
+    }
+
+    pub(crate) fn remove(&self, peer: &EndpointId) {
+        self.0.lock().expect("poisoned").next.remove(peer);
+    }
+}
+
+/// Number `envelope` with `counter`, unless it already carries a signature,
+/// which covers the counter it was signed with
+pub(crate) fn stamp(envelope: &mut MessageEnvelope, counter: impl FnOnce() -> u64) {
+    if envelope.signature.is_none() {
+        envelope.counter = Some(counter());
+    }
+}
+
+// ====================
+// Replay Window
+// ====================
+
+/// The counters of the messages received on one connection, refusing any
+/// that comes again
+///
+/// Messages travel on streams of their own and may arrive out of order, so a
+/// counter below the highest seen is still accepted once, as long as it is
+/// within [`REPLAY_WINDOW`] of it. Envelopes without a counter are refused on
+/// connections speaking protocol version 6 on, but for heartbeats, which are
+/// sent outside the interceptors; peers older than that never send one.
+///
+/// Datagrams carry bare messages rather than envelopes, so they have no
+/// counter and no window covers them.
+///
+/// A counter only orders the messages of one connection: a message replayed
+/// on a connection of its own starts a fresh window. Since the signature of
+/// a [`MessageEnvelope`] covers its counter, a signed message cannot be
+/// renumbered to slip past a window either.
+///
+/// Signed messages are counted apart for each signing key, as one relayed
+/// to this end keeps the counter its author gave it on a connection of its
+/// own. Only a key whose signature was verified gets a window of its own on
+/// a server, see [`refusal`](Self::refusal), so a peer cannot make up keys
+/// to fill its memory or to slip past its own window.
+#[derive(Debug, Default)]
+pub struct ReplayWindow(Mutex<HashMap<Option<[u8; 32]>, BTreeSet<u64>>>);
+
+impl ReplayWindow {
+    /// Whether `counter` of an unsigned message is new, remembering it if it
+    /// is
+    pub fn accept(&self, counter: u64) -> bool {
+        self.accept_from(None, counter)
+    }
+
+    /// Whether `counter` of a message signed with `signer`, or unsigned if
+    /// that is `None`, is new, remembering it if it is
+    ///
+    /// Once [`MAX_SIGNERS`] signers are remembered, messages of any other are
+    /// refused.
+    pub fn accept_from(&self, signer: Option<&[u8; 32]>, counter: u64) -> bool {
+        let signer = signer.copied();
+        let mut windows = self.0.lock().expect("poisoned");
+        if !windows.contains_key(&signer) && windows.len() >= MAX_SIGNERS {
+            return false;
+        }
+        let seen = windows.entry(signer).or_default();
+        let highest = seen.last().copied();
+        if highest.is_some_and(|highest| counter.saturating_add(REPLAY_WINDOW) <= highest) {
+            return false;
+        }
+        if !seen.insert(counter) {
+            return false;
+        }
+        if highest.is_none_or(|highest| counter > highest) {
+            let floor = counter.saturating_sub(REPLAY_WINDOW);
+            *seen = seen.split_off(&floor);
+        }
+        true
+    }
+
+    /// The answer to `envelope`, received on a connection speaking `version`,
+    /// if it was seen before, is too old to tell or should have had a counter
+    ///
+    /// Its counter is looked up in the window of `signer`, the key that
+    /// signed it, or in the one of unsigned messages if that is `None`.
+    pub(crate) fn refusal(
+        &self,
+        version: u32,
+        envelope: &MessageEnvelope,
+        signer: Option<&[u8; 32]>,
+    ) -> Option<Message> {
+        let Some(counter) = envelope.counter else {
+            let exempt = version < COUNTER_VERSION || is_keepalive(envelope);
+            return (!exempt).then(|| Message::Error {
+                code: ErrorCode::Unauthorized,
+                detail: "message without a counter".to_string(),
+            });
+        };
+        match self.accept_from(signer, counter) {
+            true => None,
+            false => Some(Message::Error {
+                code: ErrorCode::Unauthorized,
+                detail: format!("replayed message (counter {counter})"),
+            }),
+        }
+    }
+}
+
+/// Whether `envelope` is a heartbeat, which is sent without a counter
+fn is_keepalive(envelope: &MessageEnvelope) -> bool {
+    is_heartbeat(envelope) && matches!(envelope.body, Message::Ping { .. } | Message::Pong { .. })
+}
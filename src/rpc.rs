@@ -0,0 +1,528 @@
+use std::{
+    collections::HashMap, fmt, future::Future, marker::PhantomData, sync::Arc, time::Duration,
+};
+
+use futures::{
+    FutureExt, Stream, StreamExt,
+    future::{BoxFuture, ready},
+    stream::{self, BoxStream},
+};
+use iroh::{
+    EndpointAddr,
+    endpoint::{Connection, RecvStream, SendStream, VarInt},
+    protocol::{AcceptError, ProtocolHandler},
+};
+use n0_error::{Result, StdResultExt};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tracing::{Instrument, debug, debug_span, info, info_span, warn};
+
+use crate::{
+    access::AccessPolicy,
+    auth::{AuthProvider, AuthVerifier, authenticate, challenge},
+    client::{RequestTimedOut, connect_with_alpn},
+    close::CloseReason,
+    codec::{Bincode, Codec},
+    framed::FramedConnection,
+    limits::{HandlerLimits, SHUTDOWN_GRACE},
+    protocol::{ErrorCode, MAX_MESSAGE_SIZE, Message, RemoteError},
+};
+
+pub const RPC_ALPN: &[u8] = b"iroh-example/rpc/0";
+
+/// Application code a client stops a call's stream with to cancel it
+pub const CALL_CANCELLED: VarInt = VarInt::from_u32(2);
+
+/// A typed request/response pair served under a unique method name
+pub trait Rpc: Send + Sync + 'static {
+    const NAME: &'static str;
+    type Req: Serialize + DeserializeOwned + Send + 'static;
+    type Resp: Serialize + DeserializeOwned + Send + 'static;
+}
+
+/// The echo protocol expressed as an RPC: the response is [`Message::reply`]
+#[derive(Debug)]
+pub struct EchoRpc;
+
+impl Rpc for EchoRpc {
+    const NAME: &'static str = "echo";
+    type Req = Message;
+    type Resp = Message;
+}
+
+/// What the client writes on the request half of an RPC stream
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcRequest {
+    method: String,
+    payload: Vec<u8>,
+    /// Whether the caller reads the responses as frames, see
+    /// [`RpcClient::call_streaming`]
+    ///
+    /// Trails the request, so servers that predate streaming ignore it.
+    #[serde(default)]
+    streaming: bool,
+}
+
+/// An [`RpcRequest`] as sent by clients that predate streaming
+#[derive(Debug, Deserialize)]
+struct LegacyRpcRequest {
+    method: String,
+    payload: Vec<u8>,
+}
+
+impl RpcRequest {
+    fn decode<C: Codec>(codec: &C, bytes: &[u8]) -> Result<Self> {
+        codec.decode(bytes).or_else(|e| {
+            let Ok(LegacyRpcRequest { method, payload }) = codec.decode(bytes) else {
+                return Err(e);
+            };
+            Ok(Self {
+                method,
+                payload,
+                streaming: false,
+            })
+        })
+    }
+}
+
+/// What the server writes back: the encoded response or why there is none
+///
+/// A streaming call gets one per frame, and ends after the first error.
+type RpcResponse = std::result::Result<Vec<u8>, (ErrorCode, String)>;
+
+type ErasedHandler<C> =
+    Arc<dyn Fn(C, Vec<u8>) -> BoxFuture<'static, Result<Vec<u8>>> + Send + Sync>;
+
+type ErasedStreamHandler<C> =
+    Arc<dyn Fn(C, Vec<u8>) -> BoxStream<'static, Result<Vec<u8>>> + Send + Sync>;
+
+#[derive(Clone)]
+enum Handler<C> {
+    Unary(ErasedHandler<C>),
+    Streaming(ErasedStreamHandler<C>),
+}
+
+// ====================
+// Server
+// ====================
+
+/// Dispatches RPC streams to the handler registered for their method
+///
+/// Each call runs on its own bidirectional stream: the client writes one
+/// request and finishes, the server writes one response and finishes.
+#[derive(Clone)]
+pub struct RpcServer<C = Bincode> {
+    codec: C,
+    handlers: HashMap<&'static str, Handler<C>>,
+    access: AccessPolicy,
+    auth: Option<Arc<dyn AuthVerifier>>,
+    limits: HandlerLimits,
+}
+
+impl<C: Codec> fmt::Debug for RpcServer<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcServer")
+            .field("codec", &self.codec)
+            .field("methods", &self.handlers.keys().collect::<Vec<_>>())
+            .field("access", &self.access)
+            .field("auth", &self.auth)
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+impl<C: Codec> RpcServer<C> {
+    pub fn new(codec: C) -> Self {
+        Self {
+            codec,
+            handlers: HashMap::new(),
+            access: AccessPolicy::default(),
+            auth: None,
+            limits: HandlerLimits::default(),
+        }
+    }
+
+    /// Close connections from peers `access` does not allow
+    pub fn with_access(mut self, access: AccessPolicy) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Require every client to pass `verifier`'s challenge before serving calls
+    pub fn with_auth(mut self, verifier: Arc<dyn AuthVerifier>) -> Self {
+        self.auth = Some(verifier);
+        self
+    }
+
+    /// Bound how many calls are served at once, see [`HandlerLimits`]
+    pub fn with_limits(mut self, limits: HandlerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Serve `R` with `handler`, replacing any handler already registered for it
+    pub fn register<R, F, Fut>(mut self, handler: F) -> Self
+    where
+        R: Rpc,
+        F: Fn(R::Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R::Resp>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let erased: ErasedHandler<C> = Arc::new(move |codec: C, payload: Vec<u8>| {
+            let handler = handler.clone();
+            async move {
+                let req: R::Req = codec.decode(&payload)?;
+                let resp = handler(req).await?;
+                codec.encode(&resp)
+            }
+            .boxed()
+        });
+        self.handlers.insert(R::NAME, Handler::Unary(erased));
+        self
+    }
+
+    /// Serve `R` with `handler`, whose responses are sent to the caller as
+    /// they are produced, replacing any handler already registered for it
+    ///
+    /// An error ends the stream. Such methods are called with
+    /// [`RpcClient::call_streaming`].
+    pub fn register_streaming<R, F, S>(mut self, handler: F) -> Self
+    where
+        R: Rpc,
+        F: Fn(R::Req) -> S + Send + Sync + 'static,
+        S: Stream<Item = Result<R::Resp>> + Send + 'static,
+    {
+        let erased: ErasedStreamHandler<C> = Arc::new(
+            move |codec: C, payload: Vec<u8>| match codec.decode::<R::Req>(&payload) {
+                Ok(req) => handler(req).map(move |resp| codec.encode(&resp?)).boxed(),
+                Err(e) => stream::once(ready(Err(e))).boxed(),
+            },
+        );
+        self.handlers.insert(R::NAME, Handler::Streaming(erased));
+        self
+    }
+
+    /// Serve every call on `connection` until it closes
+    async fn serve(&self, connection: Connection) {
+        if !self.access.is_allowed(&connection.remote_id()) {
+            info!("rejected connection");
+            CloseReason::AccessDenied.close(&connection, "access denied");
+            return;
+        }
+        if let Some(auth) = &self.auth
+            && let Err(e) = challenge(&connection, &self.codec, auth.as_ref()).await
+        {
+            info!("authentication failed: {:#}", e);
+            return;
+        }
+        let mut handlers = self.limits.connection();
+        while let Ok((send, recv)) = connection.accept_bi().await
+            && let Some(permit) = handlers.reserve(&connection).await
+        {
+            let span = debug_span!("stream", id = %recv.id());
+            let server = self.clone();
+            let call = async move {
+                if let Err(e) = server.serve_call(send, recv).await {
+                    warn!("error serving call: {:#}", e);
+                }
+            };
+            handlers.spawn(permit, call.instrument(span));
+        }
+        handlers.join().await;
+    }
+
+    /// Answer the call on `send` and `recv`, abandoning its handler if the
+    /// caller stops waiting for the response
+    async fn serve_call(&self, mut send: SendStream, mut recv: RecvStream) -> Result<()> {
+        let bytes = recv.read_to_end(MAX_MESSAGE_SIZE).await.anyerr()?;
+        let request = match RpcRequest::decode(&self.codec, &bytes) {
+            Ok(request) => request,
+            Err(e) => {
+                let response: RpcResponse = Err((ErrorCode::Malformed, format!("{e:#}")));
+                return self.reply(send, response).await;
+            }
+        };
+        if request.streaming {
+            let frames = FramedConnection::from_streams(send, recv, self.codec.clone());
+            return self.serve_streaming(frames, request).await;
+        }
+        let response = tokio::select! {
+            response = self.dispatch(request) => response,
+            stopped = send.stopped() => {
+                match stopped {
+                    Ok(Some(CALL_CANCELLED)) => debug!("call cancelled, abandoning it"),
+                    _ => debug!("caller gave up, abandoning call"),
+                }
+                return Ok(());
+            }
+        };
+        self.reply(send, response).await
+    }
+
+    async fn reply(&self, mut send: SendStream, response: RpcResponse) -> Result<()> {
+        send.write_all(&self.codec.encode(&response)?)
+            .await
+            .anyerr()?;
+        send.finish().anyerr()?;
+        Ok(())
+    }
+
+    async fn dispatch(&self, request: RpcRequest) -> RpcResponse {
+        match self.handlers.get(request.method.as_str()) {
+            Some(Handler::Unary(handler)) => handler(self.codec.clone(), request.payload)
+                .await
+                .map_err(|e| (ErrorCode::Internal, format!("{e:#}"))),
+            Some(Handler::Streaming(_)) => Err((
+                ErrorCode::Malformed,
+                format!("method {:?} streams its responses", request.method),
+            )),
+            None => Err(unknown_method(&request.method)),
+        }
+    }
+
+    /// Send the responses to a streaming call as frames on `frames`
+    ///
+    /// A plain method answers with a single frame, so it can be called
+    /// either way.
+    async fn serve_streaming(
+        &self,
+        mut frames: FramedConnection<C>,
+        request: RpcRequest,
+    ) -> Result<()> {
+        let codec = self.codec.clone();
+        let mut responses = match self.handlers.get(request.method.as_str()) {
+            Some(Handler::Streaming(handler)) => handler(codec, request.payload),
+            Some(Handler::Unary(handler)) => handler(codec, request.payload).into_stream().boxed(),
+            None => {
+                frames
+                    .send::<RpcResponse>(&Err(unknown_method(&request.method)))
+                    .await?;
+                return frames.finish();
+            }
+        };
+        loop {
+            let response = tokio::select! {
+                response = responses.next() => response,
+                stopped = frames.stopped() => {
+                    match stopped {
+                        Ok(Some(CALL_CANCELLED)) => debug!("call cancelled, abandoning it"),
+                        _ => debug!("caller gave up, abandoning call"),
+                    }
+                    return Ok(());
+                }
+            };
+            let Some(response) = response else {
+                break;
+            };
+            let response: RpcResponse =
+                response.map_err(|e| (ErrorCode::Internal, format!("{e:#}")));
+            let failed = response.is_err();
+            frames.send(&response).await?;
+            if failed {
+                break;
+            }
+        }
+        frames.finish()
+    }
+}
+
+fn unknown_method(method: &str) -> (ErrorCode, String) {
+    (ErrorCode::NotFound, format!("unknown method {method:?}"))
+}
+
+impl<C: Codec> ProtocolHandler for RpcServer<C> {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let span = info_span!("rpc", remote = %connection.remote_id().fmt_short());
+        self.serve(connection).instrument(span).await;
+        Ok(())
+    }
+
+    async fn shutdown(&self) {
+        self.limits.drain(SHUTDOWN_GRACE).await;
+    }
+}
+
+// ====================
+// Client
+// ====================
+
+/// Calls typed RPCs on a server, one bidirectional stream per call
+#[derive(Debug, Clone)]
+pub struct RpcClient<C = Bincode> {
+    conn: Connection,
+    codec: C,
+}
+
+impl<C: Codec> RpcClient<C> {
+    /// Connect to the RPC server at `addr`
+    pub async fn connect(addr: EndpointAddr, codec: C) -> Result<Self> {
+        let conn = connect_with_alpn(addr, RPC_ALPN).await?;
+        Ok(Self::new(conn, codec))
+    }
+
+    /// Like [`RpcClient::connect`], answering the server's auth challenge
+    /// with `provider`
+    pub async fn connect_with_auth(
+        addr: EndpointAddr,
+        codec: C,
+        provider: &dyn AuthProvider,
+    ) -> Result<Self> {
+        let conn = connect_with_alpn(addr, RPC_ALPN).await?;
+        authenticate(&conn, &codec, provider).await?;
+        Ok(Self::new(conn, codec))
+    }
+
+    /// Wrap a connection that was established with [`RPC_ALPN`]
+    pub fn new(conn: Connection, codec: C) -> Self {
+        Self { conn, codec }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Call `R` with `req` and wait for its response
+    ///
+    /// An error from the server is returned as a [`RemoteError`]. Dropping
+    /// the future before the response arrived cancels the call, see
+    /// [`CallHandle`].
+    pub async fn call<R: Rpc>(&self, req: R::Req) -> Result<R::Resp> {
+        self.start::<R>(req).await?.response().await
+    }
+
+    /// Send a call of `R` with `req`, returning a handle to wait for its
+    /// response or cancel it
+    pub async fn start<R: Rpc>(&self, req: R::Req) -> Result<CallHandle<R, C>> {
+        let (mut send, recv) = self.conn.open_bi().await.anyerr()?;
+
+        let request = RpcRequest {
+            method: R::NAME.to_string(),
+            payload: self.codec.encode(&req)?,
+            streaming: false,
+        };
+        send.write_all(&self.codec.encode(&request)?)
+            .await
+            .anyerr()?;
+        send.finish().anyerr()?;
+        Ok(CallHandle {
+            recv,
+            codec: self.codec.clone(),
+            answered: false,
+            rpc: PhantomData,
+        })
+    }
+
+    /// Call `R` with `req` and read its responses as they arrive, see
+    /// [`RpcServer::register_streaming`]
+    ///
+    /// The stream ends once the server is done, after yielding the error if
+    /// one ended it. Dropping the stream early cancels the call.
+    pub async fn call_streaming<R: Rpc>(
+        &self,
+        req: R::Req,
+    ) -> Result<impl Stream<Item = Result<R::Resp>> + use<R, C>> {
+        let (mut send, recv) = self.conn.open_bi().await.anyerr()?;
+        let request = RpcRequest {
+            method: R::NAME.to_string(),
+            payload: self.codec.encode(&req)?,
+            streaming: true,
+        };
+        // The request is written as for any call, only the responses are framed
+        send.write_all(&self.codec.encode(&request)?)
+            .await
+            .anyerr()?;
+        send.finish().anyerr()?;
+
+        let responses = Responses {
+            frames: FramedConnection::from_streams(send, recv, self.codec.clone()),
+            codec: self.codec.clone(),
+            done: false,
+        };
+        Ok(stream::unfold(responses, |mut responses| async move {
+            if responses.done {
+                return None;
+            }
+            let response = match responses.frames.recv::<RpcResponse>().await {
+                Ok(Some(Ok(payload))) => {
+                    return Some((responses.codec.decode(&payload), responses));
+                }
+                Ok(Some(Err((code, detail)))) => Err(RemoteError::new(code, detail).into()),
+                // Stopping a stream that was read to the end is a no-op
+                Ok(None) => return None,
+                Err(e) => Err(e),
+            };
+            responses.done = true;
+            Some((response, responses))
+        }))
+    }
+
+    /// Like [`call`](Self::call), but give up if no response arrives within
+    /// `timeout`
+    ///
+    /// Giving up cancels the call. Fails with [`RequestTimedOut`].
+    pub async fn call_with_timeout<R: Rpc>(
+        &self,
+        req: R::Req,
+        timeout: Duration,
+    ) -> Result<R::Resp> {
+        match tokio::time::timeout(timeout, self.call::<R>(req)).await {
+            Ok(resp) => resp,
+            Err(_) => Err(RequestTimedOut::new(timeout).into()),
+        }
+    }
+}
+
+/// A call in flight, see [`RpcClient::start`]
+///
+/// Cancelling the call, or dropping the handle before the response arrived,
+/// stops the stream with [`CALL_CANCELLED`]. The server then drops the
+/// handler's future, so work it has not done yet is never done.
+#[derive(Debug)]
+pub struct CallHandle<R, C = Bincode> {
+    recv: RecvStream,
+    codec: C,
+    answered: bool,
+    rpc: PhantomData<fn() -> R>,
+}
+
+impl<R: Rpc, C: Codec> CallHandle<R, C> {
+    /// Wait for the response
+    pub async fn response(mut self) -> Result<R::Resp> {
+        let bytes = self.recv.read_to_end(MAX_MESSAGE_SIZE).await.anyerr()?;
+        self.answered = true;
+        let response: RpcResponse = self.codec.decode(&bytes)?;
+        match response {
+            Ok(payload) => self.codec.decode(&payload),
+            Err((code, detail)) => Err(RemoteError::new(code, detail).into()),
+        }
+    }
+
+    /// Tell the server to stop working on the call
+    pub fn cancel(self) {
+        drop(self);
+    }
+}
+
+impl<R, C> Drop for CallHandle<R, C> {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.recv.stop(CALL_CANCELLED).ok();
+        }
+    }
+}
+
+/// The frames of a streaming call, stopped with [`CALL_CANCELLED`] if dropped
+/// before the server is done
+#[derive(Debug)]
+struct Responses<C: Codec> {
+    frames: FramedConnection<C>,
+    codec: C,
+    done: bool,
+}
+
+impl<C: Codec> Drop for Responses<C> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.frames.stop(CALL_CANCELLED).ok();
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+use n0_error::{Result, anyerr};
+
+use crate::{client::Client, codec::Codec, protocol::Message};
+
+/// Round-trip times of a run of pings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttStats {
+    pub samples: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// Mean difference between consecutive samples
+    pub jitter: Duration,
+}
+
+impl RttStats {
+    /// Summarize `samples`, in the order they were taken
+    ///
+    /// Returns `None` if there are none.
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        let count = samples.len();
+        if count == 0 {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+        let total: Duration = samples.iter().sum();
+        let deltas: Duration = samples
+            .windows(2)
+            .map(|pair| pair[0].abs_diff(pair[1]))
+            .sum();
+        Some(Self {
+            samples: count,
+            min: sorted[0],
+            avg: total / count as u32,
+            p99: percentile(&sorted, 99),
+            max: sorted[count - 1],
+            jitter: deltas / (count - 1).max(1) as u32,
+        })
+    }
+}
+
+/// The `p`th percentile of the non-empty `sorted`, by nearest rank
+pub(crate) fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() * p).div_ceil(100).max(1) - 1]
+}
+
+/// Ping the server `samples` times, one ping in flight at a time, and time
+/// each round trip
+///
+/// Every ping carries its sequence number and send time, which the pong must
+/// echo, so a late or mismatched pong is an error rather than a wrong sample.
+pub async fn measure_rtt<C: Codec>(client: &Client<C>, samples: usize) -> Result<RttStats> {
+    let started = Instant::now();
+    let mut rtts = Vec::with_capacity(samples);
+    for seq in 0..samples as u64 {
+        let timestamp = started.elapsed().as_micros() as u64;
+        match client.request(Message::Ping { seq, timestamp }).await? {
+            Message::Pong {
+                seq: got,
+                timestamp: sent,
+            } if got == seq && sent == timestamp => {
+                let now = started.elapsed().as_micros() as u64;
+                rtts.push(Duration::from_micros(now - sent));
+            }
+            other => return Err(anyerr!("expected pong {}, got {:?}", seq, other)),
+        }
+    }
+    RttStats::from_samples(&rtts).ok_or_else(|| anyerr!("no samples taken"))
+}
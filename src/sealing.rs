@@ -0,0 +1,378 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use bincode::{Decode, Encode};
+use crypto_box::{
+    ChaChaBox,
+    aead::{Aead, AeadCore},
+};
+use crypto_secretbox::{Key, KeyInit, Nonce, XChaCha20Poly1305};
+use iroh::{EndpointId, PublicKey, SecretKey, Signature};
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{Bincode, Codec};
+
+/// Prefix of every sealed payload, telling it apart from a plain one
+pub const SEALED_MAGIC: &[u8] = b"iroh-example/echo/sealed/1\0";
+
+/// Mixed into the topic a payload is bound to, so sealed payloads can never
+/// be confused with anything else hashed the same way
+const SEALING_CONTEXT: &str = "iroh-example/echo/sealed/topic/0";
+
+/// Prefix of what a [`SessionKey`]'s signature covers, so it can never pass
+/// for a signature over anything else signed with the same key
+const SESSION_KEY_CONTEXT: &[u8] = b"iroh-example/echo/sealed/session-key/0";
+
+/// Most session keys a [`Sealer`] keeps, dropping the least recently used
+/// beyond that
+pub const MAX_SESSIONS: usize = 1024;
+
+/// Most of its own session keys a [`Sealer`] can open payloads with,
+/// forgetting the oldest once it offers more
+pub const MAX_SESSION_KEYS: usize = 4;
+
+// ====================
+// Session Keys
+// ====================
+
+/// An ephemeral X25519 key a peer offered in the auth handshake of one of its
+/// connections, for others to seal payloads to while it lasts
+///
+/// It is signed by the peer's application key, together with keying material
+/// exported from the connection it was offered on, so the server taking it
+/// can tell it was made for that connection. Peers fetching it from the
+/// server can only check who signed it: a server could still hand out a key
+/// its owner offered on an earlier connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct SessionKey {
+    /// The public half of the application key that signed
+    pub owner: [u8; 32],
+    /// The public half of the X25519 key
+    pub key: [u8; 32],
+    /// Keying material of the connection it was offered on
+    pub binding: [u8; 32],
+    /// [`Signature::LENGTH`] bytes of ed25519 signature over the rest
+    pub signature: Vec<u8>,
+}
+
+impl SessionKey {
+    /// Check the key was signed by its owner, returning the owner
+    pub fn verify(&self) -> Result<PublicKey> {
+        let owner = PublicKey::from_bytes(&self.owner).anyerr()?;
+        let signature: &[u8; Signature::LENGTH] = self
+            .signature
+            .as_slice()
+            .try_into()
+            .std_context("malformed session key signature")?;
+        owner
+            .verify(&self.signed_bytes(), &Signature::from_bytes(signature))
+            .std_context("invalid session key signature")?;
+        Ok(owner)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        [
+            SESSION_KEY_CONTEXT,
+            self.owner.as_slice(),
+            &self.key,
+            &self.binding,
+        ]
+        .concat()
+    }
+}
+
+/// The session keys the peers connected to a server offered, for others to
+/// find by their owner's application key
+///
+/// A key is only kept while the connection it was offered on lasts. Clones
+/// share the same keys.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionKeys(Arc<Mutex<HashMap<EndpointId, SessionKey>>>);
+
+impl SessionKeys {
+    /// Record the session key `peer` offered
+    pub(crate) fn offer(&self, peer: EndpointId, key: SessionKey) {
+        self.0.lock().expect("poisoned").insert(peer, key);
+    }
+
+    /// The session keys offered by any of `owners`
+    pub(crate) fn find(&self, owners: &[PublicKey]) -> Vec<SessionKey> {
+        let keys = self.0.lock().expect("poisoned");
+        keys.values()
+            .filter(|key| owners.iter().any(|owner| *owner.as_bytes() == key.owner))
+            .cloned()
+            .collect()
+    }
+
+    /// Forget the session key of `peer`, which disconnected
+    pub(crate) fn remove(&self, peer: &EndpointId) {
+        self.0.lock().expect("poisoned").remove(peer);
+    }
+}
+
+// ====================
+// Sealed Payloads
+// ====================
+
+/// A payload only its recipients can read, as it travels through a server
+/// routing it
+///
+/// The payload is encrypted once with a fresh content key, and the content
+/// key once for every session key of a recipient, with what that key and
+/// the sender's own session key agree on.
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedPayload {
+    /// The session key of the sender
+    sender: SessionKey,
+    keys: Vec<WrappedKey>,
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+/// The content key of a [`SealedPayload`], encrypted for one session key
+#[derive(Debug, Serialize, Deserialize)]
+struct WrappedKey {
+    /// The recipient's session key it is encrypted for
+    recipient: [u8; 32],
+    nonce: [u8; 24],
+    key: Vec<u8>,
+}
+
+/// A payload opened with [`Sealer::open`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opened {
+    /// Who sealed it
+    pub sender: PublicKey,
+    pub payload: Vec<u8>,
+}
+
+/// Whether `payload` was sealed with [`Sealer::seal`]
+pub fn is_sealed(payload: &[u8]) -> bool {
+    payload.starts_with(SEALED_MAGIC)
+}
+
+// ====================
+// Sealer
+// ====================
+
+/// Seals payloads for the peers meant to read them, and opens those sealed
+/// for it, so a server routing [`Message::Publish`](crate::Message::Publish)es
+/// between them learns nothing but the topic and the sizes
+///
+/// Peers are known by the public half of an application key, distinct from
+/// their endpoint's identity like the keys of [`signing`](crate::signing).
+/// Payloads are not sealed with the application keys themselves, though, but
+/// with [`SessionKey`]s: a fresh X25519 key offered in the
+/// [`auth`](crate::auth) handshake of every connection, see
+/// [`authenticate_sealing`](crate::auth::authenticate_sealing), which the
+/// server hands out to peers asking for its owner's. The application key
+/// only signs them, so the server cannot substitute keys of its own. Once a
+/// sealer offered [`MAX_SESSION_KEYS`] newer keys it forgets an old one, and
+/// nothing sealed to or by that key opens any more, not even for whoever
+/// learns the application key later.
+///
+/// What two session keys agree on is kept once a payload was sealed or
+/// opened with it, up to [`MAX_SESSIONS`] of them, so peers claiming to have
+/// sealed payloads they cannot open cost nothing to remember.
+///
+/// A payload is bound to its topic, and tells who sealed it: a recipient can
+/// only open it if its content key was wrapped with the session key of the
+/// sender named. A recipient learns the content key, though, so one could
+/// alter what the other recipients of the same payload read; sign messages
+/// as well where that matters. Clones share their keys.
+#[derive(Clone)]
+pub struct Sealer {
+    secret: SecretKey,
+    own: Arc<Mutex<VecDeque<(crypto_box::SecretKey, SessionKey)>>>,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+/// What pairs of an own and a peer's session key agree on, each with when it
+/// was last used
+#[derive(Default)]
+struct Sessions {
+    keys: HashMap<([u8; 32], [u8; 32]), (ChaChaBox, u64)>,
+    uses: u64,
+}
+
+impl fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sealer")
+            .field("public", &self.public())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sealer {
+    /// Sign the session keys of this sealer with `secret`
+    pub fn new(secret: SecretKey) -> Self {
+        Self {
+            secret,
+            own: Arc::default(),
+            sessions: Arc::default(),
+        }
+    }
+
+    /// The application key peers seal payloads for this one to
+    pub fn public(&self) -> PublicKey {
+        self.secret.public()
+    }
+
+    /// A fresh session key bound to `binding`, to offer on the connection the
+    /// keying material came from
+    ///
+    /// Payloads are sealed by the newest key offered.
+    pub fn offer(&self, binding: [u8; 32]) -> SessionKey {
+        let secret = crypto_box::SecretKey::from_bytes(rand::random());
+        let mut key = SessionKey {
+            owner: *self.public().as_bytes(),
+            key: secret.public_key().to_bytes(),
+            binding,
+            signature: Vec::new(),
+        };
+        key.signature = self.secret.sign(&key.signed_bytes()).to_bytes().to_vec();
+        let mut own = self.own.lock().expect("poisoned");
+        if own.len() >= MAX_SESSION_KEYS
+            && let Some((_, retired)) = own.pop_front()
+        {
+            let mut sessions = self.sessions.lock().expect("poisoned");
+            sessions.keys.retain(|(own, _), _| *own != retired.key);
+        }
+        own.push_back((secret, key.clone()));
+        key
+    }
+
+    /// Seal `payload` on `topic` so only the holders of `recipients` can open
+    /// it
+    pub fn seal(&self, topic: &str, recipients: &[SessionKey], payload: &[u8]) -> Result<Vec<u8>> {
+        if recipients.is_empty() {
+            return Err(anyerr!("no recipients to seal to"));
+        }
+        let (secret, sender) = self
+            .own
+            .lock()
+            .expect("poisoned")
+            .back()
+            .cloned()
+            .ok_or_else(|| anyerr!("no session key offered to seal with"))?;
+        let content_key = XChaCha20Poly1305::generate_key_with_rng(&mut rand::rng());
+        let nonce = XChaCha20Poly1305::generate_nonce_with_rng(&mut rand::rng());
+        let plaintext = [topic_hash(topic).as_slice(), payload].concat();
+        let ciphertext = XChaCha20Poly1305::new(&content_key)
+            .encrypt(&nonce, plaintext.as_slice())
+            .std_context("encrypting payload")?;
+        let mut keys = Vec::with_capacity(recipients.len());
+        for recipient in recipients {
+            recipient.verify()?;
+            let nonce = ChaChaBox::generate_nonce_with_rng(&mut rand::rng());
+            let pair = (sender.key, recipient.key);
+            let session = self.session(pair, &secret);
+            let key = session
+                .encrypt(&nonce, content_key.as_slice())
+                .std_context("encrypting content key")?;
+            self.remember(pair, session);
+            keys.push(WrappedKey {
+                recipient: recipient.key,
+                nonce: nonce.into(),
+                key,
+            });
+        }
+        let sealed = SealedPayload {
+            sender,
+            keys,
+            nonce: nonce.into(),
+            ciphertext,
+        };
+        Ok([SEALED_MAGIC, &Bincode.encode(&sealed)?].concat())
+    }
+
+    /// Open `sealed`, which must have been sealed on `topic` to one of the
+    /// session keys this sealer still has
+    pub fn open(&self, topic: &str, sealed: &[u8]) -> Result<Opened> {
+        let encoded = sealed
+            .strip_prefix(SEALED_MAGIC)
+            .ok_or_else(|| anyerr!("payload is not sealed"))?;
+        let sealed: SealedPayload = Bincode.decode(encoded)?;
+        let (secret, wrapped) = {
+            let own = self.own.lock().expect("poisoned");
+            own.iter()
+                .find_map(|(secret, own)| {
+                    let wrapped = sealed.keys.iter().find(|key| key.recipient == own.key)?;
+                    Some((secret.clone(), wrapped))
+                })
+                .ok_or_else(|| {
+                    anyerr!(
+                        "payload is not sealed for a session key of {}",
+                        self.public().fmt_short()
+                    )
+                })?
+        };
+        let sender = sealed.sender.verify()?;
+        let pair = (wrapped.recipient, sealed.sender.key);
+        let session = self.session(pair, &secret);
+        let content_key = session
+            .decrypt(&wrapped.nonce.into(), wrapped.key.as_slice())
+            .std_context("invalid content key")?;
+        // Only the holder of the sender's session key could have wrapped it
+        self.remember(pair, session);
+        let content_key: [u8; 32] = content_key
+            .as_slice()
+            .try_into()
+            .std_context("malformed content key")?;
+        let plaintext = XChaCha20Poly1305::new(&Key::from(content_key))
+            .decrypt(&Nonce::from(sealed.nonce), sealed.ciphertext.as_slice())
+            .std_context("invalid payload")?;
+        let payload = plaintext
+            .strip_prefix(topic_hash(topic).as_slice())
+            .ok_or_else(|| anyerr!("payload was sealed for another topic"))?;
+        Ok(Opened {
+            sender,
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// What the own session key of `pair`, whose secret half is `secret`,
+    /// agrees on with the peer's, worked out unless it is kept
+    fn session(&self, pair: ([u8; 32], [u8; 32]), secret: &crypto_box::SecretKey) -> ChaChaBox {
+        if let Some(session) = self.sessions.lock().expect("poisoned").get(&pair) {
+            return session;
+        }
+        ChaChaBox::new(&crypto_box::PublicKey::from_bytes(pair.1), secret)
+    }
+
+    /// Keep what the session keys of `pair` agree on for later payloads
+    fn remember(&self, pair: ([u8; 32], [u8; 32]), session: ChaChaBox) {
+        self.sessions
+            .lock()
+            .expect("poisoned")
+            .insert(pair, session);
+    }
+}
+
+impl Sessions {
+    fn get(&mut self, pair: &([u8; 32], [u8; 32])) -> Option<ChaChaBox> {
+        self.uses += 1;
+        let (session, used) = self.keys.get_mut(pair)?;
+        *used = self.uses;
+        Some(session.clone())
+    }
+
+    fn insert(&mut self, pair: ([u8; 32], [u8; 32]), session: ChaChaBox) {
+        self.uses += 1;
+        if self.keys.len() >= MAX_SESSIONS && !self.keys.contains_key(&pair) {
+            let oldest = self.keys.iter().min_by_key(|(_, (_, used))| *used);
+            if let Some((&oldest, _)) = oldest {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.insert(pair, (session, self.uses));
+    }
+}
+
+fn topic_hash(topic: &str) -> [u8; 32] {
+    blake3::derive_key(SEALING_CONTEXT, topic.as_bytes())
+}
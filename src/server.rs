@@ -0,0 +1,1834 @@
+use std::{
+    any::Any,
+    collections::HashSet,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{FutureExt, future::join_all};
+use iroh::{
+    Endpoint, EndpointId, PublicKey, SecretKey, Watcher,
+    endpoint::{Connection, VarInt},
+    protocol::{AcceptError, DynProtocolHandler, ProtocolHandler, Router, RouterBuilder},
+};
+use iroh_quinn_proto::Side;
+use n0_error::{AnyError, Result, StdResultExt, anyerr};
+use tokio::sync::broadcast;
+use tracing::{Instrument, debug, debug_span, error, info, info_span, warn};
+
+use crate::{
+    access::AccessPolicy,
+    admin::{self, ADMIN_ALPN, Bans, ConnectionInfo, ServerStats},
+    audit::{AuditLog, AuditRecord, Outcome, now_ms},
+    auth::{AuthVerifier, challenge},
+    bind::BindAddr,
+    capture::Direction,
+    chaos::{CHAOS_RESET, ChaosConfig, Strike},
+    close::{CloseReason, RemoteClose},
+    codec::{Bincode, Codec},
+    compression::Compression,
+    datagram::{decode_datagram, encode_datagram, send_encoded_datagram},
+    delivery::Receipts,
+    events::{ConnEvent, Events},
+    framed::{FramedConnection, PIPELINE_DEPTH},
+    handler::{AppHandler, ConnState, DynAppHandler, PeerCtx},
+    heartbeat::{HeartbeatConfig, Liveness, is_heartbeat, run_heartbeat, run_idle_timeout},
+    history::History,
+    limits::{Admissions, ConnectionLimits, HandlerLimits, SHUTDOWN_GRACE},
+    lobby::Lobby,
+    lookup::AddrLookup,
+    matchmaking::{Matchmaker, QueueEntry},
+    metrics::Metrics,
+    middleware::{Context, Interceptor, Middleware, Verdict, intercept},
+    outbox::Outbox,
+    permissions::{Capabilities, Permissions},
+    protocol::{
+        ErrorCode, IncompatibleProtocol, Message, MessageEnvelope, MessageKind, PUSH_ID,
+        ProtocolConfig, RemoteError, connection_version, decode_frame, encode_pooled, envelope_id,
+        read_message, send_raw, strip_preamble, supported_alpns,
+    },
+    pubsub::Subscriptions,
+    qlog::Qlog,
+    registry::{PeerHandle, Registry},
+    relay::Relays,
+    rendezvous::Directory,
+    replay::{self, ReplayWindow},
+    rpc::{EchoRpc, RPC_ALPN, RpcServer},
+    sealing::SessionKeys,
+    session::SessionManager,
+    signing::{self, KeyProvider, Signed},
+    telemetry::{TraceContext, handler_span},
+    throttle::RateLimit,
+    tuning::TransportTuning,
+};
+
+/// Application close code for connections dropped because the server shut down
+pub const SERVER_SHUTDOWN: VarInt = CloseReason::GoingAway.code();
+
+/// Bind a fresh endpoint on `port` (0 picks any) and spawn a router serving
+/// `echo` and its RPC counterpart
+pub async fn spawn<C: Codec>(port: u16, echo: Echo<C>) -> Result<Server<C>> {
+    let endpoint = bind(port).await?;
+    Server::builder(endpoint, echo).spawn()
+}
+
+/// Bind a fresh server endpoint on `port` (0 picks any)
+pub async fn bind(port: u16) -> Result<Endpoint> {
+    BindAddr::port(port).bind(Endpoint::builder()).await
+}
+
+/// Like [`bind`], but with a fixed identity instead of a random one
+pub async fn bind_with_key(port: u16, secret_key: SecretKey) -> Result<Endpoint> {
+    let builder = Endpoint::builder().secret_key(secret_key);
+    BindAddr::port(port).bind(builder).await
+}
+
+/// Like [`bind`], but on `addr`, with a fixed identity if `secret_key` is
+/// given, reachable through `relays`, publishing its address as `lookup`
+/// says and with its transport tuned by `tuning`
+pub async fn bind_with(
+    addr: &BindAddr,
+    secret_key: Option<SecretKey>,
+    relays: &Relays,
+    lookup: &AddrLookup,
+    tuning: &TransportTuning,
+) -> Result<Endpoint> {
+    let mut builder = tuning.apply(lookup.apply(relays.apply(Endpoint::builder())));
+    if let Some(secret_key) = secret_key {
+        builder = builder.secret_key(secret_key);
+    }
+    addr.bind(builder).await
+}
+
+/// Register `echo` under every supported version, its RPC counterpart and,
+/// with operators, its control channel, leaving `builder` open for further
+/// protocols
+pub fn routes<C: Codec>(builder: RouterBuilder, echo: Echo<C>) -> RouterBuilder {
+    let mut rpc = RpcServer::new(echo.codec.clone())
+        .with_access(echo.access.clone())
+        .with_limits(echo.limits.clone())
+        .register::<EchoRpc, _, _>(|msg| async move { Ok(msg.reply()) });
+    if let Some(auth) = &echo.auth {
+        rpc = rpc.with_auth(auth.clone());
+    }
+    let mut builder = builder.accept(RPC_ALPN, rpc);
+    if let Some(operators) = &echo.operators {
+        builder = builder.accept(ADMIN_ALPN, admin::server(echo.clone(), operators.clone()));
+    }
+    for alpn in supported_alpns() {
+        builder = builder.accept(alpn, echo.clone());
+    }
+    builder
+}
+
+/// Sets up a [`Server`] whose endpoint serves further protocols next to echo
+///
+/// Echo is registered as [`routes`] does; every other protocol goes under an
+/// ALPN of its own, which must not be one echo already uses.
+pub struct ServerBuilder<C = Bincode> {
+    router: RouterBuilder,
+    echo: Echo<C>,
+    alpns: HashSet<Vec<u8>>,
+    duplicate: Option<Vec<u8>>,
+}
+
+impl<C: Codec> ServerBuilder<C> {
+    pub fn new(endpoint: Endpoint, echo: Echo<C>) -> Self {
+        let mut alpns: HashSet<_> = supported_alpns().into_iter().collect();
+        alpns.insert(RPC_ALPN.to_vec());
+        if echo.operators.is_some() {
+            alpns.insert(ADMIN_ALPN.to_vec());
+        }
+        Self {
+            router: routes(Router::builder(endpoint), echo.clone()),
+            echo,
+            alpns,
+            duplicate: None,
+        }
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        self.router.endpoint()
+    }
+
+    /// Also serve `handler` to peers asking for `alpn`
+    pub fn accept(
+        mut self,
+        alpn: impl AsRef<[u8]>,
+        handler: impl Into<Box<dyn DynProtocolHandler>>,
+    ) -> Self {
+        let alpn = alpn.as_ref();
+        if !self.alpns.insert(alpn.to_vec()) {
+            self.duplicate.get_or_insert_with(|| alpn.to_vec());
+        }
+        self.router = self.router.accept(alpn, handler);
+        self
+    }
+
+    /// Let `register` add protocols to the router directly, for those like
+    /// `GossipNode` and `BlobShare` that know their own ALPNs
+    pub fn register(mut self, register: impl FnOnce(RouterBuilder) -> RouterBuilder) -> Self {
+        self.router = register(self.router);
+        self
+    }
+
+    /// Start accepting connections for every protocol registered
+    ///
+    /// Fails if two protocols were registered under the same ALPN, since only
+    /// the last would be reachable.
+    pub fn spawn(self) -> Result<Server<C>> {
+        if let Some(alpn) = self.duplicate {
+            return Err(anyerr!(
+                "ALPN {:?} registered twice",
+                String::from_utf8_lossy(&alpn)
+            ));
+        }
+        Ok(Server::new(self.router.spawn(), self.echo))
+    }
+}
+
+/// A running echo server: its router plus a handle on the echo handler
+#[derive(Debug, Clone)]
+pub struct Server<C = Bincode> {
+    router: Router,
+    echo: Echo<C>,
+    /// Set once [`Server::shutdown`] starts
+    draining: Arc<AtomicBool>,
+}
+
+impl<C: Codec> Server<C> {
+    /// Serve `echo` on `endpoint`, plus whatever else the builder is given
+    pub fn builder(endpoint: Endpoint, echo: Echo<C>) -> ServerBuilder<C> {
+        ServerBuilder::new(endpoint, echo)
+    }
+
+    /// Wrap a router spawned from [`routes`] with `echo`
+    pub fn new(router: Router, echo: Echo<C>) -> Self {
+        Self {
+            router,
+            echo,
+            draining: Arc::default(),
+        }
+    }
+
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        self.router.endpoint()
+    }
+
+    pub fn echo(&self) -> &Echo<C> {
+        &self.echo
+    }
+
+    /// Wait until peers can connect, instead of guessing how long startup takes
+    ///
+    /// The router accepts from the moment it is spawned, handshakes arriving
+    /// before it first polls waiting in the endpoint's queue, so this only
+    /// waits for the endpoint to have an address to be dialed at. Fails if
+    /// the server was shut down.
+    pub async fn ready(&self) -> Result<()> {
+        let mut addr = self.endpoint().watch_addr();
+        loop {
+            if self.router.is_shutdown() {
+                return Err(anyerr!("server is shut down"));
+            }
+            if !addr.get().is_empty() {
+                return Ok(());
+            }
+            addr.updated()
+                .await
+                .std_context("endpoint closed while starting")?;
+        }
+    }
+
+    /// Whether peers can connect: the endpoint has an address and no
+    /// shutdown has started
+    pub fn is_ready(&self) -> bool {
+        !self.draining.load(Ordering::Relaxed)
+            && !self.router.is_shutdown()
+            && !self.endpoint().watch_addr().get().is_empty()
+    }
+
+    /// Shut down without cutting off work in progress
+    ///
+    /// New connections are refused, connected peers are sent
+    /// [`Message::GoingAway`] and in-flight handlers get up to `grace` to
+    /// finish. Then the remaining connections are closed with
+    /// [`SERVER_SHUTDOWN`] and the router is shut down.
+    pub async fn shutdown(&self, grace: Duration) -> Result<()> {
+        self.draining.store(true, Ordering::Relaxed);
+        // Without any ALPN every new handshake fails
+        self.endpoint().set_alpns(Vec::new());
+        // Announced outside the history: a replay must not repeat it
+        let going_away = self
+            .echo
+            .push_to(&Message::GoingAway, None, self.echo.peers.peers());
+        for (peer, result) in going_away.await {
+            if let Err(e) = result {
+                warn!(peer = %peer.fmt_short(), "error announcing shutdown: {:#}", e);
+            }
+        }
+        if !self.echo.limits.drain(grace).await {
+            warn!(
+                in_flight = self.echo.limits.in_flight(),
+                "grace period over with handlers still running"
+            );
+        }
+        for peer in self.echo.peers.peers() {
+            CloseReason::GoingAway.close(&peer.conn, "server shutting down");
+        }
+        self.router.shutdown().await.anyerr()
+    }
+}
+
+/// Serves the echo protocol on every transport, answering with the built-in
+/// echo behaviour or the [`AppHandler`] set with [`Echo::with_app`]
+#[derive(Debug, Clone, Default)]
+pub struct Echo<C = Bincode> {
+    codec: C,
+    config: ProtocolConfig,
+    heartbeat: Option<HeartbeatConfig>,
+    idle_timeout: Option<Duration>,
+    access: AccessPolicy,
+    auth: Option<Arc<dyn AuthVerifier>>,
+    permissions: Permissions,
+    limits: HandlerLimits,
+    connection_limits: ConnectionLimits,
+    admissions: Admissions,
+    peers: Registry,
+    events: Events,
+    metrics: Metrics,
+    receipts: Receipts,
+    outbox: Option<Outbox<C>>,
+    history: Option<History>,
+    subscriptions: Subscriptions,
+    directory: Directory,
+    session_keys: SessionKeys,
+    lobby: Lobby,
+    matchmaker: Matchmaker,
+    sessions: SessionManager,
+    bans: Bans,
+    operators: Option<AccessPolicy>,
+    middleware: Arc<Vec<Arc<dyn Middleware>>>,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    signing: Option<Arc<dyn KeyProvider>>,
+    chaos: Option<ChaosConfig>,
+    rate_limit: RateLimit,
+    audit: Option<AuditLog>,
+    qlog: Option<Qlog>,
+    report_panics: bool,
+    app: Option<Arc<dyn DynAppHandler>>,
+}
+
+impl<C: Codec> Echo<C> {
+    pub fn new(codec: C) -> Self {
+        Self {
+            codec,
+            config: ProtocolConfig::default(),
+            heartbeat: None,
+            idle_timeout: None,
+            access: AccessPolicy::default(),
+            auth: None,
+            permissions: Permissions::default(),
+            limits: HandlerLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            admissions: Admissions::default(),
+            peers: Registry::default(),
+            events: Events::default(),
+            metrics: Metrics::default(),
+            receipts: Receipts::default(),
+            outbox: None,
+            history: None,
+            subscriptions: Subscriptions::default(),
+            directory: Directory::default(),
+            session_keys: SessionKeys::default(),
+            lobby: Lobby::default(),
+            matchmaker: Matchmaker::default(),
+            sessions: SessionManager::default(),
+            bans: Bans::default(),
+            operators: None,
+            middleware: Arc::default(),
+            interceptors: Arc::default(),
+            signing: None,
+            chaos: None,
+            rate_limit: RateLimit::default(),
+            audit: None,
+            qlog: None,
+            report_panics: false,
+            app: None,
+        }
+    }
+
+    /// Enforce the size limits of `config` on every connection
+    pub fn with_config(mut self, config: ProtocolConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Close connections from peers `access` does not allow, before serving
+    /// any of their streams
+    pub fn with_access(mut self, access: AccessPolicy) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Require every client to pass `verifier`'s challenge before serving
+    /// any of its streams
+    pub fn with_auth(mut self, verifier: Arc<dyn AuthVerifier>) -> Self {
+        self.auth = Some(verifier);
+        self
+    }
+
+    /// Check the capabilities each client's auth handshake granted against
+    /// `permissions` instead of the default ones
+    ///
+    /// Messages the client lacks a capability for are answered with
+    /// [`ErrorCode::PermissionDenied`] without reaching a handler. Clients of
+    /// a server that does not authenticate may do everything.
+    pub fn with_permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Bound how many streams are served at once, see [`HandlerLimits`]
+    pub fn with_limits(mut self, limits: HandlerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Cap the connections served at once and shed load past `limits`, see
+    /// [`ConnectionLimits`]
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = limits;
+        self
+    }
+
+    /// Queue messages for offline peers in `outbox`, see [`Echo::send_to`]
+    pub fn with_outbox(mut self, outbox: Outbox<C>) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Number broadcasts and relayed chat, keeping them in `history` for
+    /// clients that resume after reconnecting, see [`Message::Resume`]
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Manage rooms with `lobby` instead of a default one, see
+    /// [`Message::Lobby`]
+    pub fn with_lobby(mut self, lobby: Lobby) -> Self {
+        self.lobby = lobby;
+        self
+    }
+
+    /// Match queued clients with `matchmaker` instead of a first-come one, see
+    /// [`Message::JoinQueue`]
+    pub fn with_matchmaker(mut self, matchmaker: Matchmaker) -> Self {
+        self.matchmaker = matchmaker;
+        self
+    }
+
+    /// Serve the operator control channel on [`ADMIN_ALPN`] to the peers
+    /// `operators` allows, see [`admin`](crate::admin)
+    pub fn with_operators(mut self, operators: AccessPolicy) -> Self {
+        self.operators = Some(operators);
+        self
+    }
+
+    /// Run `middleware` on every inbound message, after the middleware added
+    /// before it, see [`Middleware`]
+    pub fn with_middleware(mut self, middleware: impl Middleware) -> Self {
+        Arc::make_mut(&mut self.middleware).push(Arc::new(middleware));
+        self
+    }
+
+    /// Run `interceptor` on every outbound message, replies and pushes alike,
+    /// after the interceptors added before it, see [`Interceptor`]
+    pub fn with_interceptor(mut self, interceptor: impl Interceptor) -> Self {
+        Arc::make_mut(&mut self.interceptors).push(Arc::new(interceptor));
+        self
+    }
+
+    /// Sign every outbound message with the key of `keys`, if it has one, and
+    /// refuse inbound messages not signed by a key it trusts, see
+    /// [`KeyProvider`]
+    ///
+    /// Refused messages are answered with [`ErrorCode::Unauthorized`].
+    /// Heartbeats and datagrams are neither signed nor checked. Relayed chat
+    /// and publishes keep the signature of their author instead.
+    pub fn with_signing(mut self, keys: impl KeyProvider) -> Self {
+        self.signing = Some(Arc::new(keys));
+        self
+    }
+
+    /// Ping every connected peer and close connections that stop answering
+    pub fn with_heartbeat(mut self, config: HeartbeatConfig) -> Self {
+        self.heartbeat = Some(config);
+        self
+    }
+
+    /// Close connections whose peer sent nothing but heartbeats for
+    /// `timeout`, with [`IDLE_TIMEOUT`](crate::heartbeat::IDLE_TIMEOUT)
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Inject the faults of `chaos` into every message received, see
+    /// [`ChaosConfig`]
+    pub fn with_chaos(mut self, chaos: ChaosConfig) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Cap the bandwidth of the framed sessions of every connection, see
+    /// [`RateLimit`]
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Record every message received or sent to `audit`, see [`AuditLog`]
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Write a qlog trace of every connection, see [`Qlog`]
+    pub fn with_qlog(mut self, qlog: Qlog) -> Self {
+        self.qlog = Some(qlog);
+        self
+    }
+
+    /// Answer messages whose handling panicked with [`ErrorCode::Internal`],
+    /// instead of sending no answer
+    ///
+    /// Panics are caught, logged and counted either way, and never take down
+    /// more than the message they happened on.
+    pub fn with_panic_reports(mut self) -> Self {
+        self.report_panics = true;
+        self
+    }
+
+    /// Answer with `app` instead of the built-in echo behaviour
+    ///
+    /// Everything else this handler does still applies, including the
+    /// limits, middleware, metrics and events.
+    pub fn with_app(mut self, app: impl AppHandler) -> Self {
+        self.app = Some(Arc::new(app));
+        self
+    }
+
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// What `chaos` does to the next message received on `conn`
+    async fn strike(&self, conn: &Connection) -> Strike {
+        match &self.chaos {
+            Some(chaos) => chaos.strike(conn).await,
+            None => Strike::Deliver,
+        }
+    }
+
+    pub fn config(&self) -> &ProtocolConfig {
+        &self.config
+    }
+
+    /// When the peer connected as `endpoint_id` was last heard from, if it is
+    /// still connected
+    pub fn last_seen(&self, endpoint_id: &EndpointId) -> Option<Instant> {
+        self.peers
+            .get(endpoint_id)
+            .map(|peer| peer.liveness.last_seen())
+    }
+
+    /// The live connections served by this handler and its clones
+    pub fn peers(&self) -> &Registry {
+        &self.peers
+    }
+
+    /// Counters and timings of the connections served by this handler and
+    /// its clones
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// What happens on the connections served by this handler and its clones
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ConnEvent> {
+        self.events.subscribe()
+    }
+
+    /// The rooms of this handler and its clones
+    pub fn lobby(&self) -> &Lobby {
+        &self.lobby
+    }
+
+    /// Peers refused on top of the access policy, changeable at runtime
+    pub fn bans(&self) -> &Bans {
+        &self.bans
+    }
+
+    /// Close the connection of `peer` with [`KICKED`], returning false if it
+    /// is not connected
+    pub fn kick(&self, peer: &EndpointId) -> bool {
+        let Some(handle) = self.peers.get(peer) else {
+            return false;
+        };
+        info!(peer = %peer.fmt_short(), "kicking peer");
+        CloseReason::Kicked.close(&handle.conn, "kicked");
+        true
+    }
+
+    /// Ban `peer` for `duration`, or for good if `None`, and kick it
+    pub fn ban(&self, peer: EndpointId, duration: Option<Duration>) {
+        self.bans.ban(peer, duration);
+        self.kick(&peer);
+    }
+
+    /// Every connected peer, as listed for an operator
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let players = self.sessions.players();
+        let name = |peer: &EndpointId| {
+            let player = players.iter().find(|player| player.peer == *peer)?;
+            Some(player.name.clone())
+        };
+        let peers = self.peers.peers().into_iter().map(|handle| {
+            let peer = handle.conn.remote_id();
+            ConnectionInfo {
+                peer,
+                rtt: handle.conn.rtt(),
+                idle: handle.liveness.last_seen().elapsed(),
+                name: name(&peer),
+            }
+        });
+        peers.collect()
+    }
+
+    /// What this handler and its clones are doing, as reported to an operator
+    pub fn stats(&self) -> ServerStats {
+        let echo = self.metrics.echo();
+        ServerStats {
+            connections: self.peers.len(),
+            in_flight: self.limits.in_flight(),
+            bytes_received: echo.bytes_received.get(),
+            bytes_sent: echo.bytes_sent.get(),
+            rooms: self.lobby.len(),
+            players: self.sessions.players().len(),
+            queued: self.matchmaker.waiting(),
+            bans: self.bans.list().len(),
+        }
+    }
+
+    /// The players logged in to this handler and its clones
+    pub fn sessions(&self) -> &SessionManager {
+        &self.sessions
+    }
+
+    /// Push `msg` to every connected peer, see [`Registry::broadcast`]
+    ///
+    /// With a history, `msg` is recorded and pushed as a
+    /// [`Message::Broadcast`] carrying its offset.
+    pub async fn broadcast(&self, msg: &Message) -> Vec<(EndpointId, Result<()>)> {
+        self.push_to(&self.logged(msg), None, self.peers.peers())
+            .await
+    }
+
+    /// `msg` as it is broadcast: recorded and numbered if there is a history
+    fn logged(&self, msg: &Message) -> Message {
+        match &self.history {
+            Some(history) => Message::Broadcast {
+                offset: history.record(msg.clone()),
+                body: Box::new(msg.clone()),
+            },
+            None => msg.clone(),
+        }
+    }
+
+    /// Push `msg` to the peer `id`
+    ///
+    /// With an outbox, a message for a peer that is offline, or that cannot
+    /// be reached, is queued and pushed once the peer connects again.
+    pub async fn send_to(&self, id: EndpointId, msg: &Message) -> Result<()> {
+        let result = match self.peers.get(&id) {
+            Some(peer) => self.push(&peer.conn, msg).await,
+            None => Err(anyerr!("peer {} is not connected", id.fmt_short())),
+        };
+        match (result, &self.outbox) {
+            (Err(e), Some(outbox)) => {
+                debug!(peer = %id.fmt_short(), "queueing message: {:#}", e);
+                outbox.push(&id, msg).await
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Send `msg` to the peer `id` and wait for its answer, see
+    /// [`Client::incoming`](crate::Client::incoming)
+    ///
+    /// The request gets a stream of its own, so it never waits behind the
+    /// peer's requests. A [`Message::Error`] answer is returned as a
+    /// [`RemoteError`].
+    pub async fn ask(&self, id: EndpointId, msg: Message) -> Result<Message> {
+        let peer = self
+            .peers
+            .get(&id)
+            .ok_or_else(|| anyerr!("peer {} is not connected", id.fmt_short()))?;
+        let version = connection_version(&peer.conn);
+        // Alone on its stream, the id only has to come back unchanged
+        let mut envelope = MessageEnvelope::new(0, msg);
+        self.intercept(id, &mut envelope);
+        let encoded = self.config.encode(&self.codec, version, &envelope)?;
+
+        let sent = async {
+            let (mut send, recv) = peer.conn.open_bi().await.anyerr()?;
+            send.write_chunk(encoded.clone()).await.anyerr()?;
+            send.finish().anyerr()?;
+            Ok(recv)
+        };
+        let sent = sent.await;
+        let outcome = Outcome::of_send(&sent);
+        self.audit(
+            id,
+            Direction::Sent,
+            Some(0),
+            Some(envelope.body.kind()),
+            encoded.len(),
+            outcome,
+        );
+        let bytes = read_message(&mut sent?, self.config.max_message_size).await?;
+        let (mut reply, size) = self.config.decode(&self.codec, version, &bytes)?;
+        // The answer comes on the stream of the request, so only its
+        // signature needs checking
+        if let Some(refusal) = signing::refusal(self.signing.as_deref(), &reply) {
+            reply.body = refusal;
+        }
+        self.audit(
+            id,
+            Direction::Received,
+            Some(0),
+            Some(reply.body.kind()),
+            size,
+            Outcome::Handled,
+        );
+        match reply.body {
+            Message::Error { code, detail } => Err(RemoteError::new(code, detail).into()),
+            body => Ok(body),
+        }
+    }
+
+    /// Push everything queued for the newly connected `conn`, putting back
+    /// what cannot be delivered
+    async fn flush_outbox(&self, outbox: &Outbox<C>, conn: &Connection) -> Result<()> {
+        let id = conn.remote_id();
+        let queued: Vec<Message> = outbox.take(&id).await?;
+        for (i, msg) in queued.iter().enumerate() {
+            if let Err(e) = self.push(conn, msg).await {
+                outbox.restore(&id, &queued[i..]).await?;
+                return Err(e);
+            }
+        }
+        if !queued.is_empty() {
+            info!(count = queued.len(), "delivered queued messages");
+        }
+        Ok(())
+    }
+
+    /// Run `envelope`, about to be sent to `peer`, through the interceptors
+    ///
+    /// It is first tagged with the current trace, so a reply is part of the
+    /// trace of the request it answers, and numbered and signed last.
+    pub(crate) fn intercept(&self, peer: EndpointId, envelope: &mut MessageEnvelope) {
+        envelope.trace = TraceContext::current();
+        intercept(&self.interceptors, peer, envelope);
+        replay::stamp(envelope, || self.peers.counters().next(peer));
+        signing::sign_outgoing(self.signing.as_deref(), envelope);
+    }
+
+    /// The answer to `envelope`, received on the connection of `state`
+    /// speaking `version`, if its signature is not one
+    /// [`with_signing`](Self::with_signing) accepts, it is a replay, or the
+    /// connection lacks the capability it takes
+    pub(crate) fn refusal(
+        &self,
+        state: &ConnState,
+        version: u32,
+        envelope: &MessageEnvelope,
+    ) -> Option<Message> {
+        // Counted by signer only once the signature checks out, as the key
+        // is the sender's to choose otherwise
+        let signer = match signing::check(self.signing.as_deref(), envelope) {
+            Ok(signer) => signer,
+            Err(refusal) => return Some(refusal),
+        };
+        let signer = signer.as_ref().map(PublicKey::as_bytes);
+        state
+            .get::<ReplayWindow>()
+            .refusal(version, envelope, signer)
+            .or_else(|| {
+                let capabilities = state.get::<Capabilities>();
+                self.permissions.refusal(&capabilities, &envelope.body)
+            })
+    }
+
+    /// Record a message exchanged with `peer` in the audit log, if there is
+    /// one
+    pub(crate) fn audit(
+        &self,
+        peer: EndpointId,
+        direction: Direction,
+        id: Option<u64>,
+        kind: Option<MessageKind>,
+        size: usize,
+        outcome: Outcome,
+    ) {
+        if let Some(audit) = &self.audit {
+            audit.record(&AuditRecord {
+                at_ms: now_ms(),
+                peer,
+                direction,
+                id,
+                kind,
+                size,
+                outcome,
+            });
+        }
+    }
+
+    /// Send `msg` to `conn`, speaking `version`, as a datagram, once through
+    /// the interceptors
+    fn send_datagram(&self, conn: &Connection, version: u32, msg: Message) -> Result<()> {
+        let mut envelope = MessageEnvelope::new(PUSH_ID, msg);
+        self.intercept(conn.remote_id(), &mut envelope);
+        let encoded = encode_datagram(&self.codec, version, &envelope.body)?;
+        let size = encoded.len();
+        let sent = send_encoded_datagram(conn, encoded);
+        let kind = Some(envelope.body.kind());
+        self.audit(
+            conn.remote_id(),
+            Direction::Sent,
+            None,
+            kind,
+            size,
+            Outcome::of_send(&sent),
+        );
+        sent
+    }
+
+    /// Send `envelope` to `conn` uncompressed, on a fresh stream
+    async fn send_envelope(&self, conn: &Connection, envelope: &MessageEnvelope) -> Result<()> {
+        let version = connection_version(conn);
+        let wire = envelope.wire(&self.codec, version)?;
+        let (encoded, size) = encode_pooled(&self.codec, Compression::None, version, &wire)?;
+        let sent = send_raw(conn, encoded).await;
+        let (id, kind) = (Some(envelope.id), Some(envelope.body.kind()));
+        self.audit(
+            conn.remote_id(),
+            Direction::Sent,
+            id,
+            kind,
+            size,
+            Outcome::of_send(&sent),
+        );
+        sent
+    }
+
+    /// Push `msg` to `conn` unprompted, on a fresh stream
+    async fn push(&self, conn: &Connection, msg: &Message) -> Result<()> {
+        self.relay(conn, msg, None).await
+    }
+
+    /// Push `msg` to `conn` like [`push`](Self::push), but with the signature
+    /// of its author if `signed` has it, rather than one of this end's
+    async fn relay(&self, conn: &Connection, msg: &Message, signed: Option<&Signed>) -> Result<()> {
+        let mut envelope = MessageEnvelope::new(PUSH_ID, msg.clone());
+        if let Some(signed) = signed {
+            signed.apply(&mut envelope);
+        }
+        self.intercept(conn.remote_id(), &mut envelope);
+        self.send_envelope(conn, &envelope).await
+    }
+
+    /// Push `msg` to every one of `peers`, see [`Registry::broadcast`], with
+    /// the signature of its author if `signed` has it
+    async fn push_to(
+        &self,
+        msg: &Message,
+        signed: Option<&Signed>,
+        peers: Vec<PeerHandle>,
+    ) -> Vec<(EndpointId, Result<()>)> {
+        let sends = peers.iter().map(|peer| async move {
+            let sent = self.relay(&peer.conn, msg, signed).await;
+            (peer.conn.remote_id(), sent)
+        });
+        join_all(sends).await
+    }
+
+    /// Push `msg` to the connected peers among `peers` in the background
+    fn notify(&self, peers: Vec<EndpointId>, msg: Message) {
+        self.notify_signed(peers, msg, None);
+    }
+
+    /// Push `msg` like [`notify`](Self::notify), with the signature of its
+    /// author if `signed` has it
+    fn notify_signed(&self, peers: Vec<EndpointId>, msg: Message, signed: Option<Signed>) {
+        if peers.is_empty() {
+            return;
+        }
+        let echo = self.clone();
+        tokio::spawn(
+            async move {
+                let peers = peers.iter().filter_map(|id| echo.peers.get(id)).collect();
+                for (peer, result) in echo.push_to(&msg, signed.as_ref(), peers).await {
+                    if let Err(e) = result {
+                        warn!(peer = %peer.fmt_short(), "error notifying peer: {:#}", e);
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Open a lobby room for the matched `players` and introduce them to
+    /// each other
+    fn start_match(&self, mode: &str, players: Vec<QueueEntry>) {
+        let ids: Vec<_> = players.iter().map(|player| player.peer).collect();
+        let Some((room, left)) = self.lobby.open(&ids) else {
+            warn!(mode, "no room left for a match, requeueing its players");
+            self.matchmaker.requeue(mode.to_string(), players);
+            return;
+        };
+        info!(mode, room, players = ids.len(), "match found");
+        for (others, event) in left {
+            self.notify(others, Message::Room(event));
+        }
+        for id in &ids {
+            let peers = ids
+                .iter()
+                .filter(|other| *other != id)
+                .map(|other| self.directory.addr(*other))
+                .collect();
+            let found = Message::MatchFound {
+                room: room.clone(),
+                peers,
+            };
+            self.notify(vec![*id], found);
+        }
+    }
+
+    /// Run `msg`, which came on the connection with `state`, through the
+    /// middleware, then have the app answer what comes out
+    ///
+    /// A message over the limit for its kind is answered with
+    /// [`ErrorCode::TooLarge`] instead of being acted on. Returns `None` if a
+    /// middleware or the app dropped the message, or if its sender stopped
+    /// waiting before it could be acted on.
+    ///
+    /// A panic in a middleware or the app is answered as configured with
+    /// [`with_panic_reports`](Self::with_panic_reports).
+    pub(crate) async fn handle(
+        &self,
+        ctx: Context,
+        state: &ConnState,
+        msg: Message,
+    ) -> Option<Message> {
+        let kind = msg.kind();
+        match AssertUnwindSafe(self.handle_unwinding(ctx, state, msg))
+            .catch_unwind()
+            .await
+        {
+            Ok(answer) => answer,
+            Err(panic) => {
+                self.panicked(Some(kind), &*panic);
+                self.report_panics.then(|| Message::Error {
+                    code: ErrorCode::Internal,
+                    detail: "handler panicked".to_string(),
+                })
+            }
+        }
+    }
+
+    async fn handle_unwinding(
+        &self,
+        ctx: Context,
+        state: &ConnState,
+        mut msg: Message,
+    ) -> Option<Message> {
+        if ctx.expired() {
+            debug!(kind = ?msg.kind(), "skipping message past its deadline");
+            return None;
+        }
+        if let Message::Batch(messages) = msg {
+            return self.handle_batch(ctx, state, messages).await;
+        }
+        if let Message::Reliable { seq, body, acked } = msg {
+            return Some(self.handle_reliable(ctx, state, seq, *body, acked).await);
+        }
+        for middleware in self.middleware.iter() {
+            msg = match middleware.on_recv(&ctx, msg) {
+                Verdict::Continue(msg) => msg,
+                Verdict::Reply(reply) => return Some(reply),
+                Verdict::Drop => return None,
+            };
+        }
+        if let Err(e) = self.config.check(msg.kind(), ctx.size) {
+            return Some(Message::Error {
+                code: ErrorCode::TooLarge,
+                detail: e.to_string(),
+            });
+        }
+        let ctx = PeerCtx::new(ctx, state.clone());
+        match &self.app {
+            Some(app) => app.handle(ctx, msg).await,
+            None => AppHandler::handle(self, ctx, msg).await,
+        }
+    }
+
+    /// Handle every message of a batch in order, answering with a batch of
+    /// their answers, or nothing if none of them had one
+    ///
+    /// Each message is checked against the limit of its kind with the size of
+    /// the whole batch, so batching never gets a message past its limit.
+    async fn handle_batch(
+        &self,
+        ctx: Context,
+        state: &ConnState,
+        messages: Vec<Message>,
+    ) -> Option<Message> {
+        // The signature covers the batch, not the messages in it
+        let ctx = Context {
+            signed: None,
+            ..ctx
+        };
+        let mut answers = Vec::new();
+        for msg in messages {
+            if let Some(answer) = Box::pin(self.handle(ctx.clone(), state, msg)).await {
+                answers.push(answer);
+            }
+        }
+        (!answers.is_empty()).then_some(Message::Batch(answers))
+    }
+
+    /// Handle the body of reliable message `seq` the first time it arrives,
+    /// answering with the cumulative ack either way
+    ///
+    /// The body goes through the middleware and the app like a message of
+    /// its own, checked against the limit of its kind with the size of the
+    /// whole message, and is recorded in the audit log. Since the ack answers
+    /// the reliable message, the answer to the body is pushed to the sender.
+    async fn handle_reliable(
+        &self,
+        ctx: Context,
+        state: &ConnState,
+        seq: u64,
+        body: Message,
+        acked: u64,
+    ) -> Message {
+        let (next, fresh) = self.receipts.record(ctx.peer, seq, acked);
+        if fresh {
+            // The signature covers the reliable message, not its body
+            let ctx = Context {
+                signed: None,
+                ..ctx
+            };
+            let (peer, kind, size) = (ctx.peer, body.kind(), ctx.size);
+            let answer = Box::pin(self.handle(ctx, state, body)).await;
+            let outcome = Outcome::of_answer(&answer);
+            self.audit(peer, Direction::Received, None, Some(kind), size, outcome);
+            if let Some(answer) = answer {
+                self.notify(vec![peer], answer);
+            }
+        }
+        Message::Ack { next }
+    }
+
+    /// Run a stream handler, logging and counting a panic instead of leaving
+    /// it to the task
+    fn isolated<F>(&self, handler: F) -> impl Future<Output = ()> + use<C, F>
+    where
+        F: Future<Output = ()>,
+    {
+        let echo = self.clone();
+        async move {
+            if let Err(panic) = AssertUnwindSafe(handler).catch_unwind().await {
+                echo.panicked(None, &*panic);
+            }
+        }
+    }
+
+    fn panicked(&self, kind: Option<MessageKind>, panic: &(dyn Any + Send)) {
+        self.metrics.echo().handler_panics.inc();
+        error!(?kind, "handler panicked: {}", panic_message(panic));
+    }
+
+    /// Hand the disconnection of `peer` to the app
+    fn disconnected(&self, peer: EndpointId) {
+        self.peers.counters().remove(&peer);
+        self.receipts.disconnected(peer);
+        self.session_keys.remove(&peer);
+        match &self.app {
+            Some(app) => app.disconnected(peer),
+            None => AppHandler::disconnected(self, peer),
+        }
+    }
+
+    /// The built-in reply to `msg` sent by `from`
+    ///
+    /// Chat is additionally relayed to every other connected peer; the sender
+    /// gets its own message back as acknowledgement. [`Message::Resume`] is
+    /// answered from the history, with nothing to
+    /// replay if there is none.
+    ///
+    /// Subscriptions are kept until the peer disconnects, and a publish is
+    /// routed to every other subscribed peer. All three are echoed back as
+    /// acknowledgement.
+    ///
+    /// Announced addresses are also kept until the peer disconnects, and
+    /// handed out by [`Message::ListPeers`] and [`Message::Introduce`]. A peer
+    /// cannot be introduced to itself, nor to a peer that is not connected.
+    /// Session keys offered in the auth handshake are handed out by
+    /// [`Message::FindSessionKeys`] while their connection lasts.
+    ///
+    /// Lobby requests are answered by the lobby, which may have news for the
+    /// other members of a room. Queueing for a match is echoed back; the
+    /// match itself is pushed once complete.
+    ///
+    /// Logging in and changing status are answered with the sender's new
+    /// presence, which is also pushed to its watchers; a peer that is not
+    /// logged in stays offline.
+    ///
+    /// A stats request is answered with the statistics of the sender's
+    /// connection.
+    ///
+    /// Relayed chat and publishes keep the signature `signed` of their
+    /// author, except chat recorded in a history, which is relayed inside a
+    /// [`Message::Broadcast`] the author never signed.
+    fn respond(&self, from: EndpointId, signed: Option<&Signed>, msg: Message) -> Message {
+        match &msg {
+            Message::Login { name } => {
+                let (presence, watchers) = self.sessions.login(from, name.clone());
+                self.notify(watchers, Message::Presence(presence.clone()));
+                return Message::Presence(presence);
+            }
+            Message::SetStatus(status) => {
+                let Some((presence, watchers)) = self.sessions.set_status(from, *status) else {
+                    return Message::Presence(self.sessions.presence(from));
+                };
+                self.notify(watchers, Message::Presence(presence.clone()));
+                return Message::Presence(presence);
+            }
+            Message::WatchPresence(peers) => {
+                return Message::PresenceList(self.sessions.watch(from, peers));
+            }
+            Message::StatsRequest => {
+                return match self.peers.get(&from) {
+                    Some(peer) => Message::StatsResponse(peer.stats()),
+                    None => Message::Error {
+                        code: ErrorCode::NotFound,
+                        detail: "no connection to report on".to_string(),
+                    },
+                };
+            }
+            _ => {}
+        }
+        if let Message::Lobby(request) = msg {
+            let outcome = self.lobby.handle(from, request);
+            if let Some(event) = outcome.event {
+                self.notify(outcome.notify, Message::Room(event));
+            }
+            return Message::Room(outcome.reply);
+        }
+        match &msg {
+            Message::ListPeers => {
+                let mut ids = self.peers.ids();
+                ids.retain(|peer| *peer != from);
+                return Message::PeerList(
+                    ids.into_iter().map(|id| self.directory.addr(id)).collect(),
+                );
+            }
+            Message::FindSessionKeys(owners) => {
+                return Message::SessionKeys(self.session_keys.find(owners));
+            }
+            Message::Introduce { peer } => {
+                let target = self.peers.get(&peer.id).filter(|_| peer.id != from);
+                let Some(target) = target else {
+                    return Message::Error {
+                        code: ErrorCode::NotFound,
+                        detail: format!("peer {} is not connected", peer.id.fmt_short()),
+                    };
+                };
+                let intro = Message::Introduce {
+                    peer: self.directory.addr(from),
+                };
+                let echo = self.clone();
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = echo.push(&target.conn, &intro).await {
+                            warn!(peer = %target.conn.remote_id().fmt_short(), "error introducing: {:#}", e);
+                        }
+                    }
+                    .in_current_span(),
+                );
+                return Message::Introduce {
+                    peer: self.directory.addr(peer.id),
+                };
+            }
+            _ => {}
+        }
+        if let Message::Resume { from_offset } = msg {
+            let (entries, next_offset) = match &self.history {
+                Some(history) => history.since(from_offset),
+                None => (Vec::new(), 0),
+            };
+            return Message::Replay {
+                entries,
+                next_offset,
+            };
+        }
+        match &msg {
+            Message::Chat { .. } => {
+                let echo = self.clone();
+                let chat = self.logged(&msg);
+                let signed = signed.filter(|_| self.history.is_none()).cloned();
+                tokio::spawn(
+                    async move {
+                        let mut peers = echo.peers.peers();
+                        peers.retain(|peer| peer.conn.remote_id() != from);
+                        let results = echo.push_to(&chat, signed.as_ref(), peers).await;
+                        for (peer, result) in results {
+                            if let Err(e) = result {
+                                warn!(peer = %peer.fmt_short(), "error relaying chat: {:#}", e);
+                            }
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+            Message::Announce(addr) => self.directory.announce(from, addr),
+            Message::JoinQueue {
+                mode,
+                party_size,
+                rating,
+            } => {
+                for players in self
+                    .matchmaker
+                    .join(from, mode.clone(), *party_size, *rating)
+                {
+                    self.start_match(mode, players);
+                }
+            }
+            Message::LeaveQueue => {
+                self.matchmaker.leave(&from);
+            }
+            Message::Subscribe(filter) => {
+                self.subscriptions.subscribe(from, filter.clone());
+            }
+            Message::Unsubscribe(filter) => {
+                self.subscriptions.unsubscribe(from, filter);
+            }
+            Message::Publish { topic, .. } => {
+                let mut subscribers = self.subscriptions.subscribers(topic);
+                subscribers.retain(|peer| *peer != from);
+                self.notify_signed(subscribers, msg.clone(), signed.cloned());
+            }
+            _ => {}
+        }
+        msg.reply()
+    }
+}
+
+/// The built-in echo behaviour, see [`respond`](Echo::respond)
+impl<C: Codec> AppHandler for Echo<C> {
+    async fn handle(&self, ctx: PeerCtx, msg: Message) -> Option<Message> {
+        Some(self.respond(ctx.peer, ctx.signed.as_ref(), msg))
+    }
+
+    fn disconnected(&self, peer: EndpointId) {
+        self.subscriptions.remove_peer(&peer);
+        self.directory.remove(&peer);
+        self.matchmaker.leave(&peer);
+        if let Some((presence, watchers)) = self.sessions.remove_peer(&peer) {
+            self.notify(watchers, Message::Presence(presence));
+        }
+        if let Some((others, event)) = self.lobby.remove_peer(&peer) {
+            self.notify(others, Message::Room(event));
+        }
+    }
+}
+
+impl<C: Codec> ProtocolHandler for Echo<C> {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let span = info_span!("conn", remote = %connection.remote_id().fmt_short());
+        self.serve(connection).instrument(span).await;
+        Ok(())
+    }
+
+    async fn shutdown(&self) {
+        if !self.limits.drain(SHUTDOWN_GRACE).await {
+            warn!(
+                in_flight = self.limits.in_flight(),
+                "shutting down with handlers still running"
+            );
+        }
+    }
+}
+
+impl<C: Codec> Echo<C> {
+    /// Serve every stream of `connection` until it closes
+    async fn serve(&self, connection: Connection) {
+        let endpoint_id = connection.remote_id();
+        if !self.access.is_allowed(&endpoint_id) || self.bans.is_banned(&endpoint_id) {
+            info!("rejected connection");
+            CloseReason::AccessDenied.close(&connection, "access denied");
+            return;
+        }
+        let _admission =
+            match self
+                .admissions
+                .admit(endpoint_id, &self.connection_limits, &self.limits)
+            {
+                Ok(admission) => admission,
+                Err(reason) => {
+                    info!(reason, "refused connection");
+                    self.metrics.echo().connections_refused.inc();
+                    CloseReason::Busy.close(&connection, reason);
+                    return;
+                }
+            };
+        let state = ConnState::default();
+        if let Some(auth) = &self.auth {
+            match challenge(&connection, &self.codec, auth.as_ref()).await {
+                Ok(authenticated) => {
+                    state.insert(authenticated.capabilities);
+                    if let Some(key) = authenticated.session_key {
+                        self.session_keys.offer(endpoint_id, key);
+                    }
+                }
+                Err(e) => {
+                    info!("authentication failed: {:#}", e);
+                    return;
+                }
+            }
+        }
+        let version = connection_version(&connection);
+        info!(version, "accepted connection");
+        self.events.emit(ConnEvent::Connected { peer: endpoint_id });
+        self.metrics.opened();
+
+        let liveness = Liveness::default();
+        let streams = Arc::new(AtomicU64::new(0));
+        self.peers.insert(PeerHandle {
+            conn: connection.clone(),
+            liveness: liveness.clone(),
+            streams: streams.clone(),
+        });
+        if let Some(outbox) = self.outbox.clone() {
+            let (echo, connection) = (self.clone(), connection.clone());
+            tokio::spawn(
+                async move {
+                    if let Err(e) = echo.flush_outbox(&outbox, &connection).await {
+                        warn!("error delivering queued messages: {:#}", e);
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+        let heartbeat = self.heartbeat.map(|config| {
+            tokio::spawn(
+                run_heartbeat(
+                    connection.clone(),
+                    self.codec.clone(),
+                    config,
+                    liveness.clone(),
+                )
+                .in_current_span(),
+            )
+        });
+        if let Some(qlog) = self.qlog.clone() {
+            tokio::spawn(
+                qlog.trace(connection.clone(), Side::Server)
+                    .in_current_span(),
+            );
+        }
+        let idle = self.idle_timeout.map(|timeout| {
+            tokio::spawn(
+                run_idle_timeout(connection.clone(), timeout, liveness.clone()).in_current_span(),
+            )
+        });
+
+        let datagrams = tokio::spawn(
+            serve_datagrams(
+                self.clone(),
+                connection.clone(),
+                version,
+                liveness.clone(),
+                state.clone(),
+            )
+            .in_current_span(),
+        );
+        let mut handlers = self.limits.connection();
+        let mut receive_count = 0u64;
+        let (egress, ingress) = self.rate_limit.throttles();
+
+        // Accept streams in a loop: each unidirectional stream carries one message,
+        // while a bidirectional stream is a persistent framed session
+        //
+        // Each stream waits for room before the next one is accepted, so excess
+        // streams queue up in QUIC flow control rather than in memory
+        loop {
+            let recv = tokio::select! {
+                recv = connection.accept_uni() => recv,
+                bi = connection.accept_bi() => match bi {
+                    Ok((send, recv)) => {
+                        self.events.emit(ConnEvent::StreamOpened { peer: endpoint_id, stream: recv.id() });
+                        streams.fetch_add(1, Ordering::Relaxed);
+                        let Some(permit) = handlers.reserve(&connection).await else {
+                            break;
+                        };
+                        let span = debug_span!("stream", id = %recv.id());
+                        let framed = FramedConnection::from_streams(send, recv, self.codec.clone())
+                            .with_max_frame_size(self.config.max_message_size)
+                            .with_throttles(egress.clone(), ingress.clone());
+                        let session = serve_framed(self.clone(), connection.clone(), framed, version, liveness.clone(), state.clone());
+                        handlers.spawn(permit, self.isolated(session).instrument(span));
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            match recv {
+                Ok(mut recv) => {
+                    self.events.emit(ConnEvent::StreamOpened {
+                        peer: endpoint_id,
+                        stream: recv.id(),
+                    });
+                    streams.fetch_add(1, Ordering::Relaxed);
+                    let Some(permit) = handlers.reserve(&connection).await else {
+                        break;
+                    };
+                    let span = debug_span!("stream", id = %recv.id());
+                    let connection = connection.clone();
+                    let echo = self.clone();
+                    let liveness = liveness.clone();
+                    let state = state.clone();
+                    // Spawn a task to handle each stream independently
+                    let handler = async move {
+                        match echo.strike(&connection).await {
+                            Strike::Deliver => {}
+                            Strike::Drop => return,
+                            Strike::Reset => {
+                                recv.stop(CHAOS_RESET).ok();
+                                return;
+                            }
+                        }
+                        // Oversized and stalled streams are stopped unread,
+                        // which the sender sees as a failed write
+                        let bytes = match echo.config.read_message(&mut recv).await {
+                            Ok(bytes) => bytes,
+                            Err(e) => {
+                                warn!("error receiving message: {:#}", e);
+                                return;
+                            }
+                        };
+                        let started = Instant::now();
+                        match echo.config.decode(&echo.codec, version, &bytes) {
+                            Ok((msg, size)) => {
+                                liveness.touch();
+                                let kind = msg.body.kind();
+                                echo.metrics.received(kind, size, started.elapsed());
+                                echo.events.emit(ConnEvent::MessageReceived {
+                                    peer: endpoint_id,
+                                    kind,
+                                    size,
+                                });
+                                // Pongs answering our own heartbeat need no reply
+                                if is_heartbeat(&msg) && matches!(msg.body, Message::Pong { .. }) {
+                                    echo.audit(
+                                        endpoint_id,
+                                        Direction::Received,
+                                        Some(msg.id),
+                                        Some(kind),
+                                        size,
+                                        Outcome::Handled,
+                                    );
+                                    return;
+                                }
+                                if !is_heartbeat(&msg) {
+                                    liveness.active();
+                                }
+                                debug!(id = msg.id, kind = ?msg.body.kind(), size, "received message");
+                                let ctx = Context {
+                                    peer: endpoint_id,
+                                    size,
+                                    deadline: deadline_of(&msg, started),
+                                    signed: Signed::of(&msg),
+                                };
+                                let span = handler_span(&msg);
+                                let answer = match echo.refusal(&state, version, &msg) {
+                                    Some(refusal) => Some(refusal),
+                                    None => {
+                                        let handled = echo.handle(ctx, &state, msg.body);
+                                        handled.instrument(span.clone()).await
+                                    }
+                                };
+                                let outcome = Outcome::of_answer(&answer);
+                                echo.audit(
+                                    endpoint_id,
+                                    Direction::Received,
+                                    Some(msg.id),
+                                    Some(kind),
+                                    size,
+                                    outcome,
+                                );
+                                let Some(body) = answer else {
+                                    return;
+                                };
+                                // Answer on a fresh stream, tagged with the request id
+                                let mut reply = MessageEnvelope::new(msg.id, body);
+                                span.in_scope(|| echo.intercept(endpoint_id, &mut reply));
+                                let encoding = Instant::now();
+                                let sent = match echo.config.encode(&echo.codec, version, &reply) {
+                                    Ok(encoded) => {
+                                        let kind = reply.body.kind();
+                                        echo.metrics.sent(kind, encoded.len(), encoding.elapsed());
+                                        let sent = echo
+                                            .config
+                                            .send_raw(&connection, encoded.clone())
+                                            .await;
+                                        let outcome = Outcome::of_send(&sent);
+                                        echo.audit(
+                                            endpoint_id,
+                                            Direction::Sent,
+                                            Some(reply.id),
+                                            Some(kind),
+                                            encoded.len(),
+                                            outcome,
+                                        );
+                                        sent
+                                    }
+                                    Err(e) => Err(e),
+                                };
+                                match sent {
+                                    Ok(()) => echo.metrics.handled(started.elapsed()),
+                                    Err(e) => warn!("error sending reply: {:#}", e),
+                                }
+                            }
+                            Err(e) => {
+                                warn!("error decoding message: {:#}", e);
+                                // Answer the request if its id survived, else as a push
+                                let id = echo.config.decode_id(&echo.codec, version, &bytes);
+                                echo.audit(
+                                    endpoint_id,
+                                    Direction::Received,
+                                    id,
+                                    None,
+                                    bytes.len(),
+                                    Outcome::Malformed,
+                                );
+                                let mut reply =
+                                    MessageEnvelope::new(id.unwrap_or(PUSH_ID), malformed(&e));
+                                echo.intercept(endpoint_id, &mut reply);
+                                if let Err(e) = echo.send_envelope(&connection, &reply).await {
+                                    warn!("error sending reply: {:#}", e);
+                                }
+                            }
+                        }
+                    };
+                    handlers.spawn(permit, self.isolated(handler).instrument(span));
+
+                    receive_count += 1;
+                }
+                // Connection closed
+                Err(_) => break,
+            }
+        }
+        let reason = connection.closed().await;
+        match RemoteClose::of(&reason) {
+            Some(close) => info!(messages = receive_count, %close, "connection closed by peer"),
+            None => info!(messages = receive_count, "connection closed"),
+        }
+        self.metrics.closed();
+        self.events.emit(ConnEvent::Disconnected {
+            peer: endpoint_id,
+            reason,
+        });
+
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.abort();
+        }
+        if let Some(idle) = idle {
+            idle.abort();
+        }
+        datagrams.abort();
+        if self.peers.remove(&connection) {
+            self.disconnected(endpoint_id);
+        }
+        handlers.join().await;
+    }
+}
+
+/// When the sender of `envelope`, which arrived at `received`, stops waiting
+/// for a response
+pub(crate) fn deadline_of(envelope: &MessageEnvelope, received: Instant) -> Option<Instant> {
+    let deadline = Duration::from_millis(envelope.deadline?);
+    received.checked_add(deadline)
+}
+
+/// The answer to a message that failed to decode with `e`
+///
+/// A frame not written for the connection's protocol version and codec is
+/// answered with [`ErrorCode::IncompatibleProtocol`], so its sender learns
+/// what it got wrong.
+fn malformed(e: &AnyError) -> Message {
+    let code = match e.downcast_ref::<IncompatibleProtocol>() {
+        Some(_) => ErrorCode::IncompatibleProtocol,
+        None => ErrorCode::Malformed,
+    };
+    Message::Error {
+        code,
+        detail: format!("{e:#}"),
+    }
+}
+
+/// Answer every datagram with a datagram, dropping what cannot be answered
+async fn serve_datagrams<C: Codec>(
+    echo: Echo<C>,
+    conn: Connection,
+    version: u32,
+    liveness: Liveness,
+    state: ConnState,
+) {
+    let from = conn.remote_id();
+    while let Ok(datagram) = conn.read_datagram().await {
+        if echo.strike(&conn).await != Strike::Deliver {
+            continue;
+        }
+        let started = Instant::now();
+        let msg: Message = match decode_datagram(&echo.codec, version, &datagram) {
+            Ok(msg) => msg,
+            Err(e) => {
+                debug!("error decoding datagram: {:#}", e);
+                echo.audit(
+                    from,
+                    Direction::Received,
+                    None,
+                    None,
+                    datagram.len(),
+                    Outcome::Malformed,
+                );
+                echo.send_datagram(&conn, version, malformed(&e)).ok();
+                continue;
+            }
+        };
+        liveness.active();
+        let (kind, size) = (msg.kind(), datagram.len());
+        echo.metrics.received(kind, size, started.elapsed());
+        echo.events.emit(ConnEvent::MessageReceived {
+            peer: from,
+            kind,
+            size,
+        });
+        let ctx = Context {
+            peer: from,
+            size,
+            deadline: None,
+            signed: None,
+        };
+        // Datagrams carry no envelope to sign or number, but the sender's
+        // capabilities still apply
+        let refusal = echo.permissions.refusal(&state.get::<Capabilities>(), &msg);
+        let answer = match refusal {
+            Some(refusal) => Some(refusal),
+            None => echo.handle(ctx, &state, msg).await,
+        };
+        let outcome = Outcome::of_answer(&answer);
+        echo.audit(from, Direction::Received, None, Some(kind), size, outcome);
+        let Some(reply) = answer else {
+            continue;
+        };
+        match echo.send_datagram(&conn, version, reply) {
+            Ok(()) => echo.metrics.handled(started.elapsed()),
+            Err(e) => debug!("error sending datagram: {:#}", e),
+        }
+    }
+}
+
+/// Echo every frame of a persistent framed session back in order
+async fn serve_framed<C: Codec>(
+    echo: Echo<C>,
+    conn: Connection,
+    framed: FramedConnection<C>,
+    version: u32,
+    liveness: Liveness,
+    state: ConnState,
+) {
+    let from = conn.remote_id();
+    // Replies are written in the background, so a slow write does not hold
+    // up reading the next request
+    let (sender, mut framed) = framed.split();
+    let pipeline = sender.pipelined(PIPELINE_DEPTH);
+    let mut finished = false;
+    loop {
+        let frame = framed.recv_bytes().await;
+        let started = Instant::now();
+        match frame {
+            Ok(Some(bytes)) => {
+                match echo.strike(&conn).await {
+                    Strike::Deliver => {}
+                    Strike::Drop => continue,
+                    Strike::Reset => {
+                        framed.stop(CHAOS_RESET).ok();
+                        break;
+                    }
+                }
+                let size = bytes.len();
+                let decoded = decode_frame(&echo.codec, version, &bytes).and_then(|msg| {
+                    echo.config.check_shape(&msg.body)?;
+                    Ok(msg)
+                });
+                let msg = match decoded {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        // Still answer, so replies stay in step with requests
+                        warn!("error decoding frame: {:#}", e);
+                        let id = strip_preamble(&echo.codec, version, &bytes)
+                            .ok()
+                            .and_then(|envelope| envelope_id(&echo.codec, envelope));
+                        echo.audit(
+                            from,
+                            Direction::Received,
+                            id,
+                            None,
+                            size,
+                            Outcome::Malformed,
+                        );
+                        let mut reply = MessageEnvelope::new(id.unwrap_or(PUSH_ID), malformed(&e));
+                        echo.intercept(from, &mut reply);
+                        let sent = match reply.frame(&echo.codec, version) {
+                            Ok(encoded) => {
+                                let size = encoded.len();
+                                let sent = pipeline.send_raw(encoded).await;
+                                let outcome = Outcome::of_send(&sent);
+                                echo.audit(
+                                    from,
+                                    Direction::Sent,
+                                    id,
+                                    Some(reply.body.kind()),
+                                    size,
+                                    outcome,
+                                );
+                                sent
+                            }
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = sent {
+                            warn!("error sending frame: {:#}", e);
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                liveness.active();
+                echo.metrics
+                    .received(msg.body.kind(), size, started.elapsed());
+                echo.events.emit(ConnEvent::MessageReceived {
+                    peer: from,
+                    kind: msg.body.kind(),
+                    size,
+                });
+                debug!(id = msg.id, kind = ?msg.body.kind(), size, "received frame");
+
+                let ctx = Context {
+                    peer: from,
+                    size,
+                    deadline: deadline_of(&msg, started),
+                    signed: Signed::of(&msg),
+                };
+                let kind = msg.body.kind();
+                let span = handler_span(&msg);
+                let answer = match echo.refusal(&state, version, &msg) {
+                    Some(refusal) => Some(refusal),
+                    None => {
+                        let handled = echo.handle(ctx, &state, msg.body);
+                        handled.instrument(span.clone()).await
+                    }
+                };
+                let outcome = Outcome::of_answer(&answer);
+                echo.audit(
+                    from,
+                    Direction::Received,
+                    Some(msg.id),
+                    Some(kind),
+                    size,
+                    outcome,
+                );
+                let Some(body) = answer else {
+                    continue;
+                };
+                let mut reply = MessageEnvelope::new(msg.id, body);
+                span.in_scope(|| echo.intercept(from, &mut reply));
+                let encoding = Instant::now();
+                let sent = match reply.frame(&echo.codec, version) {
+                    Ok(encoded) => {
+                        let (kind, size) = (reply.body.kind(), encoded.len());
+                        echo.metrics.sent(kind, size, encoding.elapsed());
+                        let sent = pipeline.send_raw(encoded).await;
+                        let outcome = Outcome::of_send(&sent);
+                        echo.audit(
+                            from,
+                            Direction::Sent,
+                            Some(reply.id),
+                            Some(kind),
+                            size,
+                            outcome,
+                        );
+                        sent
+                    }
+                    Err(e) => Err(e),
+                };
+                match sent {
+                    Ok(()) => echo.metrics.handled(started.elapsed()),
+                    Err(e) => {
+                        warn!("error sending frame: {:#}", e);
+                        break;
+                    }
+                }
+            }
+            Ok(None) => {
+                finished = true;
+                break;
+            }
+            Err(e) => {
+                warn!("error receiving frame: {:#}", e);
+                break;
+            }
+        }
+    }
+    match pipeline.finish().await {
+        Ok(mut sender) if finished => {
+            sender.finish().ok();
+        }
+        Ok(_) => {}
+        Err(e) => warn!("error sending frame: {:#}", e),
+    }
+}
+
+/// What a panic said, if it said it with a string
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown panic", String::as_str),
+    }
+}
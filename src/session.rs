@@ -0,0 +1,141 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use bincode::{Decode, Encode};
+use iroh::EndpointId;
+use serde::{Deserialize, Serialize};
+
+/// What a player is up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub enum Status {
+    Online,
+    Away,
+    InGame,
+    /// Not logged in, or disconnected
+    Offline,
+}
+
+/// The presence of one player, as pushed to the peers watching it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct Presence {
+    #[bincode(with_serde)]
+    pub peer: EndpointId,
+    /// The name the player logged in with, empty if it never did
+    pub name: String,
+    pub status: Status,
+}
+
+/// A [`Presence`] and the watchers to push it to
+pub type PresenceUpdate = (Presence, Vec<EndpointId>);
+
+#[derive(Debug, Default)]
+struct Sessions {
+    /// Name and status of every logged-in player
+    players: HashMap<EndpointId, (String, Status)>,
+    /// Who watches each peer
+    watchers: HashMap<EndpointId, HashSet<EndpointId>>,
+}
+
+impl Sessions {
+    fn presence(&self, peer: EndpointId) -> Presence {
+        match self.players.get(&peer) {
+            Some((name, status)) => Presence {
+                peer,
+                name: name.clone(),
+                status: *status,
+            },
+            None => Presence {
+                peer,
+                name: String::new(),
+                status: Status::Offline,
+            },
+        }
+    }
+
+    /// `presence` with the peers watching it
+    fn update(&self, presence: Presence) -> PresenceUpdate {
+        let peer = presence.peer;
+        let watchers = self.watchers.get(&peer).into_iter().flatten();
+        let watchers = watchers.filter(|watcher| **watcher != peer).copied();
+        (presence, watchers.collect())
+    }
+
+    /// End the session of `peer`, keeping its name in the offline presence
+    fn logout(&mut self, peer: EndpointId) -> Option<PresenceUpdate> {
+        let (name, _) = self.players.remove(&peer)?;
+        let status = Status::Offline;
+        Some(self.update(Presence { peer, name, status }))
+    }
+}
+
+/// The players logged in to a server, and who watches their presence
+///
+/// Names are for display only and need not be unique; players are told
+/// apart by their [`EndpointId`]. Clones share the same sessions.
+#[derive(Debug, Clone, Default)]
+pub struct SessionManager(Arc<Mutex<Sessions>>);
+
+impl SessionManager {
+    /// Log `peer` in as `name`, or rename it if already logged in
+    ///
+    /// A new session starts [`Status::Online`].
+    pub fn login(&self, peer: EndpointId, name: String) -> PresenceUpdate {
+        let mut sessions = self.0.lock().expect("poisoned");
+        let status = match sessions.players.get(&peer) {
+            Some((_, status)) => *status,
+            None => Status::Online,
+        };
+        sessions.players.insert(peer, (name, status));
+        sessions.update(sessions.presence(peer))
+    }
+
+    /// Change the status of the logged-in `peer`
+    ///
+    /// [`Status::Offline`] logs it out. Returns `None` if `peer` is not
+    /// logged in.
+    pub fn set_status(&self, peer: EndpointId, status: Status) -> Option<PresenceUpdate> {
+        let mut sessions = self.0.lock().expect("poisoned");
+        if status == Status::Offline {
+            return sessions.logout(peer);
+        }
+        sessions.players.get_mut(&peer)?.1 = status;
+        Some(sessions.update(sessions.presence(peer)))
+    }
+
+    /// Have `watcher` told about every presence change of `peers`
+    ///
+    /// Returns their current presence.
+    pub fn watch(&self, watcher: EndpointId, peers: &[EndpointId]) -> Vec<Presence> {
+        let mut sessions = self.0.lock().expect("poisoned");
+        for peer in peers {
+            sessions.watchers.entry(*peer).or_default().insert(watcher);
+        }
+        peers.iter().map(|peer| sessions.presence(*peer)).collect()
+    }
+
+    /// End the session of the disconnected `peer` and drop what it watched
+    ///
+    /// Returns the offline presence to push to its watchers, if it was
+    /// logged in.
+    pub fn remove_peer(&self, peer: &EndpointId) -> Option<PresenceUpdate> {
+        let mut sessions = self.0.lock().expect("poisoned");
+        sessions.watchers.retain(|_, watchers| {
+            watchers.remove(peer);
+            !watchers.is_empty()
+        });
+        sessions.logout(*peer)
+    }
+
+    pub fn presence(&self, peer: EndpointId) -> Presence {
+        self.0.lock().expect("poisoned").presence(peer)
+    }
+
+    /// Every logged-in player
+    pub fn players(&self) -> Vec<Presence> {
+        let sessions = self.0.lock().expect("poisoned");
+        let players = sessions.players.keys();
+        players.map(|peer| sessions.presence(*peer)).collect()
+    }
+}
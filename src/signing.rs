@@ -0,0 +1,224 @@
+use std::{collections::HashSet, fmt};
+
+use bincode::{Decode, Encode};
+use iroh::{PublicKey, SecretKey, Signature};
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    codec::{Bincode, Codec},
+    protocol::{ErrorCode, Message, MessageEnvelope},
+};
+
+/// Prefix of every signed message, so a signature over one can never pass for
+/// one over anything else signed with the same key
+const SIGNING_CONTEXT: &[u8] = b"iroh-example/echo/signed/0";
+
+// ====================
+// Signatures
+// ====================
+
+/// An application key's signature over the body and counter of a
+/// [`MessageEnvelope`]
+///
+/// The signature covers the body as [`Bincode`] encodes it, whatever codec
+/// the message travels in, so a node relaying it may re-encode it without
+/// breaking the signature. The envelope's id and deadline are not covered;
+/// its counter is, so a receiver's
+/// [`ReplayWindow`](crate::replay::ReplayWindow) refuses the message if it
+/// comes again on the same connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct MessageSignature {
+    /// The public half of the key that signed
+    pub key: [u8; 32],
+    /// [`Signature::LENGTH`] bytes of ed25519 signature
+    pub signature: Vec<u8>,
+}
+
+/// The signature a received message came with and the counter it covers, for
+/// passing the message on as its author signed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signed {
+    pub signature: MessageSignature,
+    pub counter: Option<u64>,
+}
+
+impl Signed {
+    /// The signature on `envelope`, if it has one
+    pub fn of(envelope: &MessageEnvelope) -> Option<Self> {
+        let signature = envelope.signature.clone()?;
+        Some(Self {
+            signature,
+            counter: envelope.counter,
+        })
+    }
+
+    /// Put the signature back on `envelope`, which must carry the body it was
+    /// made for
+    pub fn apply(&self, envelope: &mut MessageEnvelope) {
+        envelope.signature = Some(self.signature.clone());
+        envelope.counter = self.counter;
+    }
+}
+
+/// Signs the messages one end sends, and decides whose signatures it accepts
+/// on the messages it receives
+///
+/// The keys are application keys, distinct from the endpoint's identity, so
+/// a message tells who wrote it even after a node in between passed it on.
+pub trait KeyProvider: fmt::Debug + Send + Sync + 'static {
+    /// The key to sign outgoing messages with, if this end signs at all
+    fn signing_key(&self) -> Option<&SecretKey>;
+
+    /// Whether messages signed with `key` are accepted
+    fn is_trusted(&self, key: &PublicKey) -> bool;
+
+    /// Whether inbound messages must be signed at all, rather than only
+    /// outbound ones
+    fn requires_signatures(&self) -> bool {
+        true
+    }
+}
+
+/// Sign the body and counter of `envelope` with `key`, replacing any
+/// signature it had
+pub fn sign(envelope: &mut MessageEnvelope, key: &SecretKey) -> Result<()> {
+    let signature = key.sign(&signed_bytes(envelope)?);
+    envelope.signature = Some(MessageSignature {
+        key: *key.public().as_bytes(),
+        signature: signature.to_bytes().to_vec(),
+    });
+    Ok(())
+}
+
+/// Check that `envelope` is signed by a key `keys` trusts, returning that key
+pub fn verify(envelope: &MessageEnvelope, keys: &dyn KeyProvider) -> Result<PublicKey> {
+    let signed = envelope
+        .signature
+        .as_ref()
+        .ok_or_else(|| anyerr!("message is not signed"))?;
+    let key = PublicKey::from_bytes(&signed.key).anyerr()?;
+    if !keys.is_trusted(&key) {
+        return Err(anyerr!("untrusted signing key {}", key.fmt_short()));
+    }
+    let signature: &[u8; Signature::LENGTH] = signed
+        .signature
+        .as_slice()
+        .try_into()
+        .std_context("malformed signature")?;
+    key.verify(&signed_bytes(envelope)?, &Signature::from_bytes(signature))
+        .std_context("invalid signature")?;
+    Ok(key)
+}
+
+/// Sign `envelope` if `keys` has a key to sign with, unless it already
+/// carries a signature, such as that of the message's author when passing it
+/// on
+///
+/// A message that fails to sign goes out unsigned, for the peer to refuse.
+pub(crate) fn sign_outgoing(keys: Option<&dyn KeyProvider>, envelope: &mut MessageEnvelope) {
+    let Some(key) = keys.and_then(|keys| keys.signing_key()) else {
+        return;
+    };
+    if envelope.signature.is_none()
+        && let Err(e) = sign(envelope, key)
+    {
+        warn!("error signing message: {:#}", e);
+    }
+}
+
+/// The answer to a message refused for its signature, if `keys` requires one
+/// it lacks
+pub(crate) fn refusal(
+    keys: Option<&dyn KeyProvider>,
+    envelope: &MessageEnvelope,
+) -> Option<Message> {
+    check(keys, envelope).err()
+}
+
+/// Like [`refusal`], but for an envelope that passes, the key that signed
+/// it, if `keys` had it verified
+pub(crate) fn check(
+    keys: Option<&dyn KeyProvider>,
+    envelope: &MessageEnvelope,
+) -> Result<Option<PublicKey>, Message> {
+    let Some(keys) = keys.filter(|keys| keys.requires_signatures()) else {
+        return Ok(None);
+    };
+    match verify(envelope, keys) {
+        Ok(signer) => Ok(Some(signer)),
+        Err(e) => Err(Message::Error {
+            code: ErrorCode::Unauthorized,
+            detail: format!("{e:#}"),
+        }),
+    }
+}
+
+/// What the signature of `envelope` covers: its body, then its counter if it
+/// has one, as envelopes older than protocol version 6 do not
+fn signed_bytes(envelope: &MessageEnvelope) -> Result<Vec<u8>> {
+    let mut bytes = [SIGNING_CONTEXT, &Bincode.encode(&envelope.body)?].concat();
+    if let Some(counter) = envelope.counter {
+        bytes.extend_from_slice(&counter.to_be_bytes());
+    }
+    Ok(bytes)
+}
+
+// ====================
+// Keyring
+// ====================
+
+/// A [`KeyProvider`] with a fixed set of trusted keys, and optionally a key of
+/// its own to sign with
+///
+/// A keyring trusting no keys only signs, accepting unsigned messages.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    secret: Option<SecretKey>,
+    trusted: HashSet<PublicKey>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("public", &self.public())
+            .field("trusted", &self.trusted)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Keyring {
+    /// Sign with `secret`, trusting nobody until told to
+    pub fn new(secret: SecretKey) -> Self {
+        Self {
+            secret: Some(secret),
+            trusted: HashSet::new(),
+        }
+    }
+
+    /// Also accept messages signed with any of `keys`
+    pub fn with_trusted(mut self, keys: impl IntoIterator<Item = PublicKey>) -> Self {
+        self.trusted.extend(keys);
+        self
+    }
+
+    /// The key messages are signed with, which peers need to trust
+    pub fn public(&self) -> Option<PublicKey> {
+        self.secret.as_ref().map(SecretKey::public)
+    }
+}
+
+impl KeyProvider for Keyring {
+    fn signing_key(&self) -> Option<&SecretKey> {
+        self.secret.as_ref()
+    }
+
+    fn is_trusted(&self, key: &PublicKey) -> bool {
+        self.trusted.contains(key)
+    }
+
+    fn requires_signatures(&self) -> bool {
+        !self.trusted.is_empty()
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{task::JoinSet, time::MissedTickBehavior};
+use tracing::warn;
+
+use crate::{codec::Codec, protocol::Message, reconnect::ReconnectingClient};
+
+/// How long and how hard a soak test runs
+#[derive(Debug, Clone, Copy)]
+pub struct SoakConfig {
+    /// Total running time
+    pub duration: Duration,
+    /// Requests per second sent by each client, must be positive
+    pub rate: f64,
+    /// Time between two reports
+    pub report_every: Duration,
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(60 * 60),
+            rate: 10.0,
+            report_every: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The state of the process at one point of a soak test
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub elapsed: Duration,
+    /// Resident memory of the whole process, where the platform exposes it
+    pub rss_bytes: Option<u64>,
+    /// Tasks alive on the current tokio runtime
+    pub tasks: usize,
+    pub sent: u64,
+    pub answered: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: AtomicU64,
+    answered: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Keep every client sending requests cycling through `mix` at a steady rate
+/// for the whole duration, handing a [`Snapshot`] to `report` periodically
+///
+/// Failed requests are counted and the client carries on, reconnecting as
+/// needed, so a soak test survives the transient failures it is meant to
+/// surface. Returns the final snapshot.
+pub async fn run_soak<C: Codec>(
+    clients: Vec<ReconnectingClient<C>>,
+    config: SoakConfig,
+    mix: &[Message],
+    mut report: impl FnMut(&Snapshot),
+) -> Snapshot {
+    let counters = Arc::new(Counters::default());
+    let period = Duration::from_secs_f64(1.0 / config.rate);
+    let mut tasks = JoinSet::new();
+    for client in clients {
+        let counters = counters.clone();
+        let mix = mix.to_vec();
+        tasks.spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            for msg in mix.iter().cycle() {
+                ticker.tick().await;
+                counters.sent.fetch_add(1, Ordering::Relaxed);
+                match client.request(msg.clone()).await {
+                    Ok(_) => counters.answered.fetch_add(1, Ordering::Relaxed),
+                    Err(e) => {
+                        warn!("soak request failed: {:#}", e);
+                        counters.failed.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+            }
+        });
+    }
+
+    let started = Instant::now();
+    let snapshot = || Snapshot {
+        elapsed: started.elapsed(),
+        rss_bytes: resident_memory(),
+        tasks: tokio::runtime::Handle::current()
+            .metrics()
+            .num_alive_tasks(),
+        sent: counters.sent.load(Ordering::Relaxed),
+        answered: counters.answered.load(Ordering::Relaxed),
+        failed: counters.failed.load(Ordering::Relaxed),
+    };
+    let mut ticker = tokio::time::interval_at(
+        tokio::time::Instant::now() + config.report_every,
+        config.report_every,
+    );
+    let end = tokio::time::sleep(config.duration);
+    tokio::pin!(end);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => report(&snapshot()),
+            _ = &mut end => break,
+        }
+    }
+    tasks.shutdown().await;
+    snapshot()
+}
+
+/// Resident set size of this process, read from `/proc` on Linux
+pub(crate) fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use bincode::{Decode, Encode};
+use iroh::endpoint::Connection;
+use serde::{Deserialize, Serialize};
+
+// ====================
+// Connection Statistics
+// ====================
+
+/// Transport statistics of one connection, as seen from one end
+///
+/// Everything but `streams_opened` comes from the QUIC connection itself, so
+/// it covers all traffic, handshake and acknowledgements included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct ConnectionStats {
+    /// Current round-trip time estimate
+    pub rtt: Duration,
+    /// Current congestion window, in bytes
+    pub cwnd: u64,
+    pub congestion_events: u64,
+    /// UDP payload bytes sent
+    pub bytes_sent: u64,
+    /// UDP payload bytes received
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    /// Packets declared lost, which QUIC datagrams are never resent for
+    pub packets_lost: u64,
+    pub datagrams_sent: u64,
+    pub datagrams_received: u64,
+    /// Streams the other end opened
+    pub streams_opened: u64,
+}
+
+impl ConnectionStats {
+    /// Read the current statistics of `conn`, whose peer opened
+    /// `streams_opened` streams so far
+    pub fn of(conn: &Connection, streams_opened: u64) -> Self {
+        let stats = conn.stats();
+        Self {
+            rtt: stats.path.rtt,
+            cwnd: stats.path.cwnd,
+            congestion_events: stats.path.congestion_events,
+            bytes_sent: stats.udp_tx.bytes,
+            bytes_received: stats.udp_rx.bytes,
+            packets_sent: stats.path.sent_packets,
+            packets_lost: stats.path.lost_packets,
+            datagrams_sent: stats.frame_tx.datagram,
+            datagrams_received: stats.frame_rx.datagram,
+            streams_opened,
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use iroh::endpoint::Connection;
+use n0_error::{Result, anyerr};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    codec::{Bincode, Codec},
+    framed::FramedConnection,
+    protocol::MAX_MESSAGE_SIZE,
+};
+
+/// Idle streams a [`StreamPool`] keeps by default
+pub const STREAM_POOL_SIZE: usize = 8;
+
+// ====================
+// Stream Pool
+// ====================
+
+/// Bidirectional framed streams of one connection, reused across requests
+/// instead of opening a stream for every message
+///
+/// Each stream carries one request at a time and the answer comes back on
+/// it, so a request is still a simple exchange of one frame each way. A
+/// reused stream has its flow control window already grown, and skips
+/// opening a stream. Streams come from the pool idle, or are opened on
+/// demand when none is; up to the pool's size go back to it afterwards.
+///
+/// Only send requests the peer answers. A stream whose request failed or
+/// was abandoned is closed instead of returned, so a late answer can never
+/// be taken for that of the next request. Clones share their streams.
+#[derive(Debug, Clone)]
+pub struct StreamPool<C = Bincode> {
+    conn: Connection,
+    codec: C,
+    max_frame_size: usize,
+    max_idle: usize,
+    idle: Arc<Mutex<Vec<FramedConnection<C>>>>,
+}
+
+impl<C: Codec> StreamPool<C> {
+    /// A pool of streams of `conn`, keeping up to [`STREAM_POOL_SIZE`] idle
+    pub fn new(conn: Connection, codec: C) -> Self {
+        Self {
+            conn,
+            codec,
+            max_frame_size: MAX_MESSAGE_SIZE,
+            max_idle: STREAM_POOL_SIZE,
+            idle: Arc::default(),
+        }
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Keep up to `max_idle` streams idle instead of [`STREAM_POOL_SIZE`]
+    pub fn with_max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Open streams until as many are idle as the pool keeps
+    ///
+    /// The peer only learns of a stream once the first frame is sent on it,
+    /// so this just saves waiting for the stream limit later.
+    pub async fn prewarm(&self) -> Result<()> {
+        while self.idle() < self.max_idle {
+            let stream = self.open().await?;
+            self.put(stream);
+        }
+        Ok(())
+    }
+
+    /// Streams waiting in the pool
+    pub fn idle(&self) -> usize {
+        self.idle.lock().expect("poisoned").len()
+    }
+
+    /// A stream to use exclusively, idle or newly opened
+    ///
+    /// It only goes back to the pool through [`PooledStream::release`].
+    pub async fn checkout(&self) -> Result<PooledStream<C>> {
+        let idle = self.idle.lock().expect("poisoned").pop();
+        let stream = match idle {
+            Some(stream) => stream,
+            None => self.open().await?,
+        };
+        Ok(PooledStream {
+            stream: Some(stream),
+            pool: self.clone(),
+        })
+    }
+
+    /// Send `msg` on a pooled stream and wait for the answer
+    pub async fn send_message<T: Serialize, R: DeserializeOwned>(&self, msg: &T) -> Result<R> {
+        let mut stream = self.checkout().await?;
+        stream.send(msg).await?;
+        let reply = stream.recv().await?;
+        stream.release();
+        reply.ok_or_else(|| anyerr!("peer finished the stream before answering"))
+    }
+
+    /// Send a message that is already encoded on a pooled stream and wait for
+    /// the encoded answer
+    pub async fn send_raw(&self, encoded: Bytes) -> Result<Vec<u8>> {
+        let mut stream = self.checkout().await?;
+        stream.send_raw(encoded).await?;
+        let reply = stream.recv_bytes().await?;
+        stream.release();
+        reply.ok_or_else(|| anyerr!("peer finished the stream before answering"))
+    }
+
+    async fn open(&self) -> Result<FramedConnection<C>> {
+        let stream = FramedConnection::open(&self.conn, self.codec.clone()).await?;
+        Ok(stream.with_max_frame_size(self.max_frame_size))
+    }
+
+    /// Keep `stream` for the next checkout, or finish it if the pool is full
+    fn put(&self, mut stream: FramedConnection<C>) {
+        let mut idle = self.idle.lock().expect("poisoned");
+        match idle.len() < self.max_idle {
+            true => idle.push(stream),
+            false => {
+                stream.finish().ok();
+            }
+        }
+    }
+}
+
+/// A stream checked out of a [`StreamPool`]
+///
+/// Dropping it closes the stream; [`release`](Self::release) it once its
+/// exchange is complete to have it reused.
+#[derive(Debug)]
+pub struct PooledStream<C = Bincode> {
+    stream: Option<FramedConnection<C>>,
+    pool: StreamPool<C>,
+}
+
+impl<C: Codec> PooledStream<C> {
+    /// Hand the stream back to its pool
+    ///
+    /// Only release a stream with no frame still to come, or the next user
+    /// reads it as their answer.
+    pub fn release(mut self) {
+        if let Some(stream) = self.stream.take() {
+            self.pool.put(stream);
+        }
+    }
+}
+
+impl<C> Deref for PooledStream<C> {
+    type Target = FramedConnection<C>;
+
+    fn deref(&self) -> &Self::Target {
+        self.stream.as_ref().expect("released")
+    }
+}
+
+impl<C> DerefMut for PooledStream<C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.stream.as_mut().expect("released")
+    }
+}
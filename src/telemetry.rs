@@ -0,0 +1,134 @@
+use bincode::{Decode, Encode};
+#[cfg(feature = "otel")]
+use n0_error::{Result, StdResultExt};
+use serde::{Deserialize, Serialize};
+use tracing::{Span, info_span};
+
+use crate::protocol::MessageEnvelope;
+
+// ====================
+// Trace Context
+// ====================
+
+/// Where in a distributed trace a message was sent from, as the ids of a W3C
+/// trace context
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Encode, Decode)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// The sending span, which the receiving one becomes a child of
+    pub span_id: [u8; 8],
+    /// Whether the sender records the trace, so the receiver should too
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// The context of the current span, if it is part of an OpenTelemetry
+    /// trace, which it never is without the `otel` feature
+    pub fn current() -> Option<Self> {
+        #[cfg(feature = "otel")]
+        {
+            use opentelemetry::trace::TraceContextExt;
+            use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+            let cx = Span::current().context();
+            let span = cx.span();
+            let span = span.span_context();
+            span.is_valid().then(|| Self {
+                trace_id: span.trace_id().to_bytes(),
+                span_id: span.span_id().to_bytes(),
+                sampled: span.is_sampled(),
+            })
+        }
+        #[cfg(not(feature = "otel"))]
+        None
+    }
+
+    /// Make this the remote parent of `span`
+    #[cfg(feature = "otel")]
+    fn adopt(&self, span: &Span) {
+        use opentelemetry::trace::{
+            SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+        };
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let flags = match self.sampled {
+            true => TraceFlags::SAMPLED,
+            false => TraceFlags::default(),
+        };
+        let parent = SpanContext::new(
+            TraceId::from_bytes(self.trace_id),
+            SpanId::from_bytes(self.span_id),
+            flags,
+            true,
+            TraceState::default(),
+        );
+        let cx = opentelemetry::Context::new().with_remote_span_context(parent);
+        // Fails only for spans that are not recorded anyway
+        span.set_parent(cx).ok();
+    }
+}
+
+/// The span a peer's `envelope` is handled in, continuing the trace it was
+/// sent from
+pub(crate) fn handler_span(envelope: &MessageEnvelope) -> Span {
+    let span = info_span!("handle", id = envelope.id, kind = ?envelope.body.kind());
+    #[cfg(feature = "otel")]
+    if let Some(trace) = &envelope.trace {
+        trace.adopt(&span);
+    }
+    span
+}
+
+// ====================
+// OTLP Export
+// ====================
+
+/// Exports spans to an OpenTelemetry collector over OTLP/HTTP, until shut
+/// down
+#[cfg(feature = "otel")]
+#[derive(Debug)]
+pub struct Otlp {
+    provider: opentelemetry_sdk::trace::SdkTracerProvider,
+}
+
+#[cfg(feature = "otel")]
+impl Otlp {
+    /// Export to the collector at `endpoint`, e.g. `http://localhost:4318`,
+    /// as the service `service`
+    pub fn new(endpoint: &str, service: &str) -> Result<Self> {
+        use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+        use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()
+            .std_context("building OTLP exporter")?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service.to_string())
+                    .build(),
+            )
+            .build();
+        Ok(Self { provider })
+    }
+
+    /// A tracing layer turning spans into OpenTelemetry spans exported here
+    pub fn layer<S>(&self) -> impl tracing_subscriber::Layer<S> + use<S>
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        use opentelemetry::trace::TracerProvider;
+
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer("wstest"))
+    }
+
+    /// Export whatever spans are still buffered, then stop
+    pub fn shutdown(self) -> Result<()> {
+        self.provider
+            .shutdown()
+            .std_context("shutting down OTLP exporter")
+    }
+}
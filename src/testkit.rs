@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use iroh::EndpointId;
+use n0_error::{Result, anyerr};
+
+use crate::{
+    client::{Client, dial},
+    codec::Bincode,
+    middleware::{Context, Middleware, Verdict},
+    protocol::Message,
+    server::{self, Echo, Server},
+};
+
+// ====================
+// Multi-Node Test Network
+// ====================
+
+/// Which nodes of a [`TestNet`] are linked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    /// Node 0 is linked to every other node, which are not linked otherwise
+    Star,
+    /// Every node is linked to every other node
+    Mesh,
+    /// Each node is linked to the one before and the one after it
+    Chain,
+}
+
+impl Topology {
+    /// The linked pairs among `n` nodes, lower index first
+    pub fn edges(self, n: usize) -> Vec<(usize, usize)> {
+        match self {
+            Topology::Star => (1..n).map(|leaf| (0, leaf)).collect(),
+            Topology::Mesh => (0..n)
+                .flat_map(|a| (a + 1..n).map(move |b| (a, b)))
+                .collect(),
+            Topology::Chain => (1..n).map(|b| (b - 1, b)).collect(),
+        }
+    }
+}
+
+/// One message of a scenario, sent from node `from` to its neighbour `to`
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub from: usize,
+    pub to: usize,
+    pub msg: Message,
+}
+
+impl Step {
+    pub fn new(from: usize, to: usize, msg: Message) -> Self {
+        Self { from, to, msg }
+    }
+}
+
+/// Records every message that reaches a node's handler, in arrival order
+#[derive(Debug, Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<(EndpointId, Message)>>>);
+
+impl Middleware for Recorder {
+    fn on_recv(&self, ctx: &Context, msg: Message) -> Verdict {
+        self.0
+            .lock()
+            .expect("poisoned")
+            .push((ctx.peer, msg.clone()));
+        Verdict::Continue(msg)
+    }
+}
+
+/// Echo servers on in-process endpoints, linked in a [`Topology`]
+///
+/// Every node has an identity of its own and runs a default [`Echo`]
+/// handler. Each link carries a connection in both directions, dialed from
+/// the sending node's own endpoint, so a node sees its neighbours under
+/// their real ids. Everything a handler receives is recorded, for asserting
+/// on what arrived and in which order.
+#[derive(Debug)]
+pub struct TestNet {
+    nodes: Vec<Server>,
+    received: Vec<Recorder>,
+    links: HashMap<(usize, usize), Client>,
+}
+
+impl TestNet {
+    /// Spawn `n` nodes and link them as `topology` says
+    pub async fn spawn(n: usize, topology: Topology) -> Result<Self> {
+        let mut nodes = Vec::with_capacity(n);
+        let mut received = Vec::with_capacity(n);
+        for _ in 0..n {
+            let recorder = Recorder::default();
+            let echo = Echo::new(Bincode).with_middleware(recorder.clone());
+            let node = server::spawn(0, echo).await?;
+            node.ready().await?;
+            nodes.push(node);
+            received.push(recorder);
+        }
+
+        let mut links = HashMap::new();
+        for (a, b) in topology.edges(n) {
+            for (from, to) in [(a, b), (b, a)] {
+                let conn = dial(nodes[from].endpoint(), nodes[to].endpoint().addr()).await?;
+                links.insert((from, to), Client::new(conn, Bincode));
+            }
+        }
+        Ok(Self {
+            nodes,
+            received,
+            links,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The server of node `i`
+    pub fn node(&self, i: usize) -> &Server {
+        &self.nodes[i]
+    }
+
+    /// The identity of node `i`
+    pub fn id(&self, i: usize) -> EndpointId {
+        self.nodes[i].endpoint().id()
+    }
+
+    /// The client node `from` talks to its neighbour `to` with
+    pub fn link(&self, from: usize, to: usize) -> Option<&Client> {
+        self.links.get(&(from, to))
+    }
+
+    /// Send `msg` from node `from` to its neighbour `to` and wait for the
+    /// answer
+    pub async fn send(&self, from: usize, to: usize, msg: Message) -> Result<Message> {
+        let link = self
+            .link(from, to)
+            .ok_or_else(|| anyerr!("node {} is not linked to node {}", from, to))?;
+        link.request(msg).await
+    }
+
+    /// Play `script` one step after the other, each waiting for its answer
+    pub async fn run(&self, script: &[Step]) -> Result<Vec<Message>> {
+        let mut answers = Vec::with_capacity(script.len());
+        for step in script {
+            answers.push(self.send(step.from, step.to, step.msg.clone()).await?);
+        }
+        Ok(answers)
+    }
+
+    /// Everything node `to` received from node `from` so far, in order
+    pub fn received(&self, to: usize, from: usize) -> Vec<Message> {
+        let from = self.id(from);
+        self.received[to]
+            .0
+            .lock()
+            .expect("poisoned")
+            .iter()
+            .filter(|(peer, _)| *peer == from)
+            .map(|(_, msg)| msg.clone())
+            .collect()
+    }
+
+    /// Fail unless node `to` received exactly `expected` from node `from`,
+    /// in that order
+    pub fn expect_delivered(&self, to: usize, from: usize, expected: &[Message]) -> Result<()> {
+        let received = self.received(to, from);
+        // Messages only compare by how they print
+        if format!("{received:?}") != format!("{expected:?}") {
+            return Err(anyerr!(
+                "node {} expected {:?} from node {}, got {:?}",
+                to,
+                expected,
+                from,
+                received
+            ));
+        }
+        Ok(())
+    }
+
+    /// Shut every node down, without waiting for handlers still running
+    pub async fn shutdown(self) -> Result<()> {
+        drop(self.links);
+        for node in &self.nodes {
+            node.shutdown(Duration::ZERO).await?;
+        }
+        Ok(())
+    }
+}
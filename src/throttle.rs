@@ -0,0 +1,95 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// ====================
+// Bandwidth Throttling
+// ====================
+
+/// A token bucket capping how many bytes per second pass, shared by its
+/// clones
+///
+/// Up to `burst` bytes pass at once after a quiet period; beyond that
+/// callers wait their turn. A single write larger than the burst is let
+/// through once the bucket has paid it off, so nothing is ever refused.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    rate: u64,
+    burst: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may pass right now, negative while callers wait
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    /// Let `bytes_per_sec` through, in bursts of up to a second's worth
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self::with_burst(bytes_per_sec, bytes_per_sec)
+    }
+
+    /// Let `bytes_per_sec` through, in bursts of up to `burst` bytes
+    pub fn with_burst(bytes_per_sec: u64, burst: u64) -> Self {
+        let rate = bytes_per_sec.max(1);
+        Self {
+            rate,
+            burst,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: burst as f64,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.rate
+    }
+
+    /// Wait until `bytes` may pass
+    ///
+    /// Callers are served in the order they call, each one taking its bytes
+    /// out of the bucket before waiting for them.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("poisoned");
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * self.rate as f64;
+            bucket.tokens = (bucket.tokens + refill).min(self.burst as f64);
+            bucket.refilled = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.rate as f64)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Per-connection bandwidth caps, in bytes per second
+///
+/// Every connection gets buckets of its own, shared by all of its framed
+/// sessions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Cap on bytes sent
+    pub egress: Option<u64>,
+    /// Cap on bytes received; unread bytes hold up the sender through flow
+    /// control
+    pub ingress: Option<u64>,
+}
+
+impl RateLimit {
+    /// Fresh buckets for one connection, egress first
+    pub fn throttles(&self) -> (Option<Throttle>, Option<Throttle>) {
+        (
+            self.egress.map(Throttle::new),
+            self.ingress.map(Throttle::new),
+        )
+    }
+}
@@ -0,0 +1,63 @@
+use std::{fmt, str::FromStr};
+
+use iroh::EndpointAddr;
+use iroh_tickets::{ParseError, Ticket};
+use serde::{Deserialize, Serialize};
+
+use crate::codec::CodecKind;
+
+/// Everything a client needs to reach an echo server, as one pasteable string
+///
+/// Serializes to `echo` followed by base32, carrying the server's address and
+/// the codec it speaks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoTicket {
+    pub addr: EndpointAddr,
+    pub codec: CodecKind,
+}
+
+/// Wire format for [`EchoTicket`], versioned so later fields can be added
+#[derive(Serialize, Deserialize)]
+enum TicketWireFormat {
+    Variant1 {
+        addr: EndpointAddr,
+        codec: CodecKind,
+    },
+}
+
+impl EchoTicket {
+    pub fn new(addr: EndpointAddr, codec: CodecKind) -> Self {
+        Self { addr, codec }
+    }
+}
+
+impl Ticket for EchoTicket {
+    const KIND: &'static str = "echo";
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let data = TicketWireFormat::Variant1 {
+            addr: self.addr.clone(),
+            codec: self.codec,
+        };
+        postcard::to_stdvec(&data).expect("postcard serialization failed")
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let TicketWireFormat::Variant1 { addr, codec } = postcard::from_bytes(bytes)?;
+        Ok(Self { addr, codec })
+    }
+}
+
+impl FromStr for EchoTicket {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ticket::deserialize(s)
+    }
+}
+
+impl fmt::Display for EchoTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&Ticket::serialize(self))
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use iroh::{
+    endpoint::{Connection, RecvStream, SendStream},
+    protocol::{AcceptError, ProtocolHandler},
+};
+use n0_error::{Result, StdResultExt, anyerr};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf},
+    sync::watch,
+};
+use tracing::{info, warn};
+
+use crate::{
+    chunked::{CHUNK_SIZE, recv_stream, write_chunks},
+    codec::{Bincode, Codec},
+    framed::FramedConnection,
+    protocol::MAX_MESSAGE_SIZE,
+};
+
+pub const TRANSFER_ALPN: &[u8] = b"iroh-example/transfer/0";
+
+/// Describes a file ahead of its contents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileMeta {
+    /// Bare file name, without any directory components
+    pub name: String,
+    pub size: u64,
+    /// BLAKE3 hash of the contents
+    pub checksum: [u8; 32],
+}
+
+/// How far along a transfer is
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub name: String,
+    pub bytes: u64,
+    pub total: u64,
+}
+
+/// What the receiver writes back once the contents are stored or rejected
+type TransferResponse = std::result::Result<(), String>;
+
+// ====================
+// Receiver
+// ====================
+
+/// Stores files sent by peers in a directory
+///
+/// Each file travels on its own bidirectional stream: the sender writes a
+/// framed [`FileMeta`] followed by the contents as chunks, the receiver
+/// verifies size and checksum and answers with the outcome. Files are written
+/// to `<name>.part` and only renamed into place once verified.
+#[derive(Debug, Clone)]
+pub struct FileTransfer<C = Bincode> {
+    codec: C,
+    dir: PathBuf,
+    progress: watch::Sender<Progress>,
+}
+
+impl<C: Codec> FileTransfer<C> {
+    pub fn new(codec: C, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            codec,
+            dir: dir.into(),
+            progress: watch::Sender::default(),
+        }
+    }
+
+    /// Progress of the file currently being received
+    pub fn subscribe(&self) -> watch::Receiver<Progress> {
+        self.progress.subscribe()
+    }
+
+    async fn receive(&self, send: SendStream, recv: RecvStream) -> Result<FileMeta> {
+        let mut framed = FramedConnection::from_streams(send, recv, self.codec.clone());
+        let meta: FileMeta = framed
+            .recv()
+            .await?
+            .std_context("stream finished before file metadata")?;
+        let (mut send, recv) = framed.into_streams();
+
+        let result = self.store(&meta, recv).await;
+        let response: TransferResponse = match &result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("{e:#}")),
+        };
+        send.write_all(&self.codec.encode(&response)?)
+            .await
+            .anyerr()?;
+        send.finish().anyerr()?;
+        result.map(|()| meta)
+    }
+
+    async fn store(&self, meta: &FileMeta, recv: RecvStream) -> Result<()> {
+        let name = Path::new(&meta.name)
+            .file_name()
+            .filter(|name| *name == meta.name.as_str())
+            .ok_or_else(|| anyerr!("invalid file name {:?}", meta.name))?;
+        let dest = self.dir.join(name);
+        let part = self.dir.join(format!("{}.part", meta.name));
+
+        self.progress.send_replace(Progress {
+            name: meta.name.clone(),
+            bytes: 0,
+            total: meta.size,
+        });
+        let reader = Tracked {
+            inner: recv_stream(recv),
+            progress: self.progress.clone(),
+        };
+        let result = write_verified(reader, &part, meta).await;
+        match result {
+            Ok(()) => tokio::fs::rename(&part, &dest).await.anyerr(),
+            Err(e) => {
+                tokio::fs::remove_file(&part).await.ok();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<C: Codec> ProtocolHandler for FileTransfer<C> {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        while let Ok((send, recv)) = connection.accept_bi().await {
+            let transfer = self.clone();
+            tokio::spawn(async move {
+                match transfer.receive(send, recv).await {
+                    Ok(meta) => info!(name = %meta.name, size = meta.size, "received file"),
+                    Err(e) => warn!("error receiving file: {:#}", e),
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Copy `reader` into a new file at `path`, checking it against `meta`
+async fn write_verified(
+    mut reader: impl AsyncRead + Unpin,
+    path: &Path,
+    meta: &FileMeta,
+) -> Result<()> {
+    let mut file = File::create(path).await.anyerr()?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut size = 0u64;
+    loop {
+        let n = reader.read(&mut buf).await.anyerr()?;
+        if n == 0 {
+            break;
+        }
+        size += n as u64;
+        if size > meta.size {
+            return Err(anyerr!(
+                "file is larger than the announced {} bytes",
+                meta.size
+            ));
+        }
+        hasher.update(&buf[..n]);
+        file.write_all(&buf[..n]).await.anyerr()?;
+    }
+    file.flush().await.anyerr()?;
+
+    if size != meta.size {
+        return Err(anyerr!("received {} of {} bytes", size, meta.size));
+    }
+    if *hasher.finalize().as_bytes() != meta.checksum {
+        return Err(anyerr!("checksum mismatch"));
+    }
+    Ok(())
+}
+
+// ====================
+// Sender
+// ====================
+
+/// Send the file at `path` to a peer serving [`TRANSFER_ALPN`] on `conn`,
+/// reporting how many bytes went out on `progress`
+///
+/// Returns once the receiver has verified and stored the file.
+pub async fn send_file<C: Codec>(
+    conn: &Connection,
+    codec: &C,
+    path: impl AsRef<Path>,
+    progress: &watch::Sender<Progress>,
+) -> Result<FileMeta> {
+    let path = path.as_ref();
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyerr!("{} has no usable file name", path.display()))?;
+    let (size, checksum) = hash_file(path).await?;
+    let meta = FileMeta {
+        name: name.to_string(),
+        size,
+        checksum,
+    };
+
+    let (send, recv) = conn.open_bi().await.anyerr()?;
+    let mut framed = FramedConnection::from_streams(send, recv, codec.clone());
+    framed.send(&meta).await?;
+    let (mut send, mut recv) = framed.into_streams();
+
+    progress.send_replace(Progress {
+        name: meta.name.clone(),
+        bytes: 0,
+        total: size,
+    });
+    let reader = Tracked {
+        inner: File::open(path).await.anyerr()?,
+        progress: progress.clone(),
+    };
+    write_chunks(&mut send, reader).await?;
+    send.finish().anyerr()?;
+
+    let bytes = recv.read_to_end(MAX_MESSAGE_SIZE).await.anyerr()?;
+    let response: TransferResponse = codec.decode(&bytes)?;
+    match response {
+        Ok(()) => Ok(meta),
+        Err(e) => Err(anyerr!("receiver rejected {}: {}", meta.name, e)),
+    }
+}
+
+/// Size and BLAKE3 hash of the file at `path`
+async fn hash_file(path: &Path) -> Result<(u64, [u8; 32])> {
+    let mut file = File::open(path).await.anyerr()?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut size = 0u64;
+    loop {
+        let n = file.read(&mut buf).await.anyerr()?;
+        if n == 0 {
+            return Ok((size, *hasher.finalize().as_bytes()));
+        }
+        size += n as u64;
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Counts the bytes read through it into a progress channel
+struct Tracked<R> {
+    inner: R,
+    progress: watch::Sender<Progress>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tracked<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        if read > 0 {
+            self.progress.send_modify(|progress| progress.bytes += read);
+        }
+        poll
+    }
+}
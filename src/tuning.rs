@@ -0,0 +1,181 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use iroh::endpoint::{self, TransportConfig, VarInt};
+use iroh_quinn_proto::{
+    IdleTimeout,
+    congestion::{BbrConfig, CubicConfig, NewRenoConfig},
+};
+use n0_error::{AnyError, Result, anyerr};
+
+/// Keep-alive interval iroh endpoints use unless told otherwise
+const IROH_KEEP_ALIVE: Duration = Duration::from_secs(1);
+
+// ====================
+// Congestion Control
+// ====================
+
+/// The algorithm QUIC paces sending with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Congestion {
+    /// Loss-based, and what QUIC uses by default
+    #[default]
+    Cubic,
+    /// Loss-based and more conservative than Cubic
+    NewReno,
+    /// Model-based, keeping queues short even on lossy paths; experimental in
+    /// the QUIC implementation
+    Bbr,
+}
+
+/// `cubic`, `new-reno` or `bbr`
+impl FromStr for Congestion {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cubic" => Ok(Congestion::Cubic),
+            "new-reno" => Ok(Congestion::NewReno),
+            "bbr" => Ok(Congestion::Bbr),
+            other => Err(anyerr!(
+                "unknown congestion controller {other:?}, expected cubic, new-reno or bbr"
+            )),
+        }
+    }
+}
+
+// ====================
+// Transport Tuning
+// ====================
+
+/// QUIC transport settings of an endpoint, applied to every connection it
+/// makes or accepts
+///
+/// Every setting left `None` keeps iroh's default, so the default tuning
+/// changes nothing. Start from a preset such as
+/// [`low_latency`](Self::low_latency) to compare how settings affect a
+/// benchmark.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportTuning {
+    /// Bytes a peer may send on one stream before it is read
+    pub stream_receive_window: Option<u64>,
+    /// Bytes a peer may send on all streams together before they are read
+    pub receive_window: Option<u64>,
+    /// Bytes sent but not yet acknowledged, across all streams
+    pub send_window: Option<u64>,
+    /// Bidirectional streams a peer may have open at once
+    pub max_concurrent_bidi_streams: Option<u32>,
+    /// Unidirectional streams a peer may have open at once
+    pub max_concurrent_uni_streams: Option<u32>,
+    /// How often to send a packet on an otherwise quiet connection
+    pub keep_alive_interval: Option<Duration>,
+    /// How long a connection may go without hearing from the peer before QUIC
+    /// drops it, unlike the idle timeout of
+    /// [`run_idle_timeout`](crate::heartbeat::run_idle_timeout), which
+    /// heartbeats do not reset
+    pub max_idle_timeout: Option<Duration>,
+    pub congestion: Option<Congestion>,
+    /// Log TLS secrets to the file the `SSLKEYLOGFILE` environment variable
+    /// names, so captured traffic can be decrypted, e.g. in Wireshark
+    ///
+    /// Anyone holding the file can read the traffic, so only use this for
+    /// debugging.
+    pub keylog: bool,
+}
+
+impl TransportTuning {
+    /// Short queues and fast failure detection, for small interactive
+    /// messages
+    pub fn low_latency() -> Self {
+        Self {
+            keep_alive_interval: Some(Duration::from_millis(500)),
+            max_idle_timeout: Some(Duration::from_secs(10)),
+            congestion: Some(Congestion::Bbr),
+            ..Self::default()
+        }
+    }
+
+    /// Large flow control windows, for moving a lot of data over few streams
+    pub fn bulk_transfer() -> Self {
+        Self {
+            stream_receive_window: Some(16 * 1024 * 1024),
+            receive_window: Some(64 * 1024 * 1024),
+            send_window: Some(64 * 1024 * 1024),
+            congestion: Some(Congestion::Cubic),
+            ..Self::default()
+        }
+    }
+
+    /// The transport config of iroh's defaults with these settings on top
+    ///
+    /// Windows and timeouts beyond what QUIC can express are capped to its
+    /// maximum.
+    pub fn transport_config(&self) -> TransportConfig {
+        let mut config = TransportConfig::default();
+        config.keep_alive_interval(Some(self.keep_alive_interval.unwrap_or(IROH_KEEP_ALIVE)));
+        if let Some(window) = self.stream_receive_window {
+            config.stream_receive_window(varint(window));
+        }
+        if let Some(window) = self.receive_window {
+            config.receive_window(varint(window));
+        }
+        if let Some(window) = self.send_window {
+            config.send_window(window);
+        }
+        if let Some(max) = self.max_concurrent_bidi_streams {
+            config.max_concurrent_bidi_streams(max.into());
+        }
+        if let Some(max) = self.max_concurrent_uni_streams {
+            config.max_concurrent_uni_streams(max.into());
+        }
+        if let Some(timeout) = self.max_idle_timeout {
+            let timeout = IdleTimeout::try_from(timeout).unwrap_or(VarInt::MAX.into());
+            config.max_idle_timeout(Some(timeout));
+        }
+        match self.congestion {
+            None | Some(Congestion::Cubic) => {
+                config.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            Some(Congestion::NewReno) => {
+                config.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            Some(Congestion::Bbr) => {
+                config.congestion_controller_factory(Arc::new(BbrConfig::default()))
+            }
+        };
+        config
+    }
+
+    /// Configure `builder` with this tuning, leaving its transport config
+    /// alone unless the tuning changes it
+    pub fn apply(&self, builder: endpoint::Builder) -> endpoint::Builder {
+        let builder = builder.keylog(self.keylog);
+        let transport = Self {
+            keylog: false,
+            ..*self
+        };
+        match transport == Self::default() {
+            true => builder,
+            false => builder.transport_config(self.transport_config()),
+        }
+    }
+}
+
+/// `default`, `low-latency` or `bulk-transfer`
+impl FromStr for TransportTuning {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "default" => Ok(Self::default()),
+            "low-latency" => Ok(Self::low_latency()),
+            "bulk-transfer" => Ok(Self::bulk_transfer()),
+            other => Err(anyerr!(
+                "unknown tuning preset {other:?}, expected default, low-latency or bulk-transfer"
+            )),
+        }
+    }
+}
+
+fn varint(n: u64) -> VarInt {
+    VarInt::from_u64(n).unwrap_or(VarInt::MAX)
+}
@@ -0,0 +1,70 @@
+//! Datagrams to an echo server, with and without the preamble of their
+//! protocol version
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use n0_error::{Result, StdResultExt};
+use wstest::{
+    Bincode, Codec, ErrorCode, Json, Message, PROTOCOL_VERSION,
+    client::connect,
+    datagram::{recv_datagrams, send_datagram},
+    server::{self, Echo},
+};
+
+#[tokio::test]
+async fn datagrams_are_echoed() -> Result<()> {
+    let server = server::spawn(0, Echo::new(Bincode)).await?;
+    let conn = connect(server.endpoint().addr()).await?;
+    let mut answers = Box::pin(recv_datagrams::<_, Message>(
+        conn.clone(),
+        Bincode,
+        PROTOCOL_VERSION,
+    ));
+
+    send_datagram(&conn, &Bincode, PROTOCOL_VERSION, &Message::Echo)?;
+    let answer = tokio::time::timeout(Duration::from_secs(10), answers.next())
+        .await
+        .std_context("no answer")?
+        .std_context("connection closed")??;
+    assert!(matches!(answer, Message::Echo), "{answer:?}");
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn datagrams_of_another_layout_are_incompatible() -> Result<()> {
+    let server = server::spawn(0, Echo::new(Bincode)).await?;
+    let conn = connect(server.endpoint().addr()).await?;
+    let mut answers = Box::pin(recv_datagrams::<_, Message>(
+        conn.clone(),
+        Bincode,
+        PROTOCOL_VERSION,
+    ));
+
+    // Without a preamble, then with one naming another codec
+    let bare = Bytes::from(Bincode.encode(&Message::Echo)?);
+    conn.send_datagram(bare).anyerr()?;
+    send_datagram(&conn, &Json, PROTOCOL_VERSION, &Message::Echo)?;
+    for _ in 0..2 {
+        let answer = tokio::time::timeout(Duration::from_secs(10), answers.next())
+            .await
+            .std_context("no answer")?
+            .std_context("connection closed")??;
+        let incompatible = matches!(
+            answer,
+            Message::Error {
+                code: ErrorCode::IncompatibleProtocol,
+                ..
+            }
+        );
+        assert!(incompatible, "{answer:?}");
+    }
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
@@ -0,0 +1,81 @@
+//! At-least-once delivery to an echo server, across clients sharing an
+//! identity
+
+use std::time::Duration;
+
+use iroh::SecretKey;
+use n0_error::{Result, StdResultExt};
+use tokio::sync::broadcast;
+use wstest::{
+    Bincode, Client, Message, QoS, ReconnectingClient, ReliableClient,
+    delivery::RELIABLE_WINDOW,
+    server::{self, Echo},
+};
+
+fn chat(text: &str) -> Message {
+    Message::Chat {
+        from: "alice".to_string(),
+        text: text.to_string(),
+    }
+}
+
+/// Wait for the chat `text` among `pushes`
+async fn relayed(pushes: &mut broadcast::Receiver<Message>, text: &str) -> Result<()> {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            match pushes.recv().await.std_context("pushes closed")? {
+                Message::Chat { text: pushed, .. } if pushed == text => return Ok(()),
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .std_context("chat was never relayed")?
+}
+
+#[tokio::test]
+async fn clients_sharing_an_identity_both_deliver() -> Result<()> {
+    let server = server::spawn(0, Echo::new(Bincode)).await?;
+    let addr = server.endpoint().addr();
+    let watcher = Client::connect(addr.clone()).await?;
+    let mut pushes = watcher.subscribe_pushes();
+    let key = SecretKey::from_bytes(&[1; 32]);
+
+    for text in ["first", "second"] {
+        let client = ReconnectingClient::new(addr.clone(), Bincode).with_secret_key(key.clone());
+        let reliable = ReliableClient::new(client);
+        reliable.send(chat(text), QoS::AtLeastOnce).await?;
+        reliable.flush().await?;
+        relayed(&mut pushes, text).await?;
+        reliable.client().close().await;
+    }
+
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn reliable_messages_too_far_ahead_are_refused() -> Result<()> {
+    let server = server::spawn(0, Echo::new(Bincode)).await?;
+    let client = Client::connect(server.endpoint().addr()).await?;
+    let reliable = |seq, acked| Message::Reliable {
+        seq,
+        body: Box::new(Message::Echo),
+        acked,
+    };
+
+    // Answered with the current ack, but neither acted on nor remembered
+    let answer = client.request(reliable(RELIABLE_WINDOW, 0)).await?;
+    assert!(matches!(answer, Message::Ack { next: 0 }), "{answer:?}");
+    // Skipping ahead to just below the refused one, which is still missing
+    let answer = client
+        .request(reliable(RELIABLE_WINDOW - 1, RELIABLE_WINDOW - 1))
+        .await?;
+    assert!(
+        matches!(answer, Message::Ack { next } if next == RELIABLE_WINDOW),
+        "{answer:?}"
+    );
+
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Messages queued on disk for offline peers, and records cut short by a
+//! crash
+
+use std::path::{Path, PathBuf};
+
+use iroh::{EndpointId, SecretKey};
+use n0_error::{Result, StdResultExt};
+use wstest::{Bincode, Outbox};
+
+fn peer() -> EndpointId {
+    SecretKey::from_bytes(&[1; 32]).public()
+}
+
+/// An empty directory of its own for the test `name`
+fn dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::remove_dir_all(&dir).ok();
+    dir
+}
+
+/// Leave half a record at the end of `peer`'s file, as a crash mid-write does
+fn tear(dir: &Path, peer: &EndpointId) -> Result<()> {
+    use std::io::Write;
+
+    let path = dir.join(format!("{peer}.outbox"));
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .anyerr()?;
+    file.write_all(&[0, 0, 0, 100, 1, 2, 3]).anyerr()
+}
+
+#[tokio::test]
+async fn records_queued_after_a_torn_one_survive_a_restart() -> Result<()> {
+    let dir = dir("outbox-torn-restart");
+    let outbox = Outbox::open(&dir, Bincode).await?;
+    outbox.push(&peer(), &"first").await?;
+    tear(&dir, &peer())?;
+
+    let outbox = Outbox::open(&dir, Bincode).await?;
+    outbox.push(&peer(), &"second").await?;
+    let msgs: Vec<String> = outbox.take(&peer()).await?;
+    assert_eq!(msgs, ["first", "second"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn taking_keeps_a_torn_record() -> Result<()> {
+    let dir = dir("outbox-torn-take");
+    let outbox = Outbox::open(&dir, Bincode).await?;
+    outbox.push(&peer(), &"first").await?;
+    tear(&dir, &peer())?;
+
+    let msgs: Vec<String> = outbox.take(&peer()).await?;
+    assert_eq!(msgs, ["first"]);
+    let left = std::fs::read(dir.join(format!("{}.outbox", peer()))).anyerr()?;
+    assert_eq!(left, [0, 0, 0, 100, 1, 2, 3]);
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! Capabilities granted by the auth handshake, enforced on every path a
+//! message can take to the server
+
+use std::{sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use iroh::{EndpointAddr, SecretKey, endpoint::Connection};
+use n0_error::{Result, StdResultExt};
+use wstest::{
+    Bincode, Capabilities, Capability, ErrorCode, FramedConnection, LobbyRequest, MAX_MESSAGE_SIZE,
+    Message, MessageEnvelope, PROTOCOL_VERSION, Permissions, ProtocolConfig,
+    auth::{SharedToken, authenticate},
+    client::connect,
+    datagram::{recv_datagrams, send_datagram},
+    protocol::{decode_frame, read_message, send_raw},
+    server::{self, Echo, Server},
+};
+
+const TOKEN: &str = "let me in";
+
+/// Messages that reach other peers, each needing [`Capability::Broadcast`]
+fn broadcasts() -> Vec<Message> {
+    vec![
+        Message::Chat {
+            from: "alice".to_string(),
+            text: "hi".to_string(),
+        },
+        Message::Lobby(LobbyRequest::Send {
+            payload: b"hi".to_vec(),
+        }),
+        Message::Introduce {
+            peer: EndpointAddr::new(SecretKey::from_bytes(&[1; 32]).public()),
+        },
+    ]
+}
+
+fn is_denied(answer: &Message) -> bool {
+    matches!(
+        answer,
+        Message::Error {
+            code: ErrorCode::PermissionDenied,
+            ..
+        }
+    )
+}
+
+async fn answer<T>(answer: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(Duration::from_secs(10), answer)
+        .await
+        .std_context("no answer")?
+}
+
+// ====================
+// Defaults
+// ====================
+
+#[test]
+fn broadcasts_need_the_broadcast_capability() {
+    let permissions = Permissions::default();
+    let uploader = Capabilities::none().with(Capability::Upload);
+    for msg in broadcasts() {
+        assert_eq!(
+            permissions.missing(&uploader, &msg),
+            Some(Capability::Broadcast),
+            "{msg:?}"
+        );
+        assert_eq!(permissions.missing(&Capabilities::all(), &msg), None);
+    }
+    let join = Message::Lobby(LobbyRequest::Join {
+        code: "abc".to_string(),
+    });
+    assert_eq!(permissions.missing(&uploader, &join), None);
+}
+
+// ====================
+// Server
+// ====================
+
+/// A connection to a server granting only uploads to clients knowing the token
+async fn uploader() -> Result<(Server<Bincode>, Connection)> {
+    let grant = Capabilities::none().with(Capability::Upload);
+    let echo =
+        Echo::new(Bincode).with_auth(Arc::new(SharedToken::new(TOKEN).with_capabilities(grant)));
+    let server = server::spawn(0, echo).await?;
+    let conn = connect(server.endpoint().addr()).await?;
+    authenticate(&conn, &Bincode, &SharedToken::new(TOKEN)).await?;
+    Ok((server, conn))
+}
+
+#[tokio::test]
+async fn broadcasts_are_denied_on_streams_of_their_own() -> Result<()> {
+    let (server, conn) = uploader().await?;
+    let config = ProtocolConfig::default();
+    for (counter, msg) in broadcasts().into_iter().enumerate() {
+        let mut envelope = MessageEnvelope::new(1, msg);
+        envelope.counter = Some(counter as u64 + 1);
+        send_raw(&conn, config.encode(&Bincode, PROTOCOL_VERSION, &envelope)?).await?;
+        let (answer, _) = answer(async {
+            let mut recv = conn.accept_uni().await.anyerr()?;
+            let bytes = read_message(&mut recv, MAX_MESSAGE_SIZE).await?;
+            config.decode(&Bincode, PROTOCOL_VERSION, &bytes)
+        })
+        .await?;
+        assert!(is_denied(&answer.body), "{:?}", answer.body);
+    }
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn broadcasts_are_denied_on_framed_streams() -> Result<()> {
+    let (server, conn) = uploader().await?;
+    let mut framed = FramedConnection::open(&conn, Bincode).await?;
+    for (counter, msg) in broadcasts().into_iter().enumerate() {
+        let mut envelope = MessageEnvelope::new(1, msg);
+        envelope.counter = Some(counter as u64 + 1);
+        framed
+            .send_raw(envelope.frame(&Bincode, PROTOCOL_VERSION)?)
+            .await?;
+        let bytes = answer(framed.recv_bytes())
+            .await?
+            .std_context("server finished the framed stream")?;
+        let answer = decode_frame(&Bincode, PROTOCOL_VERSION, &bytes)?;
+        assert!(is_denied(&answer.body), "{:?}", answer.body);
+    }
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn broadcasts_are_denied_as_datagrams() -> Result<()> {
+    let (server, conn) = uploader().await?;
+    let mut answers = Box::pin(recv_datagrams::<_, Message>(
+        conn.clone(),
+        Bincode,
+        PROTOCOL_VERSION,
+    ));
+    for msg in broadcasts() {
+        send_datagram(&conn, &Bincode, PROTOCOL_VERSION, &msg)?;
+        let answer =
+            answer(async { answers.next().await.std_context("connection closed")? }).await?;
+        assert!(is_denied(&answer), "{answer:?}");
+    }
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
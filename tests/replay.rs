@@ -0,0 +1,149 @@
+//! Replay windows, alone and in front of a server
+
+use std::time::Duration;
+
+use iroh::endpoint::Connection;
+use n0_error::{Result, StdResultExt};
+use wstest::{
+    Bincode, Client, ErrorCode, MAX_MESSAGE_SIZE, Message, MessageEnvelope, MessageSignature,
+    PROTOCOL_VERSION, ProtocolConfig, ReplayWindow,
+    client::connect,
+    protocol::{read_message, send_raw},
+    replay::REPLAY_WINDOW,
+    server::{self, Echo},
+};
+
+// ====================
+// Window
+// ====================
+
+#[test]
+fn counters_are_accepted_once() {
+    let window = ReplayWindow::default();
+    assert!(window.accept(5));
+    assert!(!window.accept(5));
+    assert!(window.accept(6));
+}
+
+#[test]
+fn overtaken_counters_are_accepted_once() {
+    let window = ReplayWindow::default();
+    assert!(window.accept(10));
+    assert!(window.accept(7));
+    assert!(!window.accept(7));
+}
+
+#[test]
+fn counters_too_far_behind_are_refused() {
+    let window = ReplayWindow::default();
+    assert!(window.accept(REPLAY_WINDOW + 10));
+    assert!(!window.accept(9));
+    assert!(window.accept(11));
+}
+
+#[test]
+fn signers_are_counted_apart() {
+    let window = ReplayWindow::default();
+    assert!(window.accept_from(Some(&[1; 32]), 3));
+    assert!(window.accept_from(Some(&[2; 32]), 3));
+    assert!(window.accept(3));
+    assert!(!window.accept_from(Some(&[1; 32]), 3));
+}
+
+// ====================
+// Server
+// ====================
+
+/// Send `envelope` on a stream of its own and wait for the answer
+async fn ask(conn: &Connection, envelope: &MessageEnvelope) -> Result<Message> {
+    let config = ProtocolConfig::default();
+    send_raw(conn, config.encode(&Bincode, PROTOCOL_VERSION, envelope)?).await?;
+    let answer = async {
+        let mut recv = conn.accept_uni().await.anyerr()?;
+        let bytes = read_message(&mut recv, MAX_MESSAGE_SIZE).await?;
+        let (answer, _) = config.decode(&Bincode, PROTOCOL_VERSION, &bytes)?;
+        Ok(answer.body)
+    };
+    tokio::time::timeout(Duration::from_secs(10), answer)
+        .await
+        .std_context("no answer")?
+}
+
+fn is_refused(answer: &Message) -> bool {
+    matches!(
+        answer,
+        Message::Error {
+            code: ErrorCode::Unauthorized,
+            ..
+        }
+    )
+}
+
+#[tokio::test]
+async fn servers_refuse_replays_and_missing_counters() -> Result<()> {
+    let server = server::spawn(0, Echo::new(Bincode)).await?;
+    let conn = connect(server.endpoint().addr()).await?;
+
+    let mut envelope = MessageEnvelope::new(1, Message::Echo);
+    envelope.counter = Some(1);
+    assert!(matches!(ask(&conn, &envelope).await?, Message::Echo));
+    assert!(is_refused(&ask(&conn, &envelope).await?));
+
+    envelope.counter = None;
+    assert!(is_refused(&ask(&conn, &envelope).await?));
+
+    envelope.counter = Some(1 + REPLAY_WINDOW + 1);
+    assert!(matches!(ask(&conn, &envelope).await?, Message::Echo));
+    envelope.counter = Some(0);
+    assert!(is_refused(&ask(&conn, &envelope).await?));
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn servers_count_unverified_signers_as_one() -> Result<()> {
+    // Without keys of its own the server checks no signature
+    let server = server::spawn(0, Echo::new(Bincode)).await?;
+    let conn = connect(server.endpoint().addr()).await?;
+
+    let mut envelope = MessageEnvelope::new(1, Message::Echo);
+    envelope.counter = Some(1);
+    for (seed, accepted) in [(1, true), (2, false), (3, false)] {
+        envelope.signature = Some(MessageSignature {
+            key: [seed; 32],
+            signature: vec![0; 64],
+        });
+        let answer = ask(&conn, &envelope).await?;
+        assert_eq!(!is_refused(&answer), accepted, "{answer:?}");
+    }
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
+
+// ====================
+// Client
+// ====================
+
+#[tokio::test]
+async fn coalesced_sends_take_one_counter_per_batch() -> Result<()> {
+    let server = server::spawn(0, Echo::new(Bincode)).await?;
+    let client = Client::connect(server.endpoint().addr())
+        .await?
+        .with_coalescing(Duration::from_millis(100));
+
+    let before = client.counter().next();
+    tokio::try_join!(
+        client.send(Message::Echo),
+        client.send(Message::Echo),
+        client.send(Message::Echo),
+    )?;
+    assert_eq!(client.counter().next(), before + 2);
+
+    client.connection().close(0u32.into(), b"done");
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
@@ -0,0 +1,533 @@
+// This file is @generated by prost-build.
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Envelope {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    /// Which `Message` variant `body` holds, its field number less one; bodies
+    /// of tags a peer does not know are passed on as they came
+    #[prost(uint32, tag = "2")]
+    pub tag: u32,
+    /// A `Message`, encoded on its own
+    #[prost(bytes = "vec", tag = "3")]
+    pub body: ::prost::alloc::vec::Vec<u8>,
+    #[prost(uint64, optional, tag = "4")]
+    pub deadline: ::core::option::Option<u64>,
+    #[prost(message, optional, tag = "5")]
+    pub trace: ::core::option::Option<TraceContext>,
+    #[prost(message, optional, tag = "6")]
+    pub signature: ::core::option::Option<MessageSignature>,
+    #[prost(uint64, optional, tag = "7")]
+    pub counter: ::core::option::Option<u64>,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct TraceContext {
+    /// 16 bytes
+    #[prost(bytes = "vec", tag = "1")]
+    pub trace_id: ::prost::alloc::vec::Vec<u8>,
+    /// 8 bytes
+    #[prost(bytes = "vec", tag = "2")]
+    pub span_id: ::prost::alloc::vec::Vec<u8>,
+    #[prost(bool, tag = "3")]
+    pub sampled: bool,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct MessageSignature {
+    /// The 32 byte ed25519 public key that signed
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: ::prost::alloc::vec::Vec<u8>,
+    /// The 64 byte ed25519 signature
+    #[prost(bytes = "vec", tag = "2")]
+    pub signature: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Empty {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Message {
+    #[prost(
+        oneof = "message::Body",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35"
+    )]
+    pub body: ::core::option::Option<message::Body>,
+}
+/// Nested message and enum types in `Message`.
+pub mod message {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Body {
+        #[prost(message, tag = "1")]
+        Echo(super::Empty),
+        #[prost(message, tag = "2")]
+        Ping(super::Ping),
+        #[prost(message, tag = "3")]
+        Pong(super::Pong),
+        #[prost(message, tag = "4")]
+        Chat(super::Chat),
+        #[prost(message, tag = "5")]
+        Data(super::Bytes),
+        #[prost(message, tag = "6")]
+        Error(super::Error),
+        #[prost(message, tag = "7")]
+        GoingAway(super::Empty),
+        #[prost(message, tag = "8")]
+        Reliable(::prost::alloc::boxed::Box<super::Reliable>),
+        #[prost(message, tag = "9")]
+        Ack(super::Ack),
+        #[prost(message, tag = "10")]
+        Broadcast(::prost::alloc::boxed::Box<super::Broadcast>),
+        #[prost(message, tag = "11")]
+        Resume(super::Resume),
+        #[prost(message, tag = "12")]
+        Replay(super::Replay),
+        #[prost(string, tag = "13")]
+        Subscribe(::prost::alloc::string::String),
+        #[prost(string, tag = "14")]
+        Unsubscribe(::prost::alloc::string::String),
+        #[prost(message, tag = "15")]
+        Publish(super::Publish),
+        #[prost(message, tag = "16")]
+        Announce(super::EndpointAddr),
+        #[prost(message, tag = "17")]
+        ListPeers(super::Empty),
+        #[prost(message, tag = "18")]
+        PeerList(super::EndpointAddrs),
+        #[prost(message, tag = "19")]
+        Introduce(super::Introduce),
+        #[prost(message, tag = "20")]
+        Lobby(super::LobbyRequest),
+        #[prost(message, tag = "21")]
+        Room(super::RoomEvent),
+        #[prost(message, tag = "22")]
+        JoinQueue(super::JoinQueue),
+        #[prost(message, tag = "23")]
+        LeaveQueue(super::Empty),
+        #[prost(message, tag = "24")]
+        MatchFound(super::MatchFound),
+        #[prost(message, tag = "25")]
+        Login(super::Login),
+        #[prost(message, tag = "26")]
+        SetStatus(super::Status),
+        #[prost(message, tag = "27")]
+        WatchPresence(super::EndpointIds),
+        #[prost(message, tag = "28")]
+        Presence(super::Presence),
+        #[prost(message, tag = "29")]
+        PresenceList(super::Presences),
+        #[prost(message, tag = "30")]
+        StatsRequest(super::Empty),
+        #[prost(message, tag = "31")]
+        StatsResponse(super::ConnectionStats),
+        #[prost(message, tag = "32")]
+        Batch(super::Messages),
+        #[prost(message, tag = "33")]
+        Unknown(super::Unknown),
+        #[prost(message, tag = "34")]
+        FindSessionKeys(super::EndpointIds),
+        #[prost(message, tag = "35")]
+        SessionKeys(super::SessionKeys),
+    }
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Ping {
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Pong {
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Chat {
+    #[prost(string, tag = "1")]
+    pub from: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub text: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Bytes {
+    #[prost(bytes = "vec", tag = "1")]
+    pub bytes: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Error {
+    #[prost(message, optional, tag = "1")]
+    pub code: ::core::option::Option<ErrorCode>,
+    #[prost(string, tag = "2")]
+    pub detail: ::prost::alloc::string::String,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct ErrorCode {
+    #[prost(oneof = "error_code::Code", tags = "1, 2, 3, 4, 5, 6, 7")]
+    pub code: ::core::option::Option<error_code::Code>,
+}
+/// Nested message and enum types in `ErrorCode`.
+pub mod error_code {
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Oneof)]
+    pub enum Code {
+        #[prost(message, tag = "1")]
+        Malformed(super::Empty),
+        #[prost(message, tag = "2")]
+        TooLarge(super::Empty),
+        #[prost(message, tag = "3")]
+        Unauthorized(super::Empty),
+        #[prost(message, tag = "4")]
+        NotFound(super::Empty),
+        #[prost(message, tag = "5")]
+        Internal(super::Empty),
+        #[prost(message, tag = "6")]
+        PermissionDenied(super::Empty),
+        #[prost(message, tag = "7")]
+        IncompatibleProtocol(super::Empty),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Reliable {
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub body: ::core::option::Option<::prost::alloc::boxed::Box<Message>>,
+    #[prost(uint64, tag = "3")]
+    pub acked: u64,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Ack {
+    #[prost(uint64, tag = "1")]
+    pub next: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Broadcast {
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub body: ::core::option::Option<::prost::alloc::boxed::Box<Message>>,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Resume {
+    #[prost(uint64, tag = "1")]
+    pub from_offset: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replay {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: ::prost::alloc::vec::Vec<ReplayEntry>,
+    #[prost(uint64, tag = "2")]
+    pub next_offset: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplayEntry {
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    #[prost(message, optional, tag = "2")]
+    pub message: ::core::option::Option<Message>,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Publish {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Introduce {
+    #[prost(message, optional, tag = "1")]
+    pub peer: ::core::option::Option<EndpointAddr>,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct JoinQueue {
+    #[prost(string, tag = "1")]
+    pub mode: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub party_size: u64,
+    #[prost(uint32, optional, tag = "3")]
+    pub rating: ::core::option::Option<u32>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MatchFound {
+    #[prost(string, tag = "1")]
+    pub room: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub peers: ::prost::alloc::vec::Vec<EndpointAddr>,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Login {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Messages {
+    #[prost(message, repeated, tag = "1")]
+    pub messages: ::prost::alloc::vec::Vec<Message>,
+}
+/// A body of a tag the sender did not know, as it came
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Unknown {
+    #[prost(uint32, tag = "1")]
+    pub tag: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub bytes: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EndpointAddr {
+    /// The 32 byte endpoint id
+    #[prost(bytes = "vec", tag = "1")]
+    pub id: ::prost::alloc::vec::Vec<u8>,
+    #[prost(message, repeated, tag = "2")]
+    pub addrs: ::prost::alloc::vec::Vec<TransportAddr>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EndpointAddrs {
+    #[prost(message, repeated, tag = "1")]
+    pub addrs: ::prost::alloc::vec::Vec<EndpointAddr>,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct EndpointIds {
+    /// 32 bytes each
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub ids: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct TransportAddr {
+    #[prost(oneof = "transport_addr::Addr", tags = "1, 2")]
+    pub addr: ::core::option::Option<transport_addr::Addr>,
+}
+/// Nested message and enum types in `TransportAddr`.
+pub mod transport_addr {
+    #[derive(Clone, PartialEq, Eq, Hash, ::prost::Oneof)]
+    pub enum Addr {
+        #[prost(string, tag = "1")]
+        Relay(::prost::alloc::string::String),
+        #[prost(message, tag = "2")]
+        Ip(super::SocketAddr),
+    }
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct SocketAddr {
+    #[prost(oneof = "socket_addr::Addr", tags = "1, 2")]
+    pub addr: ::core::option::Option<socket_addr::Addr>,
+}
+/// Nested message and enum types in `SocketAddr`.
+pub mod socket_addr {
+    #[derive(Clone, PartialEq, Eq, Hash, ::prost::Oneof)]
+    pub enum Addr {
+        #[prost(message, tag = "1")]
+        V4(super::SocketAddrV4),
+        #[prost(message, tag = "2")]
+        V6(super::SocketAddrV6),
+    }
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct SocketAddrV4 {
+    /// 4 bytes
+    #[prost(bytes = "vec", tag = "1")]
+    pub ip: ::prost::alloc::vec::Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub port: u32,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct SocketAddrV6 {
+    /// 16 bytes
+    #[prost(bytes = "vec", tag = "1")]
+    pub ip: ::prost::alloc::vec::Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub port: u32,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct LobbyRequest {
+    #[prost(oneof = "lobby_request::Request", tags = "1, 2, 3, 4, 5")]
+    pub request: ::core::option::Option<lobby_request::Request>,
+}
+/// Nested message and enum types in `LobbyRequest`.
+pub mod lobby_request {
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct Create {
+        #[prost(uint64, tag = "1")]
+        pub capacity: u64,
+    }
+    #[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct Send {
+        #[prost(bytes = "vec", tag = "1")]
+        pub payload: ::prost::alloc::vec::Vec<u8>,
+    }
+    #[derive(Clone, PartialEq, Eq, Hash, ::prost::Oneof)]
+    pub enum Request {
+        #[prost(message, tag = "1")]
+        Create(Create),
+        #[prost(message, tag = "2")]
+        Join(super::Code),
+        #[prost(message, tag = "3")]
+        Members(super::Code),
+        #[prost(message, tag = "4")]
+        Leave(super::Empty),
+        #[prost(message, tag = "5")]
+        Send(Send),
+    }
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Code {
+    #[prost(string, tag = "1")]
+    pub code: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct RoomEvent {
+    #[prost(oneof = "room_event::Event", tags = "1, 2, 3, 4, 5, 6, 7")]
+    pub event: ::core::option::Option<room_event::Event>,
+}
+/// Nested message and enum types in `RoomEvent`.
+pub mod room_event {
+    #[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct RoomMembers {
+        #[prost(string, tag = "1")]
+        pub code: ::prost::alloc::string::String,
+        #[prost(uint64, tag = "2")]
+        pub capacity: u64,
+        /// 32 byte endpoint ids
+        #[prost(bytes = "vec", repeated, tag = "3")]
+        pub members: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    }
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct Refused {
+        #[prost(message, optional, tag = "1")]
+        pub reason: ::core::option::Option<super::Refusal>,
+    }
+    #[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct Member {
+        #[prost(string, tag = "1")]
+        pub code: ::prost::alloc::string::String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub peer: ::prost::alloc::vec::Vec<u8>,
+    }
+    #[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct RoomMessage {
+        #[prost(string, tag = "1")]
+        pub code: ::prost::alloc::string::String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub from: ::prost::alloc::vec::Vec<u8>,
+        #[prost(bytes = "vec", tag = "3")]
+        pub payload: ::prost::alloc::vec::Vec<u8>,
+    }
+    #[derive(Clone, PartialEq, Eq, Hash, ::prost::Oneof)]
+    pub enum Event {
+        #[prost(message, tag = "1")]
+        Joined(RoomMembers),
+        #[prost(message, tag = "2")]
+        Members(RoomMembers),
+        #[prost(message, tag = "3")]
+        Left(super::Code),
+        #[prost(message, tag = "4")]
+        Refused(Refused),
+        #[prost(message, tag = "5")]
+        MemberJoined(Member),
+        #[prost(message, tag = "6")]
+        MemberLeft(Member),
+        #[prost(message, tag = "7")]
+        Message(RoomMessage),
+    }
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Refusal {
+    #[prost(oneof = "refusal::Reason", tags = "1, 2, 3, 4, 5")]
+    pub reason: ::core::option::Option<refusal::Reason>,
+}
+/// Nested message and enum types in `Refusal`.
+pub mod refusal {
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Oneof)]
+    pub enum Reason {
+        #[prost(message, tag = "1")]
+        NotFound(super::Empty),
+        #[prost(message, tag = "2")]
+        Full(super::Empty),
+        #[prost(message, tag = "3")]
+        TooManyRooms(super::Empty),
+        #[prost(message, tag = "4")]
+        AlreadyInRoom(super::Empty),
+        #[prost(message, tag = "5")]
+        NotInRoom(super::Empty),
+    }
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Status {
+    #[prost(oneof = "status::Status", tags = "1, 2, 3, 4")]
+    pub status: ::core::option::Option<status::Status>,
+}
+/// Nested message and enum types in `Status`.
+pub mod status {
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Oneof)]
+    pub enum Status {
+        #[prost(message, tag = "1")]
+        Online(super::Empty),
+        #[prost(message, tag = "2")]
+        Away(super::Empty),
+        #[prost(message, tag = "3")]
+        InGame(super::Empty),
+        #[prost(message, tag = "4")]
+        Offline(super::Empty),
+    }
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Presence {
+    /// The 32 byte endpoint id
+    #[prost(bytes = "vec", tag = "1")]
+    pub peer: ::prost::alloc::vec::Vec<u8>,
+    #[prost(string, tag = "2")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub status: ::core::option::Option<Status>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Presences {
+    #[prost(message, repeated, tag = "1")]
+    pub presences: ::prost::alloc::vec::Vec<Presence>,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct ConnectionStats {
+    #[prost(message, optional, tag = "1")]
+    pub rtt: ::core::option::Option<Duration>,
+    #[prost(uint64, tag = "2")]
+    pub cwnd: u64,
+    #[prost(uint64, tag = "3")]
+    pub congestion_events: u64,
+    #[prost(uint64, tag = "4")]
+    pub bytes_sent: u64,
+    #[prost(uint64, tag = "5")]
+    pub bytes_received: u64,
+    #[prost(uint64, tag = "6")]
+    pub packets_sent: u64,
+    #[prost(uint64, tag = "7")]
+    pub packets_lost: u64,
+    #[prost(uint64, tag = "8")]
+    pub datagrams_sent: u64,
+    #[prost(uint64, tag = "9")]
+    pub datagrams_received: u64,
+    #[prost(uint64, tag = "10")]
+    pub streams_opened: u64,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Duration {
+    #[prost(uint64, tag = "1")]
+    pub secs: u64,
+    #[prost(uint32, tag = "2")]
+    pub nanos: u32,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct SessionKey {
+    /// The 32 byte ed25519 public key that signed
+    #[prost(bytes = "vec", tag = "1")]
+    pub owner: ::prost::alloc::vec::Vec<u8>,
+    /// The 32 byte X25519 public key
+    #[prost(bytes = "vec", tag = "2")]
+    pub key: ::prost::alloc::vec::Vec<u8>,
+    /// 32 bytes exported from the connection it was offered on
+    #[prost(bytes = "vec", tag = "3")]
+    pub binding: ::prost::alloc::vec::Vec<u8>,
+    /// The 64 byte ed25519 signature
+    #[prost(bytes = "vec", tag = "4")]
+    pub signature: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SessionKeys {
+    #[prost(message, repeated, tag = "1")]
+    pub keys: ::prost::alloc::vec::Vec<SessionKey>,
+}
@@ -0,0 +1,172 @@
+//! Sealing payloads for their recipients and opening them again
+
+use std::{sync::Arc, time::Duration};
+
+use iroh::SecretKey;
+use n0_error::{Result, StdResultExt};
+use wstest::{
+    Bincode, Message, ReconnectingClient, Sealer, SessionKey, SharedToken,
+    sealing::{MAX_SESSION_KEYS, MAX_SESSIONS},
+    server::{self, Echo},
+};
+
+const TOKEN: &str = "let me in";
+
+fn sealer(seed: u8) -> Sealer {
+    Sealer::new(SecretKey::from_bytes(&[seed; 32]))
+}
+
+/// A session key of `sealer`, as if offered on a connection
+fn offered(sealer: &Sealer) -> SessionKey {
+    sealer.offer([0; 32])
+}
+
+#[test]
+fn every_recipient_opens_what_was_sealed_for_it() {
+    let (alice, bob, carol) = (sealer(1), sealer(2), sealer(3));
+    offered(&alice);
+    let sealed = alice
+        .seal("news", &[offered(&bob), offered(&carol)], b"hello")
+        .unwrap();
+    for recipient in [&bob, &carol] {
+        let opened = recipient.open("news", &sealed).unwrap();
+        assert_eq!(opened.sender, alice.public());
+        assert_eq!(opened.payload, b"hello");
+    }
+}
+
+#[test]
+fn others_cannot_open_it() {
+    let (alice, bob, eve) = (sealer(1), sealer(2), sealer(4));
+    offered(&alice);
+    offered(&eve);
+    let sealed = alice.seal("news", &[offered(&bob)], b"hello").unwrap();
+    assert!(eve.open("news", &sealed).is_err());
+    // Not even the sender, who did not seal to itself
+    assert!(alice.open("news", &sealed).is_err());
+}
+
+#[test]
+fn tampered_ciphertext_fails_to_open() {
+    let (alice, bob) = (sealer(1), sealer(2));
+    offered(&alice);
+    let mut sealed = alice.seal("news", &[offered(&bob)], b"hello").unwrap();
+    // The ciphertext comes last
+    *sealed.last_mut().unwrap() ^= 1;
+    assert!(bob.open("news", &sealed).is_err());
+}
+
+#[test]
+fn payloads_open_only_on_their_topic() {
+    let (alice, bob) = (sealer(1), sealer(2));
+    offered(&alice);
+    let sealed = alice.seal("news", &[offered(&bob)], b"hello").unwrap();
+    assert!(bob.open("gossip", &sealed).is_err());
+}
+
+#[test]
+fn session_keys_signed_by_another_key_are_refused() {
+    let (alice, bob, eve) = (sealer(1), sealer(2), sealer(4));
+    offered(&alice);
+    let mut forged = offered(&eve);
+    forged.owner = *bob.public().as_bytes();
+    assert!(alice.seal("news", &[forged], b"hello").is_err());
+}
+
+#[test]
+fn nothing_is_sealed_before_a_session_key_was_offered() {
+    let (alice, bob) = (sealer(1), sealer(2));
+    assert!(alice.seal("news", &[offered(&bob)], b"hello").is_err());
+}
+
+#[test]
+fn payloads_sealed_to_retired_session_keys_no_longer_open() {
+    let (alice, bob) = (sealer(1), sealer(2));
+    offered(&alice);
+    let sealed = alice.seal("news", &[offered(&bob)], b"hello").unwrap();
+    bob.open("news", &sealed).unwrap();
+    for _ in 0..MAX_SESSION_KEYS {
+        offered(&bob);
+    }
+    assert!(bob.open("news", &sealed).is_err());
+}
+
+#[test]
+fn senders_beyond_the_kept_sessions_still_open() {
+    let (alice, bob) = (sealer(1), sealer(2));
+    let to_bob = [offered(&bob)];
+    offered(&alice);
+    let sealed = alice.seal("news", &to_bob, b"hello").unwrap();
+    bob.open("news", &sealed).unwrap();
+    // Each sender takes a session, pushing out the first one's
+    for i in 0..MAX_SESSIONS as u16 + 1 {
+        let mut seed = [0xff; 32];
+        seed[..2].copy_from_slice(&i.to_be_bytes());
+        let other = Sealer::new(SecretKey::from_bytes(&seed));
+        offered(&other);
+        let sealed = other.seal("news", &to_bob, b"hi").unwrap();
+        bob.open("news", &sealed).unwrap();
+    }
+    assert_eq!(bob.open("news", &sealed).unwrap().payload, b"hello");
+}
+
+// ====================
+// Through a Server
+// ====================
+
+/// A client offering session keys of `sealer` in its auth handshake
+fn sealing_client(server: &server::Server<Bincode>, sealer: Sealer) -> ReconnectingClient {
+    ReconnectingClient::new(server.endpoint().addr(), Bincode)
+        .with_auth(Arc::new(SharedToken::new(TOKEN)))
+        .with_sealer(sealer)
+}
+
+#[tokio::test]
+async fn payloads_are_sealed_to_the_keys_offered_on_connecting() -> Result<()> {
+    let echo = Echo::new(Bincode).with_auth(Arc::new(SharedToken::new(TOKEN)));
+    let server = server::spawn(0, echo).await?;
+    let (alice, bob) = (
+        sealing_client(&server, sealer(1)),
+        sealing_client(&server, sealer(2)),
+    );
+    let bob_key = sealer(2).public();
+    let bob_client = bob.client().await?;
+    let mut pushes = bob_client.subscribe_pushes();
+    bob_client.subscribe("news").await?;
+
+    let alice_client = alice.client().await?;
+    let alice_sealer = alice.sealer().expect("set");
+    alice_client
+        .publish_sealed(alice_sealer, "news", &[bob_key], b"hello")
+        .await?;
+    let payload = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let Message::Publish { payload, .. } = pushes.recv().await.anyerr()? {
+                return Ok::<_, n0_error::AnyError>(payload);
+            }
+        }
+    })
+    .await
+    .std_context("publish was never routed")??;
+    let opened = bob.sealer().expect("set").open("news", &payload)?;
+    assert_eq!(opened.sender, alice_sealer.public());
+    assert_eq!(opened.payload, b"hello");
+
+    // Gone with the connection it was offered on
+    bob.close().await;
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !alice_client.session_keys(&[bob_key]).await?.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok::<_, n0_error::AnyError>(())
+    })
+    .await
+    .std_context("session key outlived its connection")??;
+    let sealed = alice_client
+        .publish_sealed(alice_sealer, "news", &[bob_key], b"hello")
+        .await;
+    assert!(sealed.is_err());
+
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Signatures of envelopes, checked alone and across a relaying server
+
+use std::time::Duration;
+
+use iroh::SecretKey;
+use n0_error::{Result, StdResultExt};
+use wstest::{
+    Bincode, Client, Keyring, Message, MessageEnvelope,
+    server::{self, Echo},
+    signing::{sign, verify},
+};
+
+fn key(seed: u8) -> SecretKey {
+    SecretKey::from_bytes(&[seed; 32])
+}
+
+fn chat(text: &str) -> Message {
+    Message::Chat {
+        from: "alice".to_string(),
+        text: text.to_string(),
+    }
+}
+
+fn signed(body: Message, counter: Option<u64>, author: &SecretKey) -> MessageEnvelope {
+    let mut envelope = MessageEnvelope::new(1, body);
+    envelope.counter = counter;
+    sign(&mut envelope, author).expect("signs");
+    envelope
+}
+
+// ====================
+// Verification
+// ====================
+
+#[test]
+fn signed_envelopes_verify() {
+    let author = key(1);
+    let keys = Keyring::new(key(2)).with_trusted([author.public()]);
+    let envelope = signed(chat("hi"), Some(7), &author);
+    assert_eq!(verify(&envelope, &keys).unwrap(), author.public());
+}
+
+#[test]
+fn tampered_bodies_fail_verification() {
+    let author = key(1);
+    let keys = Keyring::new(key(2)).with_trusted([author.public()]);
+    let mut envelope = signed(chat("hi"), Some(7), &author);
+    envelope.body = chat("bye");
+    assert!(verify(&envelope, &keys).is_err());
+}
+
+#[test]
+fn renumbered_envelopes_fail_verification() {
+    let author = key(1);
+    let keys = Keyring::new(key(2)).with_trusted([author.public()]);
+    let mut envelope = signed(chat("hi"), Some(7), &author);
+    envelope.counter = Some(8);
+    assert!(verify(&envelope, &keys).is_err());
+    envelope.counter = None;
+    assert!(verify(&envelope, &keys).is_err());
+}
+
+#[test]
+fn untrusted_keys_fail_verification() {
+    let keys = Keyring::new(key(2)).with_trusted([key(3).public()]);
+    let envelope = signed(chat("hi"), Some(7), &key(1));
+    assert!(verify(&envelope, &keys).is_err());
+    assert!(verify(&MessageEnvelope::new(1, chat("hi")), &keys).is_err());
+}
+
+// ====================
+// Relaying
+// ====================
+
+#[tokio::test]
+async fn relayed_chat_keeps_the_author_signature() -> Result<()> {
+    let (author, relay) = (key(1), key(2));
+    let echo = Echo::new(Bincode).with_signing(Keyring::new(relay));
+    let server = server::spawn(0, echo).await?;
+    let addr = server.endpoint().addr();
+
+    let alice = Client::connect(addr.clone())
+        .await?
+        .with_signing(Keyring::new(author.clone()));
+    // Trusting the author alone: whatever the server signs itself is refused
+    let bob = Client::connect(addr)
+        .await?
+        .with_signing(Keyring::new(key(3)).with_trusted([author.public()]));
+    let mut pushes = bob.subscribe_pushes();
+    assert!(bob.request(Message::Echo).await.is_err());
+
+    alice.request(chat("hi")).await?;
+    let pushed = tokio::time::timeout(Duration::from_secs(10), pushes.recv())
+        .await
+        .std_context("no relayed chat")?
+        .std_context("pushes closed")?;
+    assert!(matches!(pushed, Message::Chat { text, .. } if text == "hi"));
+
+    server.shutdown(Duration::from_secs(1)).await?;
+    Ok(())
+}